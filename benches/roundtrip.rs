@@ -0,0 +1,104 @@
+use std::io::{Read, Write};
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+
+use bzippr::{compress, decompress, CompressionLevel};
+
+const SEED: u64 = 0x5eed_b212_0000_0002;
+/// About 1 MB, a block and a bit at the largest block size
+const CORPUS_SIZE: usize = 1024 * 1024;
+const LEVELS: [CompressionLevel; 2] = [CompressionLevel::FASTEST, CompressionLevel::BEST];
+
+const WORDS: &str =
+    "the quick brown fox jumps over the lazy dog bzip2 block sorting compression huffman coding and\n";
+
+/// Small xorshift generator so the corpus is identical on every machine without pulling in `rand`
+struct XorShift(u64);
+
+impl XorShift {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+}
+
+/// Half text, a quarter runs of equal bytes and a quarter random bytes, so every stage has
+/// something to do and both well and badly compressible blocks are in it
+fn corpus() -> Vec<u8> {
+    let words: Vec<&str> = WORDS.split_inclusive(' ').collect();
+    let mut rng = XorShift(SEED);
+    let mut data = Vec::with_capacity(CORPUS_SIZE + 300);
+    while data.len() < CORPUS_SIZE / 2 {
+        data.extend_from_slice(words[rng.next() as usize % words.len()].as_bytes());
+    }
+    while data.len() < CORPUS_SIZE * 3 / 4 {
+        let run_length = 4 + rng.next() as usize % 300;
+        data.extend(std::iter::repeat_n(rng.next() as u8, run_length));
+    }
+    while data.len() < CORPUS_SIZE {
+        data.push(rng.next() as u8);
+    }
+    data.truncate(CORPUS_SIZE);
+    data
+}
+
+/// The `bzip2` crate as the reference, a port of libbz2 at the same levels
+fn reference_compress(data: &[u8], level: CompressionLevel) -> Vec<u8> {
+    let level = bzip2::Compression::new(level.block_size().level().into());
+    let mut encoder = bzip2::write::BzEncoder::new(Vec::new(), level);
+    encoder.write_all(data).unwrap();
+    encoder.finish().unwrap()
+}
+
+fn reference_decompress(compressed: &[u8]) -> Vec<u8> {
+    let mut data = Vec::new();
+    bzip2::read::BzDecoder::new(compressed)
+        .read_to_end(&mut data)
+        .unwrap();
+    data
+}
+
+fn bench_compress(c: &mut Criterion) {
+    let mut group = c.benchmark_group("compress");
+    group.sample_size(10);
+    let data = corpus();
+    group.throughput(Throughput::Bytes(data.len() as u64));
+    for level in LEVELS {
+        let digit = level.block_size().level();
+        group.bench_with_input(BenchmarkId::new("bzippr", digit), &data, |b, data| {
+            b.iter(|| compress(data, level))
+        });
+        group.bench_with_input(BenchmarkId::new("bzip2", digit), &data, |b, data| {
+            b.iter(|| reference_compress(data, level))
+        });
+    }
+    group.finish();
+}
+
+fn bench_decompress(c: &mut Criterion) {
+    let mut group = c.benchmark_group("decompress");
+    group.sample_size(10);
+    let data = corpus();
+    group.throughput(Throughput::Bytes(data.len() as u64));
+    for level in LEVELS {
+        let digit = level.block_size().level();
+        let compressed = compress(&data, level);
+        assert_eq!(reference_decompress(&compressed), data);
+        group.bench_with_input(
+            BenchmarkId::new("bzippr", digit),
+            &compressed,
+            |b, compressed| b.iter(|| decompress(compressed).unwrap()),
+        );
+        group.bench_with_input(
+            BenchmarkId::new("bzip2", digit),
+            &compressed,
+            |b, compressed| b.iter(|| reference_decompress(compressed)),
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_compress, bench_decompress);
+criterion_main!(benches);
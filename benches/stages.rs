@@ -0,0 +1,145 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+
+use bzippr::{
+    bits::BitWriter,
+    bwt::BwtEncoded,
+    format::{
+        block_size::BlockSize,
+        writer::{CompressedBlock, ScratchSpace},
+    },
+    huff::HuffmanEncoder,
+    mtf::MtfTransform,
+    rle::RleSequence,
+};
+
+#[path = "../src/stages/strategies/fixtures.rs"]
+mod fixtures;
+
+use fixtures::{random, runs, text};
+
+const STAGE_INPUT_SIZE: usize = 1024 * 1024;
+const BWT_ENCODE_SIZES: [usize; 2] = [100_000, 900_000];
+const BWT_DECODE_SIZES: [usize; 2] = [100_000, 900_000];
+/// Blocks compressed one after the other, to compare fresh buffers for every block with a reused
+/// `ScratchSpace`. Text doesn't shrink in RLE1, so the blocks stay below the smallest block size.
+const SCRATCH_BLOCKS: usize = 8;
+const SCRATCH_BLOCK_LEN: usize = 90_000;
+
+fn inputs(len: usize) -> [(&'static str, Vec<u8>); 3] {
+    [
+        ("text", text(len)),
+        ("random", random(len)),
+        ("runs", runs(len)),
+    ]
+}
+
+fn bench_rle(c: &mut Criterion) {
+    let mut group = c.benchmark_group("rle1");
+    for (name, data) in inputs(STAGE_INPUT_SIZE) {
+        group.throughput(Throughput::Bytes(data.len() as u64));
+        group.bench_with_input(BenchmarkId::new("encode", name), &data, |b, data| {
+            b.iter(|| RleSequence::encode(data))
+        });
+        let encoded = RleSequence::encode(&data);
+        group.bench_with_input(BenchmarkId::new("decode", name), &encoded, |b, encoded| {
+            b.iter(|| encoded.decode())
+        });
+    }
+    group.finish();
+}
+
+fn bench_bwt(c: &mut Criterion) {
+    let mut group = c.benchmark_group("bwt");
+    group.sample_size(10);
+    for size in BWT_ENCODE_SIZES {
+        let data = RleSequence::encode(&text(size));
+        group.throughput(Throughput::Bytes(data.len() as u64));
+        group.bench_with_input(BenchmarkId::new("encode", size), &data, |b, data| {
+            b.iter(|| BwtEncoded::encode(data))
+        });
+    }
+    for size in BWT_DECODE_SIZES {
+        let encoded = BwtEncoded::encode(&RleSequence::encode(&text(size)));
+        group.throughput(Throughput::Bytes(encoded.len() as u64));
+        group.bench_with_input(BenchmarkId::new("decode", size), &encoded, |b, encoded| {
+            b.iter(|| encoded.decode())
+        });
+    }
+    group.finish();
+}
+
+fn bench_mtf(c: &mut Criterion) {
+    let mut group = c.benchmark_group("mtf");
+    for (name, data) in inputs(STAGE_INPUT_SIZE) {
+        group.throughput(Throughput::Bytes(data.len() as u64));
+        group.bench_with_input(BenchmarkId::new("encode", name), &data, |b, data| {
+            b.iter(|| MtfTransform::encode(data))
+        });
+        let encoded = MtfTransform::encode(&data);
+        group.bench_with_input(BenchmarkId::new("decode", name), &encoded, |b, encoded| {
+            b.iter(|| encoded.decode())
+        });
+    }
+    group.finish();
+}
+
+fn bench_huffman(c: &mut Criterion) {
+    let mut group = c.benchmark_group("huffman");
+    for (name, data) in inputs(STAGE_INPUT_SIZE) {
+        let mtf = MtfTransform::encode(&data);
+        group.throughput(Throughput::Bytes(data.len() as u64));
+        group.bench_with_input(BenchmarkId::new("build_table", name), &mtf, |b, mtf| {
+            b.iter(|| HuffmanEncoder::new(mtf))
+        });
+        let encoder = HuffmanEncoder::new(&mtf);
+        let mut writer = BitWriter::with_capacity(data.len());
+        group.bench_with_input(BenchmarkId::new("emit_bits", name), &mtf, |b, mtf| {
+            b.iter(|| {
+                writer.clear();
+                encoder.encode_into(mtf, &mut writer)
+            })
+        });
+    }
+    group.finish();
+}
+
+fn bench_scratch(c: &mut Criterion) {
+    let mut group = c.benchmark_group("scratch");
+    group.sample_size(10);
+    let data = text(SCRATCH_BLOCKS * SCRATCH_BLOCK_LEN);
+    group.throughput(Throughput::Bytes(data.len() as u64));
+    group.bench_with_input("fresh", &data, |b, data| {
+        b.iter(|| {
+            data.chunks(SCRATCH_BLOCK_LEN)
+                .map(|block| {
+                    CompressedBlock::new(block, BlockSize::MIN)
+                        .unwrap()
+                        .bit_len()
+                })
+                .sum::<u64>()
+        })
+    });
+    group.bench_with_input("reused", &data, |b, data| {
+        let mut scratch = ScratchSpace::new();
+        b.iter(|| {
+            data.chunks(SCRATCH_BLOCK_LEN)
+                .map(|block| {
+                    CompressedBlock::with_scratch(block, BlockSize::MIN, &mut scratch)
+                        .unwrap()
+                        .bit_len()
+                })
+                .sum::<u64>()
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    bench_rle,
+    bench_bwt,
+    bench_mtf,
+    bench_huffman,
+    bench_scratch
+);
+criterion_main!(benches);
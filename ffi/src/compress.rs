@@ -0,0 +1,398 @@
+use std::{
+    ffi::{c_int, c_uint},
+    io::Write,
+    mem,
+};
+
+use bzippr_core::{BzEncoder, CompressionLevel};
+
+use crate::{
+    bz_stream, Direction, State, BZ_FINISH, BZ_FINISH_OK, BZ_FLUSH, BZ_FLUSH_OK, BZ_OK,
+    BZ_PARAM_ERROR, BZ_RUN, BZ_RUN_OK, BZ_SEQUENCE_ERROR, BZ_STREAM_END,
+};
+
+/// Most input handed to the encoder at once, so no more than one block is compressed before its
+/// output is handed on
+const MAX_WRITE: usize = 64 * 1024;
+
+pub(crate) struct Compressor {
+    /// `None` once the stream is finished
+    encoder: Option<BzEncoder<Vec<u8>>>,
+    /// Compressed bytes taken from the encoder, `pending_pos` of which went to the caller
+    pending: Vec<u8>,
+    pending_pos: usize,
+    mode: Mode,
+}
+
+/// Where the stream is in the call sequence of `BZ2_bzCompress`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    Running,
+    /// `BZ_FLUSH` until it returns `BZ_RUN_OK`, with `avail_in` what the last call left
+    Flushing {
+        flushed: bool,
+        avail_in: c_uint,
+    },
+    /// `BZ_FINISH` until it returns `BZ_STREAM_END`, with `avail_in` what the last call left
+    Finishing {
+        avail_in: c_uint,
+    },
+    /// After `BZ_STREAM_END`, when only `BZ2_bzCompressEnd` is left
+    Idle,
+}
+
+impl Compressor {
+    /// Hand compressed bytes to the caller and consume input until either runs out, or, when
+    /// flushing or finishing, until there is nothing left to do. Whether anything moved.
+    fn compress(&mut self, strm: &mut bz_stream, input: &[u8], output: &mut [u8]) -> bool {
+        let (mut read, mut written) = (0, 0);
+        loop {
+            written += self.drain(&mut output[written..]);
+            if !self.is_drained() {
+                break;
+            }
+            let Some(encoder) = &mut self.encoder else {
+                break;
+            };
+            if read < input.len() {
+                let len = (input.len() - read).min(MAX_WRITE);
+                encoder
+                    .write_all(&input[read..read + len])
+                    .expect("Writing to a Vec never fails");
+                read += len;
+                continue;
+            }
+            match &mut self.mode {
+                Mode::Flushing { flushed, .. } if !*flushed => {
+                    encoder.flush().expect("Writing to a Vec never fails");
+                    *flushed = true;
+                }
+                Mode::Finishing { .. } => {
+                    let encoder = self.encoder.take().expect("Checked above");
+                    self.pending = encoder.finish().expect("Writing to a Vec never fails");
+                    self.pending_pos = 0;
+                }
+                _ => break,
+            }
+        }
+        strm.advance_in(read);
+        strm.advance_out(written);
+        read > 0 || written > 0
+    }
+
+    /// Copy as many pending bytes as fit into `output`, taking the next ones from the encoder
+    /// once the pending ones are gone
+    fn drain(&mut self, output: &mut [u8]) -> usize {
+        if self.pending_pos == self.pending.len() {
+            self.pending.clear();
+            self.pending_pos = 0;
+            if let Some(encoder) = &mut self.encoder {
+                mem::swap(&mut self.pending, encoder.get_mut());
+            }
+        }
+        let available = &self.pending[self.pending_pos..];
+        let len = available.len().min(output.len());
+        output[..len].copy_from_slice(&available[..len]);
+        self.pending_pos += len;
+        len
+    }
+
+    /// Whether all compressed bytes went to the caller
+    fn is_drained(&self) -> bool {
+        self.pending_pos == self.pending.len()
+            && self.encoder.as_ref().is_none_or(|e| e.get_ref().is_empty())
+    }
+}
+
+/// Prepare `strm` for compression with blocks of `block_size_100k` times 100 000 bytes, 1 to 9.
+/// `verbosity` (0 to 4) and `work_factor` (0 to 250) are checked but have no effect.
+///
+/// # Safety
+///
+/// `strm` is null or points to a `bz_stream`.
+#[no_mangle]
+pub unsafe extern "C" fn BZ2_bzCompressInit(
+    strm: *mut bz_stream,
+    block_size_100k: c_int,
+    verbosity: c_int,
+    work_factor: c_int,
+) -> c_int {
+    let Some(strm) = strm.as_mut() else {
+        return BZ_PARAM_ERROR;
+    };
+    let level = u8::try_from(block_size_100k)
+        .ok()
+        .and_then(|level| CompressionLevel::try_from(level).ok());
+    let (Some(level), 0..=4, 0..=250) = (level, verbosity, work_factor) else {
+        return BZ_PARAM_ERROR;
+    };
+    let compressor = Compressor {
+        encoder: Some(BzEncoder::new(Vec::new(), level)),
+        pending: Vec::new(),
+        pending_pos: 0,
+        mode: Mode::Running,
+    };
+    State::install(strm, Direction::Compress(compressor));
+    BZ_OK
+}
+
+/// Compress from `next_in` to `next_out`, as far as both go. `action` is `BZ_RUN` while there
+/// is input, `BZ_FLUSH` to end the block so far, repeated until it returns `BZ_RUN_OK`, and
+/// `BZ_FINISH` at the end, repeated until it returns `BZ_STREAM_END`. While flushing or
+/// finishing, `avail_in` has to stay what the call that started it left.
+///
+/// # Safety
+///
+/// `strm` is null or initialized by `BZ2_bzCompressInit`, `next_in` points to `avail_in`
+/// readable bytes and `next_out` to `avail_out` writable ones.
+#[no_mangle]
+pub unsafe extern "C" fn BZ2_bzCompress(strm: *mut bz_stream, action: c_int) -> c_int {
+    let Some((strm, Direction::Compress(compressor))) = State::get(strm) else {
+        return BZ_PARAM_ERROR;
+    };
+    let (Some(input), Some(output)) = (strm.input(), strm.output()) else {
+        return BZ_PARAM_ERROR;
+    };
+    match (compressor.mode, action) {
+        (Mode::Running, BZ_RUN) => {
+            return match compressor.compress(strm, input, output) {
+                true => BZ_RUN_OK,
+                false => BZ_PARAM_ERROR,
+            };
+        }
+        (Mode::Running, BZ_FLUSH) => {
+            compressor.mode = Mode::Flushing {
+                flushed: false,
+                avail_in: strm.avail_in,
+            }
+        }
+        (Mode::Running, BZ_FINISH) => {
+            compressor.mode = Mode::Finishing {
+                avail_in: strm.avail_in,
+            }
+        }
+        (Mode::Running, _) => return BZ_PARAM_ERROR,
+        (Mode::Flushing { avail_in, .. }, BZ_FLUSH) | (Mode::Finishing { avail_in }, BZ_FINISH)
+            if avail_in == strm.avail_in => {}
+        _ => return BZ_SEQUENCE_ERROR,
+    }
+    let moved = compressor.compress(strm, input, output);
+    let drained = compressor.is_drained();
+    match &mut compressor.mode {
+        Mode::Flushing { flushed: true, .. } if drained => {
+            compressor.mode = Mode::Running;
+            BZ_RUN_OK
+        }
+        Mode::Flushing { avail_in, .. } => {
+            *avail_in = strm.avail_in;
+            BZ_FLUSH_OK
+        }
+        Mode::Finishing { .. } if !moved => BZ_SEQUENCE_ERROR,
+        Mode::Finishing { .. } if compressor.encoder.is_none() && drained => {
+            compressor.mode = Mode::Idle;
+            BZ_STREAM_END
+        }
+        Mode::Finishing { avail_in } => {
+            *avail_in = strm.avail_in;
+            BZ_FINISH_OK
+        }
+        Mode::Running | Mode::Idle => unreachable!("Only flushing and finishing get here"),
+    }
+}
+
+/// Free the state of `strm`, whether or not the stream was finished
+///
+/// # Safety
+///
+/// `strm` is null or initialized by `BZ2_bzCompressInit`, and isn't used after but for another
+/// init.
+#[no_mangle]
+pub unsafe extern "C" fn BZ2_bzCompressEnd(strm: *mut bz_stream) -> c_int {
+    match State::get(strm) {
+        Some((_, Direction::Compress(_))) => State::remove(strm),
+        _ => BZ_PARAM_ERROR,
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::io::Read;
+
+    use proptest::prelude::*;
+    use test_case::test_case;
+
+    use super::*;
+    use crate::tests::{new_stream, total_in, total_out};
+
+    /// Compress `data` through the C API, handing it over `in_len` bytes at a time with room for
+    /// `out_len` bytes of output per call, like a C program with fixed buffers
+    pub(crate) fn compress_in_chunks(
+        data: &[u8],
+        block_size_100k: c_int,
+        in_len: usize,
+        out_len: usize,
+    ) -> Vec<u8> {
+        let mut strm = new_stream();
+        assert_eq!(
+            unsafe { BZ2_bzCompressInit(&mut strm, block_size_100k, 0, 0) },
+            BZ_OK
+        );
+        let mut out = Vec::new();
+        let mut buf = vec![0; out_len];
+        for chunk in data.chunks(in_len) {
+            strm.next_in = chunk.as_ptr() as *mut _;
+            strm.avail_in = chunk.len() as c_uint;
+            while strm.avail_in > 0 {
+                strm.next_out = buf.as_mut_ptr() as *mut _;
+                strm.avail_out = out_len as c_uint;
+                assert_eq!(unsafe { BZ2_bzCompress(&mut strm, BZ_RUN) }, BZ_RUN_OK);
+                out.extend_from_slice(&buf[..out_len - strm.avail_out as usize]);
+            }
+        }
+        loop {
+            strm.next_out = buf.as_mut_ptr() as *mut _;
+            strm.avail_out = out_len as c_uint;
+            let result = unsafe { BZ2_bzCompress(&mut strm, BZ_FINISH) };
+            out.extend_from_slice(&buf[..out_len - strm.avail_out as usize]);
+            match result {
+                BZ_FINISH_OK => continue,
+                BZ_STREAM_END => break,
+                other => panic!("BZ2_bzCompress returned {other}"),
+            }
+        }
+        assert_eq!(total_in(&strm), data.len() as u64);
+        assert_eq!(total_out(&strm), out.len() as u64);
+        assert_eq!(unsafe { BZ2_bzCompressEnd(&mut strm) }, BZ_OK);
+        out
+    }
+
+    #[test_case(b"", 9, 1, 1; "empty")]
+    #[test_case(b"hello world\n", 9, 5, 3; "hello world")]
+    #[test_case(&include_bytes!("compress.rs").repeat(30), 1, 4096, 5000; "several blocks")]
+    #[test_case(&include_bytes!("compress.rs").repeat(30), 1, 1_000_000, 1; "byte by byte output")]
+    #[test_case(&[0; 2_000_000], 2, 1_000_000, 1_000_000; "large buffers")]
+    fn test_same_as_compress(data: &[u8], block_size_100k: c_int, in_len: usize, out_len: usize) {
+        let level = CompressionLevel::try_from(block_size_100k as u8).unwrap();
+        assert_eq!(
+            compress_in_chunks(data, block_size_100k, in_len, out_len),
+            bzippr_core::compress(data, level)
+        );
+    }
+
+    #[test_case(0, 0, 0; "block size too small")]
+    #[test_case(10, 0, 0; "block size too large")]
+    #[test_case(9, 5, 0; "verbosity")]
+    #[test_case(9, 0, 251; "work factor")]
+    #[test_case(9, -1, -1; "negative")]
+    fn test_init_param_error(block_size_100k: c_int, verbosity: c_int, work_factor: c_int) {
+        let mut strm = new_stream();
+        assert_eq!(
+            unsafe { BZ2_bzCompressInit(&mut strm, block_size_100k, verbosity, work_factor) },
+            BZ_PARAM_ERROR
+        );
+        assert!(strm.state.is_null());
+    }
+
+    #[test]
+    fn test_flush() {
+        let mut strm = new_stream();
+        let mut buf = vec![0; 1000];
+        let mut out = Vec::new();
+        unsafe {
+            assert_eq!(BZ2_bzCompressInit(&mut strm, 9, 0, 0), BZ_OK);
+            for (data, action, result) in [
+                (&b"hello "[..], BZ_FLUSH, BZ_RUN_OK),
+                (b"world\n", BZ_FINISH, BZ_STREAM_END),
+            ] {
+                strm.next_in = data.as_ptr() as *mut _;
+                strm.avail_in = data.len() as c_uint;
+                strm.next_out = buf.as_mut_ptr().add(out.len()) as *mut _;
+                strm.avail_out = (buf.len() - out.len()) as c_uint;
+                assert_eq!(BZ2_bzCompress(&mut strm, action), result);
+                out = buf[..buf.len() - strm.avail_out as usize].to_vec();
+            }
+            assert_eq!(BZ2_bzCompressEnd(&mut strm), BZ_OK);
+        }
+        let mut decoder = bzippr_core::BzDecoder::new(out.as_slice());
+        let mut decompressed = Vec::new();
+        decoder.read_to_end(&mut decompressed).unwrap();
+        assert_eq!(decompressed, b"hello world\n");
+        assert_eq!(decoder.info().blocks, 2);
+    }
+
+    #[test]
+    fn test_flush_without_room() {
+        let mut strm = new_stream();
+        let data = include_bytes!("compress.rs");
+        let mut buf = [0; 10];
+        unsafe {
+            assert_eq!(BZ2_bzCompressInit(&mut strm, 9, 0, 0), BZ_OK);
+            strm.next_in = data.as_ptr() as *mut _;
+            strm.avail_in = data.len() as c_uint;
+            strm.next_out = buf.as_mut_ptr() as *mut _;
+            strm.avail_out = buf.len() as c_uint;
+            assert_eq!(BZ2_bzCompress(&mut strm, BZ_FLUSH), BZ_FLUSH_OK);
+            assert_eq!(strm.avail_out, 0);
+            assert_eq!(BZ2_bzCompress(&mut strm, BZ_RUN), BZ_SEQUENCE_ERROR);
+            assert_eq!(BZ2_bzCompress(&mut strm, BZ_FINISH), BZ_SEQUENCE_ERROR);
+            strm.avail_in += 1;
+            assert_eq!(BZ2_bzCompress(&mut strm, BZ_FLUSH), BZ_SEQUENCE_ERROR);
+            strm.avail_in -= 1;
+            strm.next_out = buf.as_mut_ptr() as *mut _;
+            strm.avail_out = buf.len() as c_uint;
+            assert_eq!(BZ2_bzCompress(&mut strm, BZ_FLUSH), BZ_FLUSH_OK);
+            assert_eq!(BZ2_bzCompressEnd(&mut strm), BZ_OK);
+        }
+    }
+
+    #[test]
+    fn test_sequence_after_stream_end() {
+        let mut strm = new_stream();
+        let mut buf = [0; 100];
+        unsafe {
+            assert_eq!(BZ2_bzCompressInit(&mut strm, 9, 0, 0), BZ_OK);
+            strm.next_out = buf.as_mut_ptr() as *mut _;
+            strm.avail_out = buf.len() as c_uint;
+            assert_eq!(BZ2_bzCompress(&mut strm, BZ_FINISH), BZ_STREAM_END);
+            assert_eq!(BZ2_bzCompress(&mut strm, BZ_FINISH), BZ_SEQUENCE_ERROR);
+            assert_eq!(BZ2_bzCompress(&mut strm, BZ_RUN), BZ_SEQUENCE_ERROR);
+            assert_eq!(BZ2_bzCompressEnd(&mut strm), BZ_OK);
+        }
+    }
+
+    #[test]
+    fn test_run_without_progress() {
+        let mut strm = new_stream();
+        unsafe {
+            assert_eq!(BZ2_bzCompressInit(&mut strm, 9, 0, 0), BZ_OK);
+            assert_eq!(BZ2_bzCompress(&mut strm, BZ_RUN), BZ_PARAM_ERROR);
+            assert_eq!(BZ2_bzCompress(&mut strm, 3), BZ_PARAM_ERROR);
+            assert_eq!(BZ2_bzCompressEnd(&mut strm), BZ_OK);
+        }
+    }
+
+    #[test]
+    fn test_null_buffers() {
+        let mut strm = new_stream();
+        unsafe {
+            assert_eq!(BZ2_bzCompressInit(&mut strm, 9, 0, 0), BZ_OK);
+            strm.avail_in = 10;
+            assert_eq!(BZ2_bzCompress(&mut strm, BZ_RUN), BZ_PARAM_ERROR);
+            assert_eq!(BZ2_bzCompressEnd(&mut strm), BZ_OK);
+        }
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(64))]
+
+        #[test]
+        fn prop_roundtrip(
+            data in prop::collection::vec(prop_oneof![any::<u8>(), Just(b'a')], 0..4096),
+            in_len in 1usize..512,
+            out_len in 1usize..512,
+        ) {
+            let compressed = compress_in_chunks(&data, 1, in_len, out_len);
+            prop_assert_eq!(bzippr_core::decompress(&compressed).unwrap(), data);
+        }
+    }
+}
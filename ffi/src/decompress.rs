@@ -0,0 +1,395 @@
+use std::{
+    ffi::c_int,
+    io::{self, Read},
+};
+
+use bzippr_core::{
+    format::{header::HEADER_LEN, scan::next_magic},
+    BzDecoder, MemoryBudget, ReadError,
+};
+
+use crate::{
+    bz_stream, Direction, State, BZ_DATA_ERROR, BZ_DATA_ERROR_MAGIC, BZ_OK, BZ_PARAM_ERROR,
+    BZ_SEQUENCE_ERROR, BZ_STREAM_END,
+};
+
+pub(crate) struct Decompressor {
+    decoder: BzDecoder<Input>,
+    /// Whether the end of the stream was returned
+    done: bool,
+}
+
+/// Most input moved from the caller to `Input::pending` at a time
+const TAKE_LEN: usize = 64 * 1024;
+
+/// The input of a `Decompressor`, as the reader of its `BzDecoder`.
+///
+/// The decoder decodes a block from its start again whenever it runs out of input in the middle
+/// of it, so input handed over a few bytes at a time would be decoded over and over. Instead, the
+/// caller's input is moved to `pending` and only released to the decoder once a block or end of
+/// stream magic shows that the block before it is complete. Running out of released input fails
+/// with `WouldBlock`, which the decoder returns as is and picks up from once called again.
+struct Input {
+    /// Input of the current `BZ2_bzDecompress` call not taken yet
+    data: *const u8,
+    len: usize,
+    /// Input taken from the caller that the decoder didn't read yet
+    pending: Vec<u8>,
+    /// Bytes taken from the caller over all calls, the last of them in `pending`
+    total: u64,
+    /// Bytes of all input the decoder may read: the stream header, and everything up to the 32
+    /// bits after the last magic found, a block CRC or the stream CRC
+    released: u64,
+    /// Bytes of all input searched for magics
+    scanned: u64,
+}
+
+impl Input {
+    fn new() -> Self {
+        Self {
+            data: std::ptr::null(),
+            len: 0,
+            pending: Vec::new(),
+            total: 0,
+            // the header, so input in another format fails before any magic shows up
+            released: HEADER_LEN as u64,
+            scanned: 0,
+        }
+    }
+
+    /// Bytes of all input the decoder read, the offset of the first byte of `pending`
+    fn handed_over(&self) -> u64 {
+        self.total - self.pending.len() as u64
+    }
+
+    /// Move up to `TAKE_LEN` bytes of the caller's input to `pending`, releasing what the magics
+    /// among them complete
+    fn take_input(&mut self) {
+        let len = self.len.min(TAKE_LEN);
+        // SAFETY: `data` points to `len` bytes of the caller's input during the call, see
+        // `BZ2_bzDecompress`
+        unsafe {
+            self.pending
+                .extend_from_slice(std::slice::from_raw_parts(self.data, len));
+            self.data = self.data.add(len);
+        }
+        self.len -= len;
+        self.total += len as u64;
+
+        // a magic that ends in the new bytes starts at most 47 bits before them
+        let start = self.handed_over();
+        let mut from = (self.scanned.saturating_sub(6).max(start) - start) * 8;
+        while let Some(offset) = next_magic(&self.pending, from) {
+            self.released = self.released.max(start + (offset + 48 + 32).div_ceil(8));
+            from = offset + 1;
+        }
+        self.scanned = self.total;
+    }
+}
+
+impl Read for Input {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let available = loop {
+            let available = (self.released.min(self.total) - self.handed_over()) as usize;
+            match (available, self.len) {
+                (0, 0) => return Err(io::ErrorKind::WouldBlock.into()),
+                (0, _) => self.take_input(),
+                (available, _) => break available,
+            }
+        };
+        let len = buf.len().min(available);
+        buf[..len].copy_from_slice(&self.pending[..len]);
+        self.pending.drain(..len);
+        Ok(len)
+    }
+}
+
+/// Prepare `strm` for decompression, with `small` 1 for the slower inverse BWT that takes less
+/// than a third of the memory, like `bzip2 -s`. `verbosity` (0 to 4) is checked but has no effect.
+///
+/// # Safety
+///
+/// `strm` is null or points to a `bz_stream`.
+#[no_mangle]
+pub unsafe extern "C" fn BZ2_bzDecompressInit(
+    strm: *mut bz_stream,
+    verbosity: c_int,
+    small: c_int,
+) -> c_int {
+    let Some(strm) = strm.as_mut() else {
+        return BZ_PARAM_ERROR;
+    };
+    let budget = match (small, verbosity) {
+        (0, 0..=4) => MemoryBudget::UNLIMITED,
+        (1, 0..=4) => MemoryBudget::SMALL,
+        _ => return BZ_PARAM_ERROR,
+    };
+    let decompressor = Decompressor {
+        decoder: BzDecoder::with_memory_budget(Input::new(), budget).single_stream(),
+        done: false,
+    };
+    State::install(strm, Direction::Decompress(decompressor));
+    BZ_OK
+}
+
+/// Decompress from `next_in` to `next_out`, as far as both go. Returns `BZ_OK` until the end of
+/// the stream, then `BZ_STREAM_END` once all of it is in the output, with `next_in` right after
+/// the stream if it ended in this call's input. Corrupt input fails with `BZ_DATA_ERROR`, input
+/// that isn't a bzip2 stream at all with `BZ_DATA_ERROR_MAGIC`.
+///
+/// # Safety
+///
+/// `strm` is null or initialized by `BZ2_bzDecompressInit`, `next_in` points to `avail_in`
+/// readable bytes and `next_out` to `avail_out` writable ones.
+#[no_mangle]
+pub unsafe extern "C" fn BZ2_bzDecompress(strm: *mut bz_stream) -> c_int {
+    let Some((strm, Direction::Decompress(decompressor))) = State::get(strm) else {
+        return BZ_PARAM_ERROR;
+    };
+    if decompressor.done {
+        return BZ_SEQUENCE_ERROR;
+    }
+    let (Some(input), Some(output)) = (strm.input(), strm.output()) else {
+        return BZ_PARAM_ERROR;
+    };
+    let decoder = &mut decompressor.decoder;
+    let total_before = decoder.get_ref().total;
+    (decoder.get_mut().data, decoder.get_mut().len) = (input.as_ptr(), input.len());
+
+    let mut written = 0;
+    let mut result = BZ_OK;
+    while written < output.len() {
+        match decoder.read(&mut output[written..]) {
+            Ok(0) => {
+                decompressor.done = true;
+                result = BZ_STREAM_END;
+                break;
+            }
+            Ok(len) => written += len,
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+            Err(err) => {
+                result = error_code(&err);
+                break;
+            }
+        }
+    }
+
+    let input_state = decoder.get_mut();
+    (input_state.data, input_state.len) = (std::ptr::null(), 0);
+    let total = input_state.total;
+    let mut taken = (total - total_before) as usize;
+    if decompressor.done {
+        // more than the stream may have been taken, give back what's still there
+        let past_end = total - decoder.info().compressed_len;
+        taken -= past_end.min(taken as u64) as usize;
+    }
+    strm.advance_in(taken);
+    strm.advance_out(written);
+    result
+}
+
+/// Free the state of `strm`, whether or not the stream ended
+///
+/// # Safety
+///
+/// `strm` is null or initialized by `BZ2_bzDecompressInit`, and isn't used after but for another
+/// init.
+#[no_mangle]
+pub unsafe extern "C" fn BZ2_bzDecompressEnd(strm: *mut bz_stream) -> c_int {
+    match State::get(strm) {
+        Some((_, Direction::Decompress(_))) => State::remove(strm),
+        _ => BZ_PARAM_ERROR,
+    }
+}
+
+/// The return code of libbz2 for a failed `read` of the decoder
+fn error_code(err: &io::Error) -> c_int {
+    match err
+        .get_ref()
+        .and_then(|err| err.downcast_ref::<ReadError>())
+    {
+        Some(ReadError::Header(_)) => BZ_DATA_ERROR_MAGIC,
+        _ => BZ_DATA_ERROR,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{ffi::c_uint, fs, path::Path};
+
+    use bzippr_core::{
+        compress,
+        format::scan::{find_magic, BLOCK_MAGIC, END_OF_STREAM_MAGIC},
+        CompressionLevel,
+    };
+    use proptest::prelude::*;
+    use test_case::test_case;
+
+    use super::*;
+    use crate::{
+        compress::tests::compress_in_chunks,
+        tests::{new_stream, total_in, total_out},
+    };
+
+    fn fixture(name: &str) -> Vec<u8> {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("../tests/fixtures")
+            .join(name);
+        fs::read(&path).unwrap_or_else(|err| panic!("Can't read {}: {err}", path.display()))
+    }
+
+    /// Decompress `data` through the C API, handing it over `in_len` bytes at a time with room
+    /// for `out_len` bytes of output per call. The result of the last call and the output.
+    fn decompress_in_chunks(
+        data: &[u8],
+        small: c_int,
+        in_len: usize,
+        out_len: usize,
+    ) -> (c_int, Vec<u8>) {
+        let mut strm = new_stream();
+        assert_eq!(unsafe { BZ2_bzDecompressInit(&mut strm, 0, small) }, BZ_OK);
+        let mut out = Vec::new();
+        let mut buf = vec![0; out_len];
+        let mut result = BZ_OK;
+        let mut chunks = data.chunks(in_len);
+        while result == BZ_OK {
+            let mut last_chunk = false;
+            if strm.avail_in == 0 {
+                let chunk = chunks.next().unwrap_or_default();
+                last_chunk = chunk.is_empty();
+                strm.next_in = chunk.as_ptr() as *mut _;
+                strm.avail_in = chunk.len() as c_uint;
+            }
+            strm.next_out = buf.as_mut_ptr() as *mut _;
+            strm.avail_out = out_len as c_uint;
+            result = unsafe { BZ2_bzDecompress(&mut strm) };
+            let written = out_len - strm.avail_out as usize;
+            out.extend_from_slice(&buf[..written]);
+            // out of input, and the decoder has nothing left either
+            if last_chunk && written == 0 {
+                break;
+            }
+        }
+        assert_eq!(total_out(&strm), out.len() as u64);
+        assert_eq!(unsafe { BZ2_bzDecompressEnd(&mut strm) }, BZ_OK);
+        (result, out)
+    }
+
+    #[test_case("empty.txt.bz2", Vec::new(); "empty")]
+    #[test_case("text.txt.9.bz2", fixture("text.txt"); "text")]
+    #[test_case("binary.bin.bz2", fixture("binary.bin"); "binary")]
+    #[test_case("text_x8.txt.1.bz2", fixture("text.txt").repeat(8); "several blocks")]
+    #[test_case("randomized.bz2", fixture("text.txt").repeat(8); "randomized blocks")]
+    fn test_reference_output(name: &str, original: Vec<u8>) {
+        let compressed = fixture(name);
+        for small in [0, 1] {
+            for (in_len, out_len) in [
+                (5000, 5000),
+                (compressed.len(), 1),
+                (100, 1_000_000),
+                (1, 1_000_000),
+            ] {
+                let (result, out) = decompress_in_chunks(&compressed, small, in_len, out_len);
+                assert_eq!(result, BZ_STREAM_END);
+                assert!(out == original);
+            }
+        }
+    }
+
+    #[test]
+    fn test_releases_whole_blocks() {
+        let data = fixture("text_x8.txt.1.bz2");
+        let mut input = Input::new();
+        let mut released = vec![input.released];
+        for byte in &data {
+            (input.data, input.len) = (byte, 1);
+            input.take_input();
+            released.push(input.released);
+        }
+        released.dedup();
+        // the header, then the end of the CRC after every magic
+        let magics = [BLOCK_MAGIC, END_OF_STREAM_MAGIC].map(|magic| find_magic(&data, magic));
+        let expected: Vec<u64> = [HEADER_LEN as u64]
+            .into_iter()
+            .chain(
+                magics
+                    .concat()
+                    .iter()
+                    .map(|offset| (offset + 80).div_ceil(8)),
+            )
+            .collect();
+        assert_eq!(released, expected);
+        assert_eq!(expected.len(), 5);
+    }
+
+    #[test]
+    fn test_stops_after_the_stream() {
+        let data = [compress(b"hello", CompressionLevel::BEST), b"junk".to_vec()].concat();
+        let mut strm = new_stream();
+        let mut buf = [0; 100];
+        unsafe {
+            assert_eq!(BZ2_bzDecompressInit(&mut strm, 0, 0), BZ_OK);
+            strm.next_in = data.as_ptr() as *mut _;
+            strm.avail_in = data.len() as c_uint;
+            strm.next_out = buf.as_mut_ptr() as *mut _;
+            strm.avail_out = buf.len() as c_uint;
+            assert_eq!(BZ2_bzDecompress(&mut strm), BZ_STREAM_END);
+            assert_eq!(&buf[..5], b"hello");
+            assert_eq!(total_out(&strm), 5);
+            assert_eq!(total_in(&strm), data.len() as u64 - 4);
+            assert_eq!(strm.avail_in, 4);
+            assert_eq!(strm.next_in as *const u8, data[data.len() - 4..].as_ptr());
+            assert_eq!(BZ2_bzDecompress(&mut strm), BZ_SEQUENCE_ERROR);
+            assert_eq!(BZ2_bzDecompressEnd(&mut strm), BZ_OK);
+        }
+    }
+
+    #[test]
+    fn test_truncated() {
+        let data = compress(
+            &include_bytes!("decompress.rs").repeat(30),
+            CompressionLevel::FASTEST,
+        );
+        let (result, out) = decompress_in_chunks(&data[..data.len() - 20], 0, 1000, 1000);
+        assert_eq!(result, BZ_OK);
+        assert!(!out.is_empty());
+    }
+
+    #[test_case(b"PK\x03\x04 not bzip2".to_vec() => BZ_DATA_ERROR_MAGIC; "not bzip2")]
+    #[test_case(b"BZh0".to_vec() => BZ_DATA_ERROR_MAGIC; "block size")]
+    #[test_case({
+        let mut data = compress(b"hello world\n", CompressionLevel::BEST);
+        data[10] ^= 0x01;
+        data
+    } => BZ_DATA_ERROR; "block crc")]
+    fn test_errors(data: Vec<u8>) -> c_int {
+        decompress_in_chunks(&data, 0, 3, 100).0
+    }
+
+    #[test_case(0, 5; "verbosity")]
+    #[test_case(2, 0; "small")]
+    fn test_init_param_error(small: c_int, verbosity: c_int) {
+        let mut strm = new_stream();
+        assert_eq!(
+            unsafe { BZ2_bzDecompressInit(&mut strm, verbosity, small) },
+            BZ_PARAM_ERROR
+        );
+        assert!(strm.state.is_null());
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(64))]
+
+        #[test]
+        fn prop_roundtrip(
+            data in prop::collection::vec(prop_oneof![any::<u8>(), Just(b'a')], 0..4096),
+            in_len in 1usize..512,
+            out_len in 1usize..512,
+        ) {
+            let compressed = compress_in_chunks(&data, 1, 4096, 4096);
+            let (result, out) = decompress_in_chunks(&compressed, 0, in_len * 8, out_len);
+            prop_assert_eq!(result, BZ_STREAM_END);
+            prop_assert_eq!(out, data);
+        }
+    }
+}
@@ -0,0 +1,254 @@
+//! `libbzippr`: the streaming API of libbz2 (`BZ2_bzCompressInit`, `BZ2_bzCompress`,
+//! `BZ2_bzCompressEnd` and their `BZ2_bzDecompress*` counterparts) on top of bzippr, with the
+//! same `bz_stream`, return codes and call sequences, so C programs written against `bzlib.h` can
+//! be linked against it instead, e.g. to run their tests on a memory-safe implementation.
+//!
+//! Differences from libbz2:
+//! - `bzalloc`, `bzfree` and `opaque` are ignored, all memory comes from the Rust allocator.
+//! - `workFactor` and `verbosity` are checked like libbz2 does, but have no effect.
+//! - A stream handed to the functions of the other direction, or copied after init, is refused
+//!   with `BZ_PARAM_ERROR` instead of being undefined behavior.
+//! - After `BZ_STREAM_END` of the decompressor, `next_in` and `avail_in` point right after the
+//!   stream only if that is within the input of the last call, input of earlier calls that
+//!   followed the stream is gone.
+//! - Input is taken from `next_in` until a whole block is there, which is known once the magic of
+//!   the next block or the end of the stream shows up, and the block is decoded after. Until then,
+//!   up to a block of compressed input is held in the stream's state.
+
+use std::ffi::{c_char, c_int, c_uint, c_void};
+
+mod compress;
+mod decompress;
+
+pub use compress::{BZ2_bzCompress, BZ2_bzCompressEnd, BZ2_bzCompressInit};
+pub use decompress::{BZ2_bzDecompress, BZ2_bzDecompressEnd, BZ2_bzDecompressInit};
+
+pub const BZ_RUN: c_int = 0;
+pub const BZ_FLUSH: c_int = 1;
+pub const BZ_FINISH: c_int = 2;
+
+pub const BZ_OK: c_int = 0;
+pub const BZ_RUN_OK: c_int = 1;
+pub const BZ_FLUSH_OK: c_int = 2;
+pub const BZ_FINISH_OK: c_int = 3;
+pub const BZ_STREAM_END: c_int = 4;
+pub const BZ_SEQUENCE_ERROR: c_int = -1;
+pub const BZ_PARAM_ERROR: c_int = -2;
+pub const BZ_MEM_ERROR: c_int = -3;
+pub const BZ_DATA_ERROR: c_int = -4;
+pub const BZ_DATA_ERROR_MAGIC: c_int = -5;
+
+/// The `bz_stream` of `bzlib.h`, field for field
+#[repr(C)]
+#[allow(non_camel_case_types)]
+pub struct bz_stream {
+    pub next_in: *mut c_char,
+    pub avail_in: c_uint,
+    pub total_in_lo32: c_uint,
+    pub total_in_hi32: c_uint,
+
+    pub next_out: *mut c_char,
+    pub avail_out: c_uint,
+    pub total_out_lo32: c_uint,
+    pub total_out_hi32: c_uint,
+
+    pub state: *mut c_void,
+
+    pub bzalloc: Option<unsafe extern "C" fn(*mut c_void, c_int, c_int) -> *mut c_void>,
+    pub bzfree: Option<unsafe extern "C" fn(*mut c_void, *mut c_void)>,
+    pub opaque: *mut c_void,
+}
+
+impl bz_stream {
+    /// The input the caller handed over, `None` for a null `next_in` with input available
+    ///
+    /// # Safety
+    ///
+    /// `next_in` has to point to `avail_in` readable bytes.
+    unsafe fn input<'a>(&self) -> Option<&'a [u8]> {
+        match (self.next_in.is_null(), self.avail_in) {
+            (_, 0) => Some(&[]),
+            (true, _) => None,
+            (false, len) => Some(std::slice::from_raw_parts(
+                self.next_in as *const u8,
+                len as usize,
+            )),
+        }
+    }
+
+    /// The space for output the caller handed over, `None` for a null `next_out` with space
+    /// available
+    ///
+    /// # Safety
+    ///
+    /// `next_out` has to point to `avail_out` writable bytes.
+    unsafe fn output<'a>(&mut self) -> Option<&'a mut [u8]> {
+        match (self.next_out.is_null(), self.avail_out) {
+            (_, 0) => Some(&mut []),
+            (true, _) => None,
+            (false, len) => Some(std::slice::from_raw_parts_mut(
+                self.next_out as *mut u8,
+                len as usize,
+            )),
+        }
+    }
+
+    /// Mark `len` bytes of the input as consumed
+    fn advance_in(&mut self, len: usize) {
+        // SAFETY: `len` bytes are within the input, so the pointer stays inside it or one past
+        unsafe { self.next_in = self.next_in.add(len) };
+        self.avail_in -= len as c_uint;
+        add_total(&mut self.total_in_lo32, &mut self.total_in_hi32, len);
+    }
+
+    /// Mark `len` bytes of the output as written
+    fn advance_out(&mut self, len: usize) {
+        // SAFETY: `len` bytes are within the output, so the pointer stays inside it or one past
+        unsafe { self.next_out = self.next_out.add(len) };
+        self.avail_out -= len as c_uint;
+        add_total(&mut self.total_out_lo32, &mut self.total_out_hi32, len);
+    }
+}
+
+/// Add `len` to the 64 bit counter split into `lo` and `hi`
+fn add_total(lo: &mut c_uint, hi: &mut c_uint, len: usize) {
+    let total = ((*hi as u64) << 32 | *lo as u64) + len as u64;
+    *lo = total as c_uint;
+    *hi = (total >> 32) as c_uint;
+}
+
+/// What `bz_stream::state` points to
+struct State {
+    /// The stream the state was made for, to refuse copies of it
+    strm: *const bz_stream,
+    direction: Direction,
+}
+
+// only ever boxed as part of `State`, so the smaller variant wastes no stack space
+#[allow(clippy::large_enum_variant)]
+enum Direction {
+    Compress(compress::Compressor),
+    Decompress(decompress::Decompressor),
+}
+
+impl State {
+    /// Box the state and hand it to `strm`
+    fn install(strm: &mut bz_stream, direction: Direction) {
+        let state = Box::new(Self { strm, direction });
+        strm.state = Box::into_raw(state) as *mut c_void;
+        (strm.total_in_lo32, strm.total_in_hi32) = (0, 0);
+        (strm.total_out_lo32, strm.total_out_hi32) = (0, 0);
+    }
+
+    /// The state of `strm`, `None` if it has none or one made for another stream
+    ///
+    /// # Safety
+    ///
+    /// `strm` is null or valid, with a state that is null or was installed by `install`.
+    unsafe fn get<'a>(strm: *mut bz_stream) -> Option<(&'a mut bz_stream, &'a mut Direction)> {
+        let strm = strm.as_mut()?;
+        let state = (strm.state as *mut Self).as_mut()?;
+        if !std::ptr::eq(state.strm, strm) {
+            return None;
+        }
+        Some((strm, &mut state.direction))
+    }
+
+    /// Drop the state of `strm`
+    ///
+    /// # Safety
+    ///
+    /// Same as `get`, and the state isn't used after.
+    unsafe fn remove(strm: *mut bz_stream) -> c_int {
+        if State::get(strm).is_none() {
+            return BZ_PARAM_ERROR;
+        }
+        let strm = &mut *strm;
+        drop(Box::from_raw(strm.state as *mut Self));
+        strm.state = std::ptr::null_mut();
+        BZ_OK
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::mem::{align_of, size_of};
+
+    use super::*;
+
+    /// A zeroed stream, like `bz_stream strm = {0};` in C
+    pub(crate) fn new_stream() -> bz_stream {
+        bz_stream {
+            next_in: std::ptr::null_mut(),
+            avail_in: 0,
+            total_in_lo32: 0,
+            total_in_hi32: 0,
+            next_out: std::ptr::null_mut(),
+            avail_out: 0,
+            total_out_lo32: 0,
+            total_out_hi32: 0,
+            state: std::ptr::null_mut(),
+            bzalloc: None,
+            bzfree: None,
+            opaque: std::ptr::null_mut(),
+        }
+    }
+
+    pub(crate) fn total_in(strm: &bz_stream) -> u64 {
+        (strm.total_in_hi32 as u64) << 32 | strm.total_in_lo32 as u64
+    }
+
+    pub(crate) fn total_out(strm: &bz_stream) -> u64 {
+        (strm.total_out_hi32 as u64) << 32 | strm.total_out_lo32 as u64
+    }
+
+    #[cfg(target_pointer_width = "64")]
+    #[test]
+    fn test_layout_of_bzlib_h() {
+        assert_eq!(size_of::<bz_stream>(), 80);
+        assert_eq!(align_of::<bz_stream>(), 8);
+        assert_eq!(std::mem::offset_of!(bz_stream, next_out), 24);
+        assert_eq!(std::mem::offset_of!(bz_stream, state), 48);
+        assert_eq!(std::mem::offset_of!(bz_stream, opaque), 72);
+    }
+
+    #[test]
+    fn test_add_total() {
+        let (mut lo, mut hi) = (u32::MAX - 1, 0);
+        add_total(&mut lo, &mut hi, 3);
+        assert_eq!((lo, hi), (1, 1));
+    }
+
+    #[test]
+    fn test_copied_stream() {
+        let mut strm = new_stream();
+        assert_eq!(unsafe { BZ2_bzDecompressInit(&mut strm, 0, 0) }, BZ_OK);
+        let mut copy = bz_stream { ..strm };
+        assert_eq!(unsafe { BZ2_bzDecompress(&mut copy) }, BZ_PARAM_ERROR);
+        assert_eq!(unsafe { BZ2_bzDecompressEnd(&mut copy) }, BZ_PARAM_ERROR);
+        assert_eq!(unsafe { BZ2_bzDecompressEnd(&mut strm) }, BZ_OK);
+        assert!(strm.state.is_null());
+    }
+
+    #[test]
+    fn test_other_direction() {
+        let mut strm = new_stream();
+        assert_eq!(unsafe { BZ2_bzCompressInit(&mut strm, 9, 0, 0) }, BZ_OK);
+        assert_eq!(unsafe { BZ2_bzDecompress(&mut strm) }, BZ_PARAM_ERROR);
+        assert_eq!(unsafe { BZ2_bzDecompressEnd(&mut strm) }, BZ_PARAM_ERROR);
+        assert_eq!(unsafe { BZ2_bzCompressEnd(&mut strm) }, BZ_OK);
+    }
+
+    #[test]
+    fn test_null_stream() {
+        let null = std::ptr::null_mut();
+        unsafe {
+            assert_eq!(BZ2_bzCompressInit(null, 9, 0, 0), BZ_PARAM_ERROR);
+            assert_eq!(BZ2_bzCompress(null, BZ_RUN), BZ_PARAM_ERROR);
+            assert_eq!(BZ2_bzCompressEnd(null), BZ_PARAM_ERROR);
+            assert_eq!(BZ2_bzDecompressInit(null, 0, 0), BZ_PARAM_ERROR);
+            assert_eq!(BZ2_bzDecompress(null), BZ_PARAM_ERROR);
+            assert_eq!(BZ2_bzDecompressEnd(null), BZ_PARAM_ERROR);
+        }
+    }
+}
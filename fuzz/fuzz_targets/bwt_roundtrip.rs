@@ -0,0 +1,9 @@
+#![no_main]
+
+use bzippr::{bwt::BwtEncoded, rle::RleSequence};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let sequence = RleSequence::from_encoded(data.to_vec());
+    assert_eq!(BwtEncoded::encode(&sequence).decode(), sequence);
+});
@@ -0,0 +1,13 @@
+#![no_main]
+
+use bzippr::{compress, decompress, CompressionLevel};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    // the first byte picks the level, so the block size digit in the header varies too
+    let Some((&level, data)) = data.split_first() else {
+        return;
+    };
+    let level = CompressionLevel::ALL[level as usize % CompressionLevel::ALL.len()];
+    assert_eq!(decompress(&compress(data, level)).unwrap(), data);
+});
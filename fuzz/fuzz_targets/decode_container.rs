@@ -0,0 +1,27 @@
+#![no_main]
+
+use std::io::Read;
+
+use bzippr::{format::recover::recover_blocks, BzDecoder, MemoryBudget};
+use libfuzzer_sys::fuzz_target;
+
+fn read_all(mut decoder: BzDecoder<&[u8]>) -> std::io::Result<Vec<u8>> {
+    let mut out = Vec::new();
+    decoder.read_to_end(&mut out)?;
+    Ok(out)
+}
+
+fuzz_target!(|data: &[u8]| {
+    let decompressed = bzippr::decompress(data);
+    let streamed = read_all(BzDecoder::new(data));
+    let small = read_all(BzDecoder::with_memory_budget(data, MemoryBudget::SMALL));
+    // salvaging blocks scans for block magics anywhere in the input, so it also sees the blocks
+    // the decoders above stopped before
+    recover_blocks(data);
+
+    // the streaming decoder, with either inverse BWT, reads what the one-shot reader reads
+    if let Ok(decompressed) = decompressed {
+        assert_eq!(streamed.unwrap(), decompressed);
+        assert_eq!(small.unwrap(), decompressed);
+    }
+});
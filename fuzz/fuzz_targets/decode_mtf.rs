@@ -0,0 +1,54 @@
+#![no_main]
+
+use bzippr::mtf::{MtfIndex, MtfTransform};
+use libfuzzer_sys::fuzz_target;
+
+/// Runs double in length with every RUNB, skip inputs that would decode to more than this
+const MAX_DECODED_LEN: usize = 1 << 24;
+
+fuzz_target!(|data: &[u8]| {
+    // the first two bytes are the length of the stack, little endian, then the stack, then one
+    // index per byte. The stack is taken as it comes, so it may be unsorted, hold duplicates, be
+    // too short for the indices or longer than any alphabet.
+    let Some((len_bytes, rest)) = data.split_first_chunk::<2>() else {
+        return;
+    };
+    let stack_len = u16::from_le_bytes(*len_bytes) as usize;
+    let (stack, indices) = rest.split_at(stack_len.min(rest.len()));
+    let indices: Vec<MtfIndex> = indices
+        .iter()
+        .map(|&byte| match byte {
+            0 => MtfIndex::RunA,
+            1 => MtfIndex::RunB,
+            byte => MtfIndex::Val(byte - 1),
+        })
+        .collect();
+
+    // what `from_parts` promises to check, which rules out stacks of more than 256 bytes
+    let ascending = stack.windows(2).all(|pair| pair[0] < pair[1]);
+    let in_stack = indices.iter().all(|index| match index {
+        MtfIndex::Val(value) => (*value as usize) < stack.len(),
+        _ => true,
+    });
+    let valid = ascending && in_stack && (!stack.is_empty() || indices.is_empty());
+
+    let mtf = match MtfTransform::from_parts(indices, stack.to_vec()) {
+        Ok(mtf) => mtf,
+        Err(_) => {
+            assert!(!valid, "valid parts were refused");
+            return;
+        }
+    };
+    assert!(valid, "invalid parts were accepted");
+    let Ok(len) = mtf.decoded_len() else {
+        assert!(mtf.decode().is_err());
+        return;
+    };
+    if len > MAX_DECODED_LEN {
+        return;
+    }
+    // `from_parts` checked everything that could go wrong
+    let decoded = mtf.decode().unwrap();
+    assert_eq!(decoded.len(), len);
+    assert!(decoded.iter().all(|byte| stack.binary_search(byte).is_ok()));
+});
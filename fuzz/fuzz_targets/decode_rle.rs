@@ -0,0 +1,19 @@
+#![no_main]
+
+use bzippr::rle::RleSequence;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let sequence = RleSequence::from_encoded(data.to_vec());
+    assert_eq!(
+        RleSequence::try_from(data).is_ok(),
+        sequence.validate().is_ok()
+    );
+    let Ok(decoded) = sequence.decode() else {
+        return;
+    };
+    if let Ok(strict) = sequence.try_decode(true) {
+        assert_eq!(sequence.validate(), Ok(strict.len()));
+        assert_eq!(strict, decoded);
+    }
+});
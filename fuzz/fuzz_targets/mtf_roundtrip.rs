@@ -0,0 +1,8 @@
+#![no_main]
+
+use bzippr::mtf::MtfTransform;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    assert_eq!(MtfTransform::encode(data).decode().unwrap(), data);
+});
@@ -0,0 +1,16 @@
+#![no_main]
+
+use bzippr::rle::{RleEncoder, RleSequence};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let encoded = RleSequence::encode(data);
+    assert_eq!(encoded.decode().unwrap(), data);
+
+    // feeding the same data in uneven chunks must not change the encoding
+    let mut encoder = RleEncoder::new();
+    for chunk in data.chunks(7) {
+        encoder.push(chunk);
+    }
+    assert_eq!(encoder.finish(), encoded);
+});
@@ -0,0 +1,53 @@
+"""Time bzippr against the standard library's bz2 module, which uses libbz2, on the same input.
+
+    python bench.py [FILE ...] [--level N] [--repeat N]
+
+Without files, a few megabytes of generated text are used.
+"""
+
+import argparse
+import bz2
+import time
+
+import bzippr
+
+
+def best_of(repeat, func, *args):
+    best = float("inf")
+    for _ in range(repeat):
+        start = time.perf_counter()
+        result = func(*args)
+        best = min(best, time.perf_counter() - start)
+    return best, result
+
+
+def main():
+    parser = argparse.ArgumentParser(description=__doc__.splitlines()[0])
+    parser.add_argument("files", nargs="*")
+    parser.add_argument("--level", type=int, default=9)
+    parser.add_argument("--repeat", type=int, default=3)
+    args = parser.parse_args()
+
+    inputs = []
+    for name in args.files:
+        with open(name, "rb") as f:
+            inputs.append((name, f.read()))
+    if not inputs:
+        text = b"".join(b"%d the quick brown fox jumps over the lazy dog\n" % i for i in range(100_000))
+        inputs.append(("generated text", text))
+
+    print(f"{'input':<24} {'module':<8} {'ratio':>7} {'compress':>12} {'decompress':>12}")
+    for name, data in inputs:
+        for module in (bz2, bzippr):
+            compress_time, compressed = best_of(args.repeat, module.compress, data, args.level)
+            decompress_time, decompressed = best_of(args.repeat, module.decompress, compressed)
+            assert decompressed == data
+            mib = len(data) / 2**20
+            print(
+                f"{name[-24:]:<24} {module.__name__:<8} {len(compressed) / len(data):>7.3f} "
+                f"{mib / compress_time:>7.1f} MiB/s {mib / decompress_time:>7.1f} MiB/s"
+            )
+
+
+if __name__ == "__main__":
+    main()
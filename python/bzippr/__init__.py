@@ -0,0 +1,208 @@
+"""bzip2 compression from scratch in Rust, with the API of the standard library's bz2 module.
+
+``compress``, ``decompress``, ``BZ2Compressor``, also called ``Compressor``, and
+``BZ2Decompressor`` are native, ``BZ2File`` and ``open`` wrap them in file objects the way ``bz2``
+does, so ``import bzippr as bz2`` switches a program, a test suite or a benchmark over.
+"""
+
+import io
+import os
+
+try:
+    from compression._common._streams import BaseStream, DecompressReader
+except ImportError:
+    from _compression import BaseStream, DecompressReader
+
+from ._bzippr import (
+    BZ2Compressor,
+    BZ2Decompressor,
+    BzipprError,
+    Compressor,
+    compress,
+    decompress,
+)
+
+__all__ = [
+    "BZ2Compressor",
+    "BZ2Decompressor",
+    "BZ2File",
+    "BzipprError",
+    "Compressor",
+    "compress",
+    "decompress",
+    "open",
+]
+
+_MODE_CLOSED = 0
+_MODE_READ = 1
+_MODE_WRITE = 3
+
+
+class BZ2File(BaseStream):
+    """A file object for ``.bz2`` data, like ``bz2.BZ2File``.
+
+    ``filename`` is a path or a file object to read from or write to. ``mode`` is ``"r"`` to
+    read, which takes any number of streams back to back, or ``"w"``, ``"x"`` or ``"a"`` to write
+    one stream, with blocks of ``compresslevel`` times 100 000 bytes.
+    """
+
+    def __init__(self, filename, mode="r", *, compresslevel=9):
+        self._fp = None
+        self._close_fp = False
+        self._mode = _MODE_CLOSED
+
+        if not 1 <= compresslevel <= 9:
+            raise ValueError("compresslevel must be between 1 and 9")
+        if mode in ("", "r", "rb"):
+            mode, mode_code = "rb", _MODE_READ
+        elif mode in ("w", "wb", "x", "xb", "a", "ab"):
+            mode, mode_code = mode.rstrip("b") + "b", _MODE_WRITE
+            self._compressor = BZ2Compressor(compresslevel)
+        else:
+            raise ValueError(f"Invalid mode: {mode!r}")
+
+        if isinstance(filename, (str, bytes, os.PathLike)):
+            self._fp = io.open(filename, mode)
+            self._close_fp = True
+        elif hasattr(filename, "read") or hasattr(filename, "write"):
+            self._fp = filename
+        else:
+            raise TypeError("filename must be a str, bytes, file or PathLike object")
+        self._mode = mode_code
+
+        if mode_code == _MODE_READ:
+            raw = DecompressReader(self._fp, BZ2Decompressor, trailing_error=OSError)
+            self._buffer = io.BufferedReader(raw)
+        else:
+            self._pos = 0
+
+    def close(self):
+        """Finish the stream when writing, and close the file if it was opened by name."""
+        if self.closed:
+            return
+        try:
+            if self._mode == _MODE_READ:
+                self._buffer.close()
+            elif self._mode == _MODE_WRITE:
+                self._fp.write(self._compressor.flush())
+                self._compressor = None
+        finally:
+            try:
+                if self._close_fp:
+                    self._fp.close()
+            finally:
+                self._fp = None
+                self._close_fp = False
+                self._mode = _MODE_CLOSED
+                self._buffer = None
+
+    @property
+    def closed(self):
+        return self._mode == _MODE_CLOSED
+
+    @property
+    def name(self):
+        self._check_not_closed()
+        return self._fp.name
+
+    @property
+    def mode(self):
+        return "wb" if self._mode == _MODE_WRITE else "rb"
+
+    def fileno(self):
+        self._check_not_closed()
+        return self._fp.fileno()
+
+    def seekable(self):
+        return self.readable() and self._buffer.seekable()
+
+    def readable(self):
+        self._check_not_closed()
+        return self._mode == _MODE_READ
+
+    def writable(self):
+        self._check_not_closed()
+        return self._mode == _MODE_WRITE
+
+    def peek(self, n=0):
+        self._check_can_read()
+        return self._buffer.peek(n)
+
+    def read(self, size=-1):
+        self._check_can_read()
+        return self._buffer.read(size)
+
+    def read1(self, size=-1):
+        self._check_can_read()
+        if size < 0:
+            size = io.DEFAULT_BUFFER_SIZE
+        return self._buffer.read1(size)
+
+    def readinto(self, b):
+        self._check_can_read()
+        return self._buffer.readinto(b)
+
+    def readline(self, size=-1):
+        if not isinstance(size, int):
+            if not hasattr(size, "__index__"):
+                raise TypeError("Integer argument expected")
+            size = size.__index__()
+        self._check_can_read()
+        return self._buffer.readline(size)
+
+    def readlines(self, size=-1):
+        if not isinstance(size, int):
+            if not hasattr(size, "__index__"):
+                raise TypeError("Integer argument expected")
+            size = size.__index__()
+        self._check_can_read()
+        return self._buffer.readlines(size)
+
+    def write(self, data):
+        """Compress ``data``, any bytes-like object, and return the number of bytes it had."""
+        self._check_can_write()
+        if not isinstance(data, bytes):
+            data = memoryview(data).tobytes()
+        self._fp.write(self._compressor.compress(data))
+        self._pos += len(data)
+        return len(data)
+
+    def writelines(self, seq):
+        return BaseStream.writelines(self, seq)
+
+    def seek(self, offset, whence=io.SEEK_SET):
+        """Move to ``offset`` in the decompressed data, which decompresses everything up to it
+        and, backwards, everything from the start of the file again."""
+        self._check_can_seek()
+        return self._buffer.seek(offset, whence)
+
+    def tell(self):
+        self._check_not_closed()
+        if self._mode == _MODE_READ:
+            return self._buffer.tell()
+        return self._pos
+
+
+def open(filename, mode="rb", compresslevel=9, encoding=None, errors=None, newline=None):
+    """Open a ``.bz2`` file in binary or text mode, like ``bz2.open``.
+
+    ``mode`` is one of ``"r"``, ``"w"``, ``"x"`` or ``"a"``, binary by default or with ``"b"``,
+    text with ``"t"``, in which case ``encoding``, ``errors`` and ``newline`` go to the
+    ``io.TextIOWrapper`` around the file.
+    """
+    if "t" in mode:
+        if "b" in mode:
+            raise ValueError(f"Invalid mode: {mode!r}")
+    else:
+        if encoding is not None:
+            raise ValueError("Argument 'encoding' not supported in binary mode")
+        if errors is not None:
+            raise ValueError("Argument 'errors' not supported in binary mode")
+        if newline is not None:
+            raise ValueError("Argument 'newline' not supported in binary mode")
+
+    binary_file = BZ2File(filename, mode.replace("t", ""), compresslevel=compresslevel)
+    if "t" in mode:
+        encoding = io.text_encoding(encoding)
+        return io.TextIOWrapper(binary_file, encoding, errors, newline)
+    return binary_file
@@ -0,0 +1,378 @@
+//! The native part of the `bzippr` Python package: `compress`, `decompress`, `BZ2Compressor`
+//! and `BZ2Decompressor` with the signatures and semantics of the standard library's `bz2`
+//! module, on top of bzippr instead of libbz2. `Compressor` is another name for
+//! `BZ2Compressor`. `bzippr/__init__.py` adds `BZ2File` and `open`
+//! on top of them, so `import bzippr as bz2` is all a benchmark or test needs to switch.
+//!
+//! The same exceptions are raised as by `bz2`: `ValueError` for a bad `compresslevel` or input
+//! that ends too early, `EOFError` for data past the end of a stream, and `BzipprError`, a
+//! subclass of `OSError`, for corrupt data, with the reason in its message.
+
+use std::{
+    io::{self, Read, Write},
+    sync::Mutex,
+};
+
+use bzippr_core::{format::header::HeaderError, BzDecoder, BzEncoder, CompressionLevel, Error};
+use pyo3::{
+    create_exception,
+    exceptions::{PyEOFError, PyOSError, PyValueError},
+    prelude::*,
+    types::PyBytes,
+};
+
+create_exception!(
+    _bzippr,
+    BzipprError,
+    PyOSError,
+    "Corrupt bzip2 data, an `OSError` like the ones the `bz2` module raises"
+);
+
+/// Most output decoded at once while `max_length` leaves room for more
+const MAX_READ: usize = 64 * 1024;
+
+fn compression_level(compresslevel: i32) -> PyResult<CompressionLevel> {
+    u8::try_from(compresslevel)
+        .ok()
+        .and_then(|level| CompressionLevel::try_from(level).ok())
+        .ok_or_else(|| PyValueError::new_err("compresslevel must be between 1 and 9"))
+}
+
+/// Compress `data` into one `.bz2` stream, like `bz2.compress`
+#[pyfunction]
+#[pyo3(signature = (data, compresslevel = 9))]
+fn compress<'py>(
+    py: Python<'py>,
+    data: &[u8],
+    compresslevel: i32,
+) -> PyResult<Bound<'py, PyBytes>> {
+    let level = compression_level(compresslevel)?;
+    let compressed = py.detach(|| bzippr_core::compress(data, level));
+    Ok(PyBytes::new(py, &compressed))
+}
+
+/// Decompress `data`, any number of `.bz2` streams back to back, like `bz2.decompress`
+#[pyfunction]
+fn decompress<'py>(py: Python<'py>, data: &[u8]) -> PyResult<Bound<'py, PyBytes>> {
+    if data.is_empty() {
+        return Ok(PyBytes::new(py, b""));
+    }
+    match py.detach(|| bzippr_core::decompress(data)) {
+        Ok(decompressed) => Ok(PyBytes::new(py, &decompressed)),
+        Err(Error::UnexpectedEof { .. } | Error::InvalidHeader(HeaderError::TruncatedHeader)) => {
+            Err(PyValueError::new_err(
+                "Compressed data ended before the end-of-stream marker was reached",
+            ))
+        }
+        Err(err) => Err(BzipprError::new_err(err.to_string())),
+    }
+}
+
+/// Compresses the data handed to `compress` into one `.bz2` stream, which `flush` ends, like
+/// `bz2.BZ2Compressor`
+#[pyclass(module = "bzippr")]
+struct BZ2Compressor {
+    /// `None` once flushed. Python classes have to be `Sync`, but every method takes `&mut self`
+    /// anyway, so the lock is never taken.
+    encoder: Mutex<Option<BzEncoder<Vec<u8>>>>,
+}
+
+#[pymethods]
+impl BZ2Compressor {
+    #[new]
+    #[pyo3(signature = (compresslevel = 9))]
+    fn new(compresslevel: i32) -> PyResult<Self> {
+        let level = compression_level(compresslevel)?;
+        Ok(Self {
+            encoder: Mutex::new(Some(BzEncoder::new(Vec::new(), level))),
+        })
+    }
+
+    /// Add `data` to the input and return the compressed bytes of the blocks it completed,
+    /// often none
+    fn compress<'py>(&mut self, py: Python<'py>, data: &[u8]) -> PyResult<Bound<'py, PyBytes>> {
+        let encoder = self.encoder().as_mut().ok_or_else(flushed)?;
+        py.detach(|| encoder.write_all(data))
+            .expect("Writing to a Vec never fails");
+        let compressed = PyBytes::new(py, encoder.get_ref());
+        encoder.get_mut().clear();
+        Ok(compressed)
+    }
+
+    /// Compress the rest of the input and return the end of the stream. The compressor can't be
+    /// used after.
+    fn flush<'py>(&mut self, py: Python<'py>) -> PyResult<Bound<'py, PyBytes>> {
+        let encoder = self.encoder().take().ok_or_else(flushed)?;
+        let compressed = py
+            .detach(|| encoder.finish())
+            .expect("Writing to a Vec never fails");
+        Ok(PyBytes::new(py, &compressed))
+    }
+}
+
+impl BZ2Compressor {
+    fn encoder(&mut self) -> &mut Option<BzEncoder<Vec<u8>>> {
+        self.encoder
+            .get_mut()
+            .expect("Nothing panics while holding the lock")
+    }
+}
+
+fn flushed() -> PyErr {
+    PyValueError::new_err("Compressor has been flushed")
+}
+
+/// Decompresses one `.bz2` stream handed to `decompress` in pieces, like `bz2.BZ2Decompressor`.
+/// Whatever follows the stream ends up in `unused_data`.
+#[pyclass(module = "bzippr")]
+struct BZ2Decompressor {
+    /// In a `Mutex` for the same reason as the encoder of `BZ2Compressor`
+    decoder: Mutex<BzDecoder<Input>>,
+    /// Whether the end of the stream was reached
+    #[pyo3(get)]
+    eof: bool,
+    /// Whether `decompress` needs more input to return anything more
+    #[pyo3(get)]
+    needs_input: bool,
+    unused_data: Vec<u8>,
+}
+
+/// The input of a `BZ2Decompressor` from the first byte the decoder didn't consume yet, `pos`
+/// bytes of which it read into its buffer. Running out of it fails with `WouldBlock`, so the
+/// decoder stops until more arrives.
+struct Input {
+    data: Vec<u8>,
+    pos: usize,
+    /// Offset of the first byte of `data` in the whole input
+    offset: u64,
+}
+
+impl Input {
+    /// Drop the bytes before `offset`, which the decoder consumed
+    fn consume_to(&mut self, offset: u64) {
+        let len = (offset - self.offset) as usize;
+        self.data.drain(..len);
+        self.pos -= len;
+        self.offset = offset;
+    }
+}
+
+impl Read for Input {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let available = &self.data[self.pos..];
+        if available.is_empty() {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        let len = available.len().min(buf.len());
+        buf[..len].copy_from_slice(&available[..len]);
+        self.pos += len;
+        Ok(len)
+    }
+}
+
+#[pymethods]
+impl BZ2Decompressor {
+    #[new]
+    fn new() -> Self {
+        let input = Input {
+            data: Vec::new(),
+            pos: 0,
+            offset: 0,
+        };
+        Self {
+            decoder: Mutex::new(BzDecoder::new(input).single_stream()),
+            eof: false,
+            needs_input: true,
+            unused_data: Vec::new(),
+        }
+    }
+
+    /// Add `data` to the input and return what it decompresses to so far, at most `max_length`
+    /// bytes unless that is negative. With more output than that, `needs_input` is false and the
+    /// next call returns more even without new input.
+    #[pyo3(signature = (data, max_length = -1))]
+    fn decompress<'py>(
+        &mut self,
+        py: Python<'py>,
+        data: &[u8],
+        max_length: isize,
+    ) -> PyResult<Bound<'py, PyBytes>> {
+        if self.eof {
+            return Err(PyEOFError::new_err("End of stream already reached"));
+        }
+        let limit = usize::try_from(max_length).unwrap_or(usize::MAX);
+        let decoder = self
+            .decoder
+            .get_mut()
+            .expect("Nothing panics while holding the lock");
+        decoder.get_mut().data.extend_from_slice(data);
+        let mut out = Vec::new();
+        let result = py.detach(|| read_up_to(decoder, &mut out, limit));
+        let consumed = decoder.info().compressed_len;
+        let input = decoder.get_mut();
+        input.consume_to(consumed);
+        match result {
+            Ok(Some(true)) => {
+                self.eof = true;
+                self.needs_input = false;
+                self.unused_data = std::mem::take(&mut input.data);
+            }
+            Ok(Some(false)) => self.needs_input = true,
+            Ok(None) => self.needs_input = false,
+            Err(err) => return Err(BzipprError::new_err(err.to_string())),
+        }
+        Ok(PyBytes::new(py, &out))
+    }
+
+    /// Whatever came after the end of the stream
+    #[getter]
+    fn unused_data<'py>(&self, py: Python<'py>) -> Bound<'py, PyBytes> {
+        PyBytes::new(py, &self.unused_data)
+    }
+}
+
+/// Decode into `out` until it holds `limit` bytes, `None` then, or until the decoder stops for
+/// lack of input, `Some(false)`, or at the end of the stream, `Some(true)`
+fn read_up_to(
+    decoder: &mut BzDecoder<Input>,
+    out: &mut Vec<u8>,
+    limit: usize,
+) -> io::Result<Option<bool>> {
+    while out.len() < limit {
+        let len = out.len();
+        out.resize(len + (limit - len).min(MAX_READ), 0);
+        let result = decoder.read(&mut out[len..]);
+        out.truncate(len + *result.as_ref().unwrap_or(&0));
+        match result {
+            Ok(0) => return Ok(Some(true)),
+            Ok(_) => {}
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(Some(false)),
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(None)
+}
+
+#[pymodule]
+fn _bzippr(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_function(wrap_pyfunction!(compress, m)?)?;
+    m.add_function(wrap_pyfunction!(decompress, m)?)?;
+    m.add_class::<BZ2Compressor>()?;
+    m.add("Compressor", m.getattr("BZ2Compressor")?)?;
+    m.add_class::<BZ2Decompressor>()?;
+    m.add("BzipprError", m.py().get_type::<BzipprError>())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::CString;
+
+    use pyo3::{conversion::FromPyObjectOwned, types::PyDict};
+    use test_case::test_case;
+
+    use super::*;
+
+    /// Run `code` with the module imported as `bzippr`, and `bz2` for comparison, and return its
+    /// globals
+    fn exec<'py>(py: Python<'py>, code: &str) -> PyResult<Bound<'py, PyDict>> {
+        let module = PyModule::new(py, "bzippr")?;
+        _bzippr(&module)?;
+        let globals = PyDict::new(py);
+        globals.set_item("bzippr", module)?;
+        globals.set_item("bz2", py.import("bz2")?)?;
+        py.run(&CString::new(code).unwrap(), Some(&globals), None)?;
+        Ok(globals)
+    }
+
+    /// The value `code` leaves in `result`
+    fn run<T: for<'py> FromPyObjectOwned<'py>>(code: &str) -> T {
+        Python::attach(|py| {
+            let globals = exec(py, code).unwrap();
+            let result = globals.get_item("result").unwrap().expect("No `result`");
+            result.extract().ok().expect("`result` has another type")
+        })
+    }
+
+    /// The name of the exception `code` raises
+    fn error_type(code: &str) -> String {
+        Python::attach(|py| {
+            let err = exec(py, code).unwrap_err();
+            err.get_type(py).name().unwrap().to_string()
+        })
+    }
+
+    #[test]
+    fn test_roundtrip_with_bz2() {
+        let result: bool = run(r#"
+data = b"".join(b"%d bottles of beer\n" % i for i in range(50_000))
+result = (
+    bz2.decompress(bzippr.compress(data, 1)) == data
+    and bzippr.decompress(bz2.compress(data) * 2) == data * 2
+)
+"#);
+        assert!(result);
+    }
+
+    #[test]
+    fn test_compressor() {
+        let result: bool = run(r#"
+compressor = bzippr.BZ2Compressor(1)
+data = b"hello world\n" * 100_000
+out = b"".join(compressor.compress(data[i : i + 9999]) for i in range(0, len(data), 9999))
+result = out + compressor.flush() == bzippr.compress(data, 1)
+"#);
+        assert!(result);
+    }
+
+    #[test]
+    fn test_compressor_name() {
+        let result: bool = run("result = bzippr.Compressor is bzippr.BZ2Compressor");
+        assert!(result);
+    }
+
+    #[test]
+    fn test_decompressor() {
+        let result: (Vec<u8>, bool, bool, Vec<u8>) = run(r#"
+decompressor = bzippr.BZ2Decompressor()
+data = bz2.compress(b"hello world\n" * 1000) + b"junk"
+out = decompressor.decompress(data[:20], 5)
+first = (out, decompressor.needs_input)
+for i in range(20, len(data), 7):
+    out += decompressor.decompress(data[i : i + 7], 100)
+while not decompressor.eof:
+    out += decompressor.decompress(b"")
+result = (out, first == (b"", True), decompressor.eof, decompressor.unused_data)
+"#);
+        assert_eq!(
+            result,
+            (b"hello world\n".repeat(1000), true, true, b"junk".to_vec())
+        );
+    }
+
+    #[test]
+    fn test_max_length() {
+        let result: (usize, bool) = run(r#"
+decompressor = bzippr.BZ2Decompressor()
+out = decompressor.decompress(bz2.compress(bytes(10_000)), 100)
+result = (len(out), decompressor.needs_input)
+"#);
+        assert_eq!(result, (100, false));
+    }
+
+    #[test_case("bzippr.compress(b'', 0)" => "ValueError"; "level")]
+    #[test_case("bzippr.decompress(bz2.compress(b'hello')[:-5])" => "ValueError"; "truncated")]
+    #[test_case("bzippr.decompress(b'not bzip2')" => "BzipprError"; "magic")]
+    #[test_case("c = bzippr.BZ2Compressor(); c.flush(); c.flush()" => "ValueError"; "flushed")]
+    #[test_case("d = bzippr.BZ2Decompressor(); d.decompress(bz2.compress(b'')); d.decompress(b'')"
+        => "EOFError"; "after eof")]
+    fn test_errors(code: &str) -> String {
+        error_type(code)
+    }
+
+    #[test]
+    fn test_error_is_os_error() {
+        let result: bool = run("result = issubclass(bzippr.BzipprError, OSError)");
+        assert!(result);
+    }
+}
@@ -0,0 +1,158 @@
+"""Check bzippr against the standard library's bz2 module, which has to read what bzippr writes,
+write what bzippr reads, and behave the same on the way.
+
+Run with `python -m unittest discover python/tests` once the package is installed.
+"""
+
+import bz2
+import io
+import os
+import tempfile
+import unittest
+
+import bzippr
+
+TEXT = b"".join(b"line %d: the quick brown fox jumps over the lazy dog\n" % i for i in range(20_000))
+
+
+class FunctionTest(unittest.TestCase):
+    def test_bz2_reads_ours(self):
+        for level in (1, 9):
+            self.assertEqual(bz2.decompress(bzippr.compress(TEXT, level)), TEXT)
+
+    def test_we_read_bz2s(self):
+        self.assertEqual(bzippr.decompress(bz2.compress(TEXT, 1)), TEXT)
+
+    def test_several_streams(self):
+        data = bz2.compress(b"hello ") + bzippr.compress(b"world")
+        self.assertEqual(bzippr.decompress(data), b"hello world")
+
+    def test_empty(self):
+        self.assertEqual(bzippr.decompress(b""), b"")
+        self.assertEqual(bzippr.decompress(bzippr.compress(b"")), b"")
+
+    def test_bad_level(self):
+        for level in (0, 10, -1):
+            with self.assertRaises(ValueError):
+                bzippr.compress(TEXT, level)
+
+    def test_truncated(self):
+        with self.assertRaises(ValueError):
+            bzippr.decompress(bzippr.compress(TEXT)[:-10])
+
+    def test_corrupt(self):
+        data = bytearray(bzippr.compress(TEXT))
+        data[100] ^= 0x01
+        with self.assertRaises(OSError):
+            bzippr.decompress(bytes(data))
+        with self.assertRaises(OSError):
+            bzippr.decompress(b"not bzip2 data")
+
+
+class CompressorTest(unittest.TestCase):
+    def test_chunks(self):
+        compressor = bzippr.BZ2Compressor(1)
+        chunks = [TEXT[i : i + 7000] for i in range(0, len(TEXT), 7000)]
+        data = b"".join(compressor.compress(chunk) for chunk in chunks) + compressor.flush()
+        self.assertEqual(data, bzippr.compress(TEXT, 1))
+        self.assertEqual(bz2.decompress(data), TEXT)
+
+    def test_flushed(self):
+        compressor = bzippr.BZ2Compressor()
+        compressor.flush()
+        with self.assertRaises(ValueError):
+            compressor.compress(b"more")
+        with self.assertRaises(ValueError):
+            compressor.flush()
+
+    def test_compressor_name(self):
+        compressor = bzippr.Compressor(1)
+        data = compressor.compress(TEXT) + compressor.flush()
+        self.assertEqual(bz2.decompress(data), TEXT)
+
+
+class DecompressorTest(unittest.TestCase):
+    def test_chunks(self):
+        data = bz2.compress(TEXT)
+        decompressor = bzippr.BZ2Decompressor()
+        out = b"".join(decompressor.decompress(data[i : i + 1000]) for i in range(0, len(data), 1000))
+        self.assertEqual(out, TEXT)
+        self.assertTrue(decompressor.eof)
+        self.assertEqual(decompressor.unused_data, b"")
+
+    def test_max_length(self):
+        decompressor = bzippr.BZ2Decompressor()
+        out = decompressor.decompress(bz2.compress(TEXT), 100)
+        self.assertEqual(len(out), 100)
+        self.assertFalse(decompressor.needs_input)
+        while not decompressor.eof:
+            out += decompressor.decompress(b"", 10_000)
+        self.assertEqual(out, TEXT)
+
+    def test_unused_data(self):
+        decompressor = bzippr.BZ2Decompressor()
+        self.assertEqual(decompressor.decompress(bz2.compress(b"hello") + b"junk"), b"hello")
+        self.assertTrue(decompressor.eof)
+        self.assertEqual(decompressor.unused_data, b"junk")
+        with self.assertRaises(EOFError):
+            decompressor.decompress(b"more")
+
+    def test_needs_input(self):
+        data = bz2.compress(TEXT)
+        decompressor = bzippr.BZ2Decompressor()
+        self.assertEqual(decompressor.decompress(data[:50]), b"")
+        self.assertTrue(decompressor.needs_input)
+        self.assertFalse(decompressor.eof)
+
+
+class FileTest(unittest.TestCase):
+    def test_roundtrip_with_bz2(self):
+        with tempfile.TemporaryDirectory() as tmp:
+            path = os.path.join(tmp, "text.bz2")
+            with bzippr.BZ2File(path, "w", compresslevel=1) as f:
+                for i in range(0, len(TEXT), 5000):
+                    f.write(TEXT[i : i + 5000])
+                self.assertEqual(f.tell(), len(TEXT))
+            with bz2.open(path) as f:
+                self.assertEqual(f.read(), TEXT)
+            with bz2.open(path, "ab") as f:
+                f.write(b"appended\n")
+            with bzippr.open(path) as f:
+                self.assertEqual(f.read(), TEXT + b"appended\n")
+
+    def test_readline_and_seek(self):
+        with bzippr.BZ2File(io.BytesIO(bz2.compress(TEXT))) as f:
+            self.assertEqual(f.readline(), TEXT.split(b"\n")[0] + b"\n")
+            f.seek(100_000)
+            self.assertEqual(f.read(10), TEXT[100_000:100_010])
+            f.seek(10)
+            self.assertEqual(f.tell(), 10)
+            self.assertEqual(f.read(10), TEXT[10:20])
+
+    def test_text_mode(self):
+        buffer = io.BytesIO()
+        with bzippr.open(buffer, "wt", encoding="utf-8") as f:
+            f.write("grüße\n" * 100)
+        buffer.seek(0)
+        with bz2.open(buffer, "rt", encoding="utf-8") as f:
+            self.assertEqual(f.read(), "grüße\n" * 100)
+
+    def test_trailing_garbage(self):
+        # ignored, like bz2 does
+        data = bz2.compress(b"hello") + b"junk"
+        with bzippr.open(io.BytesIO(data)) as ours, bz2.open(io.BytesIO(data)) as theirs:
+            self.assertEqual(ours.read(), theirs.read())
+
+    def test_bad_arguments(self):
+        with self.assertRaises(ValueError):
+            bzippr.BZ2File(io.BytesIO(), "z")
+        with self.assertRaises(ValueError):
+            bzippr.BZ2File(io.BytesIO(), "w", compresslevel=0)
+        with self.assertRaises(ValueError):
+            bzippr.open(io.BytesIO(), "rb", encoding="utf-8")
+        with self.assertRaises(TypeError):
+            bzippr.BZ2File(42)
+
+
+if __name__ == "__main__":
+    unittest.main()
@@ -0,0 +1,12 @@
+//! Bit level I/O for the parts of a bzip2 stream that aren't byte aligned: Huffman codes, code
+//! lengths, selectors, the 24-bit origin pointer and everything after the first block header.
+//! Bits are written and read most significant bit first, like bzip2 does.
+
+pub mod reader;
+pub mod writer;
+
+pub use reader::{BitReadError, BitReader};
+pub use writer::BitWriter;
+
+/// Largest number of bits a single `write_bits`/`read_bits` call handles
+pub const MAX_BITS: u32 = 32;
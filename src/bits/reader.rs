@@ -0,0 +1,190 @@
+use thiserror::Error;
+
+use super::MAX_BITS;
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum BitReadError {
+    #[error("Unexpected end of input: {requested} bits requested at bit {position} of {len}")]
+    UnexpectedEnd {
+        requested: u32,
+        position: u64,
+        len: u64,
+    },
+}
+
+impl BitReadError {
+    /// How many more bits the input would have needed for the read to succeed
+    pub fn needed_bits(&self) -> u64 {
+        match *self {
+            Self::UnexpectedEnd {
+                requested,
+                position,
+                len,
+            } => position + requested as u64 - len,
+        }
+    }
+}
+
+/// Reads bits most significant bit first from a byte slice.
+#[derive(Debug, Clone)]
+pub struct BitReader<'a> {
+    data: &'a [u8],
+    /// Offset of the next bit to read, in bits from the start of `data`
+    position: u64,
+}
+
+impl<'a> BitReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data, position: 0 }
+    }
+
+    /// Offset of the next bit to read, in bits from the start of the data
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    /// Number of bits left to read
+    pub fn remaining(&self) -> u64 {
+        self.len() - self.position
+    }
+
+    /// Read the next `n` bits, `n` being at most 32, as the lowest bits of the result
+    pub fn read_bits(&mut self, n: u32) -> Result<u32, BitReadError> {
+        self.check_remaining(n)?;
+        let value = self.peek_bits(n);
+        self.position += n as u64;
+        Ok(value)
+    }
+
+    pub fn read_bit(&mut self) -> Result<bool, BitReadError> {
+        self.read_bits(1).map(|bit| bit == 1)
+    }
+
+    /// The next `n` bits without consuming them. Bits past the end of the data read as zero, so
+    /// a decoder can always look up a full code width in its tables and find out afterwards
+    /// (when consuming the code) whether the data really was that long.
+    pub fn peek_bits(&self, n: u32) -> u32 {
+        debug_assert!(n <= MAX_BITS, "Can't read {n} bits at once");
+        if n == 0 {
+            return 0;
+        }
+        // 5 bytes hold 32 bits at any bit offset into the first one
+        let first_byte = (self.position / 8) as usize;
+        let window = (first_byte..first_byte + 5).fold(0u64, |window, i| {
+            (window << 8) | *self.data.get(i).unwrap_or(&0) as u64
+        });
+        let offset = (self.position % 8) as u32;
+        ((window >> (40 - offset - n)) & ((1 << n) - 1)) as u32
+    }
+
+    /// Skip the next `n` bits
+    pub fn skip(&mut self, n: u64) -> Result<(), BitReadError> {
+        if n > self.remaining() {
+            return Err(self.unexpected_end(n.min(u32::MAX as u64) as u32));
+        }
+        self.position += n;
+        Ok(())
+    }
+
+    fn len(&self) -> u64 {
+        self.data.len() as u64 * 8
+    }
+
+    fn check_remaining(&self, n: u32) -> Result<(), BitReadError> {
+        if (n as u64) > self.remaining() {
+            Err(self.unexpected_end(n))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn unexpected_end(&self, requested: u32) -> BitReadError {
+        BitReadError::UnexpectedEnd {
+            requested,
+            position: self.position,
+            len: self.len(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+    use test_case::test_case;
+
+    use super::*;
+    use crate::bits::BitWriter;
+
+    #[test_case(&[0b1011_0001], &[1, 3, 4] => vec![1, 0b011, 0b0001]; "within a byte")]
+    #[test_case(&[0x31, 0x41, 0x59, 0x26, 0x53, 0x59], &[4, 32, 12] => vec![0x3, 0x1415_9265, 0x359]; "across bytes")]
+    #[test_case(&[0xff], &[0, 8] => vec![0, 0xff]; "zero bits")]
+    fn test_read_bits(data: &[u8], widths: &[u32]) -> Vec<u32> {
+        let mut reader = BitReader::new(data);
+        widths
+            .iter()
+            .map(|&n| reader.read_bits(n).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_peek_does_not_consume() {
+        let mut reader = BitReader::new(&[0b1100_1010, 0b0101_0000]);
+        assert_eq!(reader.peek_bits(4), 0b1100);
+        assert_eq!(reader.position(), 0);
+        reader.read_bits(3).unwrap();
+        assert_eq!(reader.peek_bits(8), 0b0101_0010);
+        assert_eq!(reader.position(), 3);
+    }
+
+    #[test]
+    fn test_peek_past_end_reads_zeros() {
+        let mut reader = BitReader::new(&[0b1111_1111]);
+        reader.read_bits(5).unwrap();
+        assert_eq!(reader.peek_bits(20), 0b111 << 17);
+        assert_eq!(
+            reader.read_bits(20),
+            Err(BitReadError::UnexpectedEnd {
+                requested: 20,
+                position: 5,
+                len: 8
+            })
+        );
+        assert_eq!(reader.read_bits(20).unwrap_err().needed_bits(), 17);
+        assert_eq!(reader.read_bits(3), Ok(0b111));
+        assert_eq!(reader.remaining(), 0);
+        assert!(reader.read_bit().is_err());
+    }
+
+    #[test]
+    fn test_skip() {
+        let mut reader = BitReader::new(b"BZh91AY&SY");
+        reader.skip(32).unwrap();
+        assert_eq!(reader.read_bits(24), Ok(0x31_41_59));
+        assert!(reader.skip(25).is_err());
+        assert_eq!(reader.position(), 56);
+        reader.skip(24).unwrap();
+        assert_eq!(reader.remaining(), 0);
+    }
+
+    proptest! {
+        #[test]
+        fn prop_roundtrip(writes in prop::collection::vec((any::<u32>(), 0..=MAX_BITS), 0..64)) {
+            let writes: Vec<(u32, u32)> = writes
+                .into_iter()
+                .map(|(value, n)| (value.checked_shr(MAX_BITS - n).unwrap_or(0), n))
+                .collect();
+            let mut writer = BitWriter::new();
+            for &(value, n) in &writes {
+                writer.write_bits(value, n);
+            }
+            let bit_len = writer.bit_len();
+            let data = writer.into_bytes();
+            let mut reader = BitReader::new(&data);
+            for &(value, n) in &writes {
+                prop_assert_eq!(reader.read_bits(n), Ok(value));
+            }
+            prop_assert_eq!(reader.position(), bit_len);
+            prop_assert!(reader.remaining() < 8);
+        }
+    }
+}
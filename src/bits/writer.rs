@@ -0,0 +1,192 @@
+use alloc::vec::Vec;
+
+use super::MAX_BITS;
+
+/// Writes bits most significant bit first into a growable buffer.
+///
+/// Bits are collected in an accumulator and moved into the buffer a byte at a time, so writing
+/// never fails. The last byte is only complete after `flush`, which pads it with zero bits.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct BitWriter {
+    buf: Vec<u8>,
+    /// Bits that don't make up a whole byte yet, in the lowest `pending` bits
+    acc: u64,
+    pending: u32,
+}
+
+impl BitWriter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_capacity(bytes: usize) -> Self {
+        Self {
+            buf: Vec::with_capacity(bytes),
+            ..Self::default()
+        }
+    }
+
+    /// Write the lowest `n` bits of `value`, `n` being at most 32
+    pub fn write_bits(&mut self, value: u32, n: u32) {
+        debug_assert!(n <= MAX_BITS, "Can't write {n} bits at once");
+        debug_assert!(
+            n == MAX_BITS || value >> n == 0,
+            "{value} doesn't fit into {n} bits"
+        );
+        if n == 0 {
+            return;
+        }
+        self.acc = (self.acc << n) | value as u64;
+        self.pending += n;
+        while self.pending >= 8 {
+            self.pending -= 8;
+            self.buf.push((self.acc >> self.pending) as u8);
+        }
+        self.acc &= (1 << self.pending) - 1;
+    }
+
+    pub fn write_bit(&mut self, bit: bool) {
+        self.write_bits(bit as u32, 1);
+    }
+
+    /// Write whole bytes, which need not be aligned to the bytes of the buffer
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        if self.pending == 0 {
+            self.buf.extend_from_slice(bytes);
+        } else {
+            for &byte in bytes {
+                self.write_bits(byte as u32, 8);
+            }
+        }
+    }
+
+    /// Write everything written to `other`, including the bits of its partial last byte, so that
+    /// pieces written independently can be put together with no padding in between
+    pub fn append(&mut self, other: &BitWriter) {
+        self.write_bytes(&other.buf);
+        self.write_bits(other.acc as u32, other.pending);
+    }
+
+    /// Number of bits written so far, including the ones not yet flushed
+    pub fn bit_len(&self) -> u64 {
+        self.buf.len() as u64 * 8 + self.pending as u64
+    }
+
+    /// Pad the last byte with zero bits, so the buffer holds everything written so far
+    pub fn flush(&mut self) {
+        if self.pending > 0 {
+            self.write_bits(0, 8 - self.pending);
+        }
+    }
+
+    /// The bytes written so far, excluding a partial last byte
+    pub fn bytes(&self) -> &[u8] {
+        &self.buf
+    }
+
+    /// Flush and take the buffer out of the writer
+    pub fn into_bytes(mut self) -> Vec<u8> {
+        self.flush();
+        self.buf
+    }
+
+    /// Drop the complete bytes, e.g. once they have been handed on, but keep the bits of a
+    /// partial last byte. `bit_len` counts from here on.
+    pub fn discard_bytes(&mut self) {
+        self.buf.clear();
+    }
+
+    /// Drop everything written so far but keep the buffer, so the writer can be reused
+    pub fn clear(&mut self) {
+        self.buf.clear();
+        self.acc = 0;
+        self.pending = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use test_case::test_case;
+
+    use super::*;
+
+    #[test_case(&[] => Vec::<u8>::new(); "nothing")]
+    #[test_case(&[(1, 1)] => vec![0b1000_0000]; "single bit padded")]
+    #[test_case(&[(0b101, 3), (0b11111, 5)] => vec![0b1011_1111]; "one byte")]
+    #[test_case(&[(0b1, 1), (0xff, 8)] => vec![0b1111_1111, 0b1000_0000]; "across bytes")]
+    #[test_case(&[(0x3141_5926, 32), (0x5359, 16)] => vec![0x31, 0x41, 0x59, 0x26, 0x53, 0x59]; "block magic")]
+    #[test_case(&[(0, 3), (u32::MAX, 32)] => vec![0x1f, 0xff, 0xff, 0xff, 0xe0]; "32 bits unaligned")]
+    #[test_case(&[(0, 0), (1, 1)] => vec![0b1000_0000]; "zero bits")]
+    fn test_write_bits(writes: &[(u32, u32)]) -> Vec<u8> {
+        let mut writer = BitWriter::new();
+        for &(value, n) in writes {
+            writer.write_bits(value, n);
+        }
+        writer.into_bytes()
+    }
+
+    #[test]
+    fn test_discard_bytes_keeps_partial_byte() {
+        let mut writer = BitWriter::new();
+        writer.write_bits(0xabc, 12);
+        assert_eq!(writer.bytes(), &[0xab]);
+        writer.discard_bytes();
+        assert_eq!(writer.bit_len(), 4);
+        writer.write_bits(0xd, 4);
+        assert_eq!(writer.into_bytes(), vec![0xcd]);
+    }
+
+    #[test_case(0 => vec![b'B', b'Z', b'h']; "aligned")]
+    #[test_case(4 => vec![0x04, 0x25, 0xa6, 0x80]; "unaligned")]
+    fn test_write_bytes(offset: u32) -> Vec<u8> {
+        let mut writer = BitWriter::new();
+        writer.write_bits(0, offset);
+        writer.write_bytes(b"BZh");
+        writer.into_bytes()
+    }
+
+    #[test_case(0, 0; "both aligned")]
+    #[test_case(3, 0; "aligned onto unaligned")]
+    #[test_case(0, 5; "unaligned onto aligned")]
+    #[test_case(6, 7; "both unaligned")]
+    fn test_append(offset: u32, tail: u32) {
+        let mut first = BitWriter::new();
+        first.write_bits(0x55 >> (8 - offset), offset);
+        let mut second = BitWriter::new();
+        second.write_bytes(b"BZh");
+        second.write_bits((1 << tail) - 1, tail);
+
+        let mut whole = first.clone();
+        whole.write_bytes(b"BZh");
+        whole.write_bits((1 << tail) - 1, tail);
+        first.append(&second);
+        assert_eq!(first.bit_len(), offset as u64 + 24 + tail as u64);
+        assert_eq!(first, whole);
+    }
+
+    #[test]
+    fn test_bit_len_and_flush() {
+        let mut writer = BitWriter::new();
+        writer.write_bits(0b10, 2);
+        writer.write_bits(0xabc, 12);
+        assert_eq!(writer.bit_len(), 14);
+        assert_eq!(writer.bytes(), &[0b1010_1010]);
+        writer.flush();
+        assert_eq!(writer.bit_len(), 16);
+        assert_eq!(writer.bytes(), &[0b1010_1010, 0b1111_0000]);
+        writer.flush();
+        assert_eq!(writer.bit_len(), 16);
+    }
+
+    #[test]
+    fn test_clear_keeps_buffer() {
+        let mut writer = BitWriter::with_capacity(64);
+        writer.write_bits(0x1234, 13);
+        let ptr = writer.bytes().as_ptr();
+        writer.clear();
+        assert_eq!(writer.bit_len(), 0);
+        writer.write_bit(true);
+        assert_eq!(writer.bytes().as_ptr(), ptr);
+        assert_eq!(writer.into_bytes(), vec![0b1000_0000]);
+    }
+}
@@ -0,0 +1,147 @@
+//! The checksums of the bzip2 format.
+//!
+//! Every block stores a CRC-32 of its uncompressed bytes. It uses the polynomial of the zlib CRC,
+//! but feeds the bits of each byte most significant first instead of reflecting them, so the
+//! values differ from `crc32` tools. The footer of a stream stores a combined CRC, folded from the
+//! block CRCs in order.
+
+/// Generator polynomial, most significant bit first
+const POLY: u32 = 0x04c1_1db7;
+
+/// CRC of every byte value, so `update` handles a byte per lookup instead of a bit per step
+const TABLE: [u32; 256] = build_table();
+
+const fn build_table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut byte = 0;
+    while byte < 256 {
+        let mut crc = (byte as u32) << 24;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 0x8000_0000 != 0 {
+                (crc << 1) ^ POLY
+            } else {
+                crc << 1
+            };
+            bit += 1;
+        }
+        table[byte] = crc;
+        byte += 1;
+    }
+    table
+}
+
+/// Running CRC of the uncompressed bytes of a block.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockCrc(u32);
+
+impl BlockCrc {
+    pub fn new() -> Self {
+        Self(u32::MAX)
+    }
+
+    /// CRC of `data` in one go
+    pub fn checksum(data: &[u8]) -> u32 {
+        let mut crc = Self::new();
+        crc.update(data);
+        crc.value()
+    }
+
+    /// Feed the next bytes of the block, in as many pieces as convenient
+    pub fn update(&mut self, data: &[u8]) {
+        for &byte in data {
+            self.0 = (self.0 << 8) ^ TABLE[((self.0 >> 24) as u8 ^ byte) as usize];
+        }
+    }
+
+    /// CRC of all bytes fed so far, as stored in the block header
+    pub fn value(self) -> u32 {
+        !self.0
+    }
+}
+
+impl Default for BlockCrc {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Combined CRC of the blocks of a stream, as stored in the stream footer.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct StreamCrc(u32);
+
+impl StreamCrc {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Fold in the CRC of the next block: the combined CRC is rotated left by one bit, then
+    /// XORed with the block CRC, so the order of the blocks matters
+    pub fn combine(&mut self, block_crc: u32) {
+        self.0 = self.0.rotate_left(1) ^ block_crc;
+    }
+
+    pub fn value(self) -> u32 {
+        self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+    use test_case::test_case;
+
+    use super::*;
+    use crate::stages::strategies;
+
+    /// The definition: one bit at a time
+    fn bitwise_crc(data: &[u8]) -> u32 {
+        let mut crc = u32::MAX;
+        for &byte in data {
+            crc ^= (byte as u32) << 24;
+            for _ in 0..8 {
+                crc = if crc & 0x8000_0000 != 0 {
+                    (crc << 1) ^ POLY
+                } else {
+                    crc << 1
+                };
+            }
+        }
+        !crc
+    }
+
+    #[test_case(b"" => 0; "empty")]
+    #[test_case(b"hello world\n" => 0x4eec_e836; "hello world")]
+    #[test_case(b"123456789" => 0xfc89_1918; "check value")]
+    fn test_block_crc(data: &[u8]) -> u32 {
+        BlockCrc::checksum(data)
+    }
+
+    #[test_case(&[] => 0; "no blocks")]
+    #[test_case(&[0x4eec_e836] => 0x4eec_e836; "single block")]
+    #[test_case(&[0x8000_0001, 0x1] => 0x2; "rotation wraps around")]
+    #[test_case(&[0x1, 0x8000_0001] => 0x8000_0003; "order matters")]
+    fn test_stream_crc(block_crcs: &[u32]) -> u32 {
+        let mut crc = StreamCrc::new();
+        for &block_crc in block_crcs {
+            crc.combine(block_crc);
+        }
+        crc.value()
+    }
+
+    proptest! {
+        #[test]
+        fn prop_table_matches_bitwise(data in strategies::bytes(4096)) {
+            prop_assert_eq!(BlockCrc::checksum(&data), bitwise_crc(&data));
+        }
+
+        #[test]
+        fn prop_update_in_pieces(data in strategies::bytes(4096), split in any::<prop::sample::Index>()) {
+            let (head, tail) = data.split_at(split.index(data.len() + 1));
+            let mut crc = BlockCrc::new();
+            crc.update(head);
+            crc.update(tail);
+            prop_assert_eq!(crc.value(), BlockCrc::checksum(&data));
+        }
+    }
+}
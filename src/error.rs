@@ -0,0 +1,255 @@
+use std::{fmt, io};
+
+use thiserror::Error;
+
+use crate::{
+    bits::BitReadError,
+    bwt::BwtError,
+    format::{
+        block_size::BlockSizeError, header::HeaderError, index::IndexError, reader::ReadError,
+        verify::Mismatch, writer::WriteError,
+    },
+    huff::HuffmanError,
+    mtf::MtfError,
+    rle::RleError,
+    symbol::SymbolError,
+};
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// Any error of the library, for callers that don't care which stage or part of the format it
+/// comes from.
+///
+/// Every module has an error type of its own, which converts into this one, so `?` works across
+/// modules. The conversions sort the errors into the variants below by what went wrong rather
+/// than where, e.g. a truncated stream is `UnexpectedEof` whether the header, a Huffman code or
+/// the stream CRC was cut off.
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum Error {
+    #[error("Invalid stream header: {0}")]
+    InvalidHeader(#[from] HeaderError),
+    #[error("Unexpected end of input: {needed_bits} more bits needed")]
+    UnexpectedEof { needed_bits: u64 },
+    #[error("{kind} CRC mismatch: stored {stored:#010x}, computed {computed:#010x}")]
+    CrcMismatch {
+        kind: CrcKind,
+        stored: u32,
+        computed: u32,
+    },
+    #[error("Index {index} is out of bounds for {len} entries")]
+    IndexOutOfBounds { index: usize, len: usize },
+    #[error("Trailing data after the end of the stream at byte {offset}")]
+    TrailingGarbage { offset: usize },
+    /// Any other violation of the format inside a block
+    #[error("Invalid block: {0}")]
+    CorruptBlock(ReadError),
+    /// A `BlockIndex` read from elsewhere that can't be the index of any stream
+    #[error("Invalid block index: {0}")]
+    InvalidIndex(#[from] IndexError),
+    /// What a stream decompresses to isn't its original, see `verify_against`
+    #[error("Decompressed data doesn't match the original: {0}")]
+    Mismatch(#[from] Mismatch),
+    #[error(transparent)]
+    BlockSize(#[from] BlockSizeError),
+    #[error(transparent)]
+    Write(#[from] WriteError),
+    #[error(transparent)]
+    Rle(#[from] RleError),
+    #[error(transparent)]
+    Mtf(MtfError),
+    #[error(transparent)]
+    Huffman(HuffmanError),
+    #[error(transparent)]
+    Symbol(#[from] SymbolError),
+    /// The reader or writer of a streaming function failed
+    #[error(transparent)]
+    Io(IoError),
+}
+
+/// An `io::Error` inside an `Error`. Two of them are equal if they are of the same kind, which is
+/// all that tests usually care about.
+#[derive(Debug, Error)]
+#[error(transparent)]
+pub struct IoError(#[from] io::Error);
+
+impl IoError {
+    pub fn kind(&self) -> io::ErrorKind {
+        self.0.kind()
+    }
+
+    pub fn into_inner(self) -> io::Error {
+        self.0
+    }
+}
+
+impl PartialEq for IoError {
+    fn eq(&self, other: &Self) -> bool {
+        self.kind() == other.kind()
+    }
+}
+
+impl Eq for IoError {}
+
+/// Which checksum of a stream didn't match
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CrcKind {
+    Block,
+    Stream,
+}
+
+impl fmt::Display for CrcKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Block => "Block",
+            Self::Stream => "Stream",
+        })
+    }
+}
+
+impl From<ReadError> for Error {
+    fn from(err: ReadError) -> Self {
+        match err {
+            ReadError::Header(err) => err.into(),
+            ReadError::Truncated { needed_bits } => Self::UnexpectedEof { needed_bits },
+            ReadError::Huffman(err) => err.into(),
+            ReadError::Mtf(err) => err.into(),
+            ReadError::Rle(err) => err.into(),
+            ReadError::BlockCrcMismatch {
+                stored, computed, ..
+            } => Self::CrcMismatch {
+                kind: CrcKind::Block,
+                stored,
+                computed,
+            },
+            ReadError::StreamCrcMismatch { stored, computed } => Self::CrcMismatch {
+                kind: CrcKind::Stream,
+                stored,
+                computed,
+            },
+            ReadError::OrigPtrOutOfRange { orig_ptr, len } => Self::IndexOutOfBounds {
+                index: orig_ptr,
+                len,
+            },
+            ReadError::TrailingGarbage { offset } => Self::TrailingGarbage { offset },
+            err => Self::CorruptBlock(err),
+        }
+    }
+}
+
+/// The errors of `BzDecoder` and `BzEncoder` are I/O errors, one that wraps an error of the
+/// library becomes that error again
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        if err.get_ref().is_some_and(|inner| inner.is::<ReadError>()) {
+            let inner = err.into_inner().expect("Checked above");
+            return (*inner.downcast::<ReadError>().expect("Checked above")).into();
+        }
+        Self::Io(err.into())
+    }
+}
+
+impl From<BitReadError> for Error {
+    fn from(err: BitReadError) -> Self {
+        Self::UnexpectedEof {
+            needed_bits: err.needed_bits(),
+        }
+    }
+}
+
+impl From<BwtError> for Error {
+    fn from(err: BwtError) -> Self {
+        match err {
+            BwtError::IndexOutOfBounds { index, len } => Self::IndexOutOfBounds { index, len },
+        }
+    }
+}
+
+impl From<MtfError> for Error {
+    fn from(err: MtfError) -> Self {
+        match err {
+            MtfError::IndexOutOfBounds { index, stack_len } => Self::IndexOutOfBounds {
+                index: index as usize,
+                len: stack_len,
+            },
+            err => Self::Mtf(err),
+        }
+    }
+}
+
+impl From<HuffmanError> for Error {
+    fn from(err: HuffmanError) -> Self {
+        match err {
+            HuffmanError::Read(err) => err.into(),
+            err => Self::Huffman(err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use test_case::test_case;
+
+    use super::*;
+    use crate::{compress, decompress, CompressionLevel};
+
+    fn hello_world() -> Vec<u8> {
+        compress(b"hello world\n", CompressionLevel::BEST)
+    }
+
+    #[test_case(b"PK\x03\x04".to_vec() => matches Error::InvalidHeader(HeaderError::BadStreamMagic); "not bzip2")]
+    #[test_case(hello_world()[..20].to_vec() => matches Error::UnexpectedEof { .. }; "truncated")]
+    #[test_case([hello_world(), b"junk".to_vec()].concat() => matches Error::TrailingGarbage { .. }; "trailing garbage")]
+    fn test_decompress_errors(data: Vec<u8>) -> Error {
+        decompress(&data).unwrap_err()
+    }
+
+    #[test]
+    fn test_block_crc_mismatch() {
+        let mut data = hello_world();
+        data[10] ^= 0x01;
+        let err = decompress(&data).unwrap_err();
+        assert_eq!(
+            err,
+            Error::CrcMismatch {
+                kind: CrcKind::Block,
+                stored: 0x4fec_e836,
+                computed: 0x4eec_e836
+            }
+        );
+        assert_eq!(
+            err.to_string(),
+            "Block CRC mismatch: stored 0x4fece836, computed 0x4eece836"
+        );
+    }
+
+    #[test_case(ReadError::Truncated { needed_bits: 8 } => Error::UnexpectedEof { needed_bits: 8 }; "truncated")]
+    #[test_case(ReadError::OrigPtrOutOfRange { orig_ptr: 7, len: 3 } => Error::IndexOutOfBounds { index: 7, len: 3 }; "origin pointer")]
+    #[test_case(ReadError::Mtf(MtfError::IndexOutOfBounds { index: 4, stack_len: 2 }) => Error::IndexOutOfBounds { index: 4, len: 2 }; "mtf index")]
+    #[test_case(ReadError::Mtf(MtfError::EmptyStack) => Error::Mtf(MtfError::EmptyStack); "other mtf error")]
+    #[test_case(ReadError::InvalidSymbolMap => Error::CorruptBlock(ReadError::InvalidSymbolMap); "anything else")]
+    fn test_from_read_error(err: ReadError) -> Error {
+        err.into()
+    }
+
+    #[test]
+    fn test_from_io_error() {
+        let err = io::Error::new(io::ErrorKind::InvalidData, ReadError::InvalidSymbolMap);
+        assert_eq!(
+            Error::from(err),
+            Error::CorruptBlock(ReadError::InvalidSymbolMap)
+        );
+        let err = io::Error::new(io::ErrorKind::BrokenPipe, "closed");
+        assert!(
+            matches!(Error::from(err), Error::Io(err) if err.kind() == io::ErrorKind::BrokenPipe)
+        );
+    }
+
+    #[test]
+    fn test_from_bwt_error() {
+        let err = crate::bwt::BwtEncoded::try_new(b"abc".to_vec(), 3).unwrap_err();
+        assert_eq!(
+            Error::from(err),
+            Error::IndexOutOfBounds { index: 3, len: 3 }
+        );
+    }
+}
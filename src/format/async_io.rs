@@ -0,0 +1,693 @@
+//! `AsyncBzEncoder` and `AsyncBzDecoder`: `BzEncoder` and `BzDecoder` for tokio's `AsyncWrite`
+//! and `AsyncRead`, e.g. to compress the responses of a network service.
+//!
+//! Compressing or decoding a block keeps a core busy for milliseconds, far too long for an async
+//! task, so the blocks are handed to `spawn_blocking` while the inner reader or writer stays on
+//! the task, which needs a tokio runtime. `with_inline_compression` and `with_inline_decoding`
+//! run them on the task instead, for tasks that have a thread to themselves, or executors that
+//! aren't tokio.
+
+use std::{
+    future::Future,
+    io::{self, Read, Write},
+    mem,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    task::{spawn_blocking, JoinError, JoinHandle},
+};
+
+use super::{block_size::CompressionLevel, decoder::BzDecoder, encoder::BzEncoder};
+
+/// Most decoded bytes a blocking task produces before handing them over
+const MAX_OUTPUT: usize = 1024 * 1024;
+/// Least compressed input collected before decoding again after the decoder ran out of it
+const MIN_INPUT: usize = 64 * 1024;
+/// Bytes read from the inner reader at once
+const READ_LEN: usize = 16 * 1024;
+
+/// Compresses everything written to it into a `.bz2` stream on the inner writer, like
+/// `BzEncoder`.
+///
+/// Input is collected until it makes up a block, which is compressed on a blocking thread while
+/// the next `poll_write` waits for it, so at most one block is in flight. `poll_flush` ends a
+/// block, like `BzEncoder::flush`, and `poll_shutdown` (`AsyncWriteExt::shutdown`) finishes the
+/// stream and shuts the inner writer down; a dropped encoder leaves the stream incomplete.
+pub struct AsyncBzEncoder<W> {
+    inner: W,
+    state: EncoderState,
+    /// Input not handed to the encoder yet
+    input: Vec<u8>,
+    /// Input collected before compressing it
+    block_len: usize,
+    /// Compressed bytes not written to `inner` yet, from `output_pos` on
+    output: Vec<u8>,
+    output_pos: usize,
+    /// Whether anything was written since the last flush
+    dirty: bool,
+    /// Whether blocks are compressed on the task instead of a blocking thread
+    inline: bool,
+}
+
+type Encoder = BzEncoder<Vec<u8>>;
+
+enum EncoderState {
+    Idle(Box<Encoder>),
+    /// Compressing the input, which hands back the encoder, its output in the inner `Vec`, and
+    /// the emptied input buffer
+    Compressing(Task<(Box<Encoder>, Vec<u8>)>),
+    /// Compressing the rest of the input and the end of the stream
+    Finishing(Task<Vec<u8>>),
+    Done,
+}
+
+/// Work on a block, on a blocking thread or already done on the task
+enum Task<T> {
+    Blocking(JoinHandle<T>),
+    /// The result, until it was taken
+    Inline(Option<T>),
+}
+
+impl<T: Send + 'static> Task<T> {
+    /// Run `work` right away if `inline`, otherwise hand it to `spawn_blocking`
+    fn run(inline: bool, work: impl FnOnce() -> T + Send + 'static) -> Self {
+        if inline {
+            Self::Inline(Some(work()))
+        } else {
+            Self::Blocking(spawn_blocking(work))
+        }
+    }
+
+    fn poll(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<T>> {
+        match self {
+            Self::Blocking(task) => Pin::new(task).poll(cx).map(join),
+            Self::Inline(result) => {
+                Poll::Ready(Ok(result.take().expect("Not polled again once done")))
+            }
+        }
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncBzEncoder<W> {
+    pub fn new(inner: W, level: CompressionLevel) -> Self {
+        Self {
+            inner,
+            state: EncoderState::Idle(Box::new(Encoder::new(Vec::new(), level))),
+            input: Vec::new(),
+            block_len: level.block_size().bytes(),
+            output: Vec::new(),
+            output_pos: 0,
+            dirty: false,
+            inline: false,
+        }
+    }
+
+    /// Compress the blocks on the task that writes to the encoder instead of a blocking thread,
+    /// which blocks that task while they are compressed but needs no tokio runtime
+    pub fn with_inline_compression(mut self) -> Self {
+        self.inline = true;
+        self
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    /// The inner writer. Writing to it directly corrupts the stream.
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+
+    /// Write the compressed bytes there are to the inner writer
+    fn poll_write_output(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.output_pos < self.output.len() {
+            let written =
+                ready!(Pin::new(&mut self.inner).poll_write(cx, &self.output[self.output_pos..]))?;
+            if written == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.output_pos += written;
+        }
+        self.output.clear();
+        self.output_pos = 0;
+        Poll::Ready(Ok(()))
+    }
+
+    /// The encoder, taken out of the idle state
+    fn take_encoder(&mut self) -> Box<Encoder> {
+        match mem::replace(&mut self.state, EncoderState::Done) {
+            EncoderState::Idle(encoder) => encoder,
+            _ => unreachable!("Only called while idle"),
+        }
+    }
+
+    /// Compress the collected input on a blocking thread, and end a block after it if `flush`
+    fn start_compressing(&mut self, flush: bool) {
+        let mut encoder = self.take_encoder();
+        let mut input = mem::take(&mut self.input);
+        self.state = EncoderState::Compressing(Task::run(self.inline, move || {
+            encoder
+                .write_all(&input)
+                .and_then(|()| if flush { encoder.flush() } else { Ok(()) })
+                .expect("Writing to a Vec never fails");
+            input.clear();
+            (encoder, input)
+        }));
+    }
+
+    /// Write out everything there is and wait for the block being compressed, until the encoder
+    /// is idle or done
+    fn poll_settle(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        loop {
+            ready!(self.poll_write_output(cx))?;
+            match &mut self.state {
+                EncoderState::Compressing(task) => {
+                    let (mut encoder, input) = ready!(task.poll(cx))?;
+                    self.output = mem::take(encoder.get_mut());
+                    self.input = input;
+                    self.state = EncoderState::Idle(encoder);
+                }
+                EncoderState::Finishing(task) => {
+                    self.output = ready!(task.poll(cx))?;
+                    self.state = EncoderState::Done;
+                }
+                EncoderState::Idle(_) | EncoderState::Done => return Poll::Ready(Ok(())),
+            }
+        }
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for AsyncBzEncoder<W> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        loop {
+            ready!(this.poll_settle(cx))?;
+            if matches!(this.state, EncoderState::Done) {
+                return Poll::Ready(Err(io::Error::other("Write after shutdown")));
+            }
+            if this.input.len() < this.block_len || buf.is_empty() {
+                let len = buf.len().min(this.block_len - this.input.len());
+                this.input.extend_from_slice(&buf[..len]);
+                this.dirty |= len > 0;
+                return Poll::Ready(Ok(len));
+            }
+            this.start_compressing(false);
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_settle(cx))?;
+        if this.dirty && matches!(this.state, EncoderState::Idle(_)) {
+            this.dirty = false;
+            this.start_compressing(true);
+            ready!(this.poll_settle(cx))?;
+        }
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_settle(cx))?;
+        if let EncoderState::Idle(_) = this.state {
+            let mut encoder = this.take_encoder();
+            let input = mem::take(&mut this.input);
+            this.state = EncoderState::Finishing(Task::run(this.inline, move || {
+                encoder
+                    .write_all(&input)
+                    .and_then(|()| encoder.finish())
+                    .expect("Writing to a Vec never fails")
+            }));
+            ready!(this.poll_settle(cx))?;
+        }
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+/// Decompresses the `.bz2` data read from the inner reader, like `BzDecoder`: streams back to
+/// back until the inner reader ends, with every CRC checked and the same errors.
+///
+/// Compressed input is collected on the task and decoded on a blocking thread, up to a
+/// megabyte of output at a time. A block that isn't all there yet is decoded again once more
+/// input arrived, and the decoder waits for at least as much new input as it holds of the block
+/// before trying again, so that happens only a few times per block.
+pub struct AsyncBzDecoder<R> {
+    inner: R,
+    state: DecoderState,
+    /// Decoded bytes not returned yet, from `output_pos` on
+    output: Vec<u8>,
+    output_pos: usize,
+    /// Whether the decoder ran out of input the last time
+    needs_input: bool,
+    /// Compressed bytes read from `inner` so far
+    read: u64,
+    /// Whether blocks are decoded on the task instead of a blocking thread
+    inline: bool,
+}
+
+enum DecoderState {
+    Idle(Box<BzDecoder<Input>>),
+    /// Decoding, which hands back the decoder, its output, and what `decode_up_to` returned
+    Decoding(Task<DecodeResult>),
+    Done,
+}
+
+type DecodeResult = (Box<BzDecoder<Input>>, Vec<u8>, io::Result<Decoded>);
+
+/// How far a blocking task got
+enum Decoded {
+    /// It produced `MAX_OUTPUT` bytes, there may be more
+    Full,
+    /// The decoder needs more input
+    Starved,
+    /// The input is done
+    End,
+}
+
+/// The compressed input read from the inner reader that the decoder didn't read yet. Running out
+/// of it before the inner reader ended fails with `WouldBlock`, so the decoder stops and picks up
+/// again once there is more.
+struct Input {
+    data: Vec<u8>,
+    pos: usize,
+    /// Whether the inner reader ended
+    finished: bool,
+}
+
+impl Read for Input {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let available = &self.data[self.pos..];
+        if available.is_empty() && !self.finished {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        let len = available.len().min(buf.len());
+        buf[..len].copy_from_slice(&available[..len]);
+        self.pos += len;
+        if self.pos == self.data.len() {
+            self.data.clear();
+            self.pos = 0;
+        }
+        Ok(len)
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncBzDecoder<R> {
+    pub fn new(inner: R) -> Self {
+        let input = Input {
+            data: Vec::new(),
+            pos: 0,
+            finished: false,
+        };
+        Self {
+            inner,
+            state: DecoderState::Idle(Box::new(BzDecoder::new(input))),
+            output: Vec::new(),
+            output_pos: 0,
+            needs_input: true,
+            read: 0,
+            inline: false,
+        }
+    }
+
+    /// Decode the blocks on the task that reads from the decoder instead of a blocking thread,
+    /// like `AsyncBzEncoder::with_inline_compression`
+    pub fn with_inline_decoding(mut self) -> Self {
+        self.inline = true;
+        self
+    }
+
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+
+    /// The inner reader. Reading from it directly corrupts the stream.
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+
+    /// Read compressed input from the inner reader into the decoder's input: as much as the
+    /// decoder holds of the block it is stuck on, and at least `MIN_INPUT` bytes, or whatever
+    /// the inner reader has before it would block or ends. Pending only if that is nothing.
+    fn poll_fill(
+        inner: &mut R,
+        decoder: &mut BzDecoder<Input>,
+        read: &mut u64,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        let buffered = *read - decoder.info().compressed_len;
+        let want = (buffered as usize).max(MIN_INPUT);
+        let input = decoder.get_mut();
+        let mut fresh = 0;
+        let mut chunk = [0; READ_LEN];
+        while fresh < want && !input.finished {
+            let mut buf = ReadBuf::new(&mut chunk);
+            match Pin::new(&mut *inner).poll_read(cx, &mut buf) {
+                Poll::Ready(Ok(())) if buf.filled().is_empty() => input.finished = true,
+                Poll::Ready(Ok(())) => {
+                    input.data.extend_from_slice(buf.filled());
+                    fresh += buf.filled().len();
+                    *read += buf.filled().len() as u64;
+                }
+                Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                Poll::Pending if fresh == 0 => return Poll::Pending,
+                Poll::Pending => break,
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for AsyncBzDecoder<R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if this.output_pos < this.output.len() || buf.remaining() == 0 {
+                let available = &this.output[this.output_pos..];
+                let len = available.len().min(buf.remaining());
+                buf.put_slice(&available[..len]);
+                this.output_pos += len;
+                return Poll::Ready(Ok(()));
+            }
+            match &mut this.state {
+                DecoderState::Idle(decoder) => {
+                    if this.needs_input {
+                        ready!(Self::poll_fill(
+                            &mut this.inner,
+                            decoder,
+                            &mut this.read,
+                            cx
+                        ))?;
+                    }
+                    let DecoderState::Idle(mut decoder) =
+                        mem::replace(&mut this.state, DecoderState::Done)
+                    else {
+                        unreachable!("Matched as idle above");
+                    };
+                    let mut output = mem::take(&mut this.output);
+                    this.state = DecoderState::Decoding(Task::run(this.inline, move || {
+                        output.clear();
+                        let result = decode_up_to(&mut decoder, &mut output, MAX_OUTPUT);
+                        (decoder, output, result)
+                    }));
+                }
+                DecoderState::Decoding(task) => {
+                    let (decoder, output, result) = ready!(task.poll(cx))?;
+                    (this.output, this.output_pos) = (output, 0);
+                    this.state = DecoderState::Idle(decoder);
+                    match result? {
+                        Decoded::Full => this.needs_input = false,
+                        Decoded::Starved => this.needs_input = true,
+                        Decoded::End => this.state = DecoderState::Done,
+                    }
+                }
+                DecoderState::Done => return Poll::Ready(Ok(())),
+            }
+        }
+    }
+}
+
+/// Decode into `out` until it holds `limit` bytes, the decoder runs out of input or the input
+/// ends
+fn decode_up_to(
+    decoder: &mut BzDecoder<Input>,
+    out: &mut Vec<u8>,
+    limit: usize,
+) -> io::Result<Decoded> {
+    while out.len() < limit {
+        let len = out.len();
+        out.resize(limit, 0);
+        let result = decoder.read(&mut out[len..]);
+        out.truncate(len + *result.as_ref().unwrap_or(&0));
+        match result {
+            Ok(0) => return Ok(Decoded::End),
+            Ok(_) => {}
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(Decoded::Starved),
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(Decoded::Full)
+}
+
+/// The result of a blocking task, with its panic passed on
+fn join<T>(result: Result<T, JoinError>) -> io::Result<T> {
+    result.map_err(|err| match err.try_into_panic() {
+        Ok(panic) => std::panic::resume_unwind(panic),
+        Err(err) => io::Error::other(err),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+    use test_case::test_case;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+    use crate::{compress, decompress, stages::strategies};
+
+    fn runtime() -> tokio::runtime::Runtime {
+        tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap()
+    }
+
+    async fn encode_in_chunks(data: &[u8], level: CompressionLevel, chunk_len: usize) -> Vec<u8> {
+        let mut encoder = AsyncBzEncoder::new(Vec::new(), level);
+        for chunk in data.chunks(chunk_len) {
+            encoder.write_all(chunk).await.unwrap();
+        }
+        encoder.shutdown().await.unwrap();
+        encoder.into_inner()
+    }
+
+    /// Most bytes `Stutter` passes on at a time
+    const CHUNK: usize = 4096;
+
+    /// Passes reads and writes on to `inner`, but returns `Pending` on every other poll, waking
+    /// the task right away, and takes at most `CHUNK` bytes at a time
+    struct Stutter<T> {
+        inner: T,
+        pending: bool,
+        /// Number of times it returned `Pending`
+        pendings: usize,
+    }
+
+    impl<T: Unpin> Stutter<T> {
+        fn new(inner: T) -> Self {
+            Self {
+                inner,
+                pending: false,
+                pendings: 0,
+            }
+        }
+
+        fn poll_inner<O>(
+            &mut self,
+            cx: &mut Context<'_>,
+            poll: impl FnOnce(Pin<&mut T>, &mut Context<'_>) -> Poll<O>,
+        ) -> Poll<O> {
+            self.pending = !self.pending;
+            if self.pending {
+                self.pendings += 1;
+                cx.waker().wake_by_ref();
+                return Poll::Pending;
+            }
+            poll(Pin::new(&mut self.inner), cx)
+        }
+    }
+
+    impl<W: AsyncWrite + Unpin> AsyncWrite for Stutter<W> {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            let buf = &buf[..buf.len().min(CHUNK)];
+            self.get_mut()
+                .poll_inner(cx, |inner, cx| inner.poll_write(cx, buf))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            self.get_mut().poll_inner(cx, AsyncWrite::poll_flush)
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            self.get_mut().poll_inner(cx, AsyncWrite::poll_shutdown)
+        }
+    }
+
+    impl<R: AsyncRead + Unpin> AsyncRead for Stutter<R> {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            let mut chunk = [0; CHUNK];
+            let len = CHUNK.min(buf.remaining());
+            let mut chunk = ReadBuf::new(&mut chunk[..len]);
+            let poll = self
+                .get_mut()
+                .poll_inner(cx, |inner, cx| inner.poll_read(cx, &mut chunk));
+            buf.put_slice(chunk.filled());
+            poll
+        }
+    }
+
+    async fn decode(data: &[u8]) -> io::Result<Vec<u8>> {
+        let mut out = Vec::new();
+        AsyncBzDecoder::new(data).read_to_end(&mut out).await?;
+        Ok(out)
+    }
+
+    #[test_case(Vec::new(), 1000; "empty")]
+    #[test_case(b"hello world\n".to_vec(), 5; "hello world")]
+    #[test_case(include_bytes!("async_io.rs").repeat(30), 999; "several blocks")]
+    fn test_same_as_compress(data: Vec<u8>, chunk_len: usize) {
+        runtime().block_on(async {
+            let compressed = encode_in_chunks(&data, CompressionLevel::FASTEST, chunk_len).await;
+            assert_eq!(compressed, compress(&data, CompressionLevel::FASTEST));
+            assert_eq!(decode(&compressed).await.unwrap(), data);
+        });
+    }
+
+    #[test_case(false; "blocking")]
+    #[test_case(true; "inline")]
+    fn test_pending_every_other_poll(inline: bool) {
+        let data = include_bytes!("async_io.rs").repeat(30);
+        let level = CompressionLevel::FASTEST;
+        runtime().block_on(async {
+            let mut encoder = AsyncBzEncoder::new(Stutter::new(Vec::new()), level);
+            if inline {
+                encoder = encoder.with_inline_compression();
+            }
+            for chunk in data.chunks(10_000) {
+                encoder.write_all(chunk).await.unwrap();
+            }
+            encoder.shutdown().await.unwrap();
+            let writer = encoder.into_inner();
+            // one for every chunk the compressed bytes were written in, at least
+            assert!(writer.pendings >= writer.inner.len() / CHUNK);
+            assert_eq!(writer.inner, compress(&data, level));
+
+            let mut decoder = AsyncBzDecoder::new(Stutter::new(&writer.inner[..]));
+            if inline {
+                decoder = decoder.with_inline_decoding();
+            }
+            let mut out = Vec::new();
+            decoder.read_to_end(&mut out).await.unwrap();
+            assert!(decoder.get_ref().pendings > 0);
+            assert!(out == data);
+        });
+    }
+
+    #[test]
+    fn test_flush_ends_block() {
+        runtime().block_on(async {
+            let mut encoder = AsyncBzEncoder::new(Vec::new(), CompressionLevel::BEST);
+            encoder.write_all(b"hello ").await.unwrap();
+            encoder.flush().await.unwrap();
+            assert!(encoder.get_ref().len() > 4);
+            encoder.write_all(b"world\n").await.unwrap();
+            encoder.shutdown().await.unwrap();
+            assert_eq!(decompress(encoder.get_ref()), Ok(b"hello world\n".to_vec()));
+        });
+    }
+
+    #[test]
+    fn test_write_after_shutdown() {
+        runtime().block_on(async {
+            let mut encoder = AsyncBzEncoder::new(Vec::new(), CompressionLevel::BEST);
+            encoder.shutdown().await.unwrap();
+            assert!(encoder.write_all(b"more").await.is_err());
+        });
+    }
+
+    #[test]
+    fn test_duplex() {
+        // the decoder reads what the encoder writes while it writes, through a pipe smaller than
+        // a block, on a runtime with a single thread for the tasks
+        let data = include_bytes!("async_io.rs").repeat(30);
+        let decoded = runtime().block_on(async {
+            let (writer, reader) = tokio::io::duplex(4096);
+            let input = data.clone();
+            let writing = tokio::spawn(async move {
+                let mut encoder = AsyncBzEncoder::new(writer, CompressionLevel::FASTEST);
+                encoder.write_all(&input).await.unwrap();
+                encoder.shutdown().await.unwrap();
+            });
+            let mut out = Vec::new();
+            let mut decoder = AsyncBzDecoder::new(reader);
+            decoder.read_to_end(&mut out).await.unwrap();
+            writing.await.unwrap();
+            out
+        });
+        assert!(decoded == data);
+    }
+
+    #[test]
+    fn test_decodes_before_the_input_ends() {
+        runtime().block_on(async {
+            let (mut writer, reader) = tokio::io::duplex(1024 * 1024);
+            writer
+                .write_all(&compress(b"hello world\n", CompressionLevel::BEST))
+                .await
+                .unwrap();
+            let mut decoder = AsyncBzDecoder::new(reader);
+            let mut buf = [0; 100];
+            // the stream is all there, so the block is returned without waiting for more
+            let len = decoder.read(&mut buf).await.unwrap();
+            assert_eq!(&buf[..len], b"hello world\n");
+            drop(writer);
+            assert_eq!(decoder.read(&mut buf).await.unwrap(), 0);
+        });
+    }
+
+    #[test]
+    fn test_truncated() {
+        let compressed = compress(&vec![7; 1000], CompressionLevel::BEST);
+        let err = runtime()
+            .block_on(decode(&compressed[..compressed.len() / 2]))
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn test_corrupt() {
+        let mut compressed = compress(b"hello world\n", CompressionLevel::BEST);
+        compressed[10] ^= 0x01;
+        let err = runtime().block_on(decode(&compressed)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(64))]
+
+        #[test]
+        fn prop_roundtrip(data in strategies::bytes(4096), chunk_len in 1usize..512) {
+            let decoded = runtime().block_on(async {
+                let compressed = encode_in_chunks(&data, CompressionLevel::FASTEST, chunk_len).await;
+                decode(&compressed).await.unwrap()
+            });
+            prop_assert_eq!(decoded, data);
+        }
+    }
+}
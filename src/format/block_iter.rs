@@ -0,0 +1,368 @@
+use alloc::vec::Vec;
+
+#[cfg(feature = "std")]
+use super::raw::RawBlock;
+use super::{
+    block_size::BlockSize,
+    header::{parse_header, HEADER_LEN},
+    reader::{read_bits48, read_block_within, read_symbols, BlockHeader, ReadError},
+    scan::{BLOCK_MAGIC, END_OF_STREAM_MAGIC},
+    symbol_map::SymbolMap,
+};
+use crate::{bits::BitReader, bwt::MemoryBudget, crc::StreamCrc};
+
+/// Walks the blocks of a `.bz2` stream without decompressing them.
+///
+/// `Blocks::new` reads the stream header at the position of the reader it borrows, then every
+/// block yields a `BlockInfo` with what its header declares. Its end is found by reading its
+/// Huffman coded symbols up to the end of block, without undoing the MTF, BWT and RLE1 stages,
+/// `BlockInfo::decode` does that on demand. The iterator stops at the footer, leaving the reader
+/// at the byte after it, where a concatenated stream would start, and stops for good after the
+/// first error.
+pub struct Blocks<'r, 'a> {
+    reader: &'r mut BitReader<'a>,
+    block_size: BlockSize,
+    index: usize,
+    /// The combined CRC of the CRCs the blocks so far declare
+    stream_crc: StreamCrc,
+    footer: Option<Footer>,
+    done: bool,
+}
+
+/// A block of a stream, as `Blocks` yields it
+#[derive(Debug, Clone)]
+pub struct BlockInfo<'a> {
+    /// Index of the block in its stream
+    pub index: usize,
+    /// Offset of the block magic in bits, from the start of the data of the reader
+    pub bit_offset: u64,
+    /// Length of the block in bits, from its magic to the end of its coded symbols
+    pub bit_len: u64,
+    /// The CRC of the block's bytes the block declares
+    pub crc: u32,
+    pub randomized: bool,
+    /// The origin pointer of the BWT
+    pub orig_ptr: usize,
+    /// The bytes the block uses
+    pub symbol_map: SymbolMap,
+    /// Code lengths of every Huffman table, one for each symbol of the block's alphabet
+    pub code_lengths: Vec<Vec<u8>>,
+    /// The table of every group of 50 symbols
+    pub selectors: Vec<u8>,
+    /// Length of the Huffman coded symbols in bits, up to and including the end of block
+    pub coded_bits: u64,
+    /// Right after the block magic, where `decode` starts
+    reader: BitReader<'a>,
+    block_size: BlockSize,
+}
+
+/// The end of stream marker of a stream, as `Blocks::footer` returns it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Footer {
+    /// Offset of the end of stream magic in bits, from the start of the data of the reader
+    pub bit_offset: u64,
+    /// The combined CRC of the stream the footer declares
+    pub crc: u32,
+    /// The combined CRC of the CRCs the blocks declare, equal to `crc` for an intact stream. Only
+    /// the blocks since `Blocks::resume` count.
+    pub blocks_crc: u32,
+}
+
+impl<'r, 'a> Blocks<'r, 'a> {
+    /// Read the stream header at the position of `reader`, which has to be at a whole byte
+    pub fn new(reader: &'r mut BitReader<'a>) -> Result<Self, ReadError> {
+        let mut header = [0; HEADER_LEN];
+        let available = (reader.remaining() / 8).min(HEADER_LEN as u64) as usize;
+        for byte in &mut header[..available] {
+            *byte = reader.peek_bits(8) as u8;
+            reader.skip(8)?;
+        }
+        let block_size = parse_header(&header[..available])?;
+        Ok(Self::resume(reader, block_size, 0))
+    }
+
+    /// Walk the blocks from the position of `reader` on, inside a stream of `block_size` whose
+    /// header is already behind it, counting them from `index`. E.g. to go on after a damaged
+    /// block at the next block magic `format::scan` finds.
+    pub fn resume(reader: &'r mut BitReader<'a>, block_size: BlockSize, index: usize) -> Self {
+        Self {
+            reader,
+            block_size,
+            index,
+            stream_crc: StreamCrc::new(),
+            footer: None,
+            done: false,
+        }
+    }
+
+    /// The block size the stream header declares
+    pub fn block_size(&self) -> BlockSize {
+        self.block_size
+    }
+
+    /// The footer of the stream, once the iterator got there
+    pub fn footer(&self) -> Option<Footer> {
+        self.footer
+    }
+
+    fn next_block(&mut self) -> Result<Option<BlockInfo<'a>>, ReadError> {
+        let bit_offset = self.reader.position();
+        match read_bits48(self.reader)? {
+            BLOCK_MAGIC => {
+                let start = self.reader.clone();
+                let header = BlockHeader::read(self.reader)?;
+                let coded_from = self.reader.position();
+                read_symbols(
+                    self.reader,
+                    &header.code_lengths,
+                    &header.selectors,
+                    header.symbol_map.len(),
+                )?;
+                self.stream_crc.combine(header.crc);
+                let block = BlockInfo {
+                    index: self.index,
+                    bit_offset,
+                    bit_len: self.reader.position() - bit_offset,
+                    crc: header.crc,
+                    randomized: header.randomized,
+                    orig_ptr: header.orig_ptr,
+                    symbol_map: header.symbol_map,
+                    code_lengths: header.code_lengths,
+                    selectors: header.selectors,
+                    coded_bits: self.reader.position() - coded_from,
+                    reader: start,
+                    block_size: self.block_size,
+                };
+                self.index += 1;
+                Ok(Some(block))
+            }
+            END_OF_STREAM_MAGIC => {
+                let crc = self.reader.read_bits(32)?;
+                self.footer = Some(Footer {
+                    bit_offset,
+                    crc,
+                    blocks_crc: self.stream_crc.value(),
+                });
+                // the padding up to the next byte, which the next stream would start at
+                let padding = (8 - self.reader.position() % 8) % 8;
+                self.reader.skip(padding.min(self.reader.remaining()))?;
+                Ok(None)
+            }
+            found => Err(ReadError::BadBlockMagic { bit_offset, found }),
+        }
+    }
+}
+
+impl<'a> Iterator for Blocks<'_, 'a> {
+    type Item = Result<BlockInfo<'a>, ReadError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let block = self.next_block();
+        self.done = !matches!(block, Ok(Some(_)));
+        block.transpose()
+    }
+}
+
+impl BlockInfo<'_> {
+    /// Number of Huffman tables
+    pub fn tables(&self) -> usize {
+        self.code_lengths.len()
+    }
+
+    /// The block on its own, e.g. to append it to another stream with
+    /// `StreamWriter::append_raw_block`
+    #[cfg(feature = "std")]
+    pub fn to_raw(&self) -> Result<RawBlock, ReadError> {
+        RawBlock::read(&mut self.reader.clone())
+    }
+
+    /// Decompress the block, checking it against its CRC
+    pub fn decode(&self) -> Result<Vec<u8>, ReadError> {
+        let mut out = Vec::new();
+        read_block_within(
+            &mut self.reader.clone(),
+            self.block_size.bytes(),
+            MemoryBudget::UNLIMITED,
+            self.index,
+            &mut (),
+            &mut out,
+        )?;
+        Ok(out)
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use test_case::test_case;
+
+    use super::*;
+    use crate::{
+        analyze, compress,
+        crc::BlockCrc,
+        format::{
+            block_size::CompressionLevel,
+            header::{FormatGuess, HeaderError},
+            reader::decompress,
+            scan::find_magic,
+            writer::StreamWriter,
+        },
+        huff::GROUP_SIZE,
+    };
+
+    /// Three blocks of 100 000 bytes at level 1, none of them byte aligned after the first
+    fn three_blocks() -> (Vec<u8>, StreamWriter) {
+        let data: Vec<u8> = (0..250_000u32)
+            .map(|i| (i.wrapping_mul(2_654_435_761) >> 27) as u8)
+            .collect();
+        let mut writer = StreamWriter::builder()
+            .level(CompressionLevel::FASTEST)
+            .build();
+        writer.write_blocks(&data);
+        (data, writer)
+    }
+
+    #[test]
+    fn test_blocks() {
+        let (data, writer) = three_blocks();
+        let index = writer.index().clone();
+        let compressed = writer.finish();
+        let mut reader = BitReader::new(&compressed);
+        let mut blocks = Blocks::new(&mut reader).unwrap();
+        assert_eq!(blocks.block_size(), CompressionLevel::FASTEST.block_size());
+
+        let infos: Vec<_> = blocks.by_ref().collect::<Result<_, _>>().unwrap();
+        assert_eq!(infos.len(), 3);
+        assert!(infos.iter().any(|block| block.bit_offset % 8 != 0));
+        let mut decoded = Vec::new();
+        for (block, entry) in infos.iter().zip(index.entries()) {
+            assert_eq!(block.bit_offset, entry.bit_offset);
+            assert_eq!(block.bit_len, entry.bit_len);
+            let bytes = block.decode().unwrap();
+            let report = &analyze(&bytes).blocks[0];
+            assert_eq!(block.orig_ptr, report.original_index);
+            assert_eq!(block.symbol_map.len() + 2, report.alphabet_size);
+            assert_eq!(block.code_lengths, report.code_lengths);
+            let symbols: u32 = report.symbol_histogram.iter().sum();
+            assert_eq!(
+                block.selectors.len(),
+                symbols.div_ceil(GROUP_SIZE as u32) as usize
+            );
+            assert_eq!(block.crc, BlockCrc::checksum(&bytes));
+            decoded.extend(bytes);
+        }
+        assert_eq!(decoded, data);
+        assert_eq!(Ok(decoded), decompress(&compressed));
+
+        let footer = blocks.footer().unwrap();
+        assert_eq!(footer.crc, footer.blocks_crc);
+        let last = &infos[2];
+        assert_eq!(footer.bit_offset, last.bit_offset + last.bit_len);
+        assert_eq!(reader.position(), compressed.len() as u64 * 8);
+    }
+
+    #[test]
+    fn test_concatenated_streams() {
+        let parts = [&include_bytes!("block_iter.rs")[..], b"", b"hello world\n"];
+        let compressed: Vec<u8> = parts
+            .iter()
+            .flat_map(|part| compress(part, CompressionLevel::BEST))
+            .collect();
+        let mut reader = BitReader::new(&compressed);
+        for part in parts {
+            let mut blocks = Blocks::new(&mut reader).unwrap();
+            let mut decoded = Vec::new();
+            for block in blocks.by_ref() {
+                decoded.extend(block.unwrap().decode().unwrap());
+            }
+            assert_eq!(decoded, part);
+            let footer = blocks.footer().unwrap();
+            assert_eq!(footer.crc, footer.blocks_crc);
+        }
+        assert_eq!(reader.remaining(), 0);
+    }
+
+    #[test_case(|data| data.truncate(20) => ReadError::Truncated { needed_bits: 9 }; "truncated")]
+    #[test_case(|data| data[4] = 0 => ReadError::BadBlockMagic { bit_offset: 32, found: 0x0041_5926_5359 }; "bad block magic")]
+    #[test_case(|data| data.splice(..4, [0x1f, 0x8b, 8, 0]).for_each(drop) => ReadError::Header(HeaderError::UnsupportedFormat { detected: FormatGuess::Gzip }); "gzip")]
+    fn test_errors(damage: fn(&mut Vec<u8>)) -> ReadError {
+        let mut compressed = compress(b"hello world\n", CompressionLevel::BEST);
+        damage(&mut compressed);
+        let mut reader = BitReader::new(&compressed);
+        let mut blocks = match Blocks::new(&mut reader) {
+            Ok(blocks) => blocks,
+            Err(err) => return err,
+        };
+        let err = blocks.next().unwrap().unwrap_err();
+        // it stops at the first error
+        assert!(blocks.next().is_none());
+        err
+    }
+
+    #[test]
+    fn test_resume() {
+        let (data, writer) = three_blocks();
+        let entries = writer.index().entries().to_vec();
+        let mut compressed = writer.finish();
+        // the symbol map of the second block uses no bytes at all
+        let symbol_map = entries[1].bit_offset + 48 + 32 + 1 + 24;
+        for bit in symbol_map..symbol_map + 16 {
+            compressed[bit as usize / 8] &= !(0x80 >> (bit % 8));
+        }
+
+        let mut reader = BitReader::new(&compressed);
+        let mut blocks = Blocks::new(&mut reader).unwrap();
+        assert_eq!(blocks.next().unwrap().unwrap().index, 0);
+        assert_eq!(
+            blocks.next().unwrap().unwrap_err(),
+            ReadError::InvalidSymbolMap
+        );
+        assert!(blocks.next().is_none());
+
+        let next = find_magic(&compressed, BLOCK_MAGIC)
+            .into_iter()
+            .find(|&offset| offset > entries[1].bit_offset)
+            .unwrap();
+        assert_eq!(next, entries[2].bit_offset);
+        let mut reader = BitReader::new(&compressed);
+        reader.skip(next).unwrap();
+        let block_size = CompressionLevel::FASTEST.block_size();
+        let mut blocks = Blocks::resume(&mut reader, block_size, 2);
+        let block = blocks.next().unwrap().unwrap();
+        assert_eq!(block.index, 2);
+        let start = entries[2].uncompressed_offset as usize;
+        assert_eq!(block.decode().unwrap(), data[start..]);
+        assert!(blocks.next().is_none());
+        let mut blocks_crc = StreamCrc::new();
+        blocks_crc.combine(block.crc);
+        assert_eq!(blocks.footer().unwrap().blocks_crc, blocks_crc.value());
+    }
+
+    #[test]
+    fn test_to_raw() {
+        let (data, writer) = three_blocks();
+        let index = writer.index().clone();
+        let compressed = writer.finish();
+        let mut reader = BitReader::new(&compressed);
+        let mut copy = StreamWriter::builder()
+            .level(CompressionLevel::FASTEST)
+            .build();
+        for (block, entry) in Blocks::new(&mut reader).unwrap().zip(index.entries()) {
+            let block = block.unwrap();
+            let raw = block.to_raw().unwrap();
+            assert_eq!(raw.bit_len() + 48, block.bit_len);
+            assert_eq!(raw.crc(), block.crc);
+            assert_eq!(raw.orig_ptr(), block.orig_ptr);
+            assert_eq!(raw.symbol_map(), block.symbol_map);
+            assert_eq!(raw.code_lengths(), block.code_lengths);
+            assert_eq!(raw.selectors(), block.selectors);
+            copy.append_raw_block(&raw, entry.uncompressed_len as usize);
+        }
+        assert_eq!(copy.index(), &index);
+        let copy = copy.finish();
+        assert_eq!(copy, compressed);
+        assert_eq!(decompress(&copy), Ok(data));
+    }
+}
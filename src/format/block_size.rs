@@ -0,0 +1,263 @@
+use alloc::vec::Vec;
+use core::{iter, ops::Range};
+
+use thiserror::Error;
+
+/// Number of bytes per unit of the block size level, i.e. level 9 allows blocks of 900 000 bytes
+pub const BLOCK_SIZE_UNIT: usize = 100_000;
+
+/// Length of each region `auto` samples from the input
+const SAMPLE_REGION_LEN: usize = 16 * 1024;
+/// Number of evenly spaced regions `auto` samples from the input
+const SAMPLE_REGIONS: usize = 4;
+/// A sample with at least this many distinct bytes and ...
+const INCOMPRESSIBLE_MIN_DISTINCT: usize = 240;
+/// ... fewer than one byte in this many repeating its predecessor looks like random or already
+/// compressed data
+const INCOMPRESSIBLE_RUN_RATIO: usize = 64;
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum BlockSizeError {
+    #[error("Block size level {0} is not between 1 and 9")]
+    InvalidLevel(u8),
+}
+
+/// Block size of a stream, as the level `1..=9` from the stream header (the `9` in `BZh9`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct BlockSize(u8);
+
+impl BlockSize {
+    pub const MIN: Self = Self(1);
+    pub const MAX: Self = Self(9);
+
+    pub fn new(level: u8) -> Result<Self, BlockSizeError> {
+        if (Self::MIN.0..=Self::MAX.0).contains(&level) {
+            Ok(Self(level))
+        } else {
+            Err(BlockSizeError::InvalidLevel(level))
+        }
+    }
+
+    pub fn level(self) -> u8 {
+        self.0
+    }
+
+    /// Maximum number of bytes in a block of this size
+    pub fn bytes(self) -> usize {
+        self.0 as usize * BLOCK_SIZE_UNIT
+    }
+
+    /// Pick a block size for an input of `data_len` bytes, of which `sample` is a representative
+    /// part.
+    ///
+    /// If the input fits into a single block, the smallest block size that holds it is enough, a
+    /// larger one would only make the decoder reserve memory it never uses. Otherwise the sample
+    /// decides: random or already compressed data doesn't get any smaller with larger blocks, so
+    /// it gets the smallest block size, which keeps the memory use of both sides low. Anything
+    /// else (text, runs, small alphabets) gets the largest block size, since the BWT finds more
+    /// context the more data it sorts at once.
+    pub fn auto_for(data_len: usize, sample: &[u8]) -> Self {
+        if data_len <= Self::MAX.bytes() {
+            return Self(data_len.div_ceil(BLOCK_SIZE_UNIT).max(1) as u8);
+        }
+        if looks_incompressible(sample) {
+            Self::MIN
+        } else {
+            Self::MAX
+        }
+    }
+
+    /// Same as `auto_for`, sampling a few evenly spaced regions of `data`.
+    pub fn auto(data: &[u8]) -> Self {
+        Self::auto_for(data.len(), &sample_regions(data))
+    }
+}
+
+impl Default for BlockSize {
+    fn default() -> Self {
+        Self::MAX
+    }
+}
+
+/// Compression level as the `bzip2` tool takes it, `-1` to `-9`.
+///
+/// The level is nothing but the block size: level `n` compresses blocks of `n * 100 000` bytes
+/// and writes `n` as the digit of the stream header. Larger blocks compress better and need more
+/// memory on both sides.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum CompressionLevel {
+    One = 1,
+    Two,
+    Three,
+    Four,
+    Five,
+    Six,
+    Seven,
+    Eight,
+    #[default]
+    Nine,
+}
+
+impl CompressionLevel {
+    /// `--fast` of the reference tool
+    pub const FASTEST: Self = Self::One;
+    /// `--best` of the reference tool, also the default
+    pub const BEST: Self = Self::Nine;
+
+    pub const ALL: [Self; 9] = [
+        Self::One,
+        Self::Two,
+        Self::Three,
+        Self::Four,
+        Self::Five,
+        Self::Six,
+        Self::Seven,
+        Self::Eight,
+        Self::Nine,
+    ];
+
+    pub fn value(self) -> u8 {
+        self as u8
+    }
+
+    pub fn block_size(self) -> BlockSize {
+        BlockSize(self.value())
+    }
+}
+
+impl TryFrom<u8> for CompressionLevel {
+    type Error = BlockSizeError;
+
+    fn try_from(level: u8) -> Result<Self, BlockSizeError> {
+        Self::ALL
+            .get((level as usize).wrapping_sub(1))
+            .copied()
+            .ok_or(BlockSizeError::InvalidLevel(level))
+    }
+}
+
+impl From<CompressionLevel> for BlockSize {
+    fn from(level: CompressionLevel) -> Self {
+        level.block_size()
+    }
+}
+
+/// The `SAMPLE_REGIONS` evenly spaced regions `BlockSize::auto` samples from an input of `len`
+/// bytes, or all of it if it is shorter, e.g. to read just those of a file for `auto_for`
+pub fn sample_ranges(len: usize) -> Vec<Range<usize>> {
+    if len <= SAMPLE_REGIONS * SAMPLE_REGION_LEN {
+        return iter::once(0..len).collect();
+    }
+    let stride = (len - SAMPLE_REGION_LEN) / (SAMPLE_REGIONS - 1);
+    (0..SAMPLE_REGIONS)
+        .map(|region| region * stride..region * stride + SAMPLE_REGION_LEN)
+        .collect()
+}
+
+/// Concatenate the `sample_ranges` of `data`
+fn sample_regions(data: &[u8]) -> Vec<u8> {
+    sample_ranges(data.len())
+        .into_iter()
+        .flat_map(|range| &data[range])
+        .copied()
+        .collect()
+}
+
+/// Whether `sample` uses nearly the whole byte range with hardly any repeated bytes
+fn looks_incompressible(sample: &[u8]) -> bool {
+    let mut present = [false; 256];
+    for &byte in sample {
+        present[byte as usize] = true;
+    }
+    let distinct = present.iter().filter(|&&present| present).count();
+    let repeats = sample.windows(2).filter(|pair| pair[0] == pair[1]).count();
+    distinct >= INCOMPRESSIBLE_MIN_DISTINCT && repeats * INCOMPRESSIBLE_RUN_RATIO < sample.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use test_case::test_case;
+
+    use super::*;
+    use crate::stages::strategies::fixtures::{random, runs, text};
+
+    #[test_case(0 => Err(BlockSizeError::InvalidLevel(0)); "zero")]
+    #[test_case(1 => Ok(BlockSize(1)); "smallest")]
+    #[test_case(9 => Ok(BlockSize(9)); "largest")]
+    #[test_case(10 => Err(BlockSizeError::InvalidLevel(10)); "too large")]
+    fn test_new(level: u8) -> Result<BlockSize, BlockSizeError> {
+        BlockSize::new(level)
+    }
+
+    #[test_case(0 => Err(BlockSizeError::InvalidLevel(0)); "zero")]
+    #[test_case(1 => Ok(CompressionLevel::FASTEST); "fastest")]
+    #[test_case(6 => Ok(CompressionLevel::Six); "six")]
+    #[test_case(9 => Ok(CompressionLevel::BEST); "best")]
+    #[test_case(10 => Err(BlockSizeError::InvalidLevel(10)); "too large")]
+    fn test_compression_level(level: u8) -> Result<CompressionLevel, BlockSizeError> {
+        CompressionLevel::try_from(level)
+    }
+
+    #[test]
+    fn test_compression_level_block_sizes() {
+        for (i, level) in CompressionLevel::ALL.into_iter().enumerate() {
+            assert_eq!(level.value() as usize, i + 1);
+            assert_eq!(level.block_size().bytes(), (i + 1) * 100_000);
+            assert_eq!(
+                BlockSize::from(level),
+                BlockSize::new(level.value()).unwrap()
+            );
+        }
+        assert_eq!(
+            BlockSize::from(CompressionLevel::default()),
+            BlockSize::default()
+        );
+    }
+
+    #[test_case(text(50 * 1024) => 1; "50 KB text")]
+    #[test_case(random(50 * 1024) => 1; "50 KB random")]
+    #[test_case(text(600 * 1024) => 7; "600 KB text")]
+    #[test_case(random(600 * 1024) => 7; "600 KB random")]
+    #[test_case(text(900_000) => 9; "exactly one largest block")]
+    #[test_case(text(5 * 1024 * 1024) => 9; "5 MB text")]
+    #[test_case(runs(5 * 1024 * 1024) => 9; "5 MB runs")]
+    #[test_case(random(5 * 1024 * 1024) => 1; "5 MB random")]
+    #[test_case(Vec::new() => 1; "empty")]
+    fn test_auto(data: Vec<u8>) -> u8 {
+        BlockSize::auto(&data).level()
+    }
+
+    #[test_case(50 * 1024, text(4096) => 1; "50 KB text")]
+    #[test_case(50 * 1024, random(4096) => 1; "50 KB random")]
+    #[test_case(600 * 1024, text(4096) => 7; "600 KB text")]
+    #[test_case(600 * 1024, random(4096) => 7; "600 KB random")]
+    #[test_case(5 * 1024 * 1024, text(4096) => 9; "5 MB text")]
+    #[test_case(5 * 1024 * 1024, runs(4096) => 9; "5 MB runs")]
+    #[test_case(5 * 1024 * 1024, random(4096) => 1; "5 MB random")]
+    #[test_case(5 * 1024 * 1024, Vec::new() => 9; "5 MB without sample")]
+    fn test_auto_for(data_len: usize, sample: Vec<u8>) -> u8 {
+        BlockSize::auto_for(data_len, &sample).level()
+    }
+
+    #[test]
+    fn test_auto_is_deterministic() {
+        let data = text(2 * 1024 * 1024);
+        assert_eq!(BlockSize::auto(&data), BlockSize::auto(&data));
+        assert_eq!(
+            BlockSize::auto(&data),
+            BlockSize::auto_for(data.len(), &sample_regions(&data))
+        );
+    }
+
+    #[test]
+    fn test_sample_regions_covers_start_and_end() {
+        let data: Vec<u8> = (0..1_000_000u32).map(|i| (i % 251) as u8).collect();
+        let sample = sample_regions(&data);
+        assert_eq!(sample.len(), SAMPLE_REGIONS * SAMPLE_REGION_LEN);
+        assert_eq!(&sample[..SAMPLE_REGION_LEN], &data[..SAMPLE_REGION_LEN]);
+        assert_eq!(
+            &sample[sample.len() - SAMPLE_REGION_LEN..],
+            &data[data.len() - SAMPLE_REGION_LEN..]
+        );
+    }
+}
@@ -0,0 +1,267 @@
+use super::block_size::BlockSize;
+
+/// Longest run RLE1 writes as a single group of four bytes and a count
+const MAX_RLE_RUN: usize = 255;
+/// Encoded length of a run of four or more bytes: the four bytes and the count
+const RLE_GROUP_LEN: usize = 5;
+
+/// Splits an input into the blocks of a stream.
+///
+/// Every block is as long as possible while its RLE1 encoding still fits into the block size, so
+/// that `StreamWriter::write_block` accepts it. A run that doesn't fit into the rest of a block is
+/// cut, its remainder starts the next block. Each block is RLE1 encoded on its own, the decoder
+/// doesn't carry runs over from one block to the next either.
+pub struct BlockSplitter<'a> {
+    data: &'a [u8],
+    max_len: usize,
+}
+
+impl<'a> BlockSplitter<'a> {
+    pub fn new(data: &'a [u8], block_size: BlockSize) -> Self {
+        Self {
+            data,
+            max_len: block_size.bytes(),
+        }
+    }
+}
+
+impl<'a> Iterator for BlockSplitter<'a> {
+    type Item = &'a [u8];
+
+    fn next(&mut self) -> Option<&'a [u8]> {
+        if self.data.is_empty() {
+            return None;
+        }
+        let mut block_len = 0;
+        let mut encoded_len = 0;
+        for run in self.data.chunk_by(|a, b| a == b) {
+            let run_len = rle_len(run.len());
+            if encoded_len + run_len > self.max_len {
+                block_len += longest_run_within(self.max_len - encoded_len);
+                break;
+            }
+            block_len += run.len();
+            encoded_len += run_len;
+        }
+        let (block, rest) = self.data.split_at(block_len);
+        self.data = rest;
+        Some(block)
+    }
+}
+
+/// Collects input that arrives in pieces into one block, cutting it where `BlockSplitter` would.
+///
+/// `fill` takes bytes until the RLE1 encoding of the block would outgrow the block size, the
+/// caller then compresses `block`, `clear`s the buffer and fills it with the rest of its input.
+/// However the input is chunked, the blocks come out the same as from `BlockSplitter` on all of
+/// it at once.
+#[derive(Debug)]
+pub struct BlockBuffer {
+    block: Vec<u8>,
+    max_len: usize,
+    /// RLE1 length of the block up to its last run
+    closed_len: usize,
+    /// Length of the last run of the block, which later input may continue
+    run_len: usize,
+}
+
+impl BlockBuffer {
+    pub fn new(block_size: BlockSize) -> Self {
+        Self {
+            block: Vec::new(),
+            max_len: block_size.bytes(),
+            closed_len: 0,
+            run_len: 0,
+        }
+    }
+
+    /// Append as much of `data` to the block as fits and return how many bytes that was. Less
+    /// than all of `data` means the block is full.
+    pub fn fill(&mut self, data: &[u8]) -> usize {
+        let mut taken = 0;
+        for run in data.chunk_by(|a, b| a == b) {
+            if self.block.last() != Some(&run[0]) {
+                self.closed_len += rle_len(self.run_len);
+                self.run_len = 0;
+            }
+            let capacity = self.max_len - self.closed_len;
+            let fits = if rle_len(self.run_len + run.len()) <= capacity {
+                run.len()
+            } else {
+                longest_run_within(capacity) - self.run_len
+            };
+            self.block.extend_from_slice(&run[..fits]);
+            self.run_len += fits;
+            taken += fits;
+            if fits < run.len() {
+                break;
+            }
+        }
+        taken
+    }
+
+    pub fn block(&self) -> &[u8] {
+        &self.block
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.block.is_empty()
+    }
+
+    /// Empty the buffer for the next block, keeping its allocation
+    pub fn clear(&mut self) {
+        self.block.clear();
+        self.closed_len = 0;
+        self.run_len = 0;
+    }
+}
+
+/// Length of a run of `run_len` equal bytes after RLE1
+fn rle_len(run_len: usize) -> usize {
+    let remainder = run_len % MAX_RLE_RUN;
+    run_len / MAX_RLE_RUN * RLE_GROUP_LEN
+        + if remainder < 4 {
+            remainder
+        } else {
+            RLE_GROUP_LEN
+        }
+}
+
+/// Longest run whose RLE1 encoding takes at most `capacity` bytes
+fn longest_run_within(capacity: usize) -> usize {
+    // full runs are the densest, the bytes left over take at most three more bytes of a run
+    capacity / RLE_GROUP_LEN * MAX_RLE_RUN + (capacity % RLE_GROUP_LEN).min(3)
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+    use test_case::test_case;
+
+    use super::*;
+    use crate::{rle::RleSequence, stages::strategies};
+
+    fn block_lens(data: &[u8], level: u8) -> Vec<usize> {
+        BlockSplitter::new(data, BlockSize::new(level).unwrap())
+            .map(<[u8]>::len)
+            .collect()
+    }
+
+    #[test_case(0 => 0; "empty")]
+    #[test_case(3 => 3; "too short to encode")]
+    #[test_case(4 => 5; "shortest encoded run")]
+    #[test_case(255 => 5; "longest encoded run")]
+    #[test_case(256 => 6; "one byte past a full run")]
+    #[test_case(259 => 10; "two encoded runs")]
+    #[test_case(1000 => 20; "several full runs")]
+    fn test_rle_len(run_len: usize) -> usize {
+        assert_eq!(
+            RleSequence::encode(&vec![b'a'; run_len]).len(),
+            rle_len(run_len)
+        );
+        rle_len(run_len)
+    }
+
+    #[test_case(0 => 0; "nothing")]
+    #[test_case(3 => 3; "three single bytes")]
+    #[test_case(4 => 3; "four bytes would need five")]
+    #[test_case(5 => 255; "one full run")]
+    #[test_case(13 => 513; "two full runs and three bytes")]
+    fn test_longest_run_within(capacity: usize) -> usize {
+        let run_len = longest_run_within(capacity);
+        assert!(rle_len(run_len) <= capacity);
+        assert!(rle_len(run_len + 1) > capacity);
+        run_len
+    }
+
+    #[test]
+    fn test_empty_input_has_no_blocks() {
+        assert_eq!(block_lens(b"", 1), Vec::<usize>::new());
+    }
+
+    #[test]
+    fn test_input_that_fits_is_one_block() {
+        let data: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+        assert_eq!(block_lens(&data, 1), vec![100_000]);
+    }
+
+    #[test]
+    fn test_split_without_runs() {
+        let data: Vec<u8> = (0..250_000u32).map(|i| (i % 251) as u8).collect();
+        assert_eq!(block_lens(&data, 1), vec![100_000, 100_000, 50_000]);
+    }
+
+    #[test]
+    fn test_split_inside_long_run() {
+        // 100 000 encoded bytes are 20 000 full runs of 255 bytes
+        let data = vec![0; 6_000_000];
+        assert_eq!(block_lens(&data, 1), vec![5_100_000, 900_000]);
+    }
+
+    #[test]
+    fn test_split_worst_case_runs() {
+        // every run of four bytes grows to five, so a block holds 20 000 of them
+        let data = b"aaaabbbb".repeat(30_000);
+        assert_eq!(block_lens(&data, 1), vec![80_000, 80_000, 80_000]);
+    }
+
+    /// Blocks of `data` fed to a `BlockBuffer` in pieces of `chunk_len` bytes
+    fn buffered_blocks(data: &[u8], max_len: usize, chunk_len: usize) -> Vec<Vec<u8>> {
+        let mut buffer = BlockBuffer {
+            max_len,
+            ..BlockBuffer::new(BlockSize::MIN)
+        };
+        let mut blocks = Vec::new();
+        for mut chunk in data.chunks(chunk_len) {
+            while !chunk.is_empty() {
+                let taken = buffer.fill(chunk);
+                chunk = &chunk[taken..];
+                if !chunk.is_empty() {
+                    blocks.push(buffer.block().to_vec());
+                    buffer.clear();
+                }
+            }
+        }
+        if !buffer.is_empty() {
+            blocks.push(buffer.block().to_vec());
+        }
+        blocks
+    }
+
+    #[test]
+    fn test_buffer_splits_long_run_across_fills() {
+        let data = vec![0; 6_000_000];
+        let blocks = buffered_blocks(&data, BlockSize::MIN.bytes(), 4096);
+        let lens: Vec<usize> = blocks.iter().map(Vec::len).collect();
+        assert_eq!(lens, vec![5_100_000, 900_000]);
+    }
+
+    proptest! {
+        #[test]
+        fn prop_buffer_matches_splitter(
+            data in strategies::runs(16 * 1024),
+            units in 1usize..40,
+            chunk_len in 1usize..2000,
+        ) {
+            let splitter = BlockSplitter { data: &data, max_len: units * 100 };
+            let expected: Vec<Vec<u8>> = splitter.map(<[u8]>::to_vec).collect();
+            prop_assert_eq!(buffered_blocks(&data, units * 100, chunk_len), expected);
+        }
+
+        #[test]
+        fn prop_blocks_fit_and_cover_input(data in strategies::bytes(16 * 1024), units in 1usize..40) {
+            let splitter = BlockSplitter { data: &data, max_len: units * 100 };
+            let blocks: Vec<&[u8]> = splitter.collect();
+            prop_assert_eq!(blocks.concat(), data.clone());
+            for (i, block) in blocks.iter().enumerate() {
+                let encoded_len = RleSequence::encode(block).len();
+                prop_assert!(!block.is_empty());
+                prop_assert!(encoded_len <= units * 100);
+                // every block but the last is full, up to the few bytes a run can't use
+                if i + 1 < blocks.len() {
+                    prop_assert!(encoded_len + RLE_GROUP_LEN > units * 100);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,558 @@
+use std::io::{self, Read};
+
+use super::{
+    block_size::BlockSize,
+    header::{parse_header, HeaderError, HEADER_LEN, STREAM_MAGIC},
+    observer::StageObserver,
+    progress::{Progress, ProgressSink},
+    reader::{read_bits48, read_block, ReadError},
+    scan::{BLOCK_MAGIC, END_OF_STREAM_MAGIC},
+};
+use crate::{bits::BitReader, bwt::MemoryBudget, crc::StreamCrc};
+
+/// Least number of bytes read from the inner reader at once
+const MIN_READ: usize = 64 * 1024;
+
+/// Decompresses the `.bz2` data read from the inner reader, one block at a time.
+///
+/// Only the compressed bytes of the block being decoded and the decoded bytes of one block are
+/// held in memory, so inputs of any size can be read through it. Like `decompress`, it reads
+/// streams back to back until the inner reader ends, checks every block and stream CRC, and
+/// fails on data after the last stream.
+///
+/// Corrupt data fails a `read` with `ErrorKind::InvalidData`, data that ends in the middle of a
+/// stream with `ErrorKind::UnexpectedEof`, both wrapping the `ReadError`. A non-blocking inner
+/// reader's `ErrorKind::WouldBlock` is passed on once it has nothing at all, and the next `read`
+/// picks up where this one stopped.
+pub struct BzDecoder<R: Read> {
+    inner: R,
+    /// Compressed bytes read from `inner` but not consumed yet
+    input: Vec<u8>,
+    /// Bits of the first byte of `input` that are already consumed
+    bit_pos: u64,
+    /// Bytes dropped from the front of `input` so far
+    consumed: u64,
+    /// Whether `inner` has no more data
+    eof: bool,
+    state: State,
+    /// The decoded bytes of the current block, `block_pos` of which `read` already returned
+    block: Vec<u8>,
+    block_pos: usize,
+    info: StreamInfo,
+    budget: MemoryBudget,
+    progress: Option<Box<dyn ProgressSink + Send>>,
+    observer: Box<dyn StageObserver + Send>,
+    /// Whether to stop at the end of the first stream
+    single_stream: bool,
+}
+
+/// What a `BzDecoder` has read and checked so far
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StreamInfo {
+    /// Streams read up to and including their end of stream marker and combined CRC
+    pub streams: u64,
+    /// Blocks decoded and checked against their CRC
+    pub blocks: u64,
+    /// Bytes of compressed input consumed
+    pub compressed_len: u64,
+    /// Bytes the blocks decoded to
+    pub uncompressed_len: u64,
+}
+
+enum State {
+    /// Expecting the header of a stream, `first` for the first one, which has to be there
+    Header {
+        first: bool,
+    },
+    /// Between the blocks of a stream that started `stream_start` bits into the input
+    Blocks {
+        block_size: BlockSize,
+        stream_crc: StreamCrc,
+        stream_start: u64,
+    },
+    Done,
+}
+
+impl<R: Read> BzDecoder<R> {
+    pub fn new(inner: R) -> Self {
+        Self::with_memory_budget(inner, MemoryBudget::UNLIMITED)
+    }
+
+    /// Same as `new`, but decodes every block with the inverse BWT `budget` picks for it, e.g.
+    /// `MemoryBudget::SMALL` for the slower inverse BWT that takes less than a third of the memory
+    pub fn with_memory_budget(inner: R, budget: MemoryBudget) -> Self {
+        Self {
+            inner,
+            input: Vec::new(),
+            bit_pos: 0,
+            consumed: 0,
+            eof: false,
+            state: State::Header { first: true },
+            block: Vec::new(),
+            block_pos: 0,
+            info: StreamInfo::default(),
+            budget,
+            progress: None,
+            observer: Box::new(()),
+            single_stream: false,
+        }
+    }
+
+    /// Report the progress to `sink` after every decoded block
+    pub fn with_progress(mut self, sink: impl ProgressSink + Send + 'static) -> Self {
+        self.progress = Some(Box::new(sink));
+        self
+    }
+
+    /// Hand what every stage of every block decodes to to `observer`
+    pub fn with_observer(mut self, observer: impl StageObserver + Send + 'static) -> Self {
+        self.observer = Box::new(observer);
+        self
+    }
+
+    /// Stop at the end of the first stream, like the streaming API of the reference library,
+    /// instead of reading streams until the inner reader ends. Whatever follows the stream isn't
+    /// checked, and may be read into the buffer already: `info().compressed_len` is where the
+    /// stream ended.
+    pub fn single_stream(mut self) -> Self {
+        self.single_stream = true;
+        self
+    }
+
+    /// Counts of everything decoded so far. After the decoder returned the end of its input,
+    /// this covers all of it.
+    pub fn info(&self) -> StreamInfo {
+        StreamInfo {
+            compressed_len: self.consumed,
+            ..self.info
+        }
+    }
+
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+
+    /// The inner reader. Reading from it directly corrupts the stream.
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.inner
+    }
+
+    /// Decode whatever comes next, a stream header, a block or the end of a stream, until there
+    /// are decoded bytes to return or the input is done
+    fn decode_next(&mut self) -> io::Result<()> {
+        match self.state {
+            State::Header { first } => self.read_header(first),
+            State::Blocks { .. } => self.read_block_or_end(),
+            State::Done => Ok(()),
+        }
+    }
+
+    fn read_header(&mut self, first: bool) -> io::Result<()> {
+        while self.input.len() < HEADER_LEN && !self.eof {
+            self.fill()?;
+        }
+        let offset = self.consumed;
+        if !first {
+            if self.input.is_empty() {
+                self.state = State::Done;
+                return Ok(());
+            }
+            if !self.input.starts_with(STREAM_MAGIC) {
+                let offset = offset as usize;
+                return Err(invalid(ReadError::TrailingGarbage { offset }, self.eof));
+            }
+        }
+        let block_size = parse_header(&self.input).map_err(|err| {
+            let kind = match err {
+                HeaderError::TruncatedHeader => io::ErrorKind::UnexpectedEof,
+                _ => io::ErrorKind::InvalidData,
+            };
+            io::Error::new(kind, ReadError::from(err))
+        })?;
+        self.consume(HEADER_LEN as u64 * 8);
+        self.state = State::Blocks {
+            block_size,
+            stream_crc: StreamCrc::new(),
+            stream_start: offset * 8,
+        };
+        Ok(())
+    }
+
+    fn read_block_or_end(&mut self) -> io::Result<()> {
+        let State::Blocks {
+            block_size,
+            mut stream_crc,
+            stream_start,
+        } = self.state
+        else {
+            unreachable!("Blocks are only read between a header and the end of the stream");
+        };
+        loop {
+            let mut reader = BitReader::new(&self.input);
+            reader
+                .skip(self.bit_pos)
+                .expect("Consumed bits are part of the input");
+            let offset = self.consumed * 8 + reader.position() - stream_start;
+            self.block.clear();
+            self.block_pos = 0;
+            let result = match read_bits48(&mut reader) {
+                Ok(BLOCK_MAGIC) => {
+                    let index = self.info.blocks as usize;
+                    read_block(
+                        &mut reader,
+                        block_size,
+                        self.budget,
+                        index,
+                        self.observer.as_mut(),
+                        &mut self.block,
+                    )
+                    .map(Some)
+                }
+                Ok(END_OF_STREAM_MAGIC) => {
+                    read_end_of_stream(&mut reader, stream_crc).map(|_| None)
+                }
+                Ok(found) => Err(ReadError::BadBlockMagic {
+                    bit_offset: offset,
+                    found,
+                }),
+                Err(err) => Err(err.into()),
+            };
+            match result {
+                Ok(block_crc) => {
+                    let position = reader.position();
+                    self.consume(position);
+                    self.state = match block_crc {
+                        Some(crc) => {
+                            self.info.blocks += 1;
+                            self.info.uncompressed_len += self.block.len() as u64;
+                            stream_crc.combine(crc);
+                            if let Some(sink) = &mut self.progress {
+                                sink.progress(Progress {
+                                    bytes_in: self.consumed,
+                                    bytes_out: self.info.uncompressed_len,
+                                    blocks_done: self.info.blocks,
+                                });
+                            }
+                            State::Blocks {
+                                block_size,
+                                stream_crc,
+                                stream_start,
+                            }
+                        }
+                        None => {
+                            self.info.streams += 1;
+                            if self.single_stream {
+                                State::Done
+                            } else {
+                                State::Header { first: false }
+                            }
+                        }
+                    };
+                    return Ok(());
+                }
+                Err(err) if is_truncation(&err) && !self.eof => self.fill()?,
+                Err(err) => {
+                    self.block.clear();
+                    return Err(invalid(err, self.eof));
+                }
+            }
+        }
+    }
+
+    /// Read more of the compressed input, at least as much as is buffered already, so a block
+    /// that doesn't fit is decoded again only a few times. A non-blocking inner reader that runs
+    /// dry after some bytes only ends the read early.
+    fn fill(&mut self) -> io::Result<()> {
+        let len = self.input.len();
+        let want = len.max(MIN_READ) as u64;
+        match (&mut self.inner).take(want).read_to_end(&mut self.input) {
+            Ok(0) => self.eof = true,
+            Ok(_) => {}
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock && self.input.len() > len => {}
+            Err(err) => return Err(err),
+        }
+        Ok(())
+    }
+
+    /// Mark everything up to `position` bits into the buffered input as consumed
+    fn consume(&mut self, position: u64) {
+        let bytes = (position / 8) as usize;
+        self.input.drain(..bytes);
+        self.consumed += bytes as u64;
+        self.bit_pos = position % 8;
+    }
+}
+
+impl<R: Read> Read for BzDecoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.block_pos == self.block.len() {
+            if matches!(self.state, State::Done) || buf.is_empty() {
+                return Ok(0);
+            }
+            self.decode_next()?;
+        }
+        let available = &self.block[self.block_pos..];
+        let len = available.len().min(buf.len());
+        buf[..len].copy_from_slice(&available[..len]);
+        self.block_pos += len;
+        Ok(len)
+    }
+}
+
+/// Check the combined CRC after the end of stream magic and skip the padding up to the next byte,
+/// where the next stream starts
+fn read_end_of_stream(reader: &mut BitReader, stream_crc: StreamCrc) -> Result<(), ReadError> {
+    let stored = reader.read_bits(32)?;
+    let computed = stream_crc.value();
+    if stored != computed {
+        return Err(ReadError::StreamCrcMismatch { stored, computed });
+    }
+    reader.skip((8 - reader.position() % 8) % 8)?;
+    Ok(())
+}
+
+/// Whether `err` only means the input ended too early
+fn is_truncation(err: &ReadError) -> bool {
+    matches!(err, ReadError::Truncated { .. })
+}
+
+/// `err` as an I/O error, `UnexpectedEof` if it is a truncation at the real end of the input
+fn invalid(err: ReadError, eof: bool) -> io::Error {
+    let kind = if eof && is_truncation(&err) {
+        io::ErrorKind::UnexpectedEof
+    } else {
+        io::ErrorKind::InvalidData
+    };
+    io::Error::new(kind, err)
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+    use test_case::test_case;
+
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::{
+        compress,
+        format::{blocks::BlockSplitter, reader},
+        stages::strategies,
+        CompressionLevel,
+    };
+
+    /// A reader that hands out at most `chunk_len` bytes per `read`, like a pipe
+    struct Trickle<'a> {
+        data: &'a [u8],
+        chunk_len: usize,
+    }
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let len = buf.len().min(self.chunk_len).min(self.data.len());
+            buf[..len].copy_from_slice(&self.data[..len]);
+            self.data = &self.data[len..];
+            Ok(len)
+        }
+    }
+
+    fn decode(data: &[u8], chunk_len: usize) -> io::Result<Vec<u8>> {
+        let mut out = Vec::new();
+        BzDecoder::new(Trickle { data, chunk_len }).read_to_end(&mut out)?;
+        Ok(out)
+    }
+
+    #[test_case(Vec::new(), 1; "empty")]
+    #[test_case(b"hello world\n".to_vec(), 1; "hello world byte by byte")]
+    #[test_case(vec![0; 6_000_000], 100_000; "run longer than a block")]
+    #[test_case(include_bytes!("decoder.rs").repeat(30), 1000; "several blocks")]
+    fn test_same_as_decompress(data: Vec<u8>, chunk_len: usize) {
+        let compressed = compress(&data, CompressionLevel::FASTEST);
+        assert_eq!(decode(&compressed, chunk_len).unwrap(), data);
+    }
+
+    #[test_case(MemoryBudget::SMALL; "small")]
+    #[test_case(MemoryBudget::limit(500_000); "small for blocks over the limit")]
+    fn test_memory_budget(budget: MemoryBudget) {
+        let data = include_bytes!("decoder.rs").repeat(30);
+        let compressed = compress(&data, CompressionLevel::FASTEST);
+        let mut out = Vec::new();
+        BzDecoder::with_memory_budget(compressed.as_slice(), budget)
+            .read_to_end(&mut out)
+            .unwrap();
+        assert_eq!(out, data);
+    }
+
+    #[test]
+    fn test_progress() {
+        let data = include_bytes!("decoder.rs").repeat(30);
+        let compressed = compress(&data, CompressionLevel::FASTEST);
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let sink = Arc::clone(&events);
+        let mut decoder = BzDecoder::new(compressed.as_slice())
+            .with_progress(move |progress| sink.lock().unwrap().push(progress));
+        io::copy(&mut decoder, &mut io::sink()).unwrap();
+
+        let events = events.lock().unwrap();
+        let blocks = decoder.info().blocks;
+        assert!(blocks > 1);
+        assert_eq!(events.len() as u64, blocks);
+        assert!(events
+            .windows(2)
+            .all(|pair| pair[0].bytes_in < pair[1].bytes_in));
+        let last = events.last().unwrap();
+        assert_eq!(last.blocks_done, blocks);
+        assert_eq!(last.bytes_out, data.len() as u64);
+        // everything but the end of stream marker and the combined CRC
+        assert!(compressed.len() as u64 - last.bytes_in <= 11);
+    }
+
+    #[test]
+    fn test_concatenated_streams() {
+        let mut data = compress(b"hello ", CompressionLevel::BEST);
+        data.extend(compress(b"world\n", CompressionLevel::FASTEST));
+        assert_eq!(decode(&data, 7).unwrap(), b"hello world\n");
+    }
+
+    #[test]
+    fn test_single_stream() {
+        let first = compress(b"hello ", CompressionLevel::BEST);
+        let data = [first.clone(), b"junk".to_vec()].concat();
+        let mut decoder = BzDecoder::new(data.as_slice()).single_stream();
+        let mut out = Vec::new();
+        decoder.read_to_end(&mut out).unwrap();
+        assert_eq!(out, b"hello ");
+        assert_eq!(decoder.info().streams, 1);
+        assert_eq!(decoder.info().compressed_len, first.len() as u64);
+    }
+
+    /// A reader that has `ready` bytes until the test makes more ready, and fails with
+    /// `WouldBlock` in between, like a non-blocking socket
+    struct NonBlocking<'a> {
+        data: &'a [u8],
+        ready: usize,
+    }
+
+    impl Read for NonBlocking<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.ready == 0 && !self.data.is_empty() {
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            let len = buf.len().min(self.ready).min(self.data.len());
+            buf[..len].copy_from_slice(&self.data[..len]);
+            self.data = &self.data[len..];
+            self.ready -= len;
+            Ok(len)
+        }
+    }
+
+    #[test]
+    fn test_non_blocking_reader() {
+        let data = include_bytes!("decoder.rs").repeat(30);
+        let compressed = compress(&data, CompressionLevel::FASTEST);
+        let mut decoder = BzDecoder::new(NonBlocking {
+            data: &compressed,
+            ready: 0,
+        });
+        let mut out = Vec::new();
+        let mut buf = vec![0; 10_000];
+        let mut would_block = 0;
+        loop {
+            match decoder.read(&mut buf) {
+                Ok(0) => break,
+                Ok(len) => out.extend_from_slice(&buf[..len]),
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    would_block += 1;
+                    decoder.get_mut().ready = 5000;
+                }
+                Err(err) => panic!("{err}"),
+            }
+        }
+        assert_eq!(out, data);
+        assert!(would_block as usize >= compressed.len() / 5000);
+    }
+
+    #[test]
+    fn test_small_reads() {
+        let data = include_bytes!("decoder.rs").repeat(3);
+        let compressed = compress(&data, CompressionLevel::FASTEST);
+        let mut decoder = BzDecoder::new(compressed.as_slice());
+        let mut out = Vec::new();
+        let mut buf = [0; 3];
+        loop {
+            match decoder.read(&mut buf).unwrap() {
+                0 => break,
+                len => out.extend_from_slice(&buf[..len]),
+            }
+        }
+        assert_eq!(out, data);
+    }
+
+    #[test]
+    fn test_holds_one_block_at_a_time() {
+        let data = include_bytes!("decoder.rs").repeat(30);
+        let compressed = compress(&data, CompressionLevel::FASTEST);
+        let mut decoder = BzDecoder::new(compressed.as_slice());
+        let mut buf = [0; 100];
+        decoder.read_exact(&mut buf).unwrap();
+        let first_block = BlockSplitter::new(&data, BlockSize::MIN).next().unwrap();
+        assert_eq!(decoder.block, first_block);
+        assert!(decoder.input.len() < compressed.len());
+    }
+
+    #[test_case(b"PK\x03\x04".to_vec() => io::ErrorKind::InvalidData; "not bzip2")]
+    #[test_case(b"BZ".to_vec() => io::ErrorKind::UnexpectedEof; "truncated header")]
+    #[test_case(compress(b"hello world\n", CompressionLevel::BEST)[..20].to_vec() => io::ErrorKind::UnexpectedEof; "truncated block")]
+    #[test_case([compress(b"hello", CompressionLevel::BEST), b"junk".to_vec()].concat() => io::ErrorKind::InvalidData; "trailing garbage")]
+    fn test_errors(data: Vec<u8>) -> io::ErrorKind {
+        let err = decode(&data, 5).unwrap_err();
+        let inner = err.get_ref().unwrap().downcast_ref::<ReadError>().unwrap();
+        let expected = reader::decompress(&data).unwrap_err();
+        assert_eq!(
+            std::mem::discriminant(inner),
+            std::mem::discriminant(&expected)
+        );
+        err.kind()
+    }
+
+    #[test]
+    fn test_block_crc_mismatch() {
+        let mut data = compress(b"hello world\n", CompressionLevel::BEST);
+        data[10] ^= 0x01;
+        let err = decode(&data, 3).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().starts_with("CRC mismatch in block 0"));
+    }
+
+    #[test]
+    fn test_info() {
+        let data = include_bytes!("decoder.rs").repeat(30);
+        let mut compressed = compress(&data, CompressionLevel::FASTEST);
+        compressed.extend(compress(b"", CompressionLevel::BEST));
+        compressed.extend(compress(b"hello world\n", CompressionLevel::BEST));
+        let mut decoder = BzDecoder::new(Trickle {
+            data: &compressed,
+            chunk_len: 999,
+        });
+        assert_eq!(decoder.info(), StreamInfo::default());
+        io::copy(&mut decoder, &mut io::sink()).unwrap();
+        let blocks = BlockSplitter::new(&data, BlockSize::MIN).count() as u64;
+        assert_eq!(
+            decoder.info(),
+            StreamInfo {
+                streams: 3,
+                blocks: blocks + 1,
+                compressed_len: compressed.len() as u64,
+                uncompressed_len: data.len() as u64 + 12,
+            }
+        );
+    }
+
+    proptest! {
+        #[test]
+        fn prop_roundtrip(data in strategies::bytes(1024), chunk_len in 1usize..256) {
+            let compressed = compress(&data, CompressionLevel::FASTEST);
+            prop_assert_eq!(decode(&compressed, chunk_len).unwrap(), data);
+        }
+    }
+}
@@ -0,0 +1,328 @@
+use std::io::{self, Write};
+
+use super::{
+    block_size::CompressionLevel,
+    blocks::BlockBuffer,
+    index::BlockIndex,
+    observer::StageObserver,
+    progress::{Progress, ProgressSink},
+    stats::{BlockStats, EncodeStats, Timings},
+    writer::{Clock, StreamWriter},
+};
+
+/// Compresses everything written to it into a `.bz2` stream on the inner writer.
+///
+/// Input is collected until it makes up a full block, which is then compressed and written out,
+/// so no more than one block of input is held in memory no matter how large the input is. Call
+/// `finish` at the end: it compresses the last block and writes the end of stream marker, a
+/// dropped encoder leaves the stream incomplete.
+pub struct BzEncoder<W: Write> {
+    inner: W,
+    stream: StreamWriter,
+    buffer: BlockBuffer,
+    /// Bytes written to `inner` so far
+    bytes_out: u64,
+    progress: Option<Box<dyn ProgressSink + Send>>,
+}
+
+impl<W: Write> BzEncoder<W> {
+    pub fn new(inner: W, level: CompressionLevel) -> Self {
+        let stream = StreamWriter::builder().level(level).build();
+        let buffer = BlockBuffer::new(stream.block_size());
+        Self {
+            inner,
+            stream,
+            buffer,
+            bytes_out: 0,
+            progress: None,
+        }
+    }
+
+    /// Report the progress to `sink` after every block, and once more when `finish` wrote the
+    /// rest of the stream
+    pub fn with_progress(mut self, sink: impl ProgressSink + Send + 'static) -> Self {
+        self.progress = Some(Box::new(sink));
+        self
+    }
+
+    /// Hand what every stage makes of every block to `observer`
+    pub fn with_observer(mut self, observer: impl StageObserver + Send + 'static) -> Self {
+        self.stream.set_observer(observer);
+        self
+    }
+
+    /// Account for where the bits of every block go in a `BlockStats`, see `block_stats`
+    pub fn with_detailed_stats(mut self) -> Self {
+        self.stream.set_detailed_stats();
+        self
+    }
+
+    /// Keep the `Timings` of every block, writing it to the inner writer included, see
+    /// `block_timings`
+    pub fn with_block_timings(mut self) -> Self {
+        self.stream.set_block_timings();
+        self
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    /// The inner writer. Writing to it directly corrupts the stream.
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.inner
+    }
+
+    /// The blocks written to the inner writer so far
+    pub fn index(&self) -> &BlockIndex {
+        self.stream.index()
+    }
+
+    /// Sizes and timings of the stages of the blocks written to the inner writer so far. The
+    /// buffered input isn't compressed yet, `flush` first to include it.
+    pub fn stats(&self) -> &EncodeStats {
+        self.stream.stats()
+    }
+
+    /// Where the bits of every block written to the inner writer so far went, empty without
+    /// `with_detailed_stats`
+    pub fn block_stats(&self) -> &[BlockStats] {
+        self.stream.block_stats()
+    }
+
+    /// How long every stage of every block written to the inner writer so far took, empty
+    /// without `with_block_timings`. Writing the end of stream marker counts towards the last
+    /// block.
+    pub fn block_timings(&self) -> &[Timings] {
+        self.stream.block_timings()
+    }
+
+    /// Compress the last block, write the end of stream marker and return the inner writer.
+    pub fn finish(self) -> io::Result<W> {
+        self.finish_with_index().map(|(inner, _)| inner)
+    }
+
+    /// Same as `finish`, but also return the index of all blocks of the stream, e.g. to store it
+    /// next to the stream for a `SeekableBzDecoder`.
+    pub fn finish_with_index(mut self) -> io::Result<(W, BlockIndex)> {
+        self.write_buffered_block()?;
+        let index = self.stream.index().clone();
+        let stats = *self.stream.stats();
+        let end = self.stream.finish();
+        self.inner.write_all(&end)?;
+        self.inner.flush()?;
+        self.bytes_out += end.len() as u64;
+        if let Some(sink) = &mut self.progress {
+            sink.progress(Progress {
+                bytes_in: stats.uncompressed_len,
+                bytes_out: self.bytes_out,
+                blocks_done: stats.blocks,
+            });
+        }
+        Ok((self.inner, index))
+    }
+
+    /// Compress the buffered input as a block, if there is any, and hand the complete bytes of
+    /// the stream on to the inner writer
+    fn write_buffered_block(&mut self) -> io::Result<()> {
+        let wrote_block = !self.buffer.is_empty();
+        if wrote_block {
+            self.stream
+                .write_block(self.buffer.block())
+                .expect("The buffer only holds blocks that fit the block size");
+            self.buffer.clear();
+        }
+        let mut clock = Clock::start();
+        self.inner.write_all(self.stream.bytes())?;
+        self.stream.add_io_time(clock.lap());
+        self.bytes_out += self.stream.bytes().len() as u64;
+        self.stream.discard_bytes();
+        if let (true, Some(sink)) = (wrote_block, &mut self.progress) {
+            let stats = self.stream.stats();
+            sink.progress(Progress {
+                bytes_in: stats.uncompressed_len,
+                bytes_out: self.bytes_out,
+                blocks_done: stats.blocks,
+            });
+        }
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for BzEncoder<W> {
+    fn write(&mut self, mut buf: &[u8]) -> io::Result<usize> {
+        let len = buf.len();
+        loop {
+            let taken = self.buffer.fill(buf);
+            buf = &buf[taken..];
+            if buf.is_empty() {
+                return Ok(len);
+            }
+            self.write_buffered_block()?;
+        }
+    }
+
+    /// Compress the input so far as a block of its own and flush the inner writer, like
+    /// `BZ_FLUSH` of the reference library. Everything written before can be decompressed from
+    /// what has reached the inner writer, up to the last few bits of the block. Every flush ends
+    /// a block, so flushing often costs compression.
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_buffered_block()?;
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+    use test_case::test_case;
+
+    use std::{
+        sync::{Arc, Mutex},
+        time::{Duration, Instant},
+    };
+
+    use super::*;
+    use crate::{
+        compress, decompress,
+        format::{
+            scan::{find_magic, BLOCK_MAGIC},
+            stats::StageKind,
+        },
+        stages::strategies,
+    };
+
+    fn encode_in_chunks(data: &[u8], level: CompressionLevel, chunk_len: usize) -> Vec<u8> {
+        let mut encoder = BzEncoder::new(Vec::new(), level);
+        for chunk in data.chunks(chunk_len) {
+            encoder.write_all(chunk).unwrap();
+        }
+        encoder.finish().unwrap()
+    }
+
+    #[test_case(Vec::new(), 1000; "empty")]
+    #[test_case(b"hello world\n".to_vec(), 5; "hello world")]
+    #[test_case(vec![0; 6_000_000], 100_000; "run longer than a block")]
+    #[test_case(include_bytes!("encoder.rs").repeat(30), 8 * 1024; "several blocks")]
+    #[test_case(include_bytes!("encoder.rs").repeat(30), 999; "several blocks in odd chunks")]
+    fn test_same_as_compress(data: Vec<u8>, chunk_len: usize) {
+        assert_eq!(
+            encode_in_chunks(&data, CompressionLevel::FASTEST, chunk_len),
+            compress(&data, CompressionLevel::FASTEST)
+        );
+    }
+
+    #[test]
+    fn test_io_copy() {
+        let data = include_bytes!("encoder.rs").repeat(30);
+        let mut encoder = BzEncoder::new(Vec::new(), CompressionLevel::FASTEST);
+        io::copy(&mut data.as_slice(), &mut encoder).unwrap();
+        assert_eq!(decompress(&encoder.finish().unwrap()), Ok(data));
+    }
+
+    #[test]
+    fn test_writes_full_blocks_before_finish() {
+        let data = include_bytes!("encoder.rs").repeat(30);
+        let mut encoder = BzEncoder::new(Vec::new(), CompressionLevel::FASTEST);
+        encoder.write_all(&data).unwrap();
+        assert!(!encoder.get_ref().is_empty());
+        assert!(encoder.buffer.block().len() < data.len());
+    }
+
+    #[test]
+    fn test_flush_ends_block() {
+        let mut encoder = BzEncoder::new(Vec::new(), CompressionLevel::BEST);
+        encoder.write_all(b"hello ").unwrap();
+        encoder.flush().unwrap();
+        assert!(encoder.buffer.is_empty());
+        assert!(encoder.get_ref().len() > 4);
+        encoder.write_all(b"world\n").unwrap();
+        assert_eq!(
+            decompress(&encoder.finish().unwrap()),
+            Ok(b"hello world\n".to_vec())
+        );
+    }
+
+    #[test]
+    fn test_finish_with_index() {
+        let data = include_bytes!("encoder.rs").repeat(30);
+        let mut encoder = BzEncoder::new(Vec::new(), CompressionLevel::FASTEST);
+        encoder.write_all(&data).unwrap();
+        let written = encoder.index().len();
+        let (compressed, index) = encoder.finish_with_index().unwrap();
+        assert_eq!(index.len(), written + 1);
+        assert_eq!(index.uncompressed_len(), data.len() as u64);
+        assert_eq!(
+            index.entries()[0].bit_offset,
+            find_magic(&compressed, BLOCK_MAGIC)[0]
+        );
+    }
+
+    #[test]
+    fn test_block_timings() {
+        let data = include_bytes!("encoder.rs").repeat(30);
+        let started = Instant::now();
+        let mut encoder =
+            BzEncoder::new(Vec::new(), CompressionLevel::FASTEST).with_block_timings();
+        io::copy(&mut data.as_slice(), &mut encoder).unwrap();
+        encoder.flush().unwrap();
+        let elapsed = started.elapsed();
+
+        let timings = encoder.stats().timings;
+        for kind in StageKind::ALL {
+            assert!(timings.get(kind) > Duration::ZERO, "{kind:?}");
+        }
+        // the stages are timed back to back, so they can't take longer than all of it, give or
+        // take the rounding of the clock
+        assert!(timings.total() <= elapsed + Duration::from_micros(100));
+        let block_timings = encoder.block_timings();
+        assert_eq!(block_timings.len() as u64, encoder.stats().blocks);
+        assert!(block_timings.len() > 1);
+        let mut sum = Timings::default();
+        for block in block_timings {
+            sum += *block;
+        }
+        assert_eq!(sum, timings);
+
+        // the stats are timed either way, only the blocks' own timings aren't kept
+        let mut encoder = BzEncoder::new(Vec::new(), CompressionLevel::FASTEST);
+        encoder.write_all(&data).unwrap();
+        assert!(encoder.stats().timings.get(StageKind::Bwt) > Duration::ZERO);
+        assert!(encoder.block_timings().is_empty());
+    }
+
+    #[test]
+    fn test_progress() {
+        let data = include_bytes!("encoder.rs").repeat(30);
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let sink = Arc::clone(&events);
+        let mut encoder = BzEncoder::new(Vec::new(), CompressionLevel::FASTEST)
+            .with_progress(move |progress| sink.lock().unwrap().push(progress));
+        io::copy(&mut data.as_slice(), &mut encoder).unwrap();
+        let compressed = encoder.finish().unwrap();
+
+        let events = events.lock().unwrap();
+        let blocks = find_magic(&compressed, BLOCK_MAGIC).len() as u64;
+        // one event for every block, and one for the end of the stream
+        assert_eq!(events.len() as u64, blocks + 1);
+        assert!(events
+            .windows(2)
+            .all(|pair| pair[0].bytes_out < pair[1].bytes_out));
+        assert_eq!(
+            events.last(),
+            Some(&Progress {
+                bytes_in: data.len() as u64,
+                bytes_out: compressed.len() as u64,
+                blocks_done: blocks,
+            })
+        );
+    }
+
+    proptest! {
+        #[test]
+        fn prop_roundtrip(data in strategies::bytes(1024), chunk_len in 1usize..256) {
+            let compressed = encode_in_chunks(&data, CompressionLevel::FASTEST, chunk_len);
+            prop_assert_eq!(decompress(&compressed), Ok(data));
+        }
+    }
+}
@@ -0,0 +1,244 @@
+use super::{
+    block_size::{BlockSize, BLOCK_SIZE_UNIT},
+    blocks::BlockSplitter,
+    symbol_map::SymbolMap,
+    writer::{ScratchSpace, WriteError},
+};
+use crate::{
+    bwt::BwtEncoded,
+    huff::{
+        mtf_selectors, serialized_lengths_len, MultiTableEncoder, GROUP_SIZE, MAX_HUFFMAN_LEN,
+        MAX_TABLES,
+    },
+    mtf::MtfTransform,
+    rle::RleSequence,
+    symbol::MAX_ALPHABET_SIZE,
+};
+
+/// Bits of the stream header, `BZh` and the block size digit
+pub const STREAM_HEADER_BITS: u64 = 32;
+/// Bits of the end of stream marker, its magic and the combined CRC, before the padding
+pub const STREAM_FOOTER_BITS: u64 = 48 + 32;
+/// Bits of the fixed part of a block header: the magic, the CRC, the randomized flag and the
+/// origin pointer
+pub const BLOCK_HEADER_BITS: u64 = 48 + 32 + 1 + 24;
+/// Bits of the number of tables and the number of selectors
+pub const TABLE_COUNT_BITS: u64 = 3 + 15;
+/// Most bits a block can take, more than any block ever does: the largest block has 900 000
+/// bytes after RLE1, so at most one symbol more after MTF and RLE2, each coded with the longest
+/// code, plus the symbol map, a selector of the most tables for every group and code length
+/// tables where every length takes the longest delta
+pub const MAX_BLOCK_BITS: u64 = {
+    let symbols = 9 * BLOCK_SIZE_UNIT as u64 + 1;
+    let longest_code = MAX_HUFFMAN_LEN as u64;
+    let selectors = symbols.div_ceil(GROUP_SIZE as u64) * MAX_TABLES as u64;
+    let tables = MAX_TABLES as u64 * (5 + MAX_ALPHABET_SIZE as u64 * (1 + 2 * longest_code));
+    BLOCK_HEADER_BITS
+        + 16
+        + 16 * 16
+        + TABLE_COUNT_BITS
+        + selectors
+        + tables
+        + symbols * longest_code
+};
+
+/// What a block would take once compressed, part by part
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BlockEstimate {
+    /// Bytes before the initial run-length encoding
+    pub uncompressed_len: u64,
+    /// Bytes after the initial run-length encoding, the length of the BWT
+    pub rle1_len: u64,
+    /// Symbols after MTF and RLE2, the end of block symbol included
+    pub mtf_symbols: u64,
+    pub symbol_map_bits: u64,
+    /// The number of tables and selectors and the selectors themselves
+    pub selector_bits: u64,
+    /// The code lengths of all tables
+    pub table_bits: u64,
+    /// The Huffman coded symbols
+    pub data_bits: u64,
+}
+
+impl BlockEstimate {
+    /// Bits of the whole block, from its magic to its last coded symbol
+    pub fn bits(&self) -> u64 {
+        BLOCK_HEADER_BITS
+            + self.symbol_map_bits
+            + self.selector_bits
+            + self.table_bits
+            + self.data_bits
+    }
+}
+
+/// What `compress` would turn an input into, without writing it
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SizeEstimate {
+    pub blocks: Vec<BlockEstimate>,
+}
+
+impl SizeEstimate {
+    /// Bits of the stream before the padding of its last byte
+    pub fn bits(&self) -> u64 {
+        STREAM_HEADER_BITS
+            + self.blocks.iter().map(BlockEstimate::bits).sum::<u64>()
+            + STREAM_FOOTER_BITS
+    }
+
+    /// Bytes of the stream
+    pub fn bytes(&self) -> u64 {
+        self.bits().div_ceil(8)
+    }
+
+    pub fn uncompressed_len(&self) -> u64 {
+        self.blocks.iter().map(|block| block.uncompressed_len).sum()
+    }
+}
+
+/// Predict the size of `data` compressed into a stream of `block_size`, by running every stage
+/// but the last and taking the Huffman stage's cost from the code lengths and selectors it
+/// settles on. Nothing is written and no codes are assigned, yet the prediction is exact: it is
+/// the length of what `StreamWriter::write_blocks` writes, bit for bit.
+pub fn estimate_compressed_size(
+    data: &[u8],
+    block_size: BlockSize,
+) -> Result<SizeEstimate, WriteError> {
+    let mut scratch = ScratchSpace::new();
+    let blocks = BlockSplitter::new(data, block_size)
+        .map(|block| estimate_block(block, block_size, &mut scratch))
+        .filter_map(Result::transpose)
+        .collect::<Result<_, _>>()?;
+    Ok(SizeEstimate { blocks })
+}
+
+/// Same as `estimate_compressed_size` for the single block `block`, in the buffers of `scratch`,
+/// `None` for an empty block, which `StreamWriter::write_block` skips
+pub fn estimate_block(
+    block: &[u8],
+    block_size: BlockSize,
+    scratch: &mut ScratchSpace,
+) -> Result<Option<BlockEstimate>, WriteError> {
+    if block.is_empty() {
+        return Ok(None);
+    }
+    RleSequence::encode_into(block, &mut scratch.rle);
+    if scratch.rle.len() > block_size.bytes() {
+        return Err(WriteError::BlockTooLarge {
+            len: scratch.rle.len(),
+            max: block_size.bytes(),
+        });
+    }
+    BwtEncoded::encode_with_scratch(&scratch.rle, &mut scratch.bwt, &mut scratch.sort);
+    MtfTransform::encode_into(scratch.bwt.last_column(), &mut scratch.mtf);
+    let mtf = &scratch.mtf;
+
+    let (code_lengths, selectors, data_bits) = MultiTableEncoder::fit(mtf);
+    Ok(Some(BlockEstimate {
+        uncompressed_len: block.len() as u64,
+        rle1_len: scratch.rle.len() as u64,
+        mtf_symbols: mtf.len() as u64 + 1,
+        symbol_map_bits: SymbolMap::from_symbols(mtf.used_symbols()).bit_len(),
+        selector_bits: selector_bits(&selectors, code_lengths.len()),
+        table_bits: table_bits(&code_lengths),
+        data_bits,
+    }))
+}
+
+/// Bits of the number of tables and selectors and of `selectors` themselves, MTF and unary coded
+/// for `tables` tables
+pub fn selector_bits(selectors: &[u8], tables: usize) -> u64 {
+    let positions = mtf_selectors(selectors, tables).expect("Selectors refer to existing tables");
+    TABLE_COUNT_BITS
+        + positions
+            .iter()
+            .map(|&position| position as u64 + 1)
+            .sum::<u64>()
+}
+
+/// Bits of the delta coded `code_lengths` of all tables
+pub fn table_bits(code_lengths: &[Vec<u8>]) -> u64 {
+    code_lengths
+        .iter()
+        .map(|lengths| serialized_lengths_len(lengths))
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use test_case::test_case;
+
+    use super::*;
+    use crate::{
+        bits::BitReader,
+        format::writer::StreamWriter,
+        huff::{deserialize_lengths, deserialize_selectors},
+        symbol::Symbol,
+    };
+
+    #[test_case(b"", BlockSize::MAX; "empty")]
+    #[test_case(b"hello world\n", BlockSize::MAX; "hello world")]
+    #[test_case(&[b'a'; 600_000], BlockSize::MIN; "one long run")]
+    #[test_case(&include_bytes!("estimate.rs").repeat(100), BlockSize::MIN; "text")]
+    #[test_case(&include_bytes!("writer.rs").repeat(40), BlockSize::new(2).unwrap(); "text with larger blocks")]
+    #[test_case(&(0..300_000u32).map(|i| (i.wrapping_mul(2_654_435_761) >> 24) as u8).collect::<Vec<u8>>(), BlockSize::MIN; "noise")]
+    fn test_estimate_is_exact(data: &[u8], block_size: BlockSize) {
+        let estimate = estimate_compressed_size(data, block_size).unwrap();
+        let mut writer = StreamWriter::new(block_size);
+        writer.write_blocks(data);
+        let entries = writer.index().entries().to_vec();
+        let stats = *writer.stats();
+        let compressed = writer.finish();
+
+        assert_eq!(estimate.bytes(), compressed.len() as u64);
+        assert_eq!(estimate.uncompressed_len(), data.len() as u64);
+        assert_eq!(estimate.blocks.len(), entries.len());
+        for (block, entry) in estimate.blocks.iter().zip(&entries) {
+            assert_eq!(block.bits(), entry.bit_len);
+        }
+        let rle1_len = estimate.blocks.iter().map(|block| block.rle1_len).sum();
+        let mtf_symbols = estimate.blocks.iter().map(|block| block.mtf_symbols).sum();
+        assert_eq!((rle1_len, mtf_symbols), (stats.rle1_len, stats.mtf_symbols));
+    }
+
+    #[test]
+    fn test_breakdown() {
+        let data = include_bytes!("estimate.rs");
+        let estimate = estimate_compressed_size(data, BlockSize::MAX).unwrap();
+        let [block] = estimate.blocks[..] else {
+            panic!("{:?}", estimate.blocks);
+        };
+        let mut writer = StreamWriter::new(BlockSize::MAX);
+        writer.write_block(data).unwrap();
+        let compressed = writer.finish();
+
+        // read the parts of the block back to see where each of them ends
+        let mut reader = BitReader::new(&compressed);
+        let mut position = STREAM_HEADER_BITS + BLOCK_HEADER_BITS;
+        reader.skip(position).unwrap();
+        let map = SymbolMap::read(&mut reader).unwrap();
+        position += block.symbol_map_bits;
+        assert_eq!(reader.position(), position);
+        let tables = reader.read_bits(3).unwrap() as usize;
+        let count = reader.read_bits(15).unwrap() as usize;
+        deserialize_selectors(&mut reader, count, tables).unwrap();
+        position += block.selector_bits;
+        assert_eq!(reader.position(), position);
+        for _ in 0..tables {
+            deserialize_lengths(&mut reader, Symbol::alphabet_size(map.len())).unwrap();
+        }
+        position += block.table_bits;
+        assert_eq!(reader.position(), position);
+        position += block.data_bits;
+        assert_eq!(position + STREAM_FOOTER_BITS, estimate.bits());
+    }
+
+    #[test]
+    fn test_block_too_large() {
+        let data = include_bytes!("estimate.rs").repeat(20);
+        let result = estimate_block(&data, BlockSize::MIN, &mut ScratchSpace::new());
+        assert!(
+            matches!(result, Err(WriteError::BlockTooLarge { max: 100_000, .. })),
+            "{result:?}"
+        );
+    }
+}
@@ -0,0 +1,136 @@
+use core::fmt;
+
+use thiserror::Error;
+
+use super::block_size::BlockSize;
+
+/// Magic at the start of every bzip2 stream, followed by the block size digit
+pub const STREAM_MAGIC: &[u8; 3] = b"BZh";
+/// Length of the stream header: the magic and the block size digit
+pub const HEADER_LEN: usize = STREAM_MAGIC.len() + 1;
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum HeaderError {
+    #[error("Unsupported format: {detected}")]
+    UnsupportedFormat { detected: FormatGuess },
+    #[error("Not a bzip2 stream: bad stream magic")]
+    BadStreamMagic,
+    #[error("Stream header is truncated")]
+    TruncatedHeader,
+    #[error("Invalid block size digit {0:?} in the stream header")]
+    InvalidBlockSizeDigit(char),
+}
+
+/// What the first bytes of some data look like, to tell bzip2 streams apart from the other
+/// formats that end up being fed to a bzip2 decompressor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FormatGuess {
+    Bzip2,
+    Gzip,
+    Xz,
+    Zstd,
+    /// `BZ0`, the format of the original bzip 0.21
+    LegacyBzip,
+    /// `BZ` followed by neither `h` nor `0`
+    OtherBz,
+    Unknown,
+}
+
+impl FormatGuess {
+    pub fn detect(data: &[u8]) -> Self {
+        match data {
+            [b'B', b'Z', b'h', ..] => Self::Bzip2,
+            [0x1f, 0x8b, ..] => Self::Gzip,
+            [0xfd, b'7', b'z', b'X', b'Z', ..] => Self::Xz,
+            [0x28, 0xb5, 0x2f, 0xfd, ..] => Self::Zstd,
+            [b'B', b'Z', b'0', ..] => Self::LegacyBzip,
+            [b'B', b'Z', _, ..] => Self::OtherBz,
+            _ => Self::Unknown,
+        }
+    }
+}
+
+impl fmt::Display for FormatGuess {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Bzip2 => "this is a bzip2 stream",
+            Self::Gzip => "this looks like gzip, try gunzip",
+            Self::Xz => "this looks like xz, try unxz",
+            Self::Zstd => "this looks like zstd, try unzstd",
+            Self::LegacyBzip => {
+                "this looks like the old bzip (0.21) format, which bzip2 can't read"
+            }
+            Self::OtherBz => "this starts like bzip, but without the 'h' of bzip2",
+            Self::Unknown => "unknown format",
+        })
+    }
+}
+
+/// Check the stream header at the start of `data` and return the block size it declares.
+///
+/// Data in one of the formats `FormatGuess` recognizes fails with `UnsupportedFormat`, so the
+/// caller can suggest the right tool, anything else that doesn't start with the magic fails with
+/// `BadStreamMagic`.
+pub fn parse_header(data: &[u8]) -> Result<BlockSize, HeaderError> {
+    match FormatGuess::detect(data) {
+        FormatGuess::Bzip2 => {}
+        FormatGuess::Unknown if STREAM_MAGIC.starts_with(data) => {
+            return Err(HeaderError::TruncatedHeader)
+        }
+        FormatGuess::Unknown => return Err(HeaderError::BadStreamMagic),
+        detected => return Err(HeaderError::UnsupportedFormat { detected }),
+    }
+    let digit = *data
+        .get(STREAM_MAGIC.len())
+        .ok_or(HeaderError::TruncatedHeader)?;
+    if !digit.is_ascii_digit() {
+        return Err(HeaderError::InvalidBlockSizeDigit(digit as char));
+    }
+    BlockSize::new(digit - b'0').map_err(|_| HeaderError::InvalidBlockSizeDigit(digit as char))
+}
+
+#[cfg(test)]
+mod tests {
+    use test_case::test_case;
+
+    use super::*;
+
+    #[test_case(b"BZh91AY&SY" => FormatGuess::Bzip2; "bzip2")]
+    #[test_case(&[0x1f, 0x8b, 0x08, 0x00] => FormatGuess::Gzip; "gzip")]
+    #[test_case(&[0xfd, b'7', b'z', b'X', b'Z', 0x00] => FormatGuess::Xz; "xz")]
+    #[test_case(&[0x28, 0xb5, 0x2f, 0xfd, 0x24] => FormatGuess::Zstd; "zstd")]
+    #[test_case(b"BZ0" => FormatGuess::LegacyBzip; "legacy bzip")]
+    #[test_case(b"BZx9" => FormatGuess::OtherBz; "bz without h")]
+    #[test_case(&[0xfd, b'7', b'z'] => FormatGuess::Unknown; "truncated xz")]
+    #[test_case(&[0x5e, 0xed, 0xb2, 0x12, 0x00, 0x01] => FormatGuess::Unknown; "random bytes")]
+    #[test_case(b"" => FormatGuess::Unknown; "empty")]
+    fn test_detect(data: &[u8]) -> FormatGuess {
+        FormatGuess::detect(data)
+    }
+
+    #[test_case(b"BZh9" => Ok(9); "largest block size")]
+    #[test_case(b"BZh11AY&SY" => Ok(1); "smallest block size")]
+    #[test_case(b"BZh0" => Err(HeaderError::InvalidBlockSizeDigit('0')); "block size zero")]
+    #[test_case(b"BZhx" => Err(HeaderError::InvalidBlockSizeDigit('x')); "block size not a digit")]
+    #[test_case(b"BZh" => Err(HeaderError::TruncatedHeader); "missing block size")]
+    #[test_case(b"B" => Err(HeaderError::TruncatedHeader); "truncated magic")]
+    #[test_case(b"" => Err(HeaderError::TruncatedHeader); "empty")]
+    #[test_case(&[0x1f, 0x8b, 0x08] => Err(HeaderError::UnsupportedFormat { detected: FormatGuess::Gzip }); "gzip")]
+    #[test_case(&[0xfd, b'7', b'z', b'X', b'Z', 0x00] => Err(HeaderError::UnsupportedFormat { detected: FormatGuess::Xz }); "xz")]
+    #[test_case(&[0x28, 0xb5, 0x2f, 0xfd] => Err(HeaderError::UnsupportedFormat { detected: FormatGuess::Zstd }); "zstd")]
+    #[test_case(b"BZ0" => Err(HeaderError::UnsupportedFormat { detected: FormatGuess::LegacyBzip }); "legacy bzip")]
+    #[test_case(b"BZ9" => Err(HeaderError::UnsupportedFormat { detected: FormatGuess::OtherBz }); "bz without h")]
+    #[test_case(b"PK\x03\x04" => Err(HeaderError::BadStreamMagic); "zip")]
+    fn test_parse_header(data: &[u8]) -> Result<u8, HeaderError> {
+        parse_header(data).map(BlockSize::level)
+    }
+
+    #[test]
+    fn test_unsupported_format_message() {
+        let err = parse_header(&[0x1f, 0x8b]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Unsupported format: this looks like gzip, try gunzip"
+        );
+    }
+}
@@ -0,0 +1,339 @@
+use std::io::{self, Read, Write};
+
+use thiserror::Error;
+
+use super::{block_iter::Blocks, estimate::MAX_BLOCK_BITS};
+use crate::{bits::BitReader, error::Error};
+
+/// First bytes of an index file
+const INDEX_MAGIC: &[u8; 4] = b"BZIX";
+const INDEX_VERSION: u8 = 1;
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum IndexError {
+    #[error("Not a block index")]
+    NotAnIndex,
+    #[error("Unsupported block index version {0}")]
+    UnsupportedVersion(u8),
+    #[error("Block {block} of the index ends past the largest possible offset")]
+    Overflow { block: usize },
+    #[error("Block {block} of the index doesn't start after the end of the block before")]
+    Overlap { block: usize },
+    #[error("Block {block} of the index is {bit_len} bits long, no block can be longer than {MAX_BLOCK_BITS} bits or empty")]
+    BadBlockLength { block: usize, bit_len: u64 },
+    #[error("Block {block} of the index ends past the end of the {input_len} bytes of the stream")]
+    PastEnd { block: usize, input_len: u64 },
+}
+
+/// Where the blocks of a `.bz2` stream are, in the compressed and in the uncompressed data.
+///
+/// `StreamWriter` records one while it writes a stream, `scan` builds one for a stream written
+/// without it, and `write_to` stores it in a sidecar file next to the stream. With it, `SeekableBzDecoder` can read from anywhere in the uncompressed
+/// data by decoding just the block that holds it, since a block can be decoded without anything
+/// that comes before it.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(try_from = "UncheckedIndex"))]
+pub struct BlockIndex {
+    entries: Vec<IndexEntry>,
+}
+
+/// A `BlockIndex` as it was deserialized, before `BlockIndex::check` saw it
+#[cfg(feature = "serde")]
+#[derive(serde::Deserialize)]
+struct UncheckedIndex {
+    entries: Vec<IndexEntry>,
+}
+
+#[cfg(feature = "serde")]
+impl TryFrom<UncheckedIndex> for BlockIndex {
+    type Error = IndexError;
+
+    fn try_from(unchecked: UncheckedIndex) -> Result<Self, IndexError> {
+        let mut index = Self::new();
+        for (block, entry) in unchecked.entries.into_iter().enumerate() {
+            if entry.uncompressed_offset != index.uncompressed_len() {
+                return Err(IndexError::Overlap { block });
+            }
+            index.push_checked(entry.bit_offset, entry.bit_len, entry.uncompressed_len)?;
+        }
+        Ok(index)
+    }
+}
+
+/// One block of a `BlockIndex`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct IndexEntry {
+    /// Bit offset of the block magic in the compressed data
+    pub bit_offset: u64,
+    /// Length of the compressed block in bits, from its magic up to the next block
+    pub bit_len: u64,
+    /// Offset of the first byte of the block in the uncompressed data
+    pub uncompressed_offset: u64,
+    pub uncompressed_len: u64,
+}
+
+impl BlockIndex {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add the next block, which starts in the uncompressed data where the last one ended
+    pub fn push(&mut self, bit_offset: u64, bit_len: u64, uncompressed_len: u64) {
+        self.entries.push(IndexEntry {
+            bit_offset,
+            bit_len,
+            uncompressed_offset: self.uncompressed_len(),
+            uncompressed_len,
+        });
+    }
+
+    /// Same as `push` for an index from elsewhere, which could be anything: the block has to
+    /// start after the last one ends, be no longer than any block can be, and neither of its
+    /// ends may overflow
+    fn push_checked(
+        &mut self,
+        bit_offset: u64,
+        bit_len: u64,
+        uncompressed_len: u64,
+    ) -> Result<(), IndexError> {
+        let block = self.entries.len();
+        if bit_len == 0 || bit_len > MAX_BLOCK_BITS {
+            return Err(IndexError::BadBlockLength { block, bit_len });
+        }
+        if bit_offset.checked_add(bit_len).is_none()
+            || self
+                .uncompressed_len()
+                .checked_add(uncompressed_len)
+                .is_none()
+        {
+            return Err(IndexError::Overflow { block });
+        }
+        if let Some(last) = self.entries.last() {
+            if bit_offset < last.bit_offset + last.bit_len {
+                return Err(IndexError::Overlap { block });
+            }
+        }
+        self.push(bit_offset, bit_len, uncompressed_len);
+        Ok(())
+    }
+
+    /// Build the index of the stream at the start of `data` by walking its blocks, for a stream
+    /// that was written without one. Every block is decoded once to learn its uncompressed length,
+    /// and checked against its CRC on the way. Streams concatenated after the first one aren't
+    /// part of the index.
+    pub fn scan(data: &[u8]) -> Result<Self, Error> {
+        let mut reader = BitReader::new(data);
+        let mut index = Self::new();
+        for block in Blocks::new(&mut reader)? {
+            let block = block?;
+            let len = block.decode()?.len();
+            index.push(block.bit_offset, block.bit_len, len as u64);
+        }
+        Ok(index)
+    }
+
+    pub fn entries(&self) -> &[IndexEntry] {
+        &self.entries
+    }
+
+    /// Number of blocks
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Length of all of the uncompressed data
+    pub fn uncompressed_len(&self) -> u64 {
+        self.entries.last().map_or(0, |entry| {
+            entry.uncompressed_offset + entry.uncompressed_len
+        })
+    }
+
+    /// Position of the block that holds the byte at `uncompressed_offset`, `None` past the end
+    pub fn find(&self, uncompressed_offset: u64) -> Option<usize> {
+        if uncompressed_offset >= self.uncompressed_len() {
+            return None;
+        }
+        Some(
+            self.entries
+                .partition_point(|entry| entry.uncompressed_offset <= uncompressed_offset)
+                - 1,
+        )
+    }
+
+    /// Write the index in its sidecar format: the magic `BZIX`, a version byte and the number of
+    /// blocks, then the bit offset, bit length and uncompressed length of every block, all as
+    /// little endian `u64`s.
+    pub fn write_to<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        writer.write_all(INDEX_MAGIC)?;
+        writer.write_all(&[INDEX_VERSION])?;
+        writer.write_all(&(self.entries.len() as u64).to_le_bytes())?;
+        for entry in &self.entries {
+            for value in [entry.bit_offset, entry.bit_len, entry.uncompressed_len] {
+                writer.write_all(&value.to_le_bytes())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Inverse of `write_to`. The file is not trusted: anything that isn't an index of this
+    /// version, or has blocks that overlap, overflow or are longer than any block can be, fails
+    /// with `Error::InvalidIndex`.
+    pub fn read_from<R: Read>(mut reader: R) -> Result<Self, Error> {
+        let mut header = [0; 5];
+        reader.read_exact(&mut header)?;
+        if &header[..4] != INDEX_MAGIC {
+            return Err(IndexError::NotAnIndex.into());
+        }
+        if header[4] != INDEX_VERSION {
+            return Err(IndexError::UnsupportedVersion(header[4]).into());
+        }
+        let len = read_u64(&mut reader)?;
+        let mut index = Self::new();
+        for _ in 0..len {
+            let bit_offset = read_u64(&mut reader)?;
+            let bit_len = read_u64(&mut reader)?;
+            let uncompressed_len = read_u64(&mut reader)?;
+            index.push_checked(bit_offset, bit_len, uncompressed_len)?;
+        }
+        Ok(index)
+    }
+}
+
+fn read_u64<R: Read>(reader: &mut R) -> io::Result<u64> {
+    let mut bytes = [0; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use test_case::test_case;
+
+    use super::*;
+    use crate::{format::writer::StreamWriter, CompressionLevel};
+
+    fn index(lens: &[u64]) -> BlockIndex {
+        let mut index = BlockIndex::new();
+        let mut bit_offset = 32;
+        for &len in lens {
+            index.push(bit_offset, 1000 + len, len);
+            bit_offset += 1000 + len;
+        }
+        index
+    }
+
+    #[test_case(&[], 0 => None; "empty")]
+    #[test_case(&[10, 20, 30], 0 => Some(0); "first byte")]
+    #[test_case(&[10, 20, 30], 9 => Some(0); "last byte of first block")]
+    #[test_case(&[10, 20, 30], 10 => Some(1); "first byte of second block")]
+    #[test_case(&[10, 20, 30], 59 => Some(2); "last byte")]
+    #[test_case(&[10, 20, 30], 60 => None; "past the end")]
+    fn test_find(lens: &[u64], offset: u64) -> Option<usize> {
+        index(lens).find(offset)
+    }
+
+    #[test]
+    fn test_uncompressed_offsets() {
+        let index = index(&[10, 20, 30]);
+        let offsets: Vec<u64> = index
+            .entries()
+            .iter()
+            .map(|entry| entry.uncompressed_offset)
+            .collect();
+        assert_eq!(offsets, vec![0, 10, 30]);
+        assert_eq!(index.uncompressed_len(), 60);
+    }
+
+    #[test_case(b""; "empty")]
+    #[test_case(b"hello world\n"; "one block")]
+    #[test_case(&include_bytes!("index.rs").repeat(40); "several blocks")]
+    fn test_scan(data: &[u8]) {
+        let mut writer = StreamWriter::builder()
+            .level(CompressionLevel::FASTEST)
+            .build();
+        writer.write_blocks(data);
+        let index = writer.index().clone();
+        let compressed = writer.finish();
+        assert_eq!(BlockIndex::scan(&compressed).unwrap(), index);
+        assert_eq!(index.uncompressed_len(), data.len() as u64);
+    }
+
+    #[test]
+    fn test_scan_errors() {
+        let mut compressed = crate::compress(b"hello world\n", CompressionLevel::BEST);
+        assert!(matches!(
+            BlockIndex::scan(&compressed[..20]),
+            Err(Error::UnexpectedEof { .. })
+        ));
+        compressed[10] ^= 0x01;
+        assert!(matches!(
+            BlockIndex::scan(&compressed),
+            Err(Error::CrcMismatch { .. })
+        ));
+    }
+
+    #[test_case(&[]; "empty")]
+    #[test_case(&[900_000, 900_000, 12]; "three blocks")]
+    fn test_sidecar_roundtrip(lens: &[u64]) {
+        let index = index(lens);
+        let mut bytes = Vec::new();
+        index.write_to(&mut bytes).unwrap();
+        assert_eq!(bytes.len(), 13 + 24 * lens.len());
+        assert_eq!(BlockIndex::read_from(bytes.as_slice()).unwrap(), index);
+    }
+
+    #[test_case(b"BZh91AY&SY\0\0\0\0\0" => matches Error::InvalidIndex(IndexError::NotAnIndex); "not an index")]
+    #[test_case(b"BZIX\x02\0\0\0\0\0\0\0\0" => matches Error::InvalidIndex(IndexError::UnsupportedVersion(2)); "other version")]
+    #[test_case(b"BZIX\x01\x01\0\0\0\0\0\0\0\x20" => matches Error::Io(err) if err.kind() == io::ErrorKind::UnexpectedEof; "truncated")]
+    fn test_read_invalid(bytes: &[u8]) -> Error {
+        BlockIndex::read_from(bytes).unwrap_err()
+    }
+
+    /// An index file with the entries `(bit_offset, bit_len, uncompressed_len)`
+    fn sidecar(entries: &[(u64, u64, u64)]) -> Vec<u8> {
+        let mut bytes = [&INDEX_MAGIC[..], &[INDEX_VERSION]].concat();
+        bytes.extend((entries.len() as u64).to_le_bytes());
+        for &(bit_offset, bit_len, uncompressed_len) in entries {
+            for value in [bit_offset, bit_len, uncompressed_len] {
+                bytes.extend(value.to_le_bytes());
+            }
+        }
+        bytes
+    }
+
+    #[test_case(&[(32, 1000, 10), (1032, 1000, u64::MAX)] => IndexError::Overflow { block: 1 }; "uncompressed length overflows")]
+    #[test_case(&[(u64::MAX - 10, 100, 10)] => IndexError::Overflow { block: 0 }; "bit offset overflows")]
+    #[test_case(&[(32, 1000, 10), (1031, 1000, 10)] => IndexError::Overlap { block: 1 }; "overlapping blocks")]
+    #[test_case(&[(5000, 1000, 10), (32, 1000, 10)] => IndexError::Overlap { block: 1 }; "decreasing offsets")]
+    #[test_case(&[(32, 1 << 40, 10)] => IndexError::BadBlockLength { block: 0, bit_len: 1 << 40 }; "longer than any block")]
+    #[test_case(&[(32, 0, 10)] => IndexError::BadBlockLength { block: 0, bit_len: 0 }; "empty block")]
+    fn test_read_hostile(entries: &[(u64, u64, u64)]) -> IndexError {
+        match BlockIndex::read_from(sidecar(entries).as_slice()) {
+            Err(Error::InvalidIndex(err)) => err,
+            result => panic!("{result:?}"),
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_deserialize_checks_entries() {
+        let index = index(&[10, 20]);
+        let json = serde_json::to_string(&index).unwrap();
+        assert_eq!(serde_json::from_str::<BlockIndex>(&json).unwrap(), index);
+        let overlapping = json.replacen("\"bit_offset\":1042", "\"bit_offset\":1041", 1);
+        assert_ne!(overlapping, json);
+        assert!(serde_json::from_str::<BlockIndex>(&overlapping).is_err());
+    }
+
+    #[test]
+    fn test_read_longest_block() {
+        let index = BlockIndex::read_from(sidecar(&[(32, MAX_BLOCK_BITS, 10)]).as_slice());
+        assert_eq!(index.unwrap().entries()[0].bit_len, MAX_BLOCK_BITS);
+    }
+}
@@ -0,0 +1,260 @@
+use std::mem::size_of;
+
+use super::{
+    block_size::{BlockSize, CompressionLevel},
+    blocks::BlockSplitter,
+    writer::{StreamWriter, WriteError},
+};
+use crate::mtf::MtfIndex;
+
+/// Bytes the Huffman stage takes on top of its symbols, for the frequencies, trees and codes of
+/// up to six tables, rounded up generously
+const HUFFMAN_TABLES_BYTES: usize = 64 * 1024;
+
+/// How `compress_with` deals with a limit on its memory
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CompressOptions {
+    /// Most bytes compressing a block may take, see `working_set`. The compressed output is the
+    /// caller's and isn't counted, except for the block being written.
+    pub max_memory: Option<usize>,
+    /// Compress with smaller blocks if the block size of the level doesn't fit `max_memory`,
+    /// rather than failing with `MemoryLimitExceeded`
+    pub shrink_blocks: bool,
+}
+
+/// Keeps the buffers of the compressor within a limit: a buffer only grows through `reserve`,
+/// which refuses to allocate what doesn't fit next to the buffers reserved before.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MemoryAccount {
+    limit: Option<usize>,
+    reserved: usize,
+}
+
+impl MemoryAccount {
+    /// An account without any buffers yet, unlimited for `None`
+    pub fn new(limit: Option<usize>) -> Self {
+        Self { limit, reserved: 0 }
+    }
+
+    pub fn limit(&self) -> Option<usize> {
+        self.limit
+    }
+
+    /// Bytes of the buffers reserved so far
+    pub fn reserved(&self) -> usize {
+        self.reserved
+    }
+
+    /// Grow `buffer` to a capacity of at least `len` elements, unless that takes the buffers
+    /// past the limit, in which case `buffer` is left alone
+    pub fn reserve<T>(&mut self, buffer: &mut Vec<T>, len: usize) -> Result<(), WriteError> {
+        let capacity = buffer.capacity();
+        if len <= capacity {
+            return Ok(());
+        }
+        self.check((len - capacity) * size_of::<T>())?;
+        buffer.reserve_exact(len - buffer.len());
+        self.reserved += (buffer.capacity() - capacity) * size_of::<T>();
+        Ok(())
+    }
+
+    /// Check that `bytes` more fit next to the buffers, for memory that is only needed for a
+    /// moment and not worth keeping
+    pub fn check(&self, bytes: usize) -> Result<(), WriteError> {
+        match self.limit {
+            Some(limit) if self.reserved + bytes > limit => Err(WriteError::MemoryLimitExceeded {
+                needed: self.reserved + bytes,
+                limit,
+            }),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Longest RLE1 encoding of a block of `len` bytes in a stream of `block_size`: runs of four
+/// bytes or more grow by a count byte, but never beyond the block size
+pub fn rle1_bound(len: usize, block_size: BlockSize) -> usize {
+    (len + len.div_ceil(4)).min(block_size.bytes())
+}
+
+/// Bytes compressing a block of `len` bytes after RLE1 takes, beyond the input: the RLE1
+/// sequence, the last column of the BWT, the five buffers of the rotation sort, the MTF indices
+/// and stack, and what `huffman_working_set` needs
+pub fn working_set(len: usize) -> usize {
+    2 * len
+        + 5 * (len + 1) * size_of::<usize>()
+        + len * size_of::<MtfIndex>()
+        + 256
+        + huffman_working_set(len)
+}
+
+/// Bytes the Huffman stage takes for a block of `len` bytes after RLE1 while the buffers of the
+/// other stages are still around: its symbols, its tables and the compressed block, at most
+/// about 1% larger than the block, like for bzip2
+pub fn huffman_working_set(len: usize) -> usize {
+    (len + 1) * size_of::<usize>() + HUFFMAN_TABLES_BYTES + len + len / 100 + 600
+}
+
+/// The block size to compress `data_len` bytes with under `options`: `block_size` if the blocks
+/// fit `max_memory`, else the largest block size that does if `shrink_blocks` allows it.
+pub fn fit_block_size(
+    block_size: BlockSize,
+    data_len: usize,
+    options: CompressOptions,
+) -> Result<BlockSize, WriteError> {
+    let Some(limit) = options.max_memory else {
+        return Ok(block_size);
+    };
+    let needed = |block_size| working_set(rle1_bound(data_len, block_size));
+    let exceeded = |block_size| WriteError::MemoryLimitExceeded {
+        needed: needed(block_size),
+        limit,
+    };
+    if needed(block_size) <= limit {
+        return Ok(block_size);
+    }
+    if !options.shrink_blocks {
+        return Err(exceeded(block_size));
+    }
+    (BlockSize::MIN.level()..block_size.level())
+        .rev()
+        .map(|level| BlockSize::new(level).expect("Levels between the smallest and a valid one"))
+        .find(|&smaller| needed(smaller) <= limit)
+        .ok_or_else(|| exceeded(BlockSize::MIN))
+}
+
+/// Same as `compress`, but within the memory `options` allow. The limit is checked against the
+/// `working_set` of the block size before anything is allocated, and the buffers of the stages
+/// are then reserved through a `MemoryAccount`, which enforces it.
+pub fn compress_with(
+    data: &[u8],
+    level: CompressionLevel,
+    options: CompressOptions,
+) -> Result<Vec<u8>, WriteError> {
+    let block_size = fit_block_size(level.block_size(), data.len(), options)?;
+    let mut builder = StreamWriter::builder().block_size(block_size);
+    if let Some(limit) = options.max_memory {
+        builder = builder.max_memory(limit);
+    }
+    let mut writer = builder.build();
+    for block in BlockSplitter::new(data, block_size) {
+        writer.write_block(block)?;
+    }
+    Ok(writer.finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use test_case::test_case;
+
+    use super::*;
+    use crate::{
+        bits::BitReader,
+        format::{block_iter::Blocks, reader::decompress},
+        stages::counting_alloc as alloc,
+    };
+
+    fn text() -> Vec<u8> {
+        include_bytes!("memory.rs").repeat(100)
+    }
+
+    #[test]
+    fn test_account() {
+        let mut account = MemoryAccount::new(Some(1000));
+        let mut buffer: Vec<u32> = Vec::new();
+        account.reserve(&mut buffer, 100).unwrap();
+        assert!(buffer.capacity() >= 100);
+        assert_eq!(account.reserved(), buffer.capacity() * 4);
+        // already there
+        account.reserve(&mut buffer, 50).unwrap();
+        assert_eq!(account.reserved(), buffer.capacity() * 4);
+
+        let mut other: Vec<u8> = Vec::new();
+        assert_eq!(
+            account.reserve(&mut other, 1000),
+            Err(WriteError::MemoryLimitExceeded {
+                needed: account.reserved() + 1000,
+                limit: 1000,
+            })
+        );
+        assert_eq!(other.capacity(), 0);
+        assert!(account.check(1000 - account.reserved()).is_ok());
+        assert!(MemoryAccount::default().check(usize::MAX).is_ok());
+    }
+
+    #[test_case(None, false => Ok(9); "unlimited")]
+    #[test_case(Some(usize::MAX), false => Ok(9); "large enough")]
+    #[test_case(Some(working_set(200_000)), true => Ok(2); "shrunk")]
+    #[test_case(Some(working_set(200_000)), false => Err(working_set(900_000)); "not shrunk")]
+    #[test_case(Some(working_set(50_000)), true => Err(working_set(100_000)); "too small for any block")]
+    fn test_fit_block_size(max_memory: Option<usize>, shrink_blocks: bool) -> Result<u8, usize> {
+        let options = CompressOptions {
+            max_memory,
+            shrink_blocks,
+        };
+        match fit_block_size(BlockSize::MAX, 2_000_000, options) {
+            Ok(block_size) => Ok(block_size.level()),
+            Err(WriteError::MemoryLimitExceeded { needed, limit }) => {
+                assert_eq!(Some(limit), max_memory);
+                Err(needed)
+            }
+            Err(err) => panic!("{err}"),
+        }
+    }
+
+    #[test]
+    fn test_small_input_fits_small_limit() {
+        let data = b"hello world\n";
+        let options = CompressOptions {
+            max_memory: Some(working_set(rle1_bound(data.len(), BlockSize::MAX))),
+            shrink_blocks: false,
+        };
+        let compressed = compress_with(data, CompressionLevel::BEST, options).unwrap();
+        assert_eq!(compressed[3], b'9');
+        assert_eq!(decompress(&compressed).unwrap(), data);
+    }
+
+    #[test]
+    fn test_shrinking_blocks() {
+        let data = text();
+        let options = CompressOptions {
+            max_memory: Some(working_set(200_000)),
+            shrink_blocks: true,
+        };
+        let compressed = compress_with(&data, CompressionLevel::BEST, options).unwrap();
+        assert_eq!(compressed[3], b'2');
+        let blocks = Blocks::new(&mut BitReader::new(&compressed))
+            .unwrap()
+            .count();
+        assert!(blocks >= data.len() / 200_000, "{blocks}");
+        assert_eq!(decompress(&compressed).unwrap(), data);
+
+        let options = CompressOptions {
+            shrink_blocks: false,
+            ..options
+        };
+        assert!(matches!(
+            compress_with(&data, CompressionLevel::BEST, options),
+            Err(WriteError::MemoryLimitExceeded { .. })
+        ));
+    }
+
+    #[test]
+    fn test_impossible_limit_fails_before_allocating() {
+        let data = text();
+        let options = CompressOptions {
+            max_memory: Some(1000),
+            shrink_blocks: true,
+        };
+        let (result, bytes) =
+            alloc::count_bytes(|| compress_with(&data, CompressionLevel::BEST, options));
+        assert_eq!(
+            result,
+            Err(WriteError::MemoryLimitExceeded {
+                needed: working_set(100_000),
+                limit: 1000,
+            })
+        );
+        assert_eq!(bytes, 0);
+    }
+}
@@ -0,0 +1,38 @@
+#[cfg(feature = "async-io")]
+pub mod async_io;
+pub mod block_iter;
+pub mod block_size;
+#[cfg(feature = "std")]
+pub mod blocks;
+#[cfg(feature = "std")]
+pub mod decoder;
+#[cfg(feature = "std")]
+pub mod encoder;
+#[cfg(feature = "std")]
+pub mod estimate;
+pub mod header;
+#[cfg(feature = "std")]
+pub mod index;
+#[cfg(feature = "std")]
+pub mod memory;
+pub mod observer;
+#[cfg(feature = "parallel")]
+pub mod parallel;
+#[cfg(feature = "std")]
+pub mod progress;
+mod randomized;
+#[cfg(feature = "std")]
+pub mod raw;
+pub mod reader;
+#[cfg(feature = "std")]
+pub mod recover;
+pub mod scan;
+#[cfg(feature = "std")]
+pub mod seekable;
+#[cfg(feature = "std")]
+pub mod stats;
+pub mod symbol_map;
+#[cfg(feature = "std")]
+pub mod verify;
+#[cfg(feature = "std")]
+pub mod writer;
@@ -0,0 +1,153 @@
+use alloc::vec::Vec;
+
+use crate::mtf::MtfIndex;
+
+/// Receives what every stage made of a block, e.g. to find the stage where a roundtrip goes
+/// wrong by comparing what the compressor and the decompressor see.
+///
+/// The compressor reports the stages of every block in the order it runs them, from `rle1` to
+/// `huffman`, and the decompressor reports them in reverse as it undoes them, with the same
+/// data for the same block. `block` counts the blocks from 0. Every method does nothing unless
+/// implemented, and `()` is an observer that ignores everything.
+pub trait StageObserver {
+    /// The block after the initial run-length encoding
+    fn rle1(&mut self, _block: usize, _rle1: &[u8]) {}
+
+    /// The last column of the BWT and the row of the original block in the sorted rotations
+    fn bwt(&mut self, _block: usize, _last_column: &[u8], _origin: usize) {}
+
+    /// The MTF indices, RUNA and RUNB coded, and the initial MTF stack, the bytes in use
+    fn mtf(&mut self, _block: usize, _indices: &[MtfIndex], _stack: &[u8]) {}
+
+    /// The code lengths of every table, the table of every group of symbols, and the number of
+    /// bits the coded symbols take
+    fn huffman(
+        &mut self,
+        _block: usize,
+        _code_lengths: &[Vec<u8>],
+        _selectors: &[u8],
+        _coded_bits: u64,
+    ) {
+    }
+}
+
+impl StageObserver for () {}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        sync::{Arc, Mutex},
+    };
+
+    use super::*;
+    use crate::{rle::RleSequence, symbol::Symbol, BzDecoder, BzEncoder, CompressionLevel};
+
+    #[derive(Debug, PartialEq, Eq)]
+    enum Stage {
+        Rle1(Vec<u8>),
+        Bwt(Vec<u8>, usize),
+        /// The symbol values of the indices, and the stack
+        Mtf(Vec<u16>, Vec<u8>),
+        Huffman(Vec<Vec<u8>>, Vec<u8>, u64),
+    }
+
+    /// Records every stage of every block in the order it is reported
+    #[derive(Clone, Default)]
+    struct Recorder(Arc<Mutex<Vec<(usize, Stage)>>>);
+
+    impl Recorder {
+        fn take(&self) -> Vec<(usize, Stage)> {
+            std::mem::take(&mut self.0.lock().unwrap())
+        }
+
+        fn push(&self, block: usize, stage: Stage) {
+            self.0.lock().unwrap().push((block, stage));
+        }
+    }
+
+    impl StageObserver for Recorder {
+        fn rle1(&mut self, block: usize, rle1: &[u8]) {
+            self.push(block, Stage::Rle1(rle1.to_vec()));
+        }
+
+        fn bwt(&mut self, block: usize, last_column: &[u8], origin: usize) {
+            self.push(block, Stage::Bwt(last_column.to_vec(), origin));
+        }
+
+        fn mtf(&mut self, block: usize, indices: &[MtfIndex], stack: &[u8]) {
+            let symbols = indices
+                .iter()
+                .map(|index| Symbol::from(index).value())
+                .collect();
+            self.push(block, Stage::Mtf(symbols, stack.to_vec()));
+        }
+
+        fn huffman(
+            &mut self,
+            block: usize,
+            code_lengths: &[Vec<u8>],
+            selectors: &[u8],
+            coded_bits: u64,
+        ) {
+            let stage = Stage::Huffman(code_lengths.to_vec(), selectors.to_vec(), coded_bits);
+            self.push(block, stage);
+        }
+    }
+
+    #[test]
+    fn test_observers() {
+        let data = &include_bytes!("observer.rs").repeat(40)[..150_000];
+        let recorder = Recorder::default();
+        let mut encoder =
+            BzEncoder::new(Vec::new(), CompressionLevel::FASTEST).with_observer(recorder.clone());
+        encoder.write_all(data).unwrap();
+        encoder.flush().unwrap();
+        let stats = *encoder.stats();
+        let compressed = encoder.finish().unwrap();
+        let encoded = recorder.take();
+        assert_eq!(stats.blocks, 2);
+
+        // every stage of the first block, then every stage of the second
+        let mut decoded_blocks = Vec::new();
+        let mut rle1_len = 0;
+        let mut mtf_symbols = 0;
+        let mut coded = 0;
+        for (block, stages) in encoded.chunks(4).enumerate() {
+            assert!(
+                stages.iter().all(|&(index, _)| index == block),
+                "{stages:?}"
+            );
+            let [(_, Stage::Rle1(rle1)), (_, Stage::Bwt(last_column, origin)), (_, Stage::Mtf(symbols, stack)), (_, Stage::Huffman(code_lengths, selectors, coded_bits))] =
+                stages
+            else {
+                panic!("{stages:?}");
+            };
+            decoded_blocks.extend(RleSequence::from_encoded(rle1.clone()).decode().unwrap());
+            assert_eq!(last_column.len(), rle1.len());
+            assert!(*origin < rle1.len());
+            assert!(stack.windows(2).all(|pair| pair[0] < pair[1]));
+            assert_eq!(code_lengths.len(), 6);
+            assert_eq!(selectors.len(), (symbols.len() + 1).div_ceil(50));
+            rle1_len += rle1.len() as u64;
+            mtf_symbols += symbols.len() as u64 + 1;
+            coded += coded_bits;
+        }
+        assert_eq!(encoded.len(), 8);
+        assert_eq!(decoded_blocks, data);
+        assert_eq!((rle1_len, mtf_symbols), (stats.rle1_len, stats.mtf_symbols));
+        assert!(coded < stats.compressed_bits);
+
+        // the decoder sees the same, the stages of each block in reverse
+        let recorder = Recorder::default();
+        let mut decoder = BzDecoder::new(compressed.as_slice()).with_observer(recorder.clone());
+        let mut decompressed = Vec::new();
+        decoder.read_to_end(&mut decompressed).unwrap();
+        assert_eq!(decompressed, data);
+        let mut decoded = recorder.take();
+        for block in decoded.chunks_mut(4) {
+            block.reverse();
+        }
+        assert_eq!(decoded, encoded);
+    }
+}
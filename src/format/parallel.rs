@@ -0,0 +1,275 @@
+use std::io;
+
+use rayon::{prelude::*, ThreadPoolBuilder};
+
+use super::{
+    block_size::BlockSize,
+    header::{parse_header, STREAM_MAGIC},
+    reader::{read_bits48, read_block, read_block_within, ReadError},
+    scan::{find_magic, BLOCK_MAGIC, END_OF_STREAM_MAGIC},
+};
+use crate::{bits::BitReader, bwt::MemoryBudget, crc::StreamCrc};
+
+/// A block decoded at a candidate offset, before it is known whether a block really starts there
+struct Candidate {
+    data: Vec<u8>,
+    crc: u32,
+    /// Length of the block before RLE1 decoding, to check it against the block size of its stream
+    len: usize,
+    /// Bit offset right after the block in the whole input
+    end: u64,
+}
+
+/// Same as `reader::decompress`, but decodes the blocks in parallel on the current rayon thread
+/// pool, the global one unless called inside `ThreadPool::install`.
+///
+/// Where a block ends is only known once it is decoded, so instead of following the streams from
+/// block to block, every place the block magic shows up is decoded at the same time. Going through
+/// the streams afterwards only picks up the blocks that really start there, a magic that shows up
+/// by chance inside a block is decoded for nothing and dropped. This works across any number of
+/// streams back to back, like the ones pbzip2 writes.
+///
+/// The result and the errors are the same as those of `reader::decompress`. All decoded blocks are
+/// held in memory until they are put together.
+pub fn decompress_parallel(data: &[u8]) -> Result<Vec<u8>, ReadError> {
+    let offsets = find_magic(data, BLOCK_MAGIC);
+    let mut candidates: Vec<Option<Candidate>> = offsets
+        .par_iter()
+        .enumerate()
+        .map(|(index, &offset)| decode_candidate(data, index, offset))
+        .collect();
+
+    let mut out = Vec::new();
+    let mut start = 0;
+    let mut blocks = 0;
+    loop {
+        start += read_stream(
+            data,
+            start,
+            &offsets,
+            &mut candidates,
+            &mut blocks,
+            &mut out,
+        )?;
+        if start == data.len() {
+            return Ok(out);
+        }
+        if !data[start..].starts_with(STREAM_MAGIC) {
+            return Err(ReadError::TrailingGarbage { offset: start });
+        }
+    }
+}
+
+/// Same as `decompress_parallel`, but on a thread pool of its own with `threads` threads, one per
+/// core for zero. The pool is built for this call and dropped after, so callers decompressing
+/// many inputs are better off with `ThreadPool::install` around `decompress_parallel`.
+pub fn decompress_parallel_with_threads(data: &[u8], threads: usize) -> crate::Result<Vec<u8>> {
+    let pool = ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .build()
+        .map_err(io::Error::other)?;
+    Ok(pool.install(|| decompress_parallel(data))?)
+}
+
+/// Decode the block whose magic is at bit `offset` of `data` as if it really were one, `None` if
+/// it isn't a valid block of any block size. `index` is that of the magic among all of them.
+fn decode_candidate(data: &[u8], index: usize, offset: u64) -> Option<Candidate> {
+    let mut reader = BitReader::new(data);
+    reader.skip(offset + 48).ok()?;
+    let mut block = Vec::new();
+    let (crc, len) = read_block_within(
+        &mut reader,
+        BlockSize::MAX.bytes(),
+        MemoryBudget::UNLIMITED,
+        index,
+        &mut (),
+        &mut block,
+    )
+    .ok()?;
+    Some(Candidate {
+        data: block,
+        crc,
+        len,
+        end: reader.position(),
+    })
+}
+
+/// Go through the stream that starts at byte `start` of `data` like `reader::read_stream` does,
+/// taking every block from `candidates`, and return the length of the stream in bytes. `blocks`
+/// counts the blocks of all streams so far.
+fn read_stream(
+    data: &[u8],
+    start: usize,
+    offsets: &[u64],
+    candidates: &mut [Option<Candidate>],
+    blocks: &mut usize,
+    out: &mut Vec<u8>,
+) -> Result<usize, ReadError> {
+    let stream = &data[start..];
+    let block_size = parse_header(stream)?;
+    let mut reader = BitReader::new(stream);
+    reader.skip(STREAM_MAGIC.len() as u64 * 8 + 8)?;
+    let mut stream_crc = StreamCrc::new();
+    loop {
+        let offset = reader.position();
+        match read_bits48(&mut reader)? {
+            BLOCK_MAGIC => {
+                let index = offsets
+                    .binary_search(&(start as u64 * 8 + offset))
+                    .expect("find_magic finds every block magic");
+                match candidates[index].take() {
+                    Some(block) if block.len <= block_size.bytes() => {
+                        out.extend_from_slice(&block.data);
+                        stream_crc.combine(block.crc);
+                        reader.skip(block.end - start as u64 * 8 - reader.position())?;
+                        *blocks += 1;
+                    }
+                    // decode it once more with the block size of the stream for the exact error
+                    _ => {
+                        return Err(read_block(
+                            &mut reader,
+                            block_size,
+                            MemoryBudget::UNLIMITED,
+                            *blocks,
+                            &mut (),
+                            &mut Vec::new(),
+                        )
+                        .expect_err("A block that failed to decode fails again"));
+                    }
+                }
+            }
+            END_OF_STREAM_MAGIC => {
+                let stored = reader.read_bits(32)?;
+                if stored != stream_crc.value() {
+                    return Err(ReadError::StreamCrcMismatch {
+                        stored,
+                        computed: stream_crc.value(),
+                    });
+                }
+                return Ok(reader.position().div_ceil(8) as usize);
+            }
+            found => {
+                return Err(ReadError::BadBlockMagic {
+                    bit_offset: offset,
+                    found,
+                })
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+    use test_case::test_case;
+
+    use super::*;
+    use crate::{compress, format::reader::decompress, stages::strategies, CompressionLevel};
+
+    fn pool(threads: usize) -> rayon::ThreadPool {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build()
+            .unwrap()
+    }
+
+    #[test_case(compress(b"", CompressionLevel::BEST); "empty")]
+    #[test_case(compress(b"hello world\n", CompressionLevel::BEST); "hello world")]
+    #[test_case(compress(&include_bytes!("parallel.rs").repeat(40), CompressionLevel::FASTEST); "several blocks")]
+    #[test_case([b"hello ", &b"world"[..], b"\n"].map(|part| compress(part, CompressionLevel::Two)).concat(); "concatenated streams")]
+    fn test_same_as_decompress(data: Vec<u8>) {
+        let decompressed = pool(4).install(|| decompress_parallel(&data));
+        assert_eq!(decompressed, decompress(&data));
+        assert!(decompressed.is_ok());
+    }
+
+    #[test]
+    fn test_threads() {
+        let data = include_bytes!("parallel.rs").repeat(40)[..350_000].to_vec();
+        let compressed = compress(&data, CompressionLevel::FASTEST);
+        assert_eq!(find_magic(&compressed, BLOCK_MAGIC).len(), 4);
+        let one = decompress_parallel_with_threads(&compressed, 1);
+        let four = decompress_parallel_with_threads(&compressed, 4);
+        assert_eq!(one, four);
+        assert_eq!(four, Ok(data));
+    }
+
+    #[test]
+    fn test_pbzip2_style_streams() {
+        // pbzip2 writes every block as a stream of its own
+        let data = include_bytes!("parallel.rs").repeat(40);
+        let streams: Vec<u8> = data
+            .chunks(50_000)
+            .flat_map(|chunk| compress(chunk, CompressionLevel::FASTEST))
+            .collect();
+        assert_eq!(pool(3).install(|| decompress_parallel(&streams)), Ok(data));
+    }
+
+    #[test]
+    fn test_chance_magic_is_dropped() {
+        let data = compress(b"hello world\n", CompressionLevel::BEST);
+        assert!(decode_candidate(&data, 0, 5).is_none());
+        // pretend the magic also showed up at bit 5 and decoded to something
+        let offsets = [5, 32];
+        let mut candidates = [
+            Some(Candidate {
+                data: b"bogus".to_vec(),
+                crc: 0,
+                len: 5,
+                end: 100,
+            }),
+            decode_candidate(&data, 1, 32),
+        ];
+        let mut out = Vec::new();
+        assert_eq!(
+            read_stream(&data, 0, &offsets, &mut candidates, &mut 0, &mut out),
+            Ok(data.len())
+        );
+        assert_eq!(out, b"hello world\n");
+        assert!(candidates[0].is_some());
+    }
+
+    #[test]
+    fn test_block_larger_than_stream_block_size() {
+        // a block of level two in a stream that claims level one
+        let mut data = compress(
+            &include_bytes!("parallel.rs").repeat(15),
+            CompressionLevel::Two,
+        );
+        data[3] = b'1';
+        let err = decompress_parallel(&data).unwrap_err();
+        assert!(matches!(err, ReadError::BlockTooLarge { max: 100_000, .. }));
+        assert_eq!(Err(err), decompress(&data));
+    }
+
+    #[test_case(b"PK\x03\x04".to_vec(); "not bzip2")]
+    #[test_case(compress(b"hello world\n", CompressionLevel::BEST)[..20].to_vec(); "truncated")]
+    #[test_case([compress(b"hello", CompressionLevel::BEST), b"junk".to_vec()].concat(); "trailing garbage")]
+    fn test_errors(data: Vec<u8>) {
+        assert_eq!(
+            decompress_parallel(&data).unwrap_err(),
+            decompress(&data).unwrap_err()
+        );
+    }
+
+    #[test]
+    fn test_block_crc_mismatch() {
+        let mut data = compress(b"hello world\n", CompressionLevel::BEST);
+        data[10] ^= 0x01;
+        assert!(matches!(
+            decompress_parallel(&data),
+            Err(ReadError::BlockCrcMismatch { .. })
+        ));
+    }
+
+    proptest! {
+        #[test]
+        fn prop_same_as_decompress(data in strategies::bytes(1024), flip in any::<prop::sample::Index>()) {
+            let mut compressed = compress(&data, CompressionLevel::FASTEST);
+            prop_assert_eq!(decompress_parallel(&compressed), Ok(data));
+            let i = flip.index(compressed.len());
+            compressed[i] ^= 0x10;
+            prop_assert_eq!(decompress_parallel(&compressed), decompress(&compressed));
+        }
+    }
+}
@@ -0,0 +1,25 @@
+/// How far a `BzEncoder` or `BzDecoder` got, reported after every block
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Progress {
+    /// Bytes of the input that went into the blocks so far: uncompressed bytes for the encoder,
+    /// compressed bytes for the decoder
+    pub bytes_in: u64,
+    /// Bytes of output so far: compressed bytes written by the encoder, decompressed bytes
+    /// decoded by the decoder
+    pub bytes_out: u64,
+    pub blocks_done: u64,
+}
+
+/// Receives the `Progress` of a `BzEncoder` or `BzDecoder`, e.g. to drive a progress bar.
+///
+/// Events come after every block, so a block's worth of input passes between two of them. Any
+/// closure that takes a `Progress` is a sink.
+pub trait ProgressSink {
+    fn progress(&mut self, progress: Progress);
+}
+
+impl<F: FnMut(Progress)> ProgressSink for F {
+    fn progress(&mut self, progress: Progress) {
+        self(progress)
+    }
+}
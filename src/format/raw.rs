@@ -0,0 +1,306 @@
+use super::{
+    block_size::BlockSize,
+    reader::{read_block_within, read_symbols, BlockHeader, ReadError},
+    scan::BLOCK_MAGIC,
+    symbol_map::SymbolMap,
+    writer::{write_bits48, CompressedBlock, WriteError},
+};
+use crate::{
+    bits::{BitReader, BitWriter},
+    bwt::MemoryBudget,
+};
+
+/// Bits of the block magic every block of a stream starts with
+const BLOCK_MAGIC_BITS: u64 = 48;
+/// The magic is a whole number of bytes, so the rest of the block keeps its alignment without it
+const BLOCK_MAGIC_BYTES: usize = BLOCK_MAGIC_BITS as usize / 8;
+
+/// One block compressed on its own, without the framing of a `.bz2` stream around it: no stream
+/// header, no block magic and no end of stream marker with the combined CRC, for formats that
+/// store bzip2 blocks their own way.
+///
+/// The bytes are the block as it would be in a stream, from its CRC to the end of its coded
+/// symbols, padded with zero bits to a whole byte. The CRC stays, so every block is still
+/// checked on its own. Next to them, a block keeps its exact length in bits and what its header
+/// declares, and `write` puts it into a stream at any bit, so blocks from different places can
+/// be assembled into one stream.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RawBlock {
+    bytes: Vec<u8>,
+    /// Length of the block in bits, without the padding
+    bit_len: u64,
+    /// `None` for an empty block
+    header: Option<BlockHeader>,
+}
+
+impl RawBlock {
+    /// Run all stages on `data`, which has to fit the largest block size after the initial
+    /// run-length encoding. An empty `data` compresses to no bytes at all.
+    pub fn compress(data: &[u8]) -> Result<Self, WriteError> {
+        let block = CompressedBlock::new(data, BlockSize::MAX)?;
+        if block.bit_len() == 0 {
+            return Ok(Self::default());
+        }
+        let bit_len = block.bit_len() - BLOCK_MAGIC_BITS;
+        let mut bytes = block.into_bits().into_bytes();
+        bytes.drain(..BLOCK_MAGIC_BYTES);
+        let header = BlockHeader::read(&mut BitReader::new(&bytes))
+            .expect("A block that was just compressed has a valid header");
+        Ok(Self {
+            bytes,
+            bit_len,
+            header: Some(header),
+        })
+    }
+
+    /// Parse the block at the position of `reader`, which is right after its block magic and can
+    /// be at any bit, and leave the reader after its coded symbols. Only the header and the
+    /// Huffman codes are checked, the rest of the stages and the CRC are left to `decompress`.
+    pub fn read(reader: &mut BitReader) -> Result<Self, ReadError> {
+        let mut start = reader.clone();
+        let header = BlockHeader::read(reader)?;
+        read_symbols(
+            reader,
+            &header.code_lengths,
+            &header.selectors,
+            header.symbol_map.len(),
+        )?;
+        let bit_len = reader.position() - start.position();
+        let mut bits = BitWriter::with_capacity(bit_len.div_ceil(8) as usize);
+        let mut remaining = bit_len;
+        while remaining > 0 {
+            let n = remaining.min(32) as u32;
+            bits.write_bits(start.read_bits(n)?, n);
+            remaining -= n as u64;
+        }
+        Ok(Self {
+            bytes: bits.into_bytes(),
+            bit_len,
+            header: Some(header),
+        })
+    }
+
+    /// Parse the bytes of a block that `as_bytes` returned, like `read` does. No bytes at all are
+    /// an empty block, anything after the byte the block ends in is an error.
+    pub fn from_bytes(bytes: Vec<u8>) -> Result<Self, ReadError> {
+        if bytes.is_empty() {
+            return Ok(Self::default());
+        }
+        let mut reader = BitReader::new(&bytes);
+        let block = Self::read(&mut reader)?;
+        let end = block.bytes.len();
+        if end < bytes.len() {
+            return Err(ReadError::TrailingGarbage { offset: end });
+        }
+        Ok(block)
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.header.is_none()
+    }
+
+    /// Length of the block in bits, from its CRC to the end of its coded symbols
+    pub fn bit_len(&self) -> u64 {
+        self.bit_len
+    }
+
+    /// The CRC of the block's bytes its header declares, 0 for an empty block
+    pub fn crc(&self) -> u32 {
+        self.header.as_ref().map_or(0, |header| header.crc)
+    }
+
+    pub fn randomized(&self) -> bool {
+        self.header.as_ref().is_some_and(|header| header.randomized)
+    }
+
+    /// The origin pointer of the BWT
+    pub fn orig_ptr(&self) -> usize {
+        self.header.as_ref().map_or(0, |header| header.orig_ptr)
+    }
+
+    /// The bytes the block uses
+    pub fn symbol_map(&self) -> SymbolMap {
+        self.header
+            .as_ref()
+            .map_or_else(SymbolMap::default, |header| header.symbol_map)
+    }
+
+    /// Code lengths of every Huffman table, one for each symbol of the block's alphabet
+    pub fn code_lengths(&self) -> &[Vec<u8>] {
+        self.header
+            .as_ref()
+            .map_or(&[], |header| &header.code_lengths)
+    }
+
+    /// The table of every group of 50 symbols
+    pub fn selectors(&self) -> &[u8] {
+        self.header.as_ref().map_or(&[], |header| &header.selectors)
+    }
+
+    /// Number of Huffman tables
+    pub fn tables(&self) -> usize {
+        self.code_lengths().len()
+    }
+
+    /// Append the block to a stream written into `bits`, with the block magic in front, at
+    /// whatever bit the stream is at. The caller adds the block's `crc` to the combined CRC of
+    /// the stream. An empty block writes nothing.
+    pub fn write(&self, bits: &mut BitWriter) {
+        if self.is_empty() {
+            return;
+        }
+        write_bits48(bits, BLOCK_MAGIC);
+        let whole_bytes = (self.bit_len / 8) as usize;
+        bits.write_bytes(&self.bytes[..whole_bytes]);
+        let rest = (self.bit_len % 8) as u32;
+        if rest > 0 {
+            bits.write_bits((self.bytes[whole_bytes] >> (8 - rest)) as u32, rest);
+        }
+    }
+
+    /// Undo all stages and check the CRC of the block
+    pub fn decompress(&self) -> Result<Vec<u8>, ReadError> {
+        let mut out = Vec::new();
+        if self.is_empty() {
+            return Ok(out);
+        }
+        read_block_within(
+            &mut BitReader::new(&self.bytes),
+            BlockSize::MAX.bytes(),
+            MemoryBudget::UNLIMITED,
+            0,
+            &mut (),
+            &mut out,
+        )?;
+        Ok(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+    use test_case::test_case;
+
+    use super::*;
+    use crate::{
+        analyze, compress,
+        crc::{BlockCrc, StreamCrc},
+        format::{header::STREAM_MAGIC, reader::decompress, scan::END_OF_STREAM_MAGIC},
+        stages::strategies,
+        CompressionLevel,
+    };
+
+    #[test_case(b""; "empty")]
+    #[test_case(b"hello world\n"; "hello world")]
+    #[test_case(&[b'a'; 1000]; "one long run")]
+    #[test_case(include_bytes!("raw.rs"); "source code")]
+    fn test_roundtrip(data: &[u8]) {
+        let block = RawBlock::compress(data).unwrap();
+        let block = RawBlock::from_bytes(block.into_bytes()).unwrap();
+        assert_eq!(block.decompress(), Ok(data.to_vec()));
+        assert_eq!(block.is_empty(), data.is_empty());
+        assert_eq!(block.crc(), BlockCrc::checksum(data));
+    }
+
+    #[test]
+    fn test_same_bits_as_in_a_stream() {
+        let data = include_bytes!("raw.rs");
+        let block = RawBlock::compress(data).unwrap();
+        let stream = compress(data, CompressionLevel::BEST);
+        // after the stream header and the block magic
+        let start = 4 + BLOCK_MAGIC_BYTES;
+        assert_eq!(
+            &stream[start..start + block.as_bytes().len() - 1],
+            &block.as_bytes()[..block.as_bytes().len() - 1]
+        );
+    }
+
+    #[test]
+    fn test_block_too_large() {
+        let data: Vec<u8> = (0..=BlockSize::MAX.bytes()).map(|i| i as u8).collect();
+        assert!(matches!(
+            RawBlock::compress(&data),
+            Err(WriteError::BlockTooLarge { .. })
+        ));
+    }
+
+    #[test]
+    fn test_corrupt_block() {
+        let mut bytes = RawBlock::compress(b"hello world\n").unwrap().into_bytes();
+        bytes[0] ^= 0x01;
+        assert!(matches!(
+            RawBlock::from_bytes(bytes).unwrap().decompress(),
+            Err(ReadError::BlockCrcMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn test_trailing_bytes() {
+        let mut bytes = RawBlock::compress(b"hello world\n").unwrap().into_bytes();
+        let len = bytes.len();
+        bytes.push(0);
+        assert_eq!(
+            RawBlock::from_bytes(bytes),
+            Err(ReadError::TrailingGarbage { offset: len })
+        );
+    }
+
+    #[test]
+    fn test_header() {
+        let data = include_bytes!("raw.rs");
+        let block = RawBlock::compress(data).unwrap();
+        let report = &analyze(data).blocks[0];
+        assert_eq!(block.orig_ptr(), report.original_index);
+        assert_eq!(block.symbol_map().len() + 2, report.alphabet_size);
+        assert_eq!(block.code_lengths(), report.code_lengths);
+        assert_eq!(block.tables(), report.code_lengths.len());
+        assert!(!block.randomized());
+        let padding = block.as_bytes().len() as u64 * 8 - block.bit_len();
+        assert!(padding < 8);
+    }
+
+    #[test]
+    fn test_two_blocks_in_one_stream() {
+        let first = b"hello world\n".repeat(3);
+        let second = include_bytes!("raw.rs");
+        let blocks = [first.as_slice(), second].map(|data| RawBlock::compress(data).unwrap());
+
+        let mut bits = BitWriter::new();
+        bits.write_bytes(STREAM_MAGIC);
+        bits.write_bytes(b"9");
+        let mut stream_crc = StreamCrc::new();
+        let mut offsets = Vec::new();
+        for block in &blocks {
+            offsets.push(bits.bit_len());
+            block.write(&mut bits);
+            stream_crc.combine(block.crc());
+        }
+        write_bits48(&mut bits, END_OF_STREAM_MAGIC);
+        bits.write_bits(stream_crc.value(), 32);
+        let stream = bits.into_bytes();
+
+        // the first block ends within a byte, so the second one is written across bytes
+        assert_ne!(offsets[1] % 8, 0);
+        assert_eq!(decompress(&stream), Ok([&first[..], second].concat()));
+
+        let mut reader = BitReader::new(&stream);
+        reader.skip(offsets[1] + BLOCK_MAGIC_BITS).unwrap();
+        assert_eq!(RawBlock::read(&mut reader).as_ref(), Ok(&blocks[1]));
+    }
+
+    proptest! {
+        #[test]
+        fn prop_roundtrip(data in strategies::bytes(1024)) {
+            let block = RawBlock::compress(&data).unwrap();
+            prop_assert_eq!(block.decompress(), Ok(data));
+        }
+    }
+}
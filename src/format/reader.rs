@@ -0,0 +1,862 @@
+use alloc::vec::Vec;
+
+use thiserror::Error;
+
+use super::{
+    block_size::BlockSize,
+    header::{parse_header, HeaderError, STREAM_MAGIC},
+    observer::StageObserver,
+    randomized::derandomize,
+    scan::{next_magic, BLOCK_MAGIC, END_OF_STREAM_MAGIC},
+    symbol_map::SymbolMap,
+};
+use crate::{
+    bits::{BitReadError, BitReader},
+    bwt::{BwtEncoded, MemoryBudget},
+    crc::{BlockCrc, StreamCrc},
+    huff::{
+        deserialize_lengths, deserialize_selectors, HuffmanDecoder, HuffmanError, GROUP_SIZE,
+        MAX_TABLES, MIN_TABLES,
+    },
+    mtf::{MtfError, MtfIndex, MtfTransform},
+    rle::{RleError, RleSequence},
+    symbol::{Symbol, SymbolError},
+};
+
+/// Most selectors a block can need: one per group of a full 900 000 byte block, plus the
+/// reference encoder's slack of two
+const MAX_SELECTORS: usize = 900_000 / GROUP_SIZE + 2;
+
+/// Everything that can be wrong with a `.bz2` stream, one variant for each kind of damage, so
+/// callers can tell a truncated download from the wrong file or a corrupted block.
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum ReadError {
+    /// Bad stream magic, unsupported format or invalid block size digit
+    #[error(transparent)]
+    Header(#[from] HeaderError),
+    #[error("Stream is truncated: {needed_bits} more bits needed")]
+    Truncated { needed_bits: u64 },
+    #[error("Expected a block or the end of the stream at bit {bit_offset}, found {found:#014x}")]
+    BadBlockMagic { bit_offset: u64, found: u64 },
+    #[error("Symbol map of the block uses no bytes at all")]
+    InvalidSymbolMap,
+    #[error("Invalid number of Huffman tables: {0}")]
+    InvalidTableCount(u32),
+    #[error("Invalid number of selectors: {0}")]
+    InvalidSelectorCount(u32),
+    #[error("Selector {selector} is out of range for {tables} Huffman tables")]
+    SelectorOutOfRange { selector: usize, tables: usize },
+    #[error("Block has more groups of symbols than selectors")]
+    MissingSelector,
+    #[error("Invalid code lengths of Huffman table {table}: {error}")]
+    InvalidHuffmanLengths { table: usize, error: HuffmanError },
+    #[error("Invalid Huffman coded data: {0}")]
+    Huffman(HuffmanError),
+    #[error("Invalid symbol: {0}")]
+    Symbol(#[from] SymbolError),
+    #[error("Invalid MTF data: {0}")]
+    Mtf(#[from] MtfError),
+    #[error("Invalid RLE1 data: {0}")]
+    Rle(#[from] RleError),
+    #[error("Block of {len} bytes exceeds the block size of {max} bytes")]
+    BlockTooLarge { len: usize, max: usize },
+    #[error("Origin pointer {orig_ptr} is out of range for a block of {len} bytes")]
+    OrigPtrOutOfRange { orig_ptr: usize, len: usize },
+    #[error("CRC mismatch in block {block}: stored {stored:#010x}, computed {computed:#010x}")]
+    BlockCrcMismatch {
+        block: usize,
+        stored: u32,
+        computed: u32,
+    },
+    #[error("Stream CRC mismatch: stored {stored:#010x}, computed {computed:#010x}")]
+    StreamCrcMismatch { stored: u32, computed: u32 },
+    #[error("Trailing data after the end of the stream at byte {offset}")]
+    TrailingGarbage { offset: usize },
+}
+
+impl From<CrcWarning> for ReadError {
+    fn from(warning: CrcWarning) -> Self {
+        Self::BlockCrcMismatch {
+            block: warning.block,
+            stored: warning.stored,
+            computed: warning.computed,
+        }
+    }
+}
+
+impl From<BitReadError> for ReadError {
+    fn from(err: BitReadError) -> Self {
+        Self::Truncated {
+            needed_bits: err.needed_bits(),
+        }
+    }
+}
+
+/// A Huffman code cut off by the end of the input is a truncation like any other
+impl From<HuffmanError> for ReadError {
+    fn from(err: HuffmanError) -> Self {
+        match err {
+            HuffmanError::Read(err) => err.into(),
+            err => Self::Huffman(err),
+        }
+    }
+}
+
+/// How `decompress_with` decodes its input and deals with damaged data
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DecompressOptions {
+    /// Keep the bytes of a block that doesn't match its CRC and report a `CrcWarning`, rather
+    /// than failing with `BlockCrcMismatch`
+    pub ignore_crc_errors: bool,
+    /// Skip a block that can't be decoded at all to the next block or end of stream magic and
+    /// report a `SkippedBlock`, rather than failing with the error, so one broken block only
+    /// costs its own bytes. CRC mismatches are left to `ignore_crc_errors`.
+    pub resync: bool,
+    /// Decode with the small inverse BWT of `MemoryBudget::SMALL`, like `bzip2 -s`: 2.5 instead
+    /// of 8 bytes per byte of a block, but slower. The output is the same.
+    pub small: bool,
+}
+
+impl DecompressOptions {
+    fn memory_budget(&self) -> MemoryBudget {
+        if self.small {
+            MemoryBudget::SMALL
+        } else {
+            MemoryBudget::UNLIMITED
+        }
+    }
+}
+
+/// A block whose bytes `decompress_with` kept although they don't match its CRC
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CrcWarning {
+    /// Index of the block in the input, counted over all streams
+    pub block: usize,
+    pub stored: u32,
+    pub computed: u32,
+}
+
+/// Bits of the input `decompress_with` skipped because they couldn't be decoded. The candidate
+/// magic at `to_bit` may be a false one, in which case skipping continues from there.
+#[derive(Debug, PartialEq, Eq)]
+pub struct SkippedBlock {
+    /// Bit offset in the input of the block, or footer, that failed
+    pub from_bit: u64,
+    /// Bit offset of the magic decoding picked up at, or the end of the input
+    pub to_bit: u64,
+    pub reason: ReadError,
+}
+
+/// What `decompress_with` got out of its input
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Decompressed {
+    pub data: Vec<u8>,
+    /// Blocks read, over all streams, including skipped ones
+    pub blocks: usize,
+    pub crc_warnings: Vec<CrcWarning>,
+    pub skipped: Vec<SkippedBlock>,
+}
+
+impl Decompressed {
+    /// Whether every block was intact, i.e. `data` is what was compressed
+    pub fn is_complete(&self) -> bool {
+        self.crc_warnings.is_empty() && self.skipped.is_empty()
+    }
+}
+
+/// Decompress `data`, one or more `.bz2` streams back to back, as `bzip2 -d` does.
+///
+/// Concatenated streams, e.g. from `cat a.bz2 b.bz2` or pbzip2, decompress to the concatenation
+/// of their contents. Each stream has its own header and block size and is padded to a whole
+/// byte, so the next one starts right at the following byte.
+///
+/// Every block is checked against its CRC and every stream against its combined CRC. Anything
+/// after the last stream that isn't another stream is an error.
+pub fn decompress(data: &[u8]) -> Result<Vec<u8>, ReadError> {
+    decompress_with(data, DecompressOptions::default()).map(|decompressed| decompressed.data)
+}
+
+/// Same as `decompress`, but getting as much out of damaged data as `options` allow. What was
+/// damaged is reported in the result, which only `is_complete` if nothing was.
+pub fn decompress_with(data: &[u8], options: DecompressOptions) -> Result<Decompressed, ReadError> {
+    let mut out = Decompressed::default();
+    let mut offset = 0;
+    loop {
+        offset = read_stream(data, offset, options, &mut out)?;
+        if offset == data.len() {
+            return Ok(out);
+        }
+        if !data[offset..].starts_with(STREAM_MAGIC) {
+            return Err(ReadError::TrailingGarbage { offset });
+        }
+    }
+}
+
+/// Decompress the stream at byte `start` of `data` into `out`, and return the byte it ends at.
+/// `out.blocks` counts the blocks of all streams so far, the index errors report them by.
+fn read_stream(
+    data: &[u8],
+    start: usize,
+    options: DecompressOptions,
+    out: &mut Decompressed,
+) -> Result<usize, ReadError> {
+    let block_size = parse_header(&data[start..])?;
+    let mut reader = BitReader::new(data);
+    reader.skip((start + STREAM_MAGIC.len() + 1) as u64 * 8)?;
+    let mut stream_crc = StreamCrc::new();
+    // the combined CRC only holds for a stream that is intact
+    let mut damaged = false;
+    loop {
+        let offset = reader.position();
+        let reason = match read_stream_part(
+            &mut reader,
+            block_size,
+            options,
+            &mut stream_crc,
+            &mut damaged,
+            out,
+        ) {
+            Ok(Some(end)) => return Ok(end),
+            Ok(None) => continue,
+            Err(
+                err @ (ReadError::BlockCrcMismatch { .. } | ReadError::StreamCrcMismatch { .. }),
+            ) => return Err(err),
+            Err(err) if !options.resync => return Err(err),
+            Err(err) => err,
+        };
+        let end = data.len() as u64 * 8;
+        let to_bit = next_magic(data, offset + 1).unwrap_or(end);
+        // anything but a broken footer was meant to be a block, even if its magic is damaged
+        let mut failed = BitReader::new(data);
+        let footer =
+            failed.skip(offset).is_ok() && read_bits48(&mut failed) == Ok(END_OF_STREAM_MAGIC);
+        if !footer {
+            out.blocks += 1;
+        }
+        out.skipped.push(SkippedBlock {
+            from_bit: offset,
+            to_bit,
+            reason,
+        });
+        damaged = true;
+        if to_bit == end {
+            return Ok(data.len());
+        }
+        reader = BitReader::new(data);
+        reader.skip(to_bit)?;
+    }
+}
+
+/// Read the block at the position of `reader` into `out`, or the footer and return the byte the
+/// stream ends at
+fn read_stream_part(
+    reader: &mut BitReader,
+    block_size: BlockSize,
+    options: DecompressOptions,
+    stream_crc: &mut StreamCrc,
+    damaged: &mut bool,
+    out: &mut Decompressed,
+) -> Result<Option<usize>, ReadError> {
+    let offset = reader.position();
+    match read_bits48(reader)? {
+        BLOCK_MAGIC => {
+            let start = out.data.len();
+            let block = decode_block(
+                reader,
+                block_size.bytes(),
+                options.memory_budget(),
+                out.blocks,
+                &mut (),
+                &mut out.data,
+            )?;
+            if block.computed_crc != block.stored_crc {
+                let warning = CrcWarning {
+                    block: out.blocks,
+                    stored: block.stored_crc,
+                    computed: block.computed_crc,
+                };
+                if !options.ignore_crc_errors {
+                    out.data.truncate(start);
+                    return Err(warning.into());
+                }
+                out.crc_warnings.push(warning);
+                *damaged = true;
+            }
+            stream_crc.combine(block.stored_crc);
+            out.blocks += 1;
+            Ok(None)
+        }
+        END_OF_STREAM_MAGIC => {
+            let stored = reader.read_bits(32)?;
+            if stored != stream_crc.value() && !*damaged {
+                return Err(ReadError::StreamCrcMismatch {
+                    stored,
+                    computed: stream_crc.value(),
+                });
+            }
+            Ok(Some(reader.position().div_ceil(8) as usize))
+        }
+        found => Err(ReadError::BadBlockMagic {
+            bit_offset: offset,
+            found,
+        }),
+    }
+}
+
+pub(super) fn read_bits48(reader: &mut BitReader) -> Result<u64, BitReadError> {
+    let high = reader.read_bits(16)? as u64;
+    let low = reader.read_bits(32)? as u64;
+    Ok(high << 32 | low)
+}
+
+/// Decode the block after its magic, append its bytes to `out` and return its CRC. `budget` picks
+/// the inverse BWT, `block` is the index of the block in the input, which a CRC mismatch and
+/// `observer` report.
+pub(super) fn read_block(
+    reader: &mut BitReader,
+    block_size: BlockSize,
+    budget: MemoryBudget,
+    block: usize,
+    observer: &mut dyn StageObserver,
+    out: &mut Vec<u8>,
+) -> Result<u32, ReadError> {
+    read_block_within(reader, block_size.bytes(), budget, block, observer, out).map(|(crc, _)| crc)
+}
+
+/// Same as `read_block`, but with the longest the block may be before RLE1 decoding in place of
+/// the block size, and also returning that length
+pub(super) fn read_block_within(
+    reader: &mut BitReader,
+    max_len: usize,
+    budget: MemoryBudget,
+    block: usize,
+    observer: &mut dyn StageObserver,
+    out: &mut Vec<u8>,
+) -> Result<(u32, usize), ReadError> {
+    let start = out.len();
+    let decoded = decode_block(reader, max_len, budget, block, observer, out)?;
+    if decoded.computed_crc != decoded.stored_crc {
+        out.truncate(start);
+        return Err(ReadError::BlockCrcMismatch {
+            block,
+            stored: decoded.stored_crc,
+            computed: decoded.computed_crc,
+        });
+    }
+    Ok((decoded.stored_crc, decoded.len))
+}
+
+/// A block `decode_block` decoded, whether it matches its CRC or not
+struct DecodedBlock {
+    stored_crc: u32,
+    computed_crc: u32,
+    /// Length before RLE1 decoding
+    len: usize,
+}
+
+/// Decode the block after its magic and append its bytes to `out`, without checking them against
+/// the CRC of the block
+fn decode_block(
+    reader: &mut BitReader,
+    max_len: usize,
+    budget: MemoryBudget,
+    block: usize,
+    observer: &mut dyn StageObserver,
+    out: &mut Vec<u8>,
+) -> Result<DecodedBlock, ReadError> {
+    let BlockHeader {
+        crc: stored_crc,
+        randomized,
+        orig_ptr,
+        symbol_map,
+        selectors,
+        code_lengths,
+    } = BlockHeader::read(reader)?;
+    let symbols_start = reader.position();
+    let indices = read_symbols(reader, &code_lengths, &selectors, symbol_map.len())?;
+    let coded_bits = reader.position() - symbols_start;
+    observer.huffman(block, &code_lengths, &selectors, coded_bits);
+    let mtf = MtfTransform::from_parts(indices, symbol_map.symbols())?;
+    observer.mtf(block, mtf.indices(), mtf.used_symbols());
+    let len = mtf.decoded_len()?;
+    if len > max_len {
+        return Err(ReadError::BlockTooLarge { len, max: max_len });
+    }
+    if orig_ptr >= len.max(1) {
+        return Err(ReadError::OrigPtrOutOfRange { orig_ptr, len });
+    }
+
+    let last_column = mtf.decode()?;
+    observer.bwt(block, &last_column, orig_ptr);
+    let bwt = BwtEncoded::new(last_column, orig_ptr);
+    let mut sequence = bwt.decode_with(budget);
+    if randomized {
+        let mut bytes = sequence.into_sequence();
+        derandomize(&mut bytes);
+        sequence = RleSequence::from_encoded(bytes);
+    }
+    observer.rle1(block, sequence.sequence());
+    // no encoder writes a count above 251, a larger one means the block is damaged
+    let decoded = sequence.try_decode(true)?;
+    out.extend_from_slice(&decoded);
+    Ok(DecodedBlock {
+        stored_crc,
+        computed_crc: BlockCrc::checksum(&decoded),
+        len,
+    })
+}
+
+/// Everything in a block between its magic and its Huffman coded symbols
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) struct BlockHeader {
+    pub crc: u32,
+    pub randomized: bool,
+    pub orig_ptr: usize,
+    pub symbol_map: SymbolMap,
+    pub selectors: Vec<u8>,
+    /// Code lengths of every Huffman table, one for each symbol of the block's alphabet
+    pub code_lengths: Vec<Vec<u8>>,
+}
+
+impl BlockHeader {
+    /// Read the header of the block after its magic, leaving `reader` at its first coded symbol
+    pub fn read(reader: &mut BitReader) -> Result<Self, ReadError> {
+        let crc = reader.read_bits(32)?;
+        let randomized = reader.read_bit()?;
+        let orig_ptr = reader.read_bits(24)? as usize;
+        let symbol_map = SymbolMap::read(reader)?;
+        if symbol_map.is_empty() {
+            return Err(ReadError::InvalidSymbolMap);
+        }
+        let alphabet_size = Symbol::alphabet_size(symbol_map.len());
+
+        let tables = reader.read_bits(3)?;
+        if !(MIN_TABLES as u32..=MAX_TABLES as u32).contains(&tables) {
+            return Err(ReadError::InvalidTableCount(tables));
+        }
+        let selectors = read_selectors(reader, tables as usize)?;
+        let code_lengths = (0..tables as usize)
+            .map(|table| match deserialize_lengths(reader, alphabet_size) {
+                Ok(lengths) => Ok(lengths),
+                Err(HuffmanError::Read(err)) => Err(err.into()),
+                Err(error) => Err(ReadError::InvalidHuffmanLengths { table, error }),
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Self {
+            crc,
+            randomized,
+            orig_ptr,
+            symbol_map,
+            selectors,
+            code_lengths,
+        })
+    }
+}
+
+/// Inverse of `writer::write_selectors`, after the number of tables
+fn read_selectors(reader: &mut BitReader, tables: usize) -> Result<Vec<u8>, ReadError> {
+    let count = reader.read_bits(15)?;
+    if count == 0 || count as usize > MAX_SELECTORS {
+        return Err(ReadError::InvalidSelectorCount(count));
+    }
+    deserialize_selectors(reader, count as usize, tables).map_err(|err| match err {
+        HuffmanError::InvalidSelector { selector, tables } => {
+            ReadError::SelectorOutOfRange { selector, tables }
+        }
+        err => err.into(),
+    })
+}
+
+/// Huffman decode the symbols of a block of `used_bytes` different bytes up to EOB with the tables
+/// of `code_lengths`, switching tables every `GROUP_SIZE` symbols
+pub(super) fn read_symbols(
+    reader: &mut BitReader,
+    code_lengths: &[Vec<u8>],
+    selectors: &[u8],
+    used_bytes: usize,
+) -> Result<Vec<MtfIndex>, ReadError> {
+    let alphabet_size = Symbol::alphabet_size(used_bytes);
+    let decoders: Vec<_> = code_lengths
+        .iter()
+        .map(|lengths| HuffmanDecoder::from_lengths(lengths))
+        .collect();
+    let mut indices = Vec::new();
+    for &selector in selectors {
+        let decoder = &decoders[selector as usize];
+        for _ in 0..GROUP_SIZE {
+            match decoder.decode_symbol(reader)?.to_mtf_index(alphabet_size)? {
+                Some(index) => indices.push(index),
+                None => return Ok(indices),
+            }
+        }
+    }
+    Err(ReadError::MissingSelector)
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use std::{
+        fs,
+        io::Write,
+        path::Path,
+        process::{Command, Stdio},
+    };
+
+    use test_case::test_case;
+
+    use super::*;
+    use crate::{
+        bits::BitWriter,
+        format::{
+            block_iter::Blocks,
+            raw::RawBlock,
+            writer::{write_transformed_block, StreamWriter},
+        },
+    };
+
+    /// `bz2.compress(b"hello world\n")` from Python, made by the reference library
+    const HELLO_WORLD_BZ2: &[u8] = &[
+        0x42, 0x5a, 0x68, 0x39, 0x31, 0x41, 0x59, 0x26, 0x53, 0x59, 0x4e, 0xec, 0xe8, 0x36, 0x00,
+        0x00, 0x02, 0x51, 0x80, 0x00, 0x10, 0x40, 0x00, 0x06, 0x44, 0x90, 0x80, 0x20, 0x00, 0x31,
+        0x06, 0x4c, 0x41, 0x01, 0xa7, 0xa9, 0xa5, 0x80, 0xbb, 0x94, 0x31, 0xf8, 0xbb, 0x92, 0x29,
+        0xc2, 0x84, 0x82, 0x77, 0x67, 0x41, 0xb0,
+    ];
+    /// `bz2.compress(b"")`, a stream without any blocks
+    const EMPTY_BZ2: &[u8] = b"BZh9\x17\x72\x45\x38\x50\x90\x00\x00\x00\x00";
+
+    fn compress(data: &[u8]) -> Vec<u8> {
+        let mut writer = StreamWriter::new(BlockSize::MIN);
+        writer.write_block(data).unwrap();
+        writer.finish()
+    }
+
+    #[test]
+    fn test_decompress_reference_stream() {
+        assert_eq!(decompress(HELLO_WORLD_BZ2), Ok(b"hello world\n".to_vec()));
+    }
+
+    /// Compress `data` with the reference `bzip2` binary, `None` if it isn't installed
+    fn reference_compress(data: &[u8], level: u8) -> Option<Vec<u8>> {
+        let mut child = Command::new("bzip2")
+            .arg(format!("-{level}c"))
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .ok()?;
+        let mut stdin = child.stdin.take().unwrap();
+        let input = data.to_vec();
+        let feeder = std::thread::spawn(move || stdin.write_all(&input));
+        let output = child.wait_with_output().unwrap();
+        feeder.join().unwrap().unwrap();
+        assert!(output.status.success());
+        Some(output.stdout)
+    }
+
+    #[test_case(include_bytes!("reader.rs").repeat(4), 9; "one block")]
+    #[test_case(include_bytes!("reader.rs").repeat(40), 1; "several blocks")]
+    #[test_case(vec![0; 300_000], 1; "long run")]
+    fn test_decompress_reference_output(data: Vec<u8>, level: u8) {
+        match reference_compress(&data, level) {
+            Some(stream) => assert_eq!(decompress(&stream), Ok(data)),
+            None => eprintln!("bzip2 not found, skipping the interop check"),
+        }
+    }
+
+    #[test_case(b"banana"; "runb")]
+    #[test_case(b"baa"; "runa")]
+    fn test_decompress_reference_block_ending_in_run(data: &[u8]) {
+        let bwt = BwtEncoded::encode(&RleSequence::encode(data));
+        let mtf = MtfTransform::from_bwt(&bwt);
+        assert!(matches!(
+            mtf.indices().last(),
+            Some(MtfIndex::RunA | MtfIndex::RunB)
+        ));
+        match reference_compress(data, 9) {
+            Some(stream) => assert_eq!(decompress(&stream), Ok(data.to_vec())),
+            None => eprintln!("bzip2 not found, skipping the interop check"),
+        }
+    }
+
+    #[test]
+    fn test_decompress_empty_stream() {
+        assert_eq!(decompress(EMPTY_BZ2), Ok(Vec::new()));
+    }
+
+    #[test_case(b"hello world\n".to_vec(); "hello world")]
+    #[test_case(vec![b'a'; 1]; "single byte")]
+    #[test_case(vec![b'a'; 10_000]; "one long run")]
+    #[test_case(b"ab".repeat(5000); "two alternating bytes")]
+    #[test_case((0..=255).collect(); "every byte once")]
+    #[test_case(include_bytes!("reader.rs").to_vec(); "source code")]
+    fn test_roundtrip(data: Vec<u8>) {
+        assert_eq!(decompress(&compress(&data)), Ok(data));
+    }
+
+    #[test_case(vec![compress(b"hello "), HELLO_WORLD_BZ2.to_vec()] => b"hello hello world\n".to_vec(); "two streams")]
+    #[test_case(vec![HELLO_WORLD_BZ2.to_vec(), EMPTY_BZ2.to_vec(), HELLO_WORLD_BZ2.to_vec()] => b"hello world\nhello world\n".to_vec(); "empty stream in between")]
+    #[test_case(vec![EMPTY_BZ2.to_vec(), EMPTY_BZ2.to_vec()] => Vec::<u8>::new(); "only empty streams")]
+    #[test_case(vec![compress(b"level one, "), HELLO_WORLD_BZ2.to_vec()] => b"level one, hello world\n".to_vec(); "different block sizes")]
+    fn test_concatenated_streams(streams: Vec<Vec<u8>>) -> Vec<u8> {
+        decompress(&streams.concat()).unwrap()
+    }
+
+    #[test]
+    fn test_concatenated_reference_output() {
+        // what `bzip2 -c a > ab.bz2; bzip2 -c b >> ab.bz2` leaves behind
+        let parts = [&include_bytes!("reader.rs")[..], b"", b"hello world\n"];
+        let mut streams = Vec::new();
+        for (part, level) in parts.iter().zip([1, 5, 9]) {
+            match reference_compress(part, level) {
+                Some(stream) => streams.extend(stream),
+                None => return eprintln!("bzip2 not found, skipping the interop check"),
+            }
+        }
+        assert_eq!(decompress(&streams), Ok(parts.concat()));
+    }
+
+    #[test_case(b"PK\x03\x04" => ReadError::Header(HeaderError::BadStreamMagic); "not bzip2")]
+    #[test_case(b"BZh0" => ReadError::Header(HeaderError::InvalidBlockSizeDigit('0')); "block size digit")]
+    #[test_case(&HELLO_WORLD_BZ2[..6] => ReadError::Truncated { needed_bits: 32 }; "truncated block magic")]
+    #[test_case(&HELLO_WORLD_BZ2[..20] => matches ReadError::Truncated { .. }; "truncated block")]
+    #[test_case(&[HELLO_WORLD_BZ2, b"junk"].concat() => ReadError::TrailingGarbage { offset: 52 }; "trailing garbage")]
+    fn test_decompress_errors(data: &[u8]) -> ReadError {
+        decompress(data).unwrap_err()
+    }
+
+    /// Every input the `decode_container` fuzz target starts from decodes or fails with an error,
+    /// so the fuzzer finds the panics that are new
+    #[test]
+    fn test_fuzz_corpus() {
+        let corpus = Path::new(env!("CARGO_MANIFEST_DIR")).join("fuzz/corpus/decode_container");
+        let mut inputs = 0;
+        for entry in fs::read_dir(&corpus).unwrap() {
+            let path = entry.unwrap().path();
+            let data = fs::read(&path).unwrap();
+            let _ = decompress(&data);
+            inputs += 1;
+        }
+        assert!(inputs > 0, "{} is empty", corpus.display());
+    }
+
+    /// `HELLO_WORLD_BZ2` with the `width` bits at bit `offset` replaced by `value`
+    fn hello_world_with_bits(offset: u64, width: u32, value: u32) -> Vec<u8> {
+        let mut data = HELLO_WORLD_BZ2.to_vec();
+        for i in 0..width as u64 {
+            let bit = (value >> (width as u64 - 1 - i)) & 1 != 0;
+            let (byte, mask) = ((offset + i) / 8, 0x80 >> ((offset + i) % 8));
+            if bit {
+                data[byte as usize] |= mask;
+            } else {
+                data[byte as usize] &= !mask;
+            }
+        }
+        data
+    }
+
+    // The block of `HELLO_WORLD_BZ2`: origin pointer at bit 113, symbol map at 137, two tables
+    // at 217, its one selector at 235 and the start lengths of the tables at 237 and 261
+    #[test_case(hello_world_with_bits(137, 16, 0) => ReadError::InvalidSymbolMap; "empty symbol map")]
+    #[test_case(hello_world_with_bits(235, 2, 0b11) => ReadError::SelectorOutOfRange { selector: 2, tables: 2 }; "selector")]
+    #[test_case(hello_world_with_bits(261, 5, 0) => ReadError::InvalidHuffmanLengths { table: 1, error: HuffmanError::InvalidCodeLength { symbol: 0, length: 0 } }; "code length of the second table")]
+    #[test_case(hello_world_with_bits(113, 24, 12) => ReadError::OrigPtrOutOfRange { orig_ptr: 12, len: 12 }; "origin pointer")]
+    fn test_invalid_block(data: Vec<u8>) -> ReadError {
+        decompress(&data).unwrap_err()
+    }
+
+    /// A stream with one block whose RLE1 stage produced `sequence`, which no encoder has to have
+    /// written: its CRC is that of the permissive decode
+    fn stream_of_sequence(sequence: RleSequence) -> Vec<u8> {
+        let block = sequence.decode().unwrap();
+        let crc = BlockCrc::checksum(&block);
+        let bwt = BwtEncoded::encode(&sequence);
+        let mtf = MtfTransform::from_bwt(&bwt);
+        let mut bits = BitWriter::new();
+        write_transformed_block(&mut bits, crc, bwt.original_index(), &mtf);
+        let bytes = bits.into_bytes();
+        let mut reader = BitReader::new(&bytes);
+        reader.skip(48).unwrap();
+        let mut writer = StreamWriter::new(BlockSize::MAX);
+        writer.append_raw_block(&RawBlock::read(&mut reader).unwrap(), block.len());
+        writer.finish()
+    }
+
+    #[test_case(251 => Ok(255); "longest run")]
+    #[test_case(252 => Err(ReadError::Rle(RleError::InvalidRunCount { value: 252, position: 4 })); "run count above 251")]
+    #[test_case(255 => Err(ReadError::Rle(RleError::InvalidRunCount { value: 255, position: 4 })); "largest count byte")]
+    fn test_run_count(count: u8) -> Result<usize, ReadError> {
+        let stream = stream_of_sequence(RleSequence::from_encoded(vec![
+            b'a', b'a', b'a', b'a', count,
+        ]));
+        decompress(&stream).map(|data| data.len())
+    }
+
+    #[test]
+    fn test_bad_block_magic() {
+        let mut data = HELLO_WORLD_BZ2.to_vec();
+        data[4] ^= 0xff;
+        assert!(matches!(
+            decompress(&data),
+            Err(ReadError::BadBlockMagic { bit_offset: 32, .. })
+        ));
+    }
+
+    #[test]
+    fn test_block_crc_mismatch() {
+        let mut data = HELLO_WORLD_BZ2.to_vec();
+        data[10] ^= 0x01;
+        // the second block of the input, counting across streams
+        let data = [HELLO_WORLD_BZ2, &data].concat();
+        assert_eq!(
+            decompress(&data),
+            Err(ReadError::BlockCrcMismatch {
+                block: 1,
+                stored: 0x4fec_e836,
+                computed: 0x4eec_e836
+            })
+        );
+    }
+
+    #[test]
+    fn test_ignore_crc_errors() {
+        let data: Vec<u8> = include_bytes!("reader.rs").repeat(12);
+        let mut writer = StreamWriter::new(BlockSize::MIN);
+        writer.write_blocks(&data);
+        let entries = writer.index().entries().to_vec();
+        let mut compressed = writer.finish();
+        assert!(entries.len() >= 3);
+        let range = |block: usize| {
+            let entry = &entries[block];
+            let start = entry.uncompressed_offset as usize;
+            start..start + entry.uncompressed_len as usize
+        };
+        // the lowest bit of the origin pointer of the second block, which still decodes, just
+        // not to its bytes
+        let bit = entries[1].bit_offset + 48 + 32 + 1 + 23;
+        compressed[bit as usize / 8] ^= 0x80 >> (bit % 8);
+        let stored = BlockCrc::checksum(&data[range(1)]);
+
+        let err = decompress(&compressed).unwrap_err();
+        assert!(
+            matches!(err, ReadError::BlockCrcMismatch { block: 1, .. }),
+            "{err}"
+        );
+
+        let options = DecompressOptions {
+            ignore_crc_errors: true,
+            ..Default::default()
+        };
+        let decompressed = decompress_with(&compressed, options).unwrap();
+        assert!(!decompressed.is_complete());
+        assert_eq!(decompressed.blocks, entries.len());
+        assert_eq!(decompressed.data.len(), data.len());
+        let [warning] = decompressed.crc_warnings[..] else {
+            panic!("{:?}", decompressed.crc_warnings);
+        };
+        assert_eq!((warning.block, warning.stored), (1, stored));
+        assert_ne!(warning.computed, stored);
+        for block in (0..entries.len()).filter(|&block| block != 1) {
+            assert_eq!(decompressed.data[range(block)], data[range(block)]);
+        }
+    }
+
+    #[test]
+    fn test_resync() {
+        let data = &include_bytes!("reader.rs").repeat(20)[..350_000];
+        let mut writer = StreamWriter::new(BlockSize::MIN);
+        writer.write_blocks(data);
+        let entries = writer.index().entries().to_vec();
+        let mut compressed = writer.finish();
+        assert_eq!(entries.len(), 4);
+
+        // zero the start length of the first Huffman table of the second block, which comes
+        // after its symbol map and its unary coded selectors
+        let mut reader = BitReader::new(&compressed);
+        let mut blocks = Blocks::new(&mut reader).unwrap();
+        let block = blocks.nth(1).unwrap().unwrap();
+        let ranges = (0..16u8)
+            .filter(|range| (0..16).any(|byte| block.symbol_map.contains(range * 16 + byte)))
+            .count() as u64;
+        let mut tables: Vec<u8> = (0..block.tables() as u8).collect();
+        let mut selector_bits = 0;
+        for &selector in &block.selectors {
+            let position = tables.iter().position(|&table| table == selector).unwrap();
+            tables.remove(position);
+            tables.insert(0, selector);
+            selector_bits += position as u64 + 1;
+        }
+        let lengths = block.bit_offset + 48 + 32 + 1 + 24 + 16 + 16 * ranges + 3 + 15;
+        for bit in lengths + selector_bits..lengths + selector_bits + 5 {
+            compressed[bit as usize / 8] &= !(0x80 >> (bit % 8));
+        }
+
+        let err = decompress(&compressed).unwrap_err();
+        assert!(
+            matches!(err, ReadError::InvalidHuffmanLengths { table: 0, .. }),
+            "{err}"
+        );
+
+        let options = DecompressOptions {
+            resync: true,
+            ..Default::default()
+        };
+        let decompressed = decompress_with(&compressed, options).unwrap();
+        assert!(!decompressed.is_complete());
+        assert!(decompressed.crc_warnings.is_empty());
+        assert_eq!(decompressed.blocks, 4);
+        let [skipped] = &decompressed.skipped[..] else {
+            panic!("{:?}", decompressed.skipped);
+        };
+        assert_eq!(
+            (skipped.from_bit, skipped.to_bit),
+            (entries[1].bit_offset, entries[2].bit_offset)
+        );
+        assert!(
+            matches!(
+                skipped.reason,
+                ReadError::InvalidHuffmanLengths { table: 0, .. }
+            ),
+            "{}",
+            skipped.reason
+        );
+        let start = |block: usize| entries[block].uncompressed_offset as usize;
+        let expected = [&data[..start(1)], &data[start(2)..]].concat();
+        assert_eq!(decompressed.data, expected);
+    }
+
+    #[test_case(EMPTY_BZ2.to_vec(); "empty")]
+    #[test_case(HELLO_WORLD_BZ2.to_vec(); "hello world")]
+    #[test_case([crate::compress(&include_bytes!("reader.rs").repeat(10), crate::CompressionLevel::FASTEST), HELLO_WORLD_BZ2.to_vec()].concat(); "several blocks and streams")]
+    #[test_case(hello_world_with_bits(80, 1, 1); "crc mismatch")]
+    fn test_small(data: Vec<u8>) {
+        let options = DecompressOptions {
+            ignore_crc_errors: true,
+            ..Default::default()
+        };
+        let small = DecompressOptions {
+            small: true,
+            ..options
+        };
+        let decompressed = decompress_with(&data, options).unwrap();
+        assert_eq!(decompress_with(&data, small), Ok(decompressed));
+    }
+
+    #[test]
+    fn test_stream_crc_mismatch() {
+        let mut data = HELLO_WORLD_BZ2.to_vec();
+        let last = data.len() - 2;
+        data[last] ^= 0x10;
+        assert!(matches!(
+            decompress(&data),
+            Err(ReadError::StreamCrcMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn test_randomized_short_block() {
+        let mut data = HELLO_WORLD_BZ2.to_vec();
+        // the randomized bit is the first bit after the block CRC. The first byte a randomized
+        // block flips is at 617, so a shorter one is the same either way.
+        data[14] |= 0x80;
+        assert_eq!(decompress(&data), Ok(b"hello world\n".to_vec()));
+    }
+}
@@ -0,0 +1,160 @@
+use super::{
+    block_size::BlockSize,
+    raw::RawBlock,
+    reader::{read_block_within, ReadError},
+    scan::{find_magic, BLOCK_MAGIC},
+    writer::StreamWriter,
+};
+use thiserror::Error;
+
+use crate::{bits::BitReader, bwt::MemoryBudget};
+
+/// An intact block of a damaged file, as a stream of its own
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecoveredBlock {
+    /// Bit offset of the block magic in the damaged file
+    pub offset: u64,
+    /// Length of the block in bits, up to where the next block or the end of stream marker starts
+    pub bit_len: u64,
+    pub crc: u32,
+    /// A complete `.bz2` stream holding just this block
+    pub stream: Vec<u8>,
+}
+
+/// A place where the block magic shows up, but no intact block follows
+#[derive(Debug, Error, PartialEq, Eq)]
+#[error("No intact block at bit {offset}: {error}")]
+pub struct BlockError {
+    /// Bit offset of the block magic in the damaged file
+    pub offset: u64,
+    /// Why the block behind it can't be read, `ReadError::BlockCrcMismatch` if it decodes to
+    /// something else than its CRC says
+    pub error: ReadError,
+}
+
+/// Salvage what can be salvaged from a damaged `.bz2` file, like `bzip2recover` does.
+///
+/// Every place the block magic shows up is a candidate, whatever comes before it, so blocks are
+/// found even after a broken header or a broken block. Each candidate is decoded and checked
+/// against its CRC; the ones that pass are copied bit for bit into a single-block stream, which
+/// decompresses on its own. The ones that don't are returned with the reason, a magic that shows
+/// up by chance inside a block is among them.
+///
+/// The streams get the smallest block size their block fits into, since the header of the
+/// original stream may be lost.
+pub fn recover_blocks(data: &[u8]) -> Vec<Result<RecoveredBlock, BlockError>> {
+    find_magic(data, BLOCK_MAGIC)
+        .into_iter()
+        .enumerate()
+        .map(|(index, offset)| {
+            recover_block(data, index, offset).map_err(|error| BlockError { offset, error })
+        })
+        .collect()
+}
+
+/// Decode the block whose magic is at bit `offset`, the `index`th magic found in `data`
+fn recover_block(data: &[u8], index: usize, offset: u64) -> Result<RecoveredBlock, ReadError> {
+    let mut reader = BitReader::new(data);
+    reader.skip(offset + 48)?;
+    let raw = RawBlock::read(&mut reader.clone())?;
+    let mut block = Vec::new();
+    let (crc, len) = read_block_within(
+        &mut reader,
+        BlockSize::MAX.bytes(),
+        MemoryBudget::UNLIMITED,
+        index,
+        &mut (),
+        &mut block,
+    )?;
+    let bit_len = 48 + raw.bit_len();
+
+    let level = len.div_ceil(BlockSize::MIN.bytes()).max(1) as u8;
+    let block_size =
+        BlockSize::new(level).expect("Blocks are at most as large as the largest size");
+    let mut stream = StreamWriter::new(block_size);
+    stream.append_raw_block(&raw, block.len());
+    Ok(RecoveredBlock {
+        offset,
+        bit_len,
+        crc,
+        stream: stream.finish(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use test_case::test_case;
+
+    use super::*;
+    use crate::{compress, format::reader::decompress, CompressionLevel};
+
+    fn blocks_of(data: &[u8]) -> Vec<Vec<u8>> {
+        crate::format::blocks::BlockSplitter::new(data, BlockSize::MIN)
+            .map(<[u8]>::to_vec)
+            .collect()
+    }
+
+    #[test]
+    fn test_intact_file() {
+        let data = include_bytes!("recover.rs").repeat(30);
+        let recovered = recover_blocks(&compress(&data, CompressionLevel::FASTEST));
+        let streams: Vec<Vec<u8>> = recovered
+            .into_iter()
+            .map(|block| decompress(&block.unwrap().stream).unwrap())
+            .collect();
+        assert_eq!(streams, blocks_of(&data));
+    }
+
+    #[test]
+    fn test_damaged_block_is_skipped() {
+        let data = include_bytes!("recover.rs").repeat(60);
+        let mut compressed = compress(&data, CompressionLevel::FASTEST);
+        // break the second block somewhere in its Huffman coded data
+        let offsets = find_magic(&compressed, BLOCK_MAGIC);
+        let broken = ((offsets[1] + offsets[2]) / 16) as usize;
+        compressed[broken] ^= 0x55;
+        assert!(decompress(&compressed).is_err());
+
+        let recovered = recover_blocks(&compressed);
+        assert_eq!(recovered.len(), offsets.len());
+        assert!(matches!(recovered[1], Err(BlockError { offset, .. }) if offset == offsets[1]));
+        let expected = blocks_of(&data);
+        for (i, block) in recovered.iter().enumerate().filter(|&(i, _)| i != 1) {
+            let block = block.as_ref().unwrap();
+            assert_eq!(block.offset, offsets[i]);
+            assert_eq!(decompress(&block.stream), Ok(expected[i].clone()));
+        }
+    }
+
+    #[test]
+    fn test_broken_header() {
+        let mut compressed = compress(b"hello world\n", CompressionLevel::BEST);
+        compressed[..4].copy_from_slice(b"\0\0\0\0");
+        let recovered = recover_blocks(&compressed);
+        let block = recovered[0].as_ref().unwrap();
+        assert_eq!(block.crc, 0x4eec_e836);
+        // the smallest block size is enough for twelve bytes
+        assert_eq!(&block.stream[..4], b"BZh1");
+        assert_eq!(decompress(&block.stream), Ok(b"hello world\n".to_vec()));
+    }
+
+    #[test_case(b"".to_vec(); "empty")]
+    #[test_case(b"no magic in here".to_vec(); "no magic")]
+    #[test_case(compress(b"", CompressionLevel::BEST); "stream without blocks")]
+    fn test_nothing_to_recover(data: Vec<u8>) {
+        assert_eq!(recover_blocks(&data), Vec::new());
+    }
+
+    #[test]
+    fn test_truncated_block() {
+        let compressed = compress(b"hello world\n", CompressionLevel::BEST);
+        let recovered = recover_blocks(&compressed[..30]);
+        assert!(matches!(
+            recovered[..],
+            [Err(BlockError {
+                offset: 32,
+                error: ReadError::Truncated { .. }
+            })]
+        ));
+    }
+}
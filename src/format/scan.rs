@@ -0,0 +1,100 @@
+use alloc::vec::Vec;
+
+/// 48-bit magic at the start of every compressed block (BCD of pi)
+pub const BLOCK_MAGIC: u64 = 0x3141_5926_5359;
+/// 48-bit magic at the start of the stream footer (BCD of sqrt(pi))
+pub const END_OF_STREAM_MAGIC: u64 = 0x1772_4538_5090;
+
+const MAGIC_BITS: u32 = 48;
+const MAGIC_MASK: u64 = (1 << MAGIC_BITS) - 1;
+
+/// Return the bit offset of every occurrence of the 48-bit `magic` in `data`.
+///
+/// Block boundaries in a bzip2 stream are not byte aligned, so the data is read as a stream of
+/// bits (most significant bit of each byte first) and the magic may start at any bit. Nothing
+/// guarantees that the magic doesn't also show up by chance inside the compressed data, so the
+/// offsets returned here are candidates that still have to be confirmed by decoding the block.
+pub fn find_magic(data: &[u8], magic: u64) -> Vec<u64> {
+    windows(data, 0)
+        .filter(|&(_, window)| window == magic)
+        .map(|(offset, _)| offset)
+        .collect()
+}
+
+/// Return the bit offset of the first block or end of stream magic at or after bit `from` of
+/// `data`, where a decoder that lost its way in a damaged block can pick up again. Like the
+/// offsets of `find_magic`, it is only a candidate.
+pub fn next_magic(data: &[u8], from: u64) -> Option<u64> {
+    windows(data, from)
+        .find(|&(_, window)| window == BLOCK_MAGIC || window == END_OF_STREAM_MAGIC)
+        .map(|(offset, _)| offset)
+}
+
+/// Every 48 bits of `data` starting at bit `from` or later, with the bit offset they start at
+fn windows(data: &[u8], from: u64) -> impl Iterator<Item = (u64, u64)> + '_ {
+    let first_byte = ((from / 8) as usize).min(data.len());
+    let bits = data[first_byte..]
+        .iter()
+        .flat_map(|&byte| (0..8).rev().map(move |shift| (byte >> shift) & 1));
+    bits.scan((0u64, first_byte as u64 * 8), |(window, bits_read), bit| {
+        *window = ((*window << 1) | bit as u64) & MAGIC_MASK;
+        *bits_read += 1;
+        Some((bits_read.checked_sub(MAGIC_BITS as u64), *window))
+    })
+    .filter_map(|(offset, window)| Some((offset?, window)))
+    .filter(move |&(offset, _)| offset >= from.max(first_byte as u64 * 8))
+}
+
+#[cfg(test)]
+mod tests {
+    use test_case::test_case;
+
+    use super::*;
+    use crate::bits::BitWriter;
+
+    /// Write `magic` after `padding` zero bits, followed by a few junk bytes
+    fn with_magic_at(padding: u32, magic: u64) -> Vec<u8> {
+        let mut writer = BitWriter::new();
+        for _ in 0..padding {
+            writer.write_bit(false);
+        }
+        writer.write_bits((magic >> 24) as u32, 24);
+        writer.write_bits((magic & 0xff_ffff) as u32, 24);
+        writer.write_bytes(b"\x00\xff");
+        writer.into_bytes()
+    }
+
+    #[test_case(0 => vec![0]; "byte aligned")]
+    #[test_case(3 => vec![3]; "unaligned")]
+    #[test_case(7 => vec![7]; "last bit of first byte")]
+    #[test_case(8 => vec![8]; "second byte")]
+    #[test_case(61 => vec![61]; "far in")]
+    fn test_find_block_magic(padding: u32) -> Vec<u64> {
+        find_magic(&with_magic_at(padding, BLOCK_MAGIC), BLOCK_MAGIC)
+    }
+
+    #[test_case(5, BLOCK_MAGIC, END_OF_STREAM_MAGIC => Vec::<u64>::new(); "other magic")]
+    #[test_case(5, END_OF_STREAM_MAGIC, END_OF_STREAM_MAGIC => vec![5]; "end of stream magic")]
+    fn test_find_specific_magic(padding: u32, written: u64, searched: u64) -> Vec<u64> {
+        find_magic(&with_magic_at(padding, written), searched)
+    }
+
+    #[test_case(0 => Some(3); "from the start")]
+    #[test_case(3 => Some(3); "at the magic")]
+    #[test_case(4 => Some(76); "after the first magic")]
+    #[test_case(77 => None; "after both")]
+    fn test_next_magic(from: u64) -> Option<u64> {
+        let mut data = with_magic_at(3, BLOCK_MAGIC);
+        data.extend(with_magic_at(4, END_OF_STREAM_MAGIC));
+        next_magic(&data, from)
+    }
+
+    #[test_case(b"" => Vec::<u64>::new(); "empty")]
+    #[test_case(b"\x31\x41\x59\x26\x53" => Vec::<u64>::new(); "truncated magic")]
+    #[test_case(b"\x31\x41\x59\x26\x53\x59" => vec![0]; "only the magic")]
+    #[test_case(b"\x31\x41\x59\x26\x53\x59\x31\x41\x59\x26\x53\x59" => vec![0, 48]; "two in a row")]
+    #[test_case(b"BZh91AY&SY" => vec![32]; "after stream header")]
+    fn test_find_magic_in_bytes(data: &[u8]) -> Vec<u64> {
+        find_magic(data, BLOCK_MAGIC)
+    }
+}
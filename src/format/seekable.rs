@@ -0,0 +1,364 @@
+use std::io::{self, Read, Seek, SeekFrom};
+
+use super::{
+    block_size::BlockSize,
+    estimate::MAX_BLOCK_BITS,
+    index::{BlockIndex, IndexError},
+    reader::{read_bits48, read_block_within, ReadError},
+    scan::BLOCK_MAGIC,
+};
+use crate::{bits::BitReader, bwt::MemoryBudget};
+
+/// Reads the uncompressed data of a `.bz2` stream from any position, with the help of the
+/// `BlockIndex` that was recorded when the stream was written.
+///
+/// Seeking only moves the position. A `read` looks up the block that holds it, reads just the
+/// compressed bytes of that block from the inner reader and decodes them, so reading a few bytes
+/// from the middle of a large stream costs one block instead of everything before it. The last
+/// decoded block is kept, reads that stay inside it decode nothing.
+///
+/// Every block is checked against its CRC, but the stream CRC isn't, since that would take all
+/// blocks. Like `BzDecoder`, corrupt data fails a `read` with `ErrorKind::InvalidData`, wrapping
+/// the `ReadError`, and so does an index that doesn't fit the stream, wrapping an `IndexError`
+/// where the index itself can't be right.
+pub struct SeekableBzDecoder<R: Read + Seek> {
+    inner: R,
+    index: BlockIndex,
+    /// Position in the uncompressed data
+    pos: u64,
+    /// The decoded bytes of block `current` of the index
+    block: Vec<u8>,
+    current: Option<usize>,
+}
+
+impl<R: Read + Seek> SeekableBzDecoder<R> {
+    /// Read the stream from `inner`, which has to be the stream `index` was recorded for, with
+    /// the stream header at its start
+    pub fn new(inner: R, index: BlockIndex) -> Self {
+        Self {
+            inner,
+            index,
+            pos: 0,
+            block: Vec::new(),
+            current: None,
+        }
+    }
+
+    pub fn index(&self) -> &BlockIndex {
+        &self.index
+    }
+
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+
+    /// The inner reader. Its position doesn't matter, every block is read after a seek.
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+
+    /// Read and decode block `index` of the index into `block`. The index may not come from
+    /// `BlockIndex::read_from`, so its entry is checked against the longest block and the length
+    /// of the stream before anything is allocated for it.
+    fn load(&mut self, index: usize) -> io::Result<()> {
+        let entry = self.index.entries()[index];
+        let invalid = |err: IndexError| io::Error::new(io::ErrorKind::InvalidData, err);
+        if entry.bit_len > MAX_BLOCK_BITS {
+            return Err(invalid(IndexError::BadBlockLength {
+                block: index,
+                bit_len: entry.bit_len,
+            }));
+        }
+        let input_len = self.inner.seek(SeekFrom::End(0))?;
+        let start = entry.bit_offset / 8;
+        let end = entry
+            .bit_offset
+            .checked_add(entry.bit_len)
+            .ok_or(invalid(IndexError::Overflow { block: index }))?
+            .div_ceil(8);
+        if end > input_len {
+            return Err(invalid(IndexError::PastEnd {
+                block: index,
+                input_len,
+            }));
+        }
+        let mut compressed = vec![0; (end - start) as usize];
+        self.inner.seek(SeekFrom::Start(start))?;
+        self.inner.read_exact(&mut compressed)?;
+
+        self.current = None;
+        self.block.clear();
+        decode_block(&compressed, entry.bit_offset, index, &mut self.block)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        if self.block.len() as u64 != entry.uncompressed_len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Block {index} decoded to {} bytes, the index says {}",
+                    self.block.len(),
+                    entry.uncompressed_len
+                ),
+            ));
+        }
+        self.current = Some(index);
+        Ok(())
+    }
+}
+
+/// Decode block `index`, whose magic is `bit_offset` bits into the stream, from its bytes in
+/// `compressed`, which start at the byte that holds the magic
+fn decode_block(
+    compressed: &[u8],
+    bit_offset: u64,
+    index: usize,
+    out: &mut Vec<u8>,
+) -> Result<(), ReadError> {
+    let mut reader = BitReader::new(compressed);
+    reader.skip(bit_offset % 8)?;
+    match read_bits48(&mut reader)? {
+        BLOCK_MAGIC => {
+            read_block_within(
+                &mut reader,
+                BlockSize::MAX.bytes(),
+                MemoryBudget::UNLIMITED,
+                index,
+                &mut (),
+                out,
+            )?;
+            Ok(())
+        }
+        found => Err(ReadError::BadBlockMagic { bit_offset, found }),
+    }
+}
+
+impl<R: Read + Seek> Read for SeekableBzDecoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let Some(index) = self.index.find(self.pos) else {
+            return Ok(0);
+        };
+        if self.current != Some(index) {
+            self.load(index)?;
+        }
+        let offset = (self.pos - self.index.entries()[index].uncompressed_offset) as usize;
+        let n = buf.len().min(self.block.len() - offset);
+        buf[..n].copy_from_slice(&self.block[offset..offset + n]);
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+/// Positions are in the uncompressed data. Seeking past the end is allowed, reads there return
+/// nothing.
+impl<R: Read + Seek> Seek for SeekableBzDecoder<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let (base, offset) = match pos {
+            SeekFrom::Start(pos) => (pos, 0),
+            SeekFrom::End(offset) => (self.index.uncompressed_len(), offset),
+            SeekFrom::Current(offset) => (self.pos, offset),
+        };
+        self.pos = base.checked_add_signed(offset).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Seek to a negative or overflowing position",
+            )
+        })?;
+        Ok(self.pos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use proptest::prelude::*;
+    use test_case::test_case;
+
+    use super::*;
+    use crate::{format::writer::StreamWriter, stages::strategies, CompressionLevel};
+
+    fn compress_with_index(data: &[u8], level: CompressionLevel) -> (Vec<u8>, BlockIndex) {
+        let mut writer = StreamWriter::builder().level(level).build();
+        writer.write_blocks(data);
+        let index = writer.index().clone();
+        (writer.finish(), index)
+    }
+
+    fn several_blocks() -> Vec<u8> {
+        include_bytes!("seekable.rs").repeat(60)
+    }
+
+    fn decoder(data: &[u8]) -> SeekableBzDecoder<Cursor<Vec<u8>>> {
+        let (compressed, index) = compress_with_index(data, CompressionLevel::FASTEST);
+        SeekableBzDecoder::new(Cursor::new(compressed), index)
+    }
+
+    #[test]
+    fn test_read_to_end() {
+        let data = several_blocks();
+        let mut decoder = decoder(&data);
+        assert!(decoder.index().len() > 2);
+        let mut out = Vec::new();
+        decoder.read_to_end(&mut out).unwrap();
+        assert_eq!(out, data);
+    }
+
+    #[test_case(SeekFrom::Start(0); "start")]
+    #[test_case(SeekFrom::Start(99_990); "across a block boundary")]
+    #[test_case(SeekFrom::Start(250_000); "middle of a later block")]
+    #[test_case(SeekFrom::End(-10); "near the end")]
+    #[test_case(SeekFrom::End(-1); "last byte")]
+    #[test_case(SeekFrom::End(0); "end")]
+    #[test_case(SeekFrom::End(100); "past the end")]
+    fn test_seek_and_read(pos: SeekFrom) {
+        let data = several_blocks();
+        let mut decoder = decoder(&data);
+        let start = decoder.seek(pos).unwrap() as usize;
+        let mut out = [0; 40];
+        let mut n = 0;
+        while n < out.len() {
+            match decoder.read(&mut out[n..]).unwrap() {
+                0 => break,
+                read => n += read,
+            }
+        }
+        let start = start.min(data.len());
+        assert_eq!(&out[..n], &data[start..(start + 40).min(data.len())]);
+    }
+
+    /// An index scanned from a stream written without one reads the same as the one the writer
+    /// recorded, at block boundaries, in the middle of a block and at the last byte
+    #[test]
+    fn test_scanned_index() {
+        let data = include_bytes!("seekable.rs").repeat(40);
+        let (compressed, recorded) = compress_with_index(&data, CompressionLevel::FASTEST);
+        let index = BlockIndex::scan(&compressed).unwrap();
+        assert_eq!(index, recorded);
+        assert!(index.len() >= 5);
+        let mut decoder = SeekableBzDecoder::new(Cursor::new(compressed), index);
+        let offsets: Vec<u64> = (decoder.index().entries().iter())
+            .flat_map(|entry| {
+                let middle = entry.uncompressed_offset + entry.uncompressed_len / 2;
+                [entry.uncompressed_offset, middle]
+            })
+            .chain([data.len() as u64 - 1])
+            .collect();
+        for offset in offsets {
+            decoder.seek(SeekFrom::Start(offset)).unwrap();
+            let mut out = Vec::new();
+            decoder.by_ref().take(100).read_to_end(&mut out).unwrap();
+            let start = offset as usize;
+            assert_eq!(
+                out,
+                &data[start..(start + 100).min(data.len())],
+                "at {offset}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_decodes_only_the_block_read() {
+        let data = several_blocks();
+        let mut decoder = decoder(&data);
+        let last = decoder.index().len() - 1;
+        let entry = decoder.index().entries()[last];
+        decoder
+            .seek(SeekFrom::Start(entry.uncompressed_offset))
+            .unwrap();
+        let mut byte = [0];
+        decoder.read_exact(&mut byte).unwrap();
+        assert_eq!(decoder.current, Some(last));
+        assert_eq!(decoder.block.len() as u64, entry.uncompressed_len);
+        // the inner reader stops at the end of the last block, before the end of stream marker
+        assert_eq!(
+            decoder.get_ref().position(),
+            (entry.bit_offset + entry.bit_len).div_ceil(8)
+        );
+    }
+
+    #[test]
+    fn test_seek_relative() {
+        let mut decoder = decoder(b"hello world\n");
+        assert_eq!(decoder.seek(SeekFrom::Current(6)).unwrap(), 6);
+        assert_eq!(decoder.seek(SeekFrom::Current(-2)).unwrap(), 4);
+        assert_eq!(
+            decoder.seek(SeekFrom::Current(-5)).unwrap_err().kind(),
+            io::ErrorKind::InvalidInput
+        );
+        let mut out = String::new();
+        decoder.read_to_string(&mut out).unwrap();
+        assert_eq!(out, "o world\n");
+    }
+
+    #[test]
+    fn test_corrupt_block() {
+        let (mut compressed, index) = compress_with_index(b"hello world\n", CompressionLevel::BEST);
+        compressed[10] ^= 0x01;
+        let mut decoder = SeekableBzDecoder::new(Cursor::new(compressed), index);
+        let err = decoder.read(&mut [0; 4]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(matches!(
+            err.into_inner().unwrap().downcast::<ReadError>().as_deref(),
+            Ok(ReadError::BlockCrcMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn test_index_of_another_stream() {
+        let (compressed, _) = compress_with_index(b"hello world\n", CompressionLevel::BEST);
+        let (_, index) = compress_with_index(&several_blocks(), CompressionLevel::FASTEST);
+        let mut decoder = SeekableBzDecoder::new(Cursor::new(compressed), index);
+        let err = decoder.read(&mut [0; 4]).unwrap_err();
+        assert!(matches!(
+            err.kind(),
+            io::ErrorKind::InvalidData | io::ErrorKind::UnexpectedEof
+        ));
+    }
+
+    #[test_case(32, 1 << 40 => matches IndexError::BadBlockLength { .. }; "longer than any block")]
+    #[test_case(32, MAX_BLOCK_BITS => matches IndexError::PastEnd { .. }; "past the end of the stream")]
+    #[test_case(u64::MAX - 10, 100 => matches IndexError::Overflow { .. }; "overflowing")]
+    fn test_hostile_index(bit_offset: u64, bit_len: u64) -> IndexError {
+        let (compressed, _) = compress_with_index(b"hello world\n", CompressionLevel::BEST);
+        let mut index = BlockIndex::new();
+        index.push(bit_offset, bit_len, 12);
+        let mut decoder = SeekableBzDecoder::new(Cursor::new(compressed), index);
+        let err = decoder.read(&mut [0; 4]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        *err.into_inner().unwrap().downcast::<IndexError>().unwrap()
+    }
+
+    #[test]
+    fn test_hostile_index_file() {
+        let (compressed, index) = compress_with_index(b"hello world\n", CompressionLevel::BEST);
+        let mut bytes = Vec::new();
+        index.write_to(&mut bytes).unwrap();
+        // the bit length of the only block
+        bytes[21..29].copy_from_slice(&(1u64 << 40).to_le_bytes());
+        assert!(matches!(
+            BlockIndex::read_from(bytes.as_slice()),
+            Err(crate::Error::InvalidIndex(
+                IndexError::BadBlockLength { .. }
+            ))
+        ));
+        let mut decoder = SeekableBzDecoder::new(Cursor::new(compressed), index);
+        let mut out = String::new();
+        decoder.read_to_string(&mut out).unwrap();
+        assert_eq!(out, "hello world\n");
+    }
+
+    proptest! {
+        #[test]
+        fn prop_read_at(data in strategies::bytes(1024), start in any::<prop::sample::Index>(), len in 0usize..64) {
+            let mut decoder = decoder(&data);
+            let start = start.index(data.len() + 1);
+            decoder.seek(SeekFrom::Start(start as u64)).unwrap();
+            let mut out = Vec::new();
+            decoder.by_ref().take(len as u64).read_to_end(&mut out).unwrap();
+            prop_assert_eq!(&out[..], &data[start..(start + len).min(data.len())]);
+        }
+    }
+}
@@ -0,0 +1,302 @@
+use std::{ops::AddAssign, time::Duration};
+
+use super::{
+    estimate::{selector_bits, table_bits, BLOCK_HEADER_BITS},
+    symbol_map::SymbolMap,
+};
+use crate::{huff::GROUP_SIZE, mtf::MtfTransform, symbol::Symbol};
+
+/// Sizes and timings of the stages of the blocks a `StreamWriter` compressed, for benchmarking
+/// the encoder.
+///
+/// The sizes are exact. The timings are measured around every stage of every block and summed
+/// up, so for blocks compressed on several threads they add up to more than the time it took.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct EncodeStats {
+    /// Number of (non-empty) blocks
+    pub blocks: u64,
+    /// Bytes before the initial run-length encoding
+    pub uncompressed_len: u64,
+    /// Bytes after the initial run-length encoding, the length of the BWT
+    pub rle1_len: u64,
+    /// Symbols after MTF and RLE2, end of block symbols included
+    pub mtf_symbols: u64,
+    /// Bits of the compressed blocks, with their headers, tables and selectors
+    pub compressed_bits: u64,
+    /// The time spent in every `StageKind`
+    pub timings: Timings,
+}
+
+/// A part of compressing a block that `Timings` measures on its own
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum StageKind {
+    /// The initial run-length encoding and the block CRC, both a pass over the uncompressed bytes
+    Rle1,
+    /// Sorting the rotations of the block
+    Bwt,
+    Mtf,
+    /// Picking the Huffman tables and selectors and building their codes
+    HuffmanBuild,
+    /// Writing the block header, symbol map, selectors, tables and coded symbols
+    BitEmission,
+    /// Handing the compressed bytes to the inner writer of a `BzEncoder`
+    Io,
+}
+
+impl StageKind {
+    pub const ALL: [Self; 6] = [
+        Self::Rle1,
+        Self::Bwt,
+        Self::Mtf,
+        Self::HuffmanBuild,
+        Self::BitEmission,
+        Self::Io,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Rle1 => "rle1",
+            Self::Bwt => "bwt",
+            Self::Mtf => "mtf",
+            Self::HuffmanBuild => "huffman",
+            Self::BitEmission => "emission",
+            Self::Io => "io",
+        }
+    }
+}
+
+/// Time spent in every `StageKind` of one or more blocks. The stages are timed on the thread that
+/// runs them, so for blocks compressed on several threads the times add up to more than the time
+/// it took.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Timings([Duration; StageKind::ALL.len()]);
+
+impl Timings {
+    pub fn get(&self, kind: StageKind) -> Duration {
+        self.0[kind as usize]
+    }
+
+    pub fn add(&mut self, kind: StageKind, duration: Duration) {
+        self.0[kind as usize] += duration;
+    }
+
+    pub fn total(&self) -> Duration {
+        self.0.iter().sum()
+    }
+}
+
+impl AddAssign for Timings {
+    fn add_assign(&mut self, other: Self) {
+        for (time, other) in self.0.iter_mut().zip(other.0) {
+            *time += other;
+        }
+    }
+}
+
+impl AddAssign for EncodeStats {
+    fn add_assign(&mut self, other: Self) {
+        self.blocks += other.blocks;
+        self.uncompressed_len += other.uncompressed_len;
+        self.rle1_len += other.rle1_len;
+        self.mtf_symbols += other.mtf_symbols;
+        self.compressed_bits += other.compressed_bits;
+        self.timings += other.timings;
+    }
+}
+
+/// Where the bits of a compressed block went, for finding out why it compresses worse than
+/// expected: the overhead of its header, symbol map, selectors and code length tables, and for
+/// every Huffman table the symbols it coded, their entropy and the bits they took.
+/// `StreamWriterBuilder::detailed_stats` collects one for every block.
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct BlockStats {
+    /// The magic, the CRC, the randomized flag and the origin pointer
+    pub header_bits: u64,
+    pub symbol_map_bits: u64,
+    /// The number of tables and selectors and the selectors themselves
+    pub selector_bits: u64,
+    /// The code lengths of all tables
+    pub table_bits: u64,
+    pub tables: Vec<TableStats>,
+}
+
+/// What one Huffman table of a block coded
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct TableStats {
+    /// Groups of `GROUP_SIZE` symbols whose selector picks the table
+    pub groups: u64,
+    /// Symbols coded with the table, the end of block symbol included
+    pub symbols: u64,
+    /// Bits the symbols take at the entropy of their own frequencies, the least any code could
+    /// take for them
+    pub shannon_bits: f64,
+    /// Bits the symbols took with the table's codes
+    pub coded_bits: u64,
+}
+
+impl BlockStats {
+    /// Account for the bits of a block whose MTF stage gave `mtf`, coded with the tables of
+    /// `code_lengths` as `selectors` pick them
+    pub fn new(mtf: &MtfTransform, code_lengths: &[Vec<u8>], selectors: &[u8]) -> Self {
+        let alphabet_size = Symbol::alphabet_size(mtf.num_stack());
+        let eob = Symbol::eob(alphabet_size);
+        let symbols: Vec<usize> = mtf
+            .indices()
+            .iter()
+            .map(Symbol::from)
+            .chain([eob])
+            .map(|symbol| symbol.value() as usize)
+            .collect();
+        let mut freqs = vec![vec![0_u64; alphabet_size]; code_lengths.len()];
+        let mut tables = vec![TableStats::default(); code_lengths.len()];
+        for (group, &selector) in symbols.chunks(GROUP_SIZE).zip(selectors) {
+            let table = selector as usize;
+            tables[table].groups += 1;
+            for &value in group {
+                freqs[table][value] += 1;
+                tables[table].coded_bits += code_lengths[table][value] as u64;
+            }
+        }
+        for (table, freqs) in tables.iter_mut().zip(&freqs) {
+            table.symbols = freqs.iter().sum();
+            let total = table.symbols as f64;
+            table.shannon_bits = freqs
+                .iter()
+                .filter(|&&freq| freq > 0)
+                .map(|&freq| freq as f64 * (total / freq as f64).log2())
+                .sum();
+        }
+        Self {
+            header_bits: BLOCK_HEADER_BITS,
+            symbol_map_bits: SymbolMap::from_symbols(mtf.used_symbols()).bit_len(),
+            selector_bits: selector_bits(selectors, code_lengths.len()),
+            table_bits: table_bits(code_lengths),
+            tables,
+        }
+    }
+
+    /// Bits of the Huffman coded symbols, over all tables
+    pub fn coded_bits(&self) -> u64 {
+        self.tables.iter().map(|table| table.coded_bits).sum()
+    }
+
+    /// Bits the symbols of every table take at their entropy, over all tables
+    pub fn shannon_bits(&self) -> f64 {
+        self.tables.iter().map(|table| table.shannon_bits).sum()
+    }
+
+    /// Bits of the whole block, from its magic to its last coded symbol
+    pub fn bits(&self) -> u64 {
+        self.header_bits
+            + self.symbol_map_bits
+            + self.selector_bits
+            + self.table_bits
+            + self.coded_bits()
+    }
+}
+
+/// What `compress_with_stats` returns
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Compressed {
+    pub data: Vec<u8>,
+    pub stats: EncodeStats,
+    /// Where the bits of every block went
+    pub blocks: Vec<BlockStats>,
+}
+
+#[cfg(test)]
+mod tests {
+    use test_case::test_case;
+
+    use super::*;
+    use crate::{
+        bits::BitReader,
+        compress, compress_with_stats,
+        format::{block_iter::Blocks, block_size::CompressionLevel, reader::decompress},
+    };
+
+    #[test_case(b"hello world\n", CompressionLevel::BEST; "hello world")]
+    #[test_case(&[b'a'; 600_000], CompressionLevel::FASTEST; "one long run")]
+    #[test_case(&include_bytes!("stats.rs").repeat(100), CompressionLevel::FASTEST; "text")]
+    #[test_case(&include_bytes!("writer.rs").repeat(40), CompressionLevel::Two; "text with larger blocks")]
+    #[test_case(&(0..300_000u32).map(|i| (i.wrapping_mul(2_654_435_761) >> 24) as u8).collect::<Vec<u8>>(), CompressionLevel::FASTEST; "noise")]
+    fn test_block_stats_account_for_every_bit(data: &[u8], level: CompressionLevel) {
+        let compressed = compress_with_stats(data, level);
+        assert_eq!(compressed.data, compress(data, level));
+        assert_eq!(decompress(&compressed.data).unwrap(), data);
+
+        let mut reader = BitReader::new(&compressed.data);
+        let blocks: Vec<_> = Blocks::new(&mut reader)
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(compressed.blocks.len(), blocks.len());
+        assert_eq!(compressed.stats.blocks, blocks.len() as u64);
+        let mut bits = 0;
+        for (stats, block) in compressed.blocks.iter().zip(&blocks) {
+            assert_eq!(stats.bits(), block.bit_len);
+            assert_eq!(stats.coded_bits(), block.coded_bits);
+            assert_eq!(stats.tables.len(), block.tables());
+            let groups: u64 = stats.tables.iter().map(|table| table.groups).sum();
+            assert_eq!(groups, block.selectors.len() as u64);
+            for table in &stats.tables {
+                // no code beats the entropy, up to rounding
+                assert!(table.shannon_bits <= table.coded_bits as f64 + 1e-6);
+            }
+            bits += stats.bits();
+        }
+        assert_eq!(bits, compressed.stats.compressed_bits);
+        let symbols: u64 = compressed
+            .blocks
+            .iter()
+            .flat_map(|block| &block.tables)
+            .map(|table| table.symbols)
+            .sum();
+        assert_eq!(symbols, compressed.stats.mtf_symbols);
+    }
+
+    #[test]
+    fn test_single_group() {
+        // fewer than `GROUP_SIZE` symbols, all of them coded with the table of the one selector
+        let compressed = compress_with_stats(b"hello world\n", CompressionLevel::BEST);
+        let [block] = &compressed.blocks[..] else {
+            panic!("{:?}", compressed.blocks);
+        };
+        let used: Vec<&TableStats> = block
+            .tables
+            .iter()
+            .filter(|table| table.groups > 0)
+            .collect();
+        let [table] = used[..] else {
+            panic!("{:?}", block.tables);
+        };
+        assert_eq!(table.groups, 1);
+        assert_eq!(table.symbols, compressed.stats.mtf_symbols);
+        // different symbols, so some entropy, but never more than their codes take
+        assert!(table.shannon_bits > 0.0);
+        assert!(table.shannon_bits <= table.coded_bits as f64);
+        assert_eq!(block.shannon_bits(), table.shannon_bits);
+        assert!(compress_with_stats(b"", CompressionLevel::BEST)
+            .blocks
+            .is_empty());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde_roundtrip() {
+        let mut stats = compress_with_stats(b"hello world\n", CompressionLevel::BEST).stats;
+        stats
+            .timings
+            .add(StageKind::Bwt, Duration::from_micros(1500));
+        let json = serde_json::to_string(&stats).unwrap();
+        assert_eq!(serde_json::from_str::<EncodeStats>(&json).unwrap(), stats);
+        let bytes = bincode::serialize(&stats).unwrap();
+        assert_eq!(bincode::deserialize::<EncodeStats>(&bytes).unwrap(), stats);
+        assert_eq!(serde_json::to_string(&StageKind::Io).unwrap(), "\"Io\"");
+    }
+}
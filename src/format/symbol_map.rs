@@ -0,0 +1,128 @@
+use alloc::vec::Vec;
+
+use crate::bits::{BitReadError, BitReader, BitWriter};
+
+/// Number of byte values each bit of the first level of the map stands for
+const RANGE_LEN: usize = 16;
+
+/// The set of bytes a block uses, as the block header transmits it.
+///
+/// It is a two-level bitmap: 16 bits telling which ranges of 16 byte values are in use, then 16
+/// bits for each range in use telling which of its bytes are, most significant bit first. The
+/// used bytes in ascending order are the initial MTF stack, so the alphabet of the block only has
+/// symbols for bytes that actually occur in it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SymbolMap([u16; 16]);
+
+impl SymbolMap {
+    pub fn from_symbols(symbols: &[u8]) -> Self {
+        let mut ranges = [0u16; 16];
+        for &byte in symbols {
+            ranges[byte as usize / RANGE_LEN] |= 0x8000 >> (byte as usize % RANGE_LEN);
+        }
+        Self(ranges)
+    }
+
+    pub fn contains(&self, byte: u8) -> bool {
+        self.0[byte as usize / RANGE_LEN] & (0x8000 >> (byte as usize % RANGE_LEN)) != 0
+    }
+
+    /// Number of bytes in use
+    pub fn len(&self) -> usize {
+        self.0.iter().map(|range| range.count_ones() as usize).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.iter().all(|&range| range == 0)
+    }
+
+    /// The bytes in use in ascending order, i.e. the MTF stack of the block
+    pub fn symbols(&self) -> Vec<u8> {
+        (0..=u8::MAX).filter(|&byte| self.contains(byte)).collect()
+    }
+
+    /// Number of bits `write` takes
+    pub fn bit_len(&self) -> u64 {
+        16 + 16 * self.0.iter().filter(|&&range| range != 0).count() as u64
+    }
+
+    pub fn write(&self, bits: &mut BitWriter) {
+        let used_ranges = self
+            .0
+            .iter()
+            .enumerate()
+            .filter(|&(_, &range)| range != 0)
+            .fold(0u16, |map, (i, _)| map | 0x8000 >> i);
+        bits.write_bits(used_ranges as u32, 16);
+        for &range in self.0.iter().filter(|&&range| range != 0) {
+            bits.write_bits(range as u32, 16);
+        }
+    }
+
+    /// Inverse of `write`. A map without any bytes is read just fine, whether that makes sense
+    /// for the block is up to the caller.
+    pub fn read(reader: &mut BitReader) -> Result<Self, BitReadError> {
+        let used_ranges = reader.read_bits(16)?;
+        let mut ranges = [0u16; 16];
+        for (i, range) in ranges.iter_mut().enumerate() {
+            if used_ranges & (0x8000 >> i) != 0 {
+                *range = reader.read_bits(16)? as u16;
+            }
+        }
+        Ok(Self(ranges))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+    use test_case::test_case;
+
+    use super::*;
+    use crate::{mtf::MtfTransform, stages::strategies};
+
+    #[test_case(b"" => (vec![0x00, 0x00], 16); "empty")]
+    #[test_case(&[0] => (vec![0x80, 0x00, 0x80, 0x00], 32); "zero")]
+    #[test_case(&[255] => (vec![0x00, 0x01, 0x00, 0x01], 32); "last byte")]
+    // `bz2.compress(b"hello world\n")` from Python has the same map
+    #[test_case(b"helo wrd\n" => (vec![0xa3, 0x00, 0x00, 0x20, 0x80, 0x00, 0x0c, 0x89, 0x21, 0x00], 80); "hello world")]
+    #[test_case(&(0..=255).collect::<Vec<u8>>() => ([vec![0xff; 2], vec![0xff; 32]].concat(), 272); "every byte")]
+    fn test_write(symbols: &[u8]) -> (Vec<u8>, u64) {
+        let mut bits = BitWriter::new();
+        let map = SymbolMap::from_symbols(symbols);
+        map.write(&mut bits);
+        assert_eq!(map.bit_len(), bits.bit_len());
+        (bits.bytes().to_vec(), bits.bit_len())
+    }
+
+    #[test_case(b"" => 0; "empty")]
+    #[test_case(b"banana" => 3; "banana")]
+    #[test_case(&(0..=255).collect::<Vec<u8>>() => 256; "every byte")]
+    fn test_len(symbols: &[u8]) -> usize {
+        let map = SymbolMap::from_symbols(symbols);
+        assert_eq!(map.is_empty(), symbols.is_empty());
+        map.len()
+    }
+
+    #[test]
+    fn test_read_truncated() {
+        // the first level announces two ranges, but only one follows
+        assert!(SymbolMap::read(&mut BitReader::new(&[0xc0, 0x00, 0xff, 0xff])).is_err());
+    }
+
+    proptest! {
+        #[test]
+        fn prop_roundtrip(data in strategies::bytes(4096)) {
+            let mtf = MtfTransform::encode(&data);
+            let map = SymbolMap::from_symbols(mtf.used_symbols());
+            prop_assert_eq!(map.symbols(), mtf.used_symbols());
+            prop_assert_eq!(map.len(), mtf.num_stack());
+
+            let mut bits = BitWriter::new();
+            map.write(&mut bits);
+            prop_assert_eq!(bits.bit_len(), 16 + 16 * map.0.iter().filter(|&&r| r != 0).count() as u64);
+            let bytes = bits.into_bytes();
+            prop_assert_eq!(SymbolMap::read(&mut BitReader::new(&bytes)), Ok(map));
+        }
+    }
+}
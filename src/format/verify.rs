@@ -0,0 +1,183 @@
+use std::io::{self, Read};
+
+use thiserror::Error;
+
+use super::decoder::{BzDecoder, StreamInfo};
+use crate::crc::BlockCrc;
+
+/// Length of the chunks `verify_against` compares at a time
+const CHUNK_LEN: usize = 1 << 16;
+
+/// How `verify_against` compares what a stream decompresses to with the original
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VerifyMode {
+    /// The length and the CRC of all of it
+    Crc,
+    /// Every byte
+    Full,
+}
+
+/// What a stream decompresses to isn't its original
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum Mismatch {
+    #[error("byte {offset} differs")]
+    Byte { offset: u64 },
+    #[error("{decompressed} bytes instead of {original}")]
+    Length { decompressed: u64, original: u64 },
+    #[error("CRC {decompressed:#010x} instead of {original:#010x}")]
+    Crc { decompressed: u32, original: u32 },
+}
+
+/// Decompress `compressed` and compare it with `original` as `mode` says, both read a chunk at a
+/// time, so neither has to fit into memory. Like `verify`, every block and stream CRC is checked
+/// along the way and what was read is returned. A difference fails with `Error::Mismatch`.
+pub fn verify_against<C: Read, O: Read>(
+    compressed: C,
+    mut original: O,
+    mode: VerifyMode,
+) -> crate::Result<StreamInfo> {
+    let mut decoder = BzDecoder::new(compressed);
+    let mut chunk = vec![0; CHUNK_LEN];
+    let mut expected = vec![0; CHUNK_LEN];
+    let mut crc = BlockCrc::new();
+    let mut original_crc = BlockCrc::new();
+    let mut len = 0;
+    let mut original_len = 0;
+    loop {
+        // both are filled all the way until their input ends, so their offsets stay the same
+        let read = read_up_to(&mut decoder, &mut chunk)?;
+        let expected_len = read_up_to(&mut original, &mut expected)?;
+        let (chunk, expected) = (&chunk[..read], &expected[..expected_len]);
+        match mode {
+            VerifyMode::Crc => {
+                crc.update(chunk);
+                original_crc.update(expected);
+            }
+            VerifyMode::Full => {
+                if let Some(offset) = chunk.iter().zip(expected).position(|(a, b)| a != b) {
+                    let offset = len + offset as u64;
+                    return Err(Mismatch::Byte { offset }.into());
+                }
+            }
+        }
+        len += read as u64;
+        original_len += expected_len as u64;
+        if read < CHUNK_LEN || expected_len < CHUNK_LEN {
+            break;
+        }
+    }
+    // one of them ended, the rest of the other one only counts towards its length
+    len += io::copy(&mut decoder, &mut io::sink())?;
+    original_len += io::copy(&mut original, &mut io::sink())?;
+    if len != original_len {
+        return Err(Mismatch::Length {
+            decompressed: len,
+            original: original_len,
+        }
+        .into());
+    }
+    if crc.value() != original_crc.value() {
+        return Err(Mismatch::Crc {
+            decompressed: crc.value(),
+            original: original_crc.value(),
+        }
+        .into());
+    }
+    Ok(decoder.info())
+}
+
+/// Fill `buffer` from `reader` as far as it goes and return how much of it that was, all of it
+/// unless `reader` ended
+fn read_up_to(reader: &mut impl Read, buffer: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match reader.read(&mut buffer[filled..]) {
+            Ok(0) => break,
+            Ok(read) => filled += read,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(filled)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs::File, io::Write};
+
+    use test_case::test_case;
+
+    use super::*;
+    use crate::{compress, CompressionLevel, CrcKind, Error};
+
+    fn text() -> Vec<u8> {
+        include_bytes!("verify.rs").repeat(20)
+    }
+
+    #[test_case(VerifyMode::Crc; "crc")]
+    #[test_case(VerifyMode::Full; "full")]
+    fn test_verify_against(mode: VerifyMode) {
+        let data = text();
+        let compressed = compress(&data, CompressionLevel::FASTEST);
+        let info = verify_against(compressed.as_slice(), data.as_slice(), mode).unwrap();
+        assert_eq!(info.uncompressed_len, data.len() as u64);
+        assert_eq!(info.compressed_len, compressed.len() as u64);
+    }
+
+    #[test_case(VerifyMode::Crc, 1000 => Mismatch::Crc { decompressed: 0, original: 0 }; "other byte by crc")]
+    #[test_case(VerifyMode::Full, 1000 => Mismatch::Byte { offset: 1000 }; "other byte")]
+    #[test_case(VerifyMode::Full, 100_000 => Mismatch::Byte { offset: 100_000 }; "other byte in a later chunk")]
+    fn test_other_original(mode: VerifyMode, offset: usize) -> Mismatch {
+        let data = text();
+        let compressed = compress(&data, CompressionLevel::FASTEST);
+        let mut original = data.clone();
+        original[offset] ^= 0x01;
+        match verify_against(compressed.as_slice(), original.as_slice(), mode) {
+            // the CRCs themselves don't matter
+            Err(Error::Mismatch(Mismatch::Crc { .. })) => Mismatch::Crc {
+                decompressed: 0,
+                original: 0,
+            },
+            Err(Error::Mismatch(mismatch)) => mismatch,
+            result => panic!("{result:?}"),
+        }
+    }
+
+    #[test_case(VerifyMode::Crc, 1; "longer by crc")]
+    #[test_case(VerifyMode::Full, 1; "longer")]
+    #[test_case(VerifyMode::Full, CHUNK_LEN; "longer by a chunk")]
+    fn test_longer_original(mode: VerifyMode, extra: usize) {
+        let data = text();
+        let compressed = compress(&data, CompressionLevel::FASTEST);
+        let original = [data.clone(), vec![b'x'; extra]].concat();
+        assert_eq!(
+            verify_against(compressed.as_slice(), original.as_slice(), mode),
+            Err(Error::Mismatch(Mismatch::Length {
+                decompressed: data.len() as u64,
+                original: original.len() as u64,
+            }))
+        );
+        assert_eq!(
+            verify_against(compressed.as_slice(), &data[..data.len() - extra], mode),
+            Err(Error::Mismatch(Mismatch::Length {
+                decompressed: data.len() as u64,
+                original: (data.len() - extra) as u64,
+            }))
+        );
+    }
+
+    /// The stream is written to a file and damaged there before it is verified, like a bit
+    /// flipped on the way to the disk
+    #[test_case(VerifyMode::Crc, 11 => matches Error::CrcMismatch { kind: CrcKind::Block, .. }; "stored block crc")]
+    #[test_case(VerifyMode::Crc, 100 => matches Error::CorruptBlock(_); "block by crc")]
+    #[test_case(VerifyMode::Full, 100 => matches Error::CorruptBlock(_); "block")]
+    fn test_damaged_file(mode: VerifyMode, offset: usize) -> Error {
+        let data = text();
+        let mut compressed = compress(&data, CompressionLevel::FASTEST);
+        compressed[offset] ^= 0xff;
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(&compressed).unwrap();
+        let reopened = File::open(file.path()).unwrap();
+        verify_against(reopened, data.as_slice(), mode).unwrap_err()
+    }
+}
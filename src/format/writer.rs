@@ -0,0 +1,1136 @@
+#[cfg(feature = "parallel")]
+use std::io::{self, BufRead, Write};
+use std::time::{Duration, Instant};
+
+use thiserror::Error;
+
+#[cfg(feature = "parallel")]
+use super::blocks::BlockBuffer;
+use super::{
+    block_size::{BlockSize, CompressionLevel},
+    blocks::BlockSplitter,
+    header::STREAM_MAGIC,
+    index::BlockIndex,
+    memory::{huffman_working_set, rle1_bound, MemoryAccount},
+    observer::StageObserver,
+    raw::RawBlock,
+    scan::{BLOCK_MAGIC, END_OF_STREAM_MAGIC},
+    stats::{BlockStats, EncodeStats, StageKind, Timings},
+    symbol_map::SymbolMap,
+};
+use crate::{
+    bits::BitWriter,
+    bwt::{BwtEncoded, SortScratch},
+    crc::{BlockCrc, StreamCrc},
+    huff::{
+        serialize_lengths, serialize_selectors, HuffmanScratch, MultiTableEncoder,
+        DEFAULT_ITERATIONS,
+    },
+    mtf::MtfTransform,
+    rle::RleSequence,
+};
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum WriteError {
+    #[error("Block of {len} bytes after RLE1 exceeds the block size of {max} bytes")]
+    BlockTooLarge { len: usize, max: usize },
+    #[error("Compressing takes {needed} bytes of memory, more than the limit of {limit} bytes")]
+    MemoryLimitExceeded { needed: usize, limit: usize },
+}
+
+/// Assembles compressed blocks into a `.bz2` stream.
+///
+/// The stream header is written on creation, every `write_block` appends one compressed block
+/// and `finish` adds the end of stream marker with the combined CRC of all blocks. Blocks follow
+/// each other without any padding, only the very end of the stream is padded to a whole byte.
+/// Where every block went is recorded in a `BlockIndex` along the way.
+pub struct StreamWriter {
+    bits: BitWriter,
+    block_size: BlockSize,
+    stream_crc: StreamCrc,
+    index: BlockIndex,
+    /// Bits dropped by `discard_bytes`, so block offsets stay offsets into the whole stream
+    discarded_bits: u64,
+    scratch: ScratchSpace,
+    stats: EncodeStats,
+    account: MemoryAccount,
+    observer: Box<dyn StageObserver + Send>,
+    /// Where the bits of every block went, `None` unless `set_detailed_stats` asked for it
+    block_stats: Option<Vec<BlockStats>>,
+    /// How long every stage of every block took, `None` unless `set_block_timings` asked for it
+    block_timings: Option<Vec<Timings>>,
+    /// How often the Huffman tables of a block are refitted at most
+    huffman_iterations: usize,
+}
+
+/// The buffers the stages of a block are encoded in, reset and reused from block to block.
+///
+/// Compressing a block allocates its RLE1 sequence, the last column of the BWT, the MTF indices
+/// and the working buffers of the rotation sort, which are several times the size of the block,
+/// and the tables, selectors and trees of the Huffman stage. Reusing them, a block no larger than
+/// the ones before it hardly allocates at all, instead of going back to the allocator for every
+/// one of the thousands of blocks of a large input.
+#[derive(Debug)]
+pub struct ScratchSpace {
+    pub(super) rle: RleSequence,
+    pub(super) bwt: BwtEncoded,
+    pub(super) sort: SortScratch,
+    pub(super) mtf: MtfTransform,
+    pub(super) huffman: MultiTableEncoder,
+    pub(super) huffman_scratch: HuffmanScratch,
+}
+
+impl ScratchSpace {
+    pub fn new() -> Self {
+        Self {
+            rle: RleSequence::from_encoded(Vec::new()),
+            bwt: BwtEncoded::new(Vec::new(), 0),
+            sort: SortScratch::default(),
+            mtf: MtfTransform::encode(&[]),
+            huffman: MultiTableEncoder::default(),
+            huffman_scratch: HuffmanScratch::default(),
+        }
+    }
+}
+
+impl ScratchSpace {
+    /// Make room for a block of up to `len` bytes after RLE1 in every buffer, through `account`,
+    /// so that encoding the block doesn't grow them any further
+    pub(super) fn reserve(
+        &mut self,
+        len: usize,
+        account: &mut MemoryAccount,
+    ) -> Result<(), WriteError> {
+        account.reserve(self.rle.buffer_mut(), len)?;
+        account.reserve(self.bwt.buffer_mut(), len)?;
+        for buffer in self.sort.buffers_mut() {
+            account.reserve(buffer, len + 1)?;
+        }
+        account.reserve(&mut self.mtf.indices, len)?;
+        account.reserve(&mut self.mtf.stack, 256)
+    }
+}
+
+impl Default for ScratchSpace {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A block compressed on its own, before it is part of a stream.
+///
+/// Compressing a block doesn't depend on the blocks around it, so blocks can be compressed in any
+/// order, or at the same time, and then appended to a stream in input order with
+/// `StreamWriter::append_block`. A block generally doesn't end on a byte boundary, appending
+/// shifts it into place after the previous one.
+#[derive(Debug, Clone)]
+pub struct CompressedBlock {
+    bits: BitWriter,
+    crc: u32,
+    /// Length of the uncompressed block
+    len: usize,
+    stats: EncodeStats,
+}
+
+/// Blocks are equal if they hold the same bits, no matter how long it took to compress them
+impl PartialEq for CompressedBlock {
+    fn eq(&self, other: &Self) -> bool {
+        self.bits == other.bits && self.crc == other.crc && self.len == other.len
+    }
+}
+
+impl Eq for CompressedBlock {}
+
+impl CompressedBlock {
+    /// Compress the uncompressed bytes `block`, which have to fit `block_size` after the initial
+    /// run-length encoding, like for `StreamWriter::write_block`. An empty block compresses to
+    /// nothing at all.
+    pub fn new(block: &[u8], block_size: BlockSize) -> Result<Self, WriteError> {
+        Self::with_scratch(block, block_size, &mut ScratchSpace::new())
+    }
+
+    /// Same as `new`, but encodes the stages in the buffers of `scratch`
+    pub fn with_scratch(
+        block: &[u8],
+        block_size: BlockSize,
+        scratch: &mut ScratchSpace,
+    ) -> Result<Self, WriteError> {
+        Self::encode(block, block_size, DEFAULT_ITERATIONS, scratch)
+    }
+
+    /// Same as `with_scratch`, refitting the Huffman tables up to `huffman_iterations` times
+    fn encode(
+        block: &[u8],
+        block_size: BlockSize,
+        huffman_iterations: usize,
+        scratch: &mut ScratchSpace,
+    ) -> Result<Self, WriteError> {
+        let mut bits = BitWriter::new();
+        let reports = BlockReports {
+            index: 0,
+            observer: &mut (),
+            block_stats: None,
+        };
+        let (crc, stats) = encode_block(
+            block,
+            block_size,
+            huffman_iterations,
+            scratch,
+            reports,
+            &mut bits,
+        )?
+        .unwrap_or_default();
+        Ok(Self {
+            bits,
+            crc,
+            len: block.len(),
+            stats,
+        })
+    }
+
+    /// CRC of the uncompressed block, as stored in its header
+    pub fn crc(&self) -> u32 {
+        self.crc
+    }
+
+    /// Length of the compressed block in bits
+    pub fn bit_len(&self) -> u64 {
+        self.bits.bit_len()
+    }
+
+    /// Sizes and timings of the stages of the block
+    pub fn stats(&self) -> &EncodeStats {
+        &self.stats
+    }
+
+    /// The bits of the block, starting with the block magic
+    pub(super) fn into_bits(self) -> BitWriter {
+        self.bits
+    }
+}
+
+/// Settings of a `StreamWriter`, see `StreamWriter::builder`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct StreamWriterBuilder {
+    block_size: BlockSize,
+    max_memory: Option<usize>,
+    detailed_stats: bool,
+    block_timings: bool,
+    huffman_iterations: Option<usize>,
+}
+
+impl StreamWriterBuilder {
+    /// Compress with the block size of `level`, the default is `CompressionLevel::BEST`
+    pub fn level(mut self, level: CompressionLevel) -> Self {
+        self.block_size = level.block_size();
+        self
+    }
+
+    /// Same as `level`, for a block size that is already at hand, e.g. from `BlockSize::auto`
+    pub fn block_size(mut self, block_size: BlockSize) -> Self {
+        self.block_size = block_size;
+        self
+    }
+
+    /// Keep the memory compressing a block takes within `bytes`, the stream written so far aside:
+    /// the buffers of the stages are reserved through a `MemoryAccount` before every block, and a
+    /// block that would take more fails `write_block` with `MemoryLimitExceeded`.
+    /// `format::memory::fit_block_size` picks a block size that stays within the limit.
+    pub fn max_memory(mut self, bytes: usize) -> Self {
+        self.max_memory = Some(bytes);
+        self
+    }
+
+    /// Collect a `BlockStats` for every block, see `StreamWriter::set_detailed_stats`
+    pub fn detailed_stats(mut self) -> Self {
+        self.detailed_stats = true;
+        self
+    }
+
+    /// Keep the `Timings` of every block, see `StreamWriter::set_block_timings`
+    pub fn block_timings(mut self) -> Self {
+        self.block_timings = true;
+        self
+    }
+
+    /// Refit the Huffman tables of every block up to `iterations` times instead of
+    /// `huff::DEFAULT_ITERATIONS`, see `MultiTableEncoder::with_iterations`. More iterations
+    /// trade time for coded symbols that never take more bits, though rarely much fewer.
+    pub fn huffman_iterations(mut self, iterations: usize) -> Self {
+        self.huffman_iterations = Some(iterations);
+        self
+    }
+
+    pub fn build(self) -> StreamWriter {
+        let mut writer = StreamWriter::new(self.block_size);
+        writer.account = MemoryAccount::new(self.max_memory);
+        if self.detailed_stats {
+            writer.set_detailed_stats();
+        }
+        if self.block_timings {
+            writer.set_block_timings();
+        }
+        if let Some(iterations) = self.huffman_iterations {
+            writer.huffman_iterations = iterations;
+        }
+        writer
+    }
+}
+
+impl StreamWriter {
+    pub fn builder() -> StreamWriterBuilder {
+        StreamWriterBuilder::default()
+    }
+
+    pub fn new(block_size: BlockSize) -> Self {
+        let mut bits = BitWriter::new();
+        bits.write_bytes(STREAM_MAGIC);
+        bits.write_bytes(&[b'0' + block_size.level()]);
+        Self {
+            bits,
+            block_size,
+            stream_crc: StreamCrc::new(),
+            index: BlockIndex::new(),
+            discarded_bits: 0,
+            scratch: ScratchSpace::new(),
+            stats: EncodeStats::default(),
+            account: MemoryAccount::default(),
+            observer: Box::new(()),
+            block_stats: None,
+            block_timings: None,
+            huffman_iterations: DEFAULT_ITERATIONS,
+        }
+    }
+
+    pub fn block_size(&self) -> BlockSize {
+        self.block_size
+    }
+
+    /// The blocks written so far, with their bit offsets from the start of the stream
+    pub fn index(&self) -> &BlockIndex {
+        &self.index
+    }
+
+    /// Sizes and timings of the stages of the blocks written so far
+    pub fn stats(&self) -> &EncodeStats {
+        &self.stats
+    }
+
+    /// Hand what every stage makes of every block `write_block` and `write_blocks` compress to
+    /// `observer`. Blocks compressed elsewhere and appended with `append_block` aren't reported.
+    pub fn set_observer(&mut self, observer: impl StageObserver + Send + 'static) {
+        self.observer = Box::new(observer);
+    }
+
+    /// Account for the bits of every block `write_block` and `write_blocks` compress from now on
+    /// in a `BlockStats`, which takes another pass over the symbols of every block. Blocks
+    /// compressed elsewhere and appended with `append_block` aren't accounted for.
+    pub fn set_detailed_stats(&mut self) {
+        self.block_stats.get_or_insert_with(Vec::new);
+    }
+
+    /// Where the bits of every block went since `set_detailed_stats`, in the order of the blocks
+    pub fn block_stats(&self) -> &[BlockStats] {
+        self.block_stats.as_deref().unwrap_or_default()
+    }
+
+    /// Keep the `Timings` of every block compressed from now on, blocks compressed by
+    /// `write_blocks_parallel` included, besides adding them up in the stats
+    pub fn set_block_timings(&mut self) {
+        self.block_timings.get_or_insert_with(Vec::new);
+    }
+
+    /// How long every stage of every block took since `set_block_timings`, in the order of the
+    /// blocks
+    pub fn block_timings(&self) -> &[Timings] {
+        self.block_timings.as_deref().unwrap_or_default()
+    }
+
+    /// Count `duration` as `StageKind::Io` of the last block, for handing its bytes on
+    pub(super) fn add_io_time(&mut self, duration: Duration) {
+        self.stats.timings.add(StageKind::Io, duration);
+        if let Some(timings) = self
+            .block_timings
+            .as_mut()
+            .and_then(|timings| timings.last_mut())
+        {
+            timings.add(StageKind::Io, duration);
+        }
+    }
+
+    /// Number of bits written since the start of the stream, including discarded ones
+    fn bit_position(&self) -> u64 {
+        self.discarded_bits + self.bits.bit_len()
+    }
+
+    /// Compress the uncompressed bytes `block` and append them as one block.
+    ///
+    /// The block has to fit the block size after the initial run-length encoding, splitting the
+    /// input is up to the caller. Empty blocks are skipped, an empty input is a stream without
+    /// any blocks, like the reference implementation writes it.
+    pub fn write_block(&mut self, block: &[u8]) -> Result<(), WriteError> {
+        if self.account.limit().is_some() {
+            let len = rle1_bound(block.len(), self.block_size);
+            self.scratch.reserve(len, &mut self.account)?;
+            self.account.check(huffman_working_set(len))?;
+        }
+        let start = self.bit_position();
+        let reports = BlockReports {
+            index: self.stats.blocks as usize,
+            observer: self.observer.as_mut(),
+            block_stats: self.block_stats.as_mut(),
+        };
+        if let Some((crc, stats)) = encode_block(
+            block,
+            self.block_size,
+            self.huffman_iterations,
+            &mut self.scratch,
+            reports,
+            &mut self.bits,
+        )? {
+            self.stream_crc.combine(crc);
+            self.stats += stats;
+            if let Some(block_timings) = &mut self.block_timings {
+                block_timings.push(stats.timings);
+            }
+            self.index
+                .push(start, self.bit_position() - start, block.len() as u64);
+        }
+        Ok(())
+    }
+
+    /// Append a block compressed ahead of time. The block has to be compressed for the block size
+    /// of this stream, the result is the same as `write_block` on its uncompressed bytes.
+    pub fn append_block(&mut self, block: &CompressedBlock) {
+        if block.bits.bit_len() > 0 {
+            self.index
+                .push(self.bit_position(), block.bits.bit_len(), block.len as u64);
+            self.bits.append(&block.bits);
+            self.stream_crc.combine(block.crc);
+            self.stats += block.stats;
+            if let Some(block_timings) = &mut self.block_timings {
+                block_timings.push(block.stats.timings);
+            }
+        }
+    }
+
+    /// Append a block taken from another stream or stored on its own, which decodes to `len`
+    /// bytes. It wasn't compressed here, so it adds nothing to the stats.
+    pub fn append_raw_block(&mut self, block: &RawBlock, len: usize) {
+        if !block.is_empty() {
+            let start = self.bit_position();
+            block.write(&mut self.bits);
+            self.index
+                .push(start, self.bit_position() - start, len as u64);
+            self.stream_crc.combine(block.crc());
+            if let Some(block_timings) = &mut self.block_timings {
+                block_timings.push(Timings::default());
+            }
+        }
+    }
+
+    /// Split `data` into as many blocks as it takes with `BlockSplitter` and append them.
+    pub fn write_blocks(&mut self, data: &[u8]) {
+        for block in BlockSplitter::new(data, self.block_size) {
+            self.write_block(block)
+                .expect("The splitter only produces blocks that fit the block size");
+        }
+    }
+
+    /// Same as `write_blocks`, but compresses the blocks on the current rayon thread pool and then
+    /// appends them in order. All compressed blocks are held in memory until they are appended,
+    /// every thread reuses a `ScratchSpace` of its own for the blocks it compresses.
+    #[cfg(feature = "parallel")]
+    pub fn write_blocks_parallel(&mut self, data: &[u8]) {
+        self.append_parallel(BlockSplitter::new(data, self.block_size).collect());
+    }
+
+    /// Same as `write_blocks_parallel`, for input that is read rather than in memory: reads
+    /// `reader` to its end `batch` blocks at a time, cut like `BlockSplitter` cuts them, and
+    /// hands the bytes of the stream to `output` after every batch, followed by `discard_bytes`.
+    /// Only the blocks of one batch and their compressed bits are held in memory, `batch` is
+    /// best about the number of threads. Returns how many bytes were read.
+    #[cfg(feature = "parallel")]
+    pub fn write_blocks_parallel_from(
+        &mut self,
+        mut reader: impl BufRead,
+        mut output: impl Write,
+        batch: usize,
+    ) -> io::Result<u64> {
+        let mut buffers: Vec<BlockBuffer> = (0..batch.max(1))
+            .map(|_| BlockBuffer::new(self.block_size))
+            .collect();
+        let mut filling = 0;
+        let mut len = 0;
+        loop {
+            let (taken, full) = match reader.fill_buf() {
+                Ok([]) => break,
+                Ok(data) => {
+                    let taken = buffers[filling].fill(data);
+                    (taken, taken < data.len())
+                }
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err),
+            };
+            reader.consume(taken);
+            len += taken as u64;
+            if full {
+                filling += 1;
+            }
+            if filling == buffers.len() {
+                self.write_batch(&mut buffers, &mut output)?;
+                filling = 0;
+            }
+        }
+        self.write_batch(&mut buffers[..=filling], &mut output)?;
+        Ok(len)
+    }
+
+    /// Compress the blocks of `buffers` in parallel, append them, empty the buffers and hand the
+    /// bytes of the stream to `output`
+    #[cfg(feature = "parallel")]
+    fn write_batch(
+        &mut self,
+        buffers: &mut [BlockBuffer],
+        output: &mut impl Write,
+    ) -> io::Result<()> {
+        let blocks = buffers
+            .iter()
+            .map(BlockBuffer::block)
+            .filter(|block| !block.is_empty())
+            .collect();
+        self.append_parallel(blocks);
+        buffers.iter_mut().for_each(BlockBuffer::clear);
+        output.write_all(self.bytes())?;
+        self.discard_bytes();
+        Ok(())
+    }
+
+    /// Compress `blocks` on the current rayon thread pool and append them in order
+    #[cfg(feature = "parallel")]
+    fn append_parallel(&mut self, blocks: Vec<&[u8]>) {
+        use rayon::prelude::*;
+
+        let compressed: Vec<CompressedBlock> = blocks
+            .into_par_iter()
+            .map_init(ScratchSpace::new, |scratch, block| {
+                CompressedBlock::encode(block, self.block_size, self.huffman_iterations, scratch)
+                    .expect("The splitter only produces blocks that fit the block size")
+            })
+            .collect();
+        for block in &compressed {
+            self.append_block(block);
+        }
+    }
+
+    /// The complete bytes of the stream written since the last `discard_bytes`, for callers that
+    /// hand the stream on block by block instead of waiting for `finish`.
+    pub fn bytes(&self) -> &[u8] {
+        self.bits.bytes()
+    }
+
+    /// Drop the bytes `bytes` returned. The bits of a partial last byte stay, the next block
+    /// continues right after them.
+    pub fn discard_bytes(&mut self) {
+        self.discarded_bits += self.bits.bytes().len() as u64 * 8;
+        self.bits.discard_bytes();
+    }
+
+    /// Write the end of stream marker and return the finished stream, or what is left of it after
+    /// `discard_bytes`.
+    pub fn finish(mut self) -> Vec<u8> {
+        write_bits48(&mut self.bits, END_OF_STREAM_MAGIC);
+        self.bits.write_bits(self.stream_crc.value(), 32);
+        self.bits.into_bytes()
+    }
+}
+
+/// The current time, `None` on wasm32-unknown-unknown, where `Instant::now` panics for lack of a
+/// clock, so the stage timings stay zero there
+fn now() -> Option<Instant> {
+    if cfg!(all(target_arch = "wasm32", target_os = "unknown")) {
+        None
+    } else {
+        Some(Instant::now())
+    }
+}
+
+/// Measures the time between one point and the next, always zero where `now` has no clock
+pub(super) struct Clock(Option<Instant>);
+
+impl Clock {
+    pub(super) fn start() -> Self {
+        Self(now())
+    }
+
+    /// The time since the last lap, or since the start for the first one
+    pub(super) fn lap(&mut self) -> Duration {
+        let now = now();
+        let elapsed = match (now, self.0) {
+            (Some(now), Some(last)) => now - last,
+            _ => Duration::ZERO,
+        };
+        self.0 = now;
+        elapsed
+    }
+}
+
+/// What `encode_block` reports about a block besides its CRC and stats
+struct BlockReports<'a> {
+    /// Position of the block in the stream, as the observer sees it
+    index: usize,
+    observer: &'a mut dyn StageObserver,
+    /// Gets a `BlockStats` of the block if given
+    block_stats: Option<&'a mut Vec<BlockStats>>,
+}
+
+/// Compress `block` in the buffers of `scratch`, refitting its Huffman tables up to
+/// `huffman_iterations` times, and write it to `bits`, returning its CRC and stats, or `None` for
+/// an empty block, which writes nothing. The stages of the block and what came of them go to
+/// `reports`.
+fn encode_block(
+    block: &[u8],
+    block_size: BlockSize,
+    huffman_iterations: usize,
+    scratch: &mut ScratchSpace,
+    reports: BlockReports,
+    bits: &mut BitWriter,
+) -> Result<Option<(u32, EncodeStats)>, WriteError> {
+    if block.is_empty() {
+        return Ok(None);
+    }
+    let BlockReports {
+        index,
+        observer,
+        block_stats,
+    } = reports;
+    let start_bits = bits.bit_len();
+    let mut timings = Timings::default();
+    // every stage takes the time since the end of the one before
+    let mut clock = Clock::start();
+
+    RleSequence::encode_into(block, &mut scratch.rle);
+    if scratch.rle.len() > block_size.bytes() {
+        return Err(WriteError::BlockTooLarge {
+            len: scratch.rle.len(),
+            max: block_size.bytes(),
+        });
+    }
+    let crc = BlockCrc::checksum(block);
+    timings.add(StageKind::Rle1, clock.lap());
+    observer.rle1(index, scratch.rle.sequence());
+
+    BwtEncoded::encode_with_scratch(&scratch.rle, &mut scratch.bwt, &mut scratch.sort);
+    timings.add(StageKind::Bwt, clock.lap());
+    let original_index = scratch.bwt.original_index();
+    observer.bwt(index, scratch.bwt.last_column(), original_index);
+    MtfTransform::encode_into(scratch.bwt.last_column(), &mut scratch.mtf);
+    timings.add(StageKind::Mtf, clock.lap());
+    let mtf = &scratch.mtf;
+    observer.mtf(index, mtf.indices(), mtf.used_symbols());
+    let encoder = &mut scratch.huffman;
+    encoder.refit(mtf, huffman_iterations, &mut scratch.huffman_scratch);
+    timings.add(StageKind::HuffmanBuild, clock.lap());
+    write_encoded_block(bits, crc, original_index, mtf, encoder);
+    timings.add(StageKind::BitEmission, clock.lap());
+    observer.huffman(
+        index,
+        encoder.code_lengths(),
+        encoder.selectors(),
+        encoder.cost(),
+    );
+    if let Some(block_stats) = block_stats {
+        block_stats.push(BlockStats::new(
+            mtf,
+            encoder.code_lengths(),
+            encoder.selectors(),
+        ));
+    }
+    let stats = EncodeStats {
+        blocks: 1,
+        uncompressed_len: block.len() as u64,
+        rle1_len: scratch.rle.len() as u64,
+        // the indices and the end of block symbol
+        mtf_symbols: mtf.len() as u64 + 1,
+        compressed_bits: bits.bit_len() - start_bits,
+        timings,
+    };
+    Ok(Some((crc, stats)))
+}
+
+/// Write a block from its magic on, given the CRC of its bytes and the result of its BWT and MTF:
+/// Huffman code `mtf` and write it with everything the decoder needs to undo the stages. Returns
+/// the encoder with the tables and selectors it picked.
+pub(super) fn write_transformed_block(
+    bits: &mut BitWriter,
+    crc: u32,
+    original_index: usize,
+    mtf: &MtfTransform,
+) -> MultiTableEncoder {
+    let encoder = MultiTableEncoder::new(mtf);
+    write_encoded_block(bits, crc, original_index, mtf, &encoder);
+    encoder
+}
+
+/// Same as `write_transformed_block`, with the tables and selectors `encoder` picked
+fn write_encoded_block(
+    bits: &mut BitWriter,
+    crc: u32,
+    original_index: usize,
+    mtf: &MtfTransform,
+    encoder: &MultiTableEncoder,
+) {
+    write_bits48(bits, BLOCK_MAGIC);
+    bits.write_bits(crc, 32);
+    // blocks are never randomized, that was only ever needed by bzip2 0.9.0's sorting
+    bits.write_bit(false);
+    bits.write_bits(original_index as u32, 24);
+    SymbolMap::from_symbols(mtf.used_symbols()).write(bits);
+    write_selectors(bits, encoder);
+    for lengths in encoder.code_lengths() {
+        serialize_lengths(lengths, bits).expect("The encoder only produces valid code lengths");
+    }
+    encoder.encode_into(mtf, bits);
+}
+
+pub(super) fn write_bits48(bits: &mut BitWriter, value: u64) {
+    bits.write_bits((value >> 32) as u32, 16);
+    bits.write_bits(value as u32, 32);
+}
+
+/// Number of tables, number of selectors, then the selectors, see `serialize_selectors`
+fn write_selectors(bits: &mut BitWriter, encoder: &MultiTableEncoder) {
+    bits.write_bits(encoder.num_tables() as u32, 3);
+    bits.write_bits(encoder.selectors().len() as u32, 15);
+    serialize_selectors(encoder.selectors(), encoder.num_tables(), bits)
+        .expect("Selectors refer to existing tables");
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::Write,
+        process::{Command, Stdio},
+    };
+
+    use proptest::prelude::*;
+    use test_case::test_case;
+
+    use super::*;
+    use crate::{
+        bits::BitReader,
+        format::{memory::working_set, reader::decompress, scan::find_magic},
+        mtf::MtfIndex,
+        stages::{counting_alloc as alloc, strategies},
+    };
+
+    fn compress(data: &[u8], level: u8) -> Vec<u8> {
+        let mut writer = StreamWriter::new(BlockSize::new(level).unwrap());
+        writer.write_block(data).unwrap();
+        writer.finish()
+    }
+
+    /// Decompress `data` with the reference `bzip2` binary, `None` if it isn't installed
+    fn reference_decompress(data: &[u8]) -> Option<Vec<u8>> {
+        let mut child = Command::new("bzip2")
+            .arg("-dc")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .ok()?;
+        let mut stdin = child.stdin.take().unwrap();
+        let input = data.to_vec();
+        let feeder = std::thread::spawn(move || stdin.write_all(&input));
+        let output = child.wait_with_output().unwrap();
+        feeder.join().unwrap().unwrap();
+        assert!(
+            output.status.success(),
+            "bzip2 rejected the stream: {}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        Some(output.stdout)
+    }
+
+    #[test_case(b"banana"; "runb")]
+    #[test_case(b"baa"; "runa")]
+    #[test_case(&b"banana".repeat(1000); "long run")]
+    fn test_block_ending_in_run(data: &[u8]) {
+        // the block's EOB comes right after a RUNA or RUNB
+        let bwt = BwtEncoded::encode(&RleSequence::encode(data));
+        assert!(matches!(
+            MtfTransform::from_bwt(&bwt).indices().last(),
+            Some(MtfIndex::RunA | MtfIndex::RunB)
+        ));
+        let stream = compress(data, 9);
+        assert_eq!(crate::decompress(&stream).unwrap(), data);
+        match reference_decompress(&stream) {
+            Some(decompressed) => assert_eq!(decompressed, data),
+            None => eprintln!("bzip2 not found, skipping the interop check"),
+        }
+    }
+
+    #[test]
+    fn test_empty_stream_matches_reference() {
+        // `bz2.compress(b"")` from Python
+        assert_eq!(
+            compress(b"", 9),
+            b"BZh9\x17\x72\x45\x38\x50\x90\x00\x00\x00\x00"
+        );
+    }
+
+    #[test_case(StreamWriter::builder() => b"BZh9".to_vec(); "default")]
+    #[test_case(StreamWriter::builder().level(CompressionLevel::FASTEST) => b"BZh1".to_vec(); "fastest")]
+    #[test_case(StreamWriter::builder().level(CompressionLevel::Six) => b"BZh6".to_vec(); "six")]
+    #[test_case(StreamWriter::builder().block_size(BlockSize::MIN).level(CompressionLevel::Four) => b"BZh4".to_vec(); "last setting wins")]
+    fn test_builder_header(builder: StreamWriterBuilder) -> Vec<u8> {
+        builder.build().finish()[..4].to_vec()
+    }
+
+    #[test]
+    fn test_huffman_iterations() {
+        // text followed by a table of numbers, so the groups of a block want different tables
+        let mut data = include_bytes!("writer.rs").to_vec();
+        data.extend((0..20_000u32).flat_map(|i| (i * 7919 % 1000).to_string().into_bytes()));
+        let compress = |iterations| {
+            let mut writer = StreamWriter::builder()
+                .level(CompressionLevel::Two)
+                .huffman_iterations(iterations)
+                .detailed_stats()
+                .build();
+            writer.write_blocks(&data);
+            let coded_bits: Vec<u64> = writer
+                .block_stats()
+                .iter()
+                .map(|block| block.tables.iter().map(|table| table.coded_bits).sum())
+                .collect();
+            (writer.finish(), coded_bits)
+        };
+        let (compressed, mut previous) = compress(1);
+        assert_eq!(decompress(&compressed), Ok(data.clone()));
+        for iterations in 2..=8 {
+            let (compressed, coded_bits) = compress(iterations);
+            // every block on its own, a refit of one doesn't depend on the others
+            for (block, (bits, previous)) in coded_bits.iter().zip(&previous).enumerate() {
+                assert!(
+                    bits <= previous,
+                    "Block {block} with {iterations} iterations: {bits} > {previous} bits"
+                );
+            }
+            assert_eq!(decompress(&compressed), Ok(data.clone()));
+            previous = coded_bits;
+        }
+        let total = |iterations| compress(iterations).1.iter().sum::<u64>();
+        assert!(total(8) < total(1));
+        assert_eq!(
+            compress(DEFAULT_ITERATIONS).0,
+            crate::compress(&data, CompressionLevel::Two)
+        );
+        assert_eq!(compress(0), compress(1));
+    }
+
+    #[test]
+    fn test_block_layout() {
+        let stream = compress(b"hello world\n", 1);
+        assert_eq!(&stream[..4], b"BZh1");
+        assert_eq!(find_magic(&stream, BLOCK_MAGIC), vec![32]);
+        // same block CRC as the reference implementation writes
+        assert_eq!(&stream[10..14], &[0x4e, 0xec, 0xe8, 0x36]);
+
+        let footer = find_magic(&stream, END_OF_STREAM_MAGIC);
+        assert_eq!(footer.len(), 1);
+        let mut reader = BitReader::new(&stream);
+        reader.skip(footer[0] + 48).unwrap();
+        // the combined CRC of a single block is the CRC of that block
+        assert_eq!(reader.read_bits(32), Ok(0x4eec_e836));
+        assert!(reader.remaining() < 8);
+    }
+
+    #[test]
+    fn test_combined_crc() {
+        let mut writer = StreamWriter::new(BlockSize::MAX);
+        writer.write_block(b"first").unwrap();
+        writer.write_block(b"").unwrap();
+        writer.write_block(b"second").unwrap();
+        assert_eq!(
+            writer.stream_crc.value(),
+            BlockCrc::checksum(b"first").rotate_left(1) ^ BlockCrc::checksum(b"second")
+        );
+    }
+
+    #[test]
+    fn test_block_too_large() {
+        let data: Vec<u8> = (0..100_001u32).map(|i| (i % 251) as u8).collect();
+        let mut writer = StreamWriter::new(BlockSize::MIN);
+        assert_eq!(
+            writer.write_block(&data),
+            Err(WriteError::BlockTooLarge {
+                len: 100_001,
+                max: 100_000
+            })
+        );
+    }
+
+    #[test]
+    fn test_write_blocks() {
+        let data: Vec<u8> = (0..250_000u32).map(|i| (i % 251) as u8).collect();
+        let mut writer = StreamWriter::new(BlockSize::MIN);
+        writer.write_blocks(&data);
+        let stream = writer.finish();
+        assert_eq!(find_magic(&stream, BLOCK_MAGIC).len(), 3);
+        if let Some(decompressed) = reference_decompress(&stream) {
+            assert_eq!(decompressed, data);
+        }
+    }
+
+    #[test]
+    fn test_discard_bytes() {
+        let mut whole = StreamWriter::new(BlockSize::MAX);
+        let mut pieces = StreamWriter::new(BlockSize::MAX);
+        let mut stream = Vec::new();
+        for block in [&b"first"[..], b"second", b"third"] {
+            whole.write_block(block).unwrap();
+            pieces.write_block(block).unwrap();
+            stream.extend_from_slice(pieces.bytes());
+            pieces.discard_bytes();
+        }
+        stream.extend(pieces.finish());
+        assert_eq!(stream, whole.finish());
+    }
+
+    #[test]
+    fn test_append_block_same_as_write_block() {
+        let mut written = StreamWriter::new(BlockSize::MIN);
+        let mut appended = StreamWriter::new(BlockSize::MIN);
+        for block in [&b"first"[..], b"", b"second", b"third block"] {
+            written.write_block(block).unwrap();
+            appended.append_block(&CompressedBlock::new(block, BlockSize::MIN).unwrap());
+        }
+        assert_eq!(appended.finish(), written.finish());
+    }
+
+    #[test]
+    fn test_index() {
+        let mut written = StreamWriter::new(BlockSize::MIN);
+        let mut appended = StreamWriter::new(BlockSize::MIN);
+        for block in [&b"first"[..], b"", b"second", b"third block"] {
+            written.write_block(block).unwrap();
+            written.discard_bytes();
+            appended.append_block(&CompressedBlock::new(block, BlockSize::MIN).unwrap());
+        }
+        let index = appended.index().clone();
+        assert_eq!(written.index(), &index);
+
+        let stream = appended.finish();
+        let offsets: Vec<u64> = index
+            .entries()
+            .iter()
+            .map(|entry| entry.bit_offset)
+            .collect();
+        assert_eq!(offsets, find_magic(&stream, BLOCK_MAGIC));
+        let last = index.entries()[2];
+        assert_eq!(
+            Some(last.bit_offset + last.bit_len),
+            find_magic(&stream, END_OF_STREAM_MAGIC).first().copied()
+        );
+        let lens: Vec<u64> = index
+            .entries()
+            .iter()
+            .map(|entry| entry.uncompressed_len)
+            .collect();
+        assert_eq!(lens, vec![5, 6, 11]);
+    }
+
+    #[test]
+    fn test_scratch_reuse() {
+        // the first block is the larger one, so the buffers it leaves are large enough
+        let first = include_bytes!("writer.rs").repeat(2);
+        let second = include_bytes!("reader.rs");
+        let mut scratch = ScratchSpace::new();
+        CompressedBlock::with_scratch(&first, BlockSize::MIN, &mut scratch).unwrap();
+
+        let (fresh, fresh_bytes) =
+            alloc::count_bytes(|| CompressedBlock::new(second, BlockSize::MIN).unwrap());
+        let (reused, reused_bytes) = alloc::count_bytes(|| {
+            CompressedBlock::with_scratch(second, BlockSize::MIN, &mut scratch).unwrap()
+        });
+        assert_eq!(reused, fresh);
+        // at the very least, the rotation sort doesn't allocate its four `usize`s per byte again
+        assert!(reused_bytes + 4 * size_of::<usize>() * second.len() <= fresh_bytes);
+    }
+
+    /// Once the scratch space has grown to the blocks of a stream, compressing another block only
+    /// allocates now and then, when the stream or a buffer that is still short of the largest
+    /// alphabet grows, instead of once for every Huffman tree and table
+    #[test]
+    fn test_steady_state_allocations() {
+        let data = include_bytes!("writer.rs").repeat(60);
+        let mut writer = StreamWriter::new(BlockSize::MIN);
+        let counts: Vec<usize> = BlockSplitter::new(&data, BlockSize::MIN)
+            .take(20)
+            .map(|block| alloc::count(|| writer.write_block(block).unwrap()).1)
+            .collect();
+        assert_eq!(counts.len(), 20);
+        for (block, &count) in counts.iter().enumerate().skip(1) {
+            assert!(count <= 8, "block {block} allocated {count} times");
+        }
+    }
+
+    #[test]
+    fn test_max_memory() {
+        let data = include_bytes!("writer.rs").repeat(40);
+        let limit = working_set(BlockSize::MIN.bytes());
+        let mut writer = StreamWriter::builder()
+            .block_size(BlockSize::MIN)
+            .max_memory(limit)
+            .build();
+        let mut blocks = BlockSplitter::new(&data, BlockSize::MIN);
+        writer.write_block(blocks.next().unwrap()).unwrap();
+        let reserved = writer.account.reserved();
+        assert!(reserved + huffman_working_set(BlockSize::MIN.bytes()) <= limit);
+
+        // the buffers reserved for the first block hold all the others, none of them grows
+        let capacities = |scratch: &mut ScratchSpace| {
+            (
+                scratch.rle.buffer_mut().capacity(),
+                scratch.bwt.buffer_mut().capacity(),
+                scratch.mtf.indices.capacity(),
+                scratch.sort.buffers_mut().map(|buffer| buffer.capacity()),
+            )
+        };
+        let before = capacities(&mut writer.scratch);
+        for block in blocks {
+            writer.write_block(block).unwrap();
+        }
+        assert_eq!(capacities(&mut writer.scratch), before);
+        assert_eq!(writer.account.reserved(), reserved);
+        assert_eq!(decompress(&writer.finish()), Ok(data.clone()));
+
+        let mut writer = StreamWriter::builder()
+            .block_size(BlockSize::MIN)
+            .max_memory(limit - 1)
+            .build();
+        let first = BlockSplitter::new(&data, BlockSize::MIN).next().unwrap();
+        assert!(matches!(
+            writer.write_block(first),
+            Err(WriteError::MemoryLimitExceeded { .. })
+        ));
+    }
+
+    #[test]
+    fn test_stats() {
+        let data = [vec![b'a'; 1000], include_bytes!("writer.rs").repeat(10)].concat();
+        let mut writer = StreamWriter::new(BlockSize::MIN);
+        writer.write_blocks(&data);
+        let stats = *writer.stats();
+        assert_eq!(stats.blocks, writer.index().len() as u64);
+        assert!(stats.blocks > 1);
+        assert_eq!(stats.uncompressed_len, data.len() as u64);
+        let rle1_len: usize = BlockSplitter::new(&data, BlockSize::MIN)
+            .map(|block| RleSequence::encode(block).len())
+            .sum();
+        assert_eq!(stats.rle1_len, rle1_len as u64);
+        assert!(stats.mtf_symbols < stats.rle1_len);
+        let bit_lens: u64 = writer
+            .index()
+            .entries()
+            .iter()
+            .map(|entry| entry.bit_len)
+            .sum();
+        assert_eq!(stats.compressed_bits, bit_lens);
+        assert!(stats.timings.get(StageKind::Bwt) > Duration::ZERO);
+
+        let mut appended = StreamWriter::new(BlockSize::MIN);
+        for block in BlockSplitter::new(&data, BlockSize::MIN) {
+            appended.append_block(&CompressedBlock::new(block, BlockSize::MIN).unwrap());
+        }
+        let appended = *appended.stats();
+        assert_eq!(
+            EncodeStats {
+                timings: Timings::default(),
+                ..appended
+            },
+            EncodeStats {
+                timings: Timings::default(),
+                ..stats
+            }
+        );
+    }
+
+    #[test]
+    fn test_compressed_block() {
+        let block = CompressedBlock::new(b"hello world\n", BlockSize::MIN).unwrap();
+        assert_eq!(block.crc(), 0x4eec_e836);
+        assert!(!block.bit_len().is_multiple_of(8));
+        assert_eq!(
+            CompressedBlock::new(b"", BlockSize::MIN).map(|block| block.bit_len()),
+            Ok(0)
+        );
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn test_write_blocks_parallel() {
+        let data = include_bytes!("writer.rs").repeat(30);
+        let mut sequential = StreamWriter::new(BlockSize::MIN);
+        sequential.write_blocks(&data);
+        let mut parallel = StreamWriter::new(BlockSize::MIN);
+        parallel.write_blocks_parallel(&data);
+        assert_eq!(parallel.finish(), sequential.finish());
+    }
+
+    /// Reading in batches cuts the same blocks as splitting all of the input, however the reads
+    /// cut it, and the stream comes out as the batches are done
+    #[cfg(feature = "parallel")]
+    #[test_case(1, 7; "a block at a time")]
+    #[test_case(3, 4096; "three blocks at a time")]
+    #[test_case(100, 4096; "all blocks at once")]
+    fn test_write_blocks_parallel_from(batch: usize, read_len: usize) {
+        let mut data = include_bytes!("writer.rs").repeat(12);
+        // a run across the end of a block
+        data.splice(99_000..99_000, [b'a'; 3000]);
+        let mut sequential = StreamWriter::new(BlockSize::MIN);
+        sequential.write_blocks(&data);
+
+        let mut parallel = StreamWriter::new(BlockSize::MIN);
+        let mut output = Vec::new();
+        let reader = std::io::BufReader::with_capacity(read_len, &data[..]);
+        let len = parallel
+            .write_blocks_parallel_from(reader, &mut output, batch)
+            .unwrap();
+        assert_eq!(len, data.len() as u64);
+        assert!(!output.is_empty());
+        assert_eq!(parallel.index(), sequential.index());
+        output.extend(parallel.finish());
+        assert_eq!(output, sequential.finish());
+    }
+
+    #[test_case(b"hello world\n".to_vec(); "hello world")]
+    #[test_case(vec![b'a'; 1]; "single byte")]
+    #[test_case(vec![b'a'; 10_000]; "one long run")]
+    #[test_case(b"ab".repeat(5000); "two alternating bytes")]
+    #[test_case((0..=255).collect(); "every byte once")]
+    #[test_case(include_bytes!("writer.rs").to_vec(); "source code")]
+    #[test_case((0..90_000u32).map(|i| (i.wrapping_mul(2_654_435_761) >> 24) as u8).collect(); "noise")]
+    fn test_reference_decompresses(data: Vec<u8>) {
+        let stream = compress(&data, 1);
+        match reference_decompress(&stream) {
+            Some(decompressed) => assert_eq!(decompressed, data),
+            None => eprintln!("bzip2 not found, skipping the interop check"),
+        }
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(32))]
+
+        /// The reference tool reads whatever the stages make of any kind of block
+        #[test]
+        fn prop_reference_decompresses(data in strategies::blocks(4096), level in strategies::levels()) {
+            let mut writer = StreamWriter::builder().level(level).build();
+            writer.write_blocks(&data);
+            if let Some(decompressed) = reference_decompress(&writer.finish()) {
+                prop_assert_eq!(decompressed, data);
+            }
+        }
+    }
+}
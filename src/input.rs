@@ -0,0 +1,139 @@
+use std::{
+    fs::File,
+    io::{self, Read, Seek, SeekFrom},
+    ops::Deref,
+    path::Path,
+};
+
+use crate::format::block_size::{sample_ranges, BlockSize};
+
+/// Contents of an input file, either read into memory or (with the `mmap` feature) mapped.
+///
+/// Both variants dereference to the file's bytes, so the stages can work on slices of it no
+/// matter how it was obtained.
+pub enum InputData {
+    Buffered(Vec<u8>),
+    #[cfg(feature = "mmap")]
+    Mapped(memmap2::Mmap),
+}
+
+impl InputData {
+    /// Read the whole file into memory.
+    pub fn read(path: &Path) -> io::Result<Self> {
+        let mut data = Vec::new();
+        File::open(path)?.read_to_end(&mut data)?;
+        Ok(Self::Buffered(data))
+    }
+
+    /// Map the file into memory instead of copying it into a buffer.
+    ///
+    /// Falls back to `read` for anything that can't be mapped: empty files (mapping zero bytes is
+    /// an error on some platforms), pipes and other non-regular files, and filesystems that don't
+    /// support mapping.
+    #[cfg(feature = "mmap")]
+    pub fn map(path: &Path) -> io::Result<Self> {
+        let file = File::open(path)?;
+        let metadata = file.metadata()?;
+        if !metadata.is_file() || metadata.len() == 0 {
+            return Self::read(path);
+        }
+        // SAFETY: the mapping is only ever read. If another process truncates or modifies the
+        // file while we hold the mapping, we may read garbage or get a SIGBUS, the same caveat
+        // every mmap-based tool has.
+        match unsafe { memmap2::Mmap::map(&file) } {
+            Ok(mmap) => Ok(Self::Mapped(mmap)),
+            Err(_) => Self::read(path),
+        }
+    }
+
+    pub fn is_mapped(&self) -> bool {
+        !matches!(self, Self::Buffered(_))
+    }
+}
+
+impl Deref for InputData {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            Self::Buffered(data) => data,
+            #[cfg(feature = "mmap")]
+            Self::Mapped(mmap) => mmap,
+        }
+    }
+}
+
+/// Pick the block size for the file at `path` like `BlockSize::auto` does for all of its
+/// contents, reading only the regions that samples instead of the whole file. Anything but a
+/// regular file can't be sampled without consuming it and gets the largest block size.
+pub fn auto_block_size(path: &Path) -> io::Result<BlockSize> {
+    let mut file = File::open(path)?;
+    let metadata = file.metadata()?;
+    if !metadata.is_file() {
+        return Ok(BlockSize::MAX);
+    }
+    let len = metadata.len() as usize;
+    let mut sample = Vec::new();
+    for range in sample_ranges(len) {
+        file.seek(SeekFrom::Start(range.start as u64))?;
+        (&mut file)
+            .take(range.len() as u64)
+            .read_to_end(&mut sample)?;
+    }
+    Ok(BlockSize::auto_for(len, &sample))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use test_case::test_case;
+
+    use super::*;
+
+    fn file_with(data: &[u8]) -> tempfile::NamedTempFile {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(data).unwrap();
+        file
+    }
+
+    #[test_case(b""; "empty")]
+    #[test_case(b"aaaaabcdefg"; "short")]
+    #[test_case(&[b'x'; 4097]; "not a multiple of the page size")]
+    fn test_read(data: &[u8]) {
+        let file = file_with(data);
+        let input = InputData::read(file.path()).unwrap();
+        assert_eq!(&*input, data);
+        assert!(!input.is_mapped());
+    }
+
+    #[test_case(Vec::new(); "empty")]
+    #[test_case(b"hello world\n".repeat(50_000); "several blocks of text")]
+    #[test_case((0..2_000_000u32).map(|i| (i.wrapping_mul(2_654_435_761) >> 24) as u8).collect(); "several blocks of noise")]
+    fn test_auto_block_size(data: Vec<u8>) {
+        let file = file_with(&data);
+        assert_eq!(
+            auto_block_size(file.path()).unwrap(),
+            BlockSize::auto(&data)
+        );
+    }
+
+    #[cfg(feature = "mmap")]
+    #[test_case(b"", false; "empty file is read")]
+    #[test_case(b"aaaaabcdefg", true; "short")]
+    #[test_case(&[b'x'; 4096], true; "exactly one page")]
+    #[test_case(&[b'x'; 4097], true; "not a multiple of the page size")]
+    fn test_map(data: &[u8], mapped: bool) {
+        let file = file_with(data);
+        let input = InputData::map(file.path()).unwrap();
+        assert_eq!(&*input, &*InputData::read(file.path()).unwrap());
+        assert_eq!(input.is_mapped(), mapped);
+    }
+
+    #[cfg(feature = "mmap")]
+    #[test]
+    fn test_map_missing_file() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(InputData::map(&dir.path().join("missing")).is_err());
+    }
+}
@@ -1,3 +1,405 @@
+//! bzip2 compression from scratch.
+//!
+//! Without the default `std` feature, the crate is `no_std` and only needs `alloc`: the stages
+//! (`bits`, `crc`, `rle`, `bwt`, `mtf`, `huff`, `symbol` and `fm_index`), decompression with
+//! `format::reader::decompress` and walking the blocks of a stream with `Blocks` are all there is.
+//! Compression, the I/O around the format and the `Error` that ties the errors of the modules
+//! together need `std`.
+
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
+
+extern crate alloc;
+
+pub mod bits;
+pub mod crc;
+#[cfg(feature = "std")]
+mod error;
+pub mod format;
+#[cfg(feature = "std")]
+pub mod input;
+#[cfg(feature = "std")]
+pub mod metadata;
 mod stages;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+
+pub use bwt::MemoryBudget;
+#[cfg(feature = "std")]
+pub use error::{CrcKind, Error, IoError, Result};
+#[cfg(feature = "async-io")]
+pub use format::async_io::{AsyncBzDecoder, AsyncBzEncoder};
+pub use format::block_iter::{BlockInfo, Blocks};
+pub use format::observer::StageObserver;
+#[cfg(feature = "std")]
+pub use format::{
+    block_size::{BlockSize, CompressionLevel},
+    decoder::{BzDecoder, StreamInfo},
+    encoder::BzEncoder,
+    estimate::{BlockEstimate, SizeEstimate},
+    index::BlockIndex,
+    memory::CompressOptions,
+    progress::{Progress, ProgressSink},
+    raw::RawBlock,
+    reader::{CrcWarning, DecompressOptions, Decompressed, ReadError, SkippedBlock},
+    seekable::SeekableBzDecoder,
+    stats::{BlockStats, Compressed, EncodeStats, StageKind, TableStats, Timings},
+    verify::{Mismatch, VerifyMode},
+};
+#[cfg(feature = "std")]
+pub use stages::pipeline::{self, analyze, BlockReport, PipelineReport};
+pub use stages::{bwt, fm_index, huff, mtf, rle, symbol};
+
+#[cfg(feature = "std")]
+use std::io::{self, Read, Write};
+#[cfg(feature = "mmap")]
+use std::path::Path;
+
+#[cfg(feature = "std")]
+use format::writer::StreamWriter;
+
+/// Compress `data` into a complete `.bz2` stream with the block size of `level`.
+///
+/// Inputs larger than one block are split into as many blocks as it takes, an empty input is a
+/// stream without any blocks.
+///
+/// The output is stable: the same `data` and `level` compress to the same bytes on every run and
+/// platform, and in every release up to one that announces a change of the output, so compressed
+/// data can be deduplicated by its hash. `BzEncoder` and `compress_parallel` write the same bytes.
+/// `tests/golden.rs` pins the hashes of the output for a few inputs and checks that compressing
+/// them a second time in the same process gives the same bytes.
+#[cfg(feature = "std")]
+pub fn compress(data: &[u8], level: CompressionLevel) -> Vec<u8> {
+    let mut writer = StreamWriter::builder().level(level).build();
+    writer.write_blocks(data);
+    writer.finish()
+}
+
+/// Compress everything `reader` has to offer into a `.bz2` stream on `writer` with blocks of
+/// `block_size`, returning the number of bytes read. Only one block of the input is held in
+/// memory at a time, see `BzEncoder`, so the memory needed doesn't grow with the input. The
+/// output is the same as that of `compress`.
+#[cfg(feature = "std")]
+pub fn compress_stream<R: Read, W: Write>(
+    mut reader: R,
+    writer: W,
+    block_size: BlockSize,
+) -> Result<u64> {
+    let level = CompressionLevel::try_from(block_size.level())?;
+    let mut encoder = BzEncoder::new(writer, level);
+    let len = io::copy(&mut reader, &mut encoder)?;
+    encoder.finish()?;
+    Ok(len)
+}
+
+/// Same as `compress`, but also returns the sizes and timings of the stages and a `BlockStats`
+/// for every block, with where its bits went, for finding out why an input compresses worse than
+/// expected. Accounting for the bits takes another pass over the symbols of every block.
+#[cfg(feature = "std")]
+pub fn compress_with_stats(data: &[u8], level: CompressionLevel) -> Compressed {
+    let mut writer = StreamWriter::builder()
+        .level(level)
+        .detailed_stats()
+        .build();
+    writer.write_blocks(data);
+    let stats = *writer.stats();
+    let blocks = writer.block_stats().to_vec();
+    Compressed {
+        data: writer.finish(),
+        stats,
+        blocks,
+    }
+}
+
+/// Same as `compress`, but within the memory `options` allow, see
+/// `format::memory::compress_with`. Fails with `MemoryLimitExceeded` if the blocks don't fit.
+#[cfg(feature = "std")]
+pub fn compress_with(
+    data: &[u8],
+    level: CompressionLevel,
+    options: CompressOptions,
+) -> Result<Vec<u8>> {
+    Ok(format::memory::compress_with(data, level, options)?)
+}
+
+/// Same as `compress`, but for the file at `path`, which is mapped into memory instead of read
+/// into a buffer, see `InputData::map`. The output is the same as that of `compress`.
+#[cfg(feature = "mmap")]
+pub fn compress_mmap(path: impl AsRef<Path>, level: CompressionLevel) -> Result<Vec<u8>> {
+    let data = input::InputData::map(path.as_ref())?;
+    Ok(compress(&data, level))
+}
+
+/// Same as `compress`, but compresses the blocks in parallel on the current rayon thread pool,
+/// the global one unless called inside `ThreadPool::install`. The output is the same as that of
+/// `compress`.
+#[cfg(feature = "parallel")]
+pub fn compress_parallel(data: &[u8], level: CompressionLevel) -> Vec<u8> {
+    let mut writer = StreamWriter::builder().level(level).build();
+    writer.write_blocks_parallel(data);
+    writer.finish()
+}
+
+/// Predict how large `compress` makes `data` with blocks of `block_size`, without compressing it,
+/// see `format::estimate::estimate_compressed_size`.
+#[cfg(feature = "std")]
+pub fn estimate_compressed_size(data: &[u8], block_size: BlockSize) -> Result<SizeEstimate> {
+    Ok(format::estimate::estimate_compressed_size(
+        data, block_size,
+    )?)
+}
+
+/// Decompress one or more `.bz2` streams back to back, see `format::reader::decompress`.
+#[cfg(feature = "std")]
+pub fn decompress(data: &[u8]) -> Result<Vec<u8>> {
+    Ok(format::reader::decompress(data)?)
+}
+
+/// Same as `decompress`, but getting as much out of damaged data as `options` allow, see
+/// `format::reader::decompress_with`.
+#[cfg(feature = "std")]
+pub fn decompress_with(data: &[u8], options: DecompressOptions) -> Result<Decompressed> {
+    Ok(format::reader::decompress_with(data, options)?)
+}
+
+/// Same as `decompress`, but decodes the blocks in parallel on the current rayon thread pool, see
+/// `format::parallel::decompress_parallel`.
+#[cfg(feature = "parallel")]
+pub fn decompress_parallel(data: &[u8]) -> Result<Vec<u8>> {
+    Ok(format::parallel::decompress_parallel(data)?)
+}
+
+/// Same as `decompress_parallel`, but on a thread pool of `threads` threads, one per core for
+/// zero, see `format::parallel::decompress_parallel_with_threads`.
+#[cfg(feature = "parallel")]
+pub fn decompress_parallel_with_threads(data: &[u8], threads: usize) -> Result<Vec<u8>> {
+    format::parallel::decompress_parallel_with_threads(data, threads)
+}
+
+/// Compress `data` into a single block without the framing of a `.bz2` stream, see `RawBlock`.
+/// `data` has to fit the largest block size after the initial run-length encoding, which
+/// anything up to 720 000 bytes does.
+#[cfg(feature = "std")]
+pub fn compress_block(data: &[u8]) -> Result<RawBlock> {
+    Ok(RawBlock::compress(data)?)
+}
+
+/// Decompress a block from `compress_block`, checking its CRC
+#[cfg(feature = "std")]
+pub fn decompress_block(block: &RawBlock) -> Result<Vec<u8>> {
+    Ok(block.decompress()?)
+}
+
+/// Decode everything `reader` has to offer, one or more `.bz2` streams back to back, checking
+/// every block and stream CRC along the way, but without keeping any of the decoded data. Returns
+/// what was read, e.g. to report it like `bzip2 -t`.
+#[cfg(feature = "std")]
+pub fn verify<R: Read>(reader: R) -> Result<StreamInfo> {
+    let mut decoder = BzDecoder::new(reader);
+    io::copy(&mut decoder, &mut io::sink())?;
+    Ok(decoder.info())
+}
+
+/// Same as `verify`, but also compare what `compressed` decompresses to with `original` as `mode`
+/// says, see `format::verify::verify_against`. Fails with `Error::Mismatch` if they differ.
+#[cfg(feature = "std")]
+pub fn verify_against<C: Read, O: Read>(
+    compressed: C,
+    original: O,
+    mode: VerifyMode,
+) -> Result<StreamInfo> {
+    format::verify::verify_against(compressed, original, mode)
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use std::iter;
+
+    use proptest::prelude::*;
+    use test_case::test_case;
+
+    use super::*;
+    use crate::stages::{counting_alloc as alloc, strategies};
+
+    /// 8 MB of text with runs of up to a few hundred bytes, whatever the length of this file
+    fn large_input() -> Vec<u8> {
+        let text = include_bytes!("lib.rs");
+        let mut data = Vec::new();
+        for run in (0..400).cycle() {
+            if data.len() >= 8_000_000 {
+                break;
+            }
+            data.extend_from_slice(text);
+            data.extend(iter::repeat_n(b'x', run));
+        }
+        data
+    }
+
+    #[test_case(b"", CompressionLevel::BEST; "empty")]
+    #[test_case(b"hello world\n", CompressionLevel::FASTEST; "hello world")]
+    #[test_case(&[b'a'; 600_000], CompressionLevel::FASTEST; "one long run")]
+    #[test_case(&include_bytes!("lib.rs").repeat(300), CompressionLevel::Two; "several blocks")]
+    fn test_roundtrip(data: &[u8], level: CompressionLevel) {
+        let compressed = compress(data, level);
+        assert_eq!(compressed[3], b'0' + level.value());
+        assert_eq!(decompress(&compressed), Ok(data.to_vec()));
+    }
+
+    #[test]
+    fn test_compress_stream() {
+        let data = large_input();
+        let mut compressed = Vec::new();
+        let len = compress_stream(data.as_slice(), &mut compressed, BlockSize::MIN).unwrap();
+        assert_eq!(len, data.len() as u64);
+        assert_eq!(compressed, compress(&data, CompressionLevel::FASTEST));
+    }
+
+    /// The memory the streaming path needs depends on the block size, not on the length of the
+    /// input, which the in-memory path holds all of plus all of the output
+    #[test]
+    fn test_compress_stream_peak_memory() {
+        let data = large_input();
+        let block_size = BlockSize::MIN;
+        let peak = |data: &[u8]| {
+            let (len, peak) = alloc::peak_bytes(|| compress_stream(data, io::sink(), block_size));
+            assert_eq!(len, Ok(data.len() as u64));
+            peak
+        };
+        let two_blocks = peak(&data[..2 * block_size.bytes()]);
+        let streamed = peak(&data);
+        assert!(
+            streamed < 2 * two_blocks,
+            "{streamed} bytes for all of the input, {two_blocks} for two blocks"
+        );
+        assert!(streamed < data.len(), "{streamed} bytes");
+        // read all of it first, like a file read into memory
+        let (_, in_memory) = alloc::peak_bytes(|| {
+            let mut buffer = Vec::new();
+            data.as_slice().read_to_end(&mut buffer).unwrap();
+            compress(&buffer, CompressionLevel::FASTEST)
+        });
+        assert!(in_memory > data.len(), "{in_memory} bytes");
+    }
+
+    /// Reading a batch of blocks at a time bounds the parallel path the same way. The allocations
+    /// are counted per thread, so the blocks are compressed on a pool of a single thread.
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn test_write_blocks_parallel_from_peak_memory() {
+        let data = large_input();
+        let block_size = BlockSize::MIN;
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(1)
+            .build()
+            .unwrap();
+        let peak = |data: &[u8]| {
+            let mut writer = StreamWriter::new(block_size);
+            let (len, peak) = pool.install(|| {
+                alloc::peak_bytes(|| writer.write_blocks_parallel_from(data, io::sink(), 2))
+            });
+            assert_eq!(len.unwrap(), data.len() as u64);
+            peak
+        };
+        let two_batches = peak(&data[..4 * block_size.bytes()]);
+        let streamed = peak(&data);
+        assert!(
+            streamed < 2 * two_batches,
+            "{streamed} bytes for all of the input, {two_batches} for two batches"
+        );
+        assert!(streamed < data.len(), "{streamed} bytes");
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test_case(b"", 1; "empty")]
+    #[test_case(&include_bytes!("lib.rs").repeat(300), 1; "several blocks on one thread")]
+    #[test_case(&include_bytes!("lib.rs").repeat(300), 4; "several blocks on four threads")]
+    fn test_compress_parallel(data: &[u8], threads: usize) {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build()
+            .unwrap();
+        let compressed = pool.install(|| compress_parallel(data, CompressionLevel::Two));
+        assert_eq!(compressed, compress(data, CompressionLevel::Two));
+    }
+
+    #[cfg(feature = "mmap")]
+    #[test_case(b""; "empty")]
+    #[test_case(b"hello world\n"; "hello world")]
+    #[test_case(&include_bytes!("lib.rs").repeat(300); "several blocks")]
+    fn test_compress_mmap(data: &[u8]) {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        io::Write::write_all(&mut file, data).unwrap();
+        let compressed = compress_mmap(file.path(), CompressionLevel::Two).unwrap();
+        assert_eq!(compressed, compress(data, CompressionLevel::Two));
+    }
+
+    #[cfg(feature = "mmap")]
+    #[test]
+    fn test_compress_mmap_missing_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let err = compress_mmap(dir.path().join("missing"), CompressionLevel::BEST).unwrap_err();
+        assert!(matches!(err, Error::Io(err) if err.kind() == io::ErrorKind::NotFound));
+    }
+
+    #[test]
+    fn test_verify() {
+        let data = include_bytes!("lib.rs").repeat(300);
+        let mut compressed = compress(&data, CompressionLevel::Two);
+        compressed.extend(compress(b"hello world\n", CompressionLevel::BEST));
+        let info = verify(compressed.as_slice()).unwrap();
+        assert_eq!(info.streams, 2);
+        assert!(info.blocks > 2);
+        assert_eq!(info.compressed_len, compressed.len() as u64);
+        assert_eq!(info.uncompressed_len, data.len() as u64 + 12);
+    }
+
+    #[test]
+    fn test_verify_errors() {
+        let mut compressed = compress(b"hello world\n", CompressionLevel::BEST);
+        assert!(matches!(
+            verify(&compressed[..20]),
+            Err(Error::UnexpectedEof { .. })
+        ));
+        compressed[10] ^= 0x01;
+        assert!(matches!(
+            verify(compressed.as_slice()),
+            Err(Error::CrcMismatch {
+                kind: CrcKind::Block,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_decompress_rejects_garbage() {
+        assert!(decompress(b"not a bzip2 stream").is_err());
+    }
+
+    proptest! {
+        #[test]
+        fn prop_roundtrip(data in strategies::bytes(4096)) {
+            prop_assert_eq!(decompress(&compress(&data, CompressionLevel::FASTEST)), Ok(data));
+        }
+
+        /// All stages and the container, read back both with `decompress` and with the streaming
+        /// `BzDecoder`
+        #[test]
+        fn prop_full_pipeline_roundtrip(data in strategies::blocks(16 * 1024), level in strategies::levels()) {
+            let compressed = compress(&data, level);
+            prop_assert_eq!(decompress(&compressed), Ok(data.clone()));
+            let mut decoded = Vec::new();
+            BzDecoder::new(compressed.as_slice()).read_to_end(&mut decoded).unwrap();
+            prop_assert_eq!(decoded, data);
+        }
+    }
+
+    proptest! {
+        #![proptest_config(strategies::large_input_config())]
 
-pub use stages::{bwt, huff, mtf, rle};
+        #[test]
+        fn prop_roundtrip_several_blocks(data in prop::collection::vec(any::<u8>(), 200_001..=300_000)) {
+            let compressed = compress(&data, CompressionLevel::FASTEST);
+            prop_assert!(verify(compressed.as_slice()).unwrap().blocks >= 3);
+            prop_assert_eq!(decompress(&compressed), Ok(data));
+        }
+    }
+}
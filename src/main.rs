@@ -1,54 +1,1872 @@
 #[warn(dead_code)]
-use std::path::PathBuf;
+use std::{
+    ffi::OsString,
+    fmt,
+    fs::{self, File},
+    io::{self, BufRead, BufReader, BufWriter, Read, Write},
+    iter,
+    path::{Path, PathBuf},
+    process::ExitCode,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
 
-use anyhow::Result;
-use clap::Parser;
+use anyhow::{bail, Context, Result};
+use clap::{ArgAction, Args as ClapArgs, Parser, Subcommand, ValueEnum};
+use indicatif::{ProgressBar, ProgressStyle};
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
+use walkdir::{DirEntry, WalkDir};
 
-use bwt::BwtEncoded;
-use rle::RleSequence;
-
-use bzippr::{bwt, mtf::MtfTransform, rle};
+#[cfg(feature = "json")]
+use bzippr::format::stats::{BlockStats, EncodeStats};
+use bzippr::{
+    bits::BitReader,
+    format::{
+        block_iter::Footer,
+        block_size::{BlockSize, CompressionLevel},
+        header::{HeaderError, STREAM_MAGIC},
+        reader::ReadError,
+        scan::{find_magic, BLOCK_MAGIC, END_OF_STREAM_MAGIC},
+    },
+    input::{self, InputData},
+    metadata::{copy_metadata, create_output},
+    mtf::MtfIndex,
+    symbol::Symbol,
+    BlockIndex, BlockInfo, Blocks, BzDecoder, BzEncoder, DecompressOptions, MemoryBudget, Progress,
+    StageKind, StageObserver, Timings, VerifyMode,
+};
 
 #[derive(Parser, Debug)]
 #[command(
     version,
     about,
-    long_about = "Will bzip2 your file and shut up about it."
+    long_about = "Will bzip2 your file and shut up about it.",
+    args_conflicts_with_subcommands = true
 )]
 struct Args {
-    /// Path of input file to compress
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// Files to compress, or to decompress with `--decompress`, each into its own output file.
+    /// Failing files are reported and skipped, the others are still processed
+    files: Vec<PathBuf>,
+    /// Compress every regular file under the directories among the files, each into a `.bz2`
+    /// next to it, or decompress or test every `.bz2` file under them. Symbolic links and hidden
+    /// files are skipped unless `--follow-symlinks` and `--hidden` are given
     #[arg(short, long)]
-    file_path: PathBuf,
-    /// Path of compressed output file
+    recursive: bool,
+    /// Follow symbolic links to files and directories while walking a directory with
+    /// `--recursive`
+    #[arg(long, requires = "recursive")]
+    follow_symlinks: bool,
+    /// Also walk into hidden directories and take hidden files, whose names start with `.`,
+    /// with `--recursive`
+    #[arg(long, requires = "recursive")]
+    hidden: bool,
+    /// Only take the files under a directory whose names match `GLOB`, e.g. `--include '*.log'`,
+    /// with `--recursive`. Given several times, a file has to match one of them. Files named on
+    /// the command line are always taken
+    #[arg(long, value_name = "GLOB", requires = "recursive")]
+    include: Vec<glob::Pattern>,
+    /// Path of input file to compress, or to decompress with `--decompress`. Without it or any
+    /// files, or with `-`, the input is read from stdin and written to stdout
     #[arg(short, long)]
+    file_path: Option<PathBuf>,
+    /// Path of the output file, by default the input path with `.bz2` added, or with `.bz2`
+    /// removed when decompressing. Only for a single input
+    #[arg(short, long, conflicts_with = "stdout")]
     output_path: Option<PathBuf>,
+    /// Write the output to stdout instead of a file
+    #[arg(short = 'c', long)]
+    stdout: bool,
+    /// Decompress a `.bz2` file instead of compressing
+    #[arg(short, long)]
+    decompress: bool,
+    /// Get as much out of a damaged `.bz2` file as possible: keep the bytes of a block that
+    /// doesn't match its CRC and skip one that can't be decoded to the next block, with a
+    /// warning, rather than failing. The output is kept, and so is the input, with the exit
+    /// status for corrupt data
+    #[arg(long, requires = "decompress")]
+    force_decode: bool,
+    /// Check the integrity of a `.bz2` file: decompress it, check every CRC and report what it
+    /// holds, without writing any output
+    #[arg(short, long, conflicts_with_all = ["decompress", "output_path", "stdout"])]
+    test: bool,
+    /// Also write an index of the compressed blocks to the output path with `.idx` added, for
+    /// reading the output from any position with `SeekableBzDecoder`
+    #[arg(long, conflicts_with_all = ["decompress", "test", "stdout"])]
+    index: bool,
+    /// Read the compressed output back from disk and decompress it before reporting success or
+    /// deleting the input: `crc` compares the CRC of what it decompresses to with the one of the
+    /// input, `full` compares every byte. If it doesn't match, the output is removed, the input
+    /// kept, and the exit status is the one for corrupt data
+    #[arg(
+        long,
+        value_enum,
+        value_name = "MODE",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "crc",
+        conflicts_with_all = ["decompress", "test", "stdout"]
+    )]
+    verify: Option<Verify>,
+    /// Keep the input file. Without it, the input is deleted once its output file is written
+    /// and checked, like the reference tool does
+    #[arg(short, long)]
+    keep: bool,
+    /// Overwrite existing output files, and process input files that have other hard links,
+    /// which are refused without it
+    #[arg(long)]
+    force: bool,
+    /// Report the compression ratio of every file like `bzip2 -v`, twice to also report its
+    /// blocks and how long it took
+    #[arg(short, long, action = ArgAction::Count)]
+    verbose: u8,
+    /// Report nothing but errors. Without it or `--verbose`, a short summary of every file is
+    /// reported, unless the output goes to stdout
+    #[arg(short, long, conflicts_with = "verbose")]
+    quiet: bool,
+    /// Show a progress bar on stderr while every input is handled, or a spinner for stdin. The
+    /// blocks are then handled one after the other, on a single thread
+    #[arg(long)]
+    progress: bool,
+    /// Print the sizes and timings of the stages of every compressed file to stdout, as one JSON
+    /// document per line. With `-vv`, also where the bits of every block went: the header, symbol
+    /// map, selector and code length overhead, and the symbols, entropy and coded bits of every
+    /// Huffman table
+    #[cfg(feature = "json")]
+    #[arg(long, conflicts_with_all = ["decompress", "test", "stdout"])]
+    stats_json: bool,
+    /// Print a table of how long every stage of every block took to stderr when compressing:
+    /// RLE1, BWT, MTF, building the Huffman tables, emitting the bits and writing them out, with
+    /// a totals row, the throughput of every stage and the wall time. The blocks are then
+    /// compressed one after the other, on a single thread
+    #[arg(long, conflicts_with_all = ["decompress", "test", "list"])]
+    time: bool,
+    /// Use less memory when decompressing and testing: a slower inverse BWT, 2.5 instead of 8
+    /// bytes per byte of a block. Compressing takes the same memory with or without it
+    #[arg(short, long)]
+    small: bool,
+    /// List every `.bz2` file like `gzip -l`: its compressed and uncompressed size, the ratio,
+    /// the block size digit, its blocks and the CRC of its stream, plus a totals row for several
+    /// files. The blocks are decoded and checked to count the bytes they hold, which are
+    /// discarded. With `--fast`, they are only walked, and the uncompressed size is unknown
+    #[arg(
+        short,
+        long,
+        conflicts_with_all = ["decompress", "test", "output_path", "stdout", "index", "verify"]
+    )]
+    list: bool,
+    /// Write what every stage made of every block of a single input to files in this directory,
+    /// created if needed: `block0003.rle1`, `.bwt` with `.origin`, `.mtf` as 16-bit big-endian
+    /// symbols with the initial `.stack`, and `.huff` with the code lengths, selectors and coded
+    /// bits as text. Compressing and decompressing the same data dumps the same files, and the
+    /// blocks are then handled one after the other, on a single thread
+    #[arg(long, value_name = "DIR", conflicts_with = "force_decode")]
+    dump_stages: Option<PathBuf>,
+    /// Compress the blocks of an input on this many threads, by default one per core, reading as
+    /// many blocks at a time as there are threads. Decompressing only spreads the blocks over the
+    /// threads when the input is in memory as a whole, with `--mmap` or as its own
+    /// `--output-path`, and `--small` isn't given, and otherwise reads it a block at a time on a
+    /// single thread. With
+    /// `--threads`, several input files are also handled at once, unless the output goes to
+    /// stdout or `--progress` is given
+    #[cfg(feature = "parallel")]
+    #[arg(short = 'T', long, value_name = "N")]
+    threads: Option<usize>,
+    /// Memory-map the input file instead of reading it a block at a time, which lets `--threads`
+    /// decompress its blocks in parallel
+    #[cfg(feature = "mmap")]
+    #[arg(long)]
+    mmap: bool,
+    #[command(flatten)]
+    level: LevelFlags,
 }
 
-fn main() -> Result<()> {
-    let args = Args::parse();
-    let data = std::fs::read(&args.file_path)?;
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Salvage the intact blocks of a damaged `.bz2` file, like `bzip2recover`. Every intact
+    /// block is written as a stream of its own to `rec0001_<file>.bz2`, `rec0002_<file>.bz2` and
+    /// so on, next to the file. Existing files are refused, the other blocks are still written
+    Recover {
+        /// Overwrite existing `recNNNN_` files
+        #[arg(long)]
+        force: bool,
+        /// Path of the damaged file
+        file: PathBuf,
+    },
+    /// Print what the headers of a `.bz2` file declare: the block size of every stream, the CRC,
+    /// origin pointer, bytes in use, Huffman tables and selectors of every block, and the
+    /// combined CRC of its footer. Damaged blocks are flagged and skipped to the next block magic
+    Inspect(InspectArgs),
+}
 
-    let rle_enc = &RleSequence::encode(&data);
-    println!("Length of RLE sequence: {}", rle_enc.len());
+#[derive(ClapArgs, Debug)]
+struct InspectArgs {
+    /// Path of the `.bz2` file
+    file: PathBuf,
+    /// Print the same as one JSON document
+    #[cfg(feature = "json")]
+    #[arg(long)]
+    json: bool,
+}
 
-    let bwt_enc = BwtEncoded::encode(rle_enc);
-    println!("Length of BWT transform: {}", bwt_enc.len());
+/// How `--verify` compares the output with its input
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Verify {
+    Crc,
+    Full,
+}
 
-    let mtf_enc: MtfTransform = MtfTransform::encode(&bwt_enc.data());
-    println!("Length of MTF transform: {}", mtf_enc.len());
+/// How much is reported on stderr, errors are always reported
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Verbosity {
+    Quiet,
+    /// One line for every file and every file written
+    Normal,
+    /// The statistics of `bzip2 -v` for every file
+    Verbose,
+    /// Also the blocks of every file and how long it took
+    VeryVerbose,
+}
 
-    println!(
-        "Compression ratio: {:.2}%",
-        100.0 - (100 * mtf_enc.len()) as f64 / data.len() as f64
+impl Args {
+    /// Verbosity for an input whose output goes to stdout or not, since the reports would end up
+    /// mixed with the output on a terminal
+    fn verbosity(&self, to_stdout: bool) -> Verbosity {
+        match (self.quiet, self.verbose) {
+            (true, _) => Verbosity::Quiet,
+            (false, 0) if to_stdout => Verbosity::Quiet,
+            (false, 0) => Verbosity::Normal,
+            (false, 1) => Verbosity::Verbose,
+            (false, _) => Verbosity::VeryVerbose,
+        }
+    }
+}
+
+/// `-1` to `-9` as the reference tool takes them, `-9` without any of them, or `--block-size auto`
+#[derive(ClapArgs, Debug)]
+#[group(multiple = false)]
+struct LevelFlags {
+    /// Compress with 100k blocks, -2 to -8 pick the sizes up to -9. With `--list`, only walk the
+    /// blocks
+    #[arg(short = '1', long = "fast")]
+    one: bool,
+    /// Compress with 200k blocks
+    #[arg(short = '2', hide = true)]
+    two: bool,
+    /// Compress with 300k blocks
+    #[arg(short = '3', hide = true)]
+    three: bool,
+    /// Compress with 400k blocks
+    #[arg(short = '4', hide = true)]
+    four: bool,
+    /// Compress with 500k blocks
+    #[arg(short = '5', hide = true)]
+    five: bool,
+    /// Compress with 600k blocks
+    #[arg(short = '6', hide = true)]
+    six: bool,
+    /// Compress with 700k blocks
+    #[arg(short = '7', hide = true)]
+    seven: bool,
+    /// Compress with 800k blocks
+    #[arg(short = '8', hide = true)]
+    eight: bool,
+    /// Compress with 900k blocks
+    #[arg(short = '9', long = "best")]
+    nine: bool,
+    /// Pick the block size from the input: the smallest that holds a small file, 100k blocks for
+    /// input that looks incompressible and 900k blocks otherwise, also for stdin
+    #[arg(long, value_enum, value_name = "SIZE")]
+    block_size: Option<BlockSizeChoice>,
+}
+
+/// Block sizes `--block-size` takes besides the ones of `-1` to `-9`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum BlockSizeChoice {
+    /// Picked from the input, see `BlockSize::auto`
+    Auto,
+}
+
+impl LevelFlags {
+    fn level(&self) -> Option<CompressionLevel> {
+        let flags = [
+            self.one, self.two, self.three, self.four, self.five, self.six, self.seven, self.eight,
+            self.nine,
+        ];
+        let level = flags.iter().position(|&set| set)?;
+        Some(CompressionLevel::ALL[level])
+    }
+}
+
+/// Exit status for problems with the environment: missing files, bad arguments, I/O errors, and
+/// input in a format other than bzip2. The codes are the reference tool's.
+const EXIT_ENVIRONMENT: u8 = 1;
+/// Exit status for a corrupt compressed input
+const EXIT_CORRUPT: u8 = 2;
+
+fn main() -> ExitCode {
+    let args = match Args::try_parse() {
+        Ok(args) => args,
+        Err(err) => {
+            // `--help` and `--version` end up here too
+            let _ = err.print();
+            return if err.use_stderr() {
+                ExitCode::from(EXIT_ENVIRONMENT)
+            } else {
+                ExitCode::SUCCESS
+            };
+        }
+    };
+    match run(args) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("Error: {err:?}");
+            ExitCode::from(exit_code(&err))
+        }
+    }
+}
+
+fn run(args: Args) -> Result<()> {
+    match &args.command {
+        Some(Command::Recover { force, file }) => {
+            return recover(file, *force, args.verbosity(false))
+        }
+        Some(Command::Inspect(inspect_args)) => return inspect(inspect_args),
+        None => {}
+    }
+    let mut inputs: Vec<PathBuf> = args.file_path.iter().chain(&args.files).cloned().collect();
+    // what the walk couldn't get at counts as a failed file, after the others are handled
+    let mut walk_failed = 0;
+    if args.recursive {
+        (inputs, walk_failed) = walk(&inputs, &args);
+    }
+    if (inputs.len() > 1 || args.recursive) && args.output_path.is_some() {
+        bail!("--output-path only works with a single input file");
+    }
+    if (inputs.len() > 1 || args.recursive) && args.dump_stages.is_some() {
+        bail!("--dump-stages only works with a single input file");
+    }
+    if args.list {
+        let mut failed = list(&inputs, args.level.one);
+        failed.extend(iter::repeat_n(EXIT_ENVIRONMENT, walk_failed));
+        return files_failed(failed, inputs.len().max(1) + walk_failed);
+    }
+
+    let runner = Runner {
+        #[cfg(feature = "parallel")]
+        pool: rayon::ThreadPoolBuilder::new()
+            .num_threads(args.threads.unwrap_or(0))
+            .build()?,
+        args,
+    };
+    // a directory without any files to handle doesn't mean stdin
+    if inputs.len() <= 1 && !runner.args.recursive {
+        return runner.run(inputs.first().map(PathBuf::as_path)).map(|_| ());
+    }
+    let (mut failed, total) = runner.run_all(&inputs);
+    if runner.args.verbosity(runner.args.stdout) > Verbosity::Quiet {
+        eprintln!(
+            "{}",
+            total.summary(runner.args.decompress || runner.args.test)
+        );
+    }
+    failed.extend(iter::repeat_n(EXIT_ENVIRONMENT, walk_failed));
+    files_failed(failed, inputs.len() + walk_failed)
+}
+
+/// `FilesFailed` if any of `total` files failed, with the exit statuses in `failed`
+fn files_failed(failed: Vec<u8>, total: usize) -> Result<()> {
+    if failed.is_empty() {
+        return Ok(());
+    }
+    Err(FilesFailed {
+        failed: failed.len(),
+        total,
+        exit_code: failed.into_iter().max().unwrap_or(EXIT_ENVIRONMENT),
+    }
+    .into())
+}
+
+/// What one or more inputs took in and gave out, for the summary after several files
+#[derive(Debug, Clone, Copy, Default)]
+struct Transferred {
+    files: u64,
+    bytes_in: u64,
+    bytes_out: u64,
+}
+
+impl Transferred {
+    fn new(bytes_in: u64, bytes_out: u64) -> Self {
+        Self {
+            files: 1,
+            bytes_in,
+            bytes_out,
+        }
+    }
+
+    /// One line of the files, the bytes in and out and the ratio of the compressed bytes to the
+    /// uncompressed ones, which are the ones in when `decompressing`
+    fn summary(&self, decompressing: bool) -> String {
+        let (compressed, uncompressed) = if decompressing {
+            (self.bytes_in, self.bytes_out)
+        } else {
+            (self.bytes_out, self.bytes_in)
+        };
+        let ratio = match uncompressed {
+            0 => "-".to_string(),
+            _ => format!(
+                "{:.2}%",
+                100.0 - 100.0 * compressed as f64 / uncompressed as f64
+            ),
+        };
+        format!(
+            "{} files, {} bytes in, {} bytes out, ratio {ratio}",
+            self.files, self.bytes_in, self.bytes_out
+        )
+    }
+}
+
+impl std::ops::AddAssign for Transferred {
+    fn add_assign(&mut self, other: Self) {
+        self.files += other.files;
+        self.bytes_in += other.bytes_in;
+        self.bytes_out += other.bytes_out;
+    }
+}
+
+/// Some of several inputs failed, the worst of them decides the exit status
+#[derive(Debug, thiserror::Error)]
+#[error("{failed} of {total} files failed")]
+struct FilesFailed {
+    failed: usize,
+    total: usize,
+    exit_code: u8,
+}
+
+/// The exit status for `err`: `EXIT_CORRUPT` if a compressed input turned out to be corrupt,
+/// `EXIT_ENVIRONMENT` for anything else, including input that isn't bzip2 at all
+fn exit_code(err: &anyhow::Error) -> u8 {
+    for cause in err.chain() {
+        if let Some(failed) = cause.downcast_ref::<FilesFailed>() {
+            return failed.exit_code;
+        }
+        if cause.is::<Damaged>() {
+            return EXIT_CORRUPT;
+        }
+        // the decoder's errors come wrapped in an `io::Error`, whose `source` skips them
+        let cause = match cause.downcast_ref::<io::Error>() {
+            Some(err) => match err.get_ref() {
+                Some(inner) => inner,
+                None => continue,
+            },
+            None => cause,
+        };
+        if let Some(err) = cause.downcast_ref::<bzippr::Error>() {
+            return match err {
+                bzippr::Error::Io(_) => EXIT_ENVIRONMENT,
+                bzippr::Error::InvalidHeader(err) => header_exit_code(err),
+                _ => EXIT_CORRUPT,
+            };
+        }
+        if let Some(err) = cause.downcast_ref::<ReadError>() {
+            return match err {
+                ReadError::Header(err) => header_exit_code(err),
+                _ => EXIT_CORRUPT,
+            };
+        }
+    }
+    EXIT_ENVIRONMENT
+}
+
+fn header_exit_code(err: &HeaderError) -> u8 {
+    match err {
+        HeaderError::UnsupportedFormat { .. } => EXIT_ENVIRONMENT,
+        _ => EXIT_CORRUPT,
+    }
+}
+
+/// Replace every directory among `inputs` with the files under it that `--recursive` handles,
+/// sorted by name: every regular file when compressing, except for ones that already look
+/// compressed, and only those when decompressing or testing. What the walk can't get at, like a
+/// symbolic link loop or a directory that can't be read, is reported and skipped, and returned as
+/// the number of failures next to the files.
+fn walk(inputs: &[PathBuf], args: &Args) -> (Vec<PathBuf>, usize) {
+    let compressed = |entry: &DirEntry| {
+        let name = entry.file_name().to_string_lossy();
+        COMPRESSED_SUFFIXES
+            .iter()
+            .any(|(suffix, _)| name.ends_with(suffix))
+    };
+    let included = |entry: &DirEntry| {
+        let name = entry.file_name().to_string_lossy();
+        args.include.is_empty() || args.include.iter().any(|glob| glob.matches(&name))
+    };
+    let mut files = Vec::new();
+    let mut failed = 0;
+    for input in inputs {
+        if !input.is_dir() {
+            files.push(input.clone());
+            continue;
+        }
+        let entries = WalkDir::new(input)
+            .follow_links(args.follow_symlinks)
+            .sort_by_file_name()
+            .into_iter()
+            // the directory given is walked even if it is hidden itself, like `.`
+            .filter_entry(|entry| {
+                args.hidden
+                    || entry.depth() == 0
+                    || !entry.file_name().to_string_lossy().starts_with('.')
+            });
+        for entry in entries {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(err) => {
+                    eprintln!("Can't walk {}: {err}", input.display());
+                    failed += 1;
+                    continue;
+                }
+            };
+            // without following links, a link is neither a file nor a directory
+            if entry.file_type().is_file()
+                && compressed(&entry) == (args.decompress || args.test || args.list)
+                && included(&entry)
+            {
+                files.push(entry.into_path());
+            }
+        }
+    }
+    (files, failed)
+}
+
+/// Compresses, decompresses or tests one input after the other with the same arguments
+struct Runner {
+    args: Args,
+    #[cfg(feature = "parallel")]
+    pool: rayon::ThreadPool,
+}
+
+impl Runner {
+    /// Handle every input file, returning the exit status of each one that failed and the sum
+    /// of what the others took in and gave out. Like the reference tool, one bad file doesn't
+    /// stop the others.
+    fn run_all(&self, inputs: &[PathBuf]) -> (Vec<u8>, Transferred) {
+        let run = |path: &PathBuf| {
+            self.run(Some(path)).map_err(|err| {
+                eprintln!("{}: {err:#}", path.display());
+                exit_code(&err)
+            })
+        };
+        // the outputs of several files can't share stdout, and neither can their progress bars
+        // share stderr
+        #[cfg(feature = "parallel")]
+        let results: Vec<Result<Transferred, u8>> =
+            if self.args.threads.is_some() && !self.args.stdout && !self.args.progress {
+                self.pool.install(|| inputs.par_iter().map(run).collect())
+            } else {
+                inputs.iter().map(run).collect()
+            };
+        #[cfg(not(feature = "parallel"))]
+        let results: Vec<Result<Transferred, u8>> = inputs.iter().map(run).collect();
+        let mut failed = Vec::new();
+        let mut total = Transferred::default();
+        for result in results {
+            match result {
+                Ok(transferred) => total += transferred,
+                Err(code) => failed.push(code),
+            }
+        }
+        (failed, total)
+    }
+
+    /// Handle the input file at `input_path`, or stdin for `None` or `-`, returning how many
+    /// bytes it took in and gave out
+    fn run(&self, input_path: Option<&Path>) -> Result<Transferred> {
+        let args = &self.args;
+        let started = Instant::now();
+        let input_path = input_path.filter(|path| *path != Path::new("-"));
+
+        if let Some(path) = input_path.filter(|path| path.is_dir()) {
+            bail!("{} is a directory, --recursive walks it", path.display());
+        }
+        // a file named as its own `--output-path` is truncated when the output is created
+        let same_file = match (input_path, &args.output_path) {
+            (Some(input), Some(output)) => fs::canonicalize(input)
+                .ok()
+                .is_some_and(|input| fs::canonicalize(output).ok() == Some(input)),
+            _ => false,
+        };
+        // a file is streamed like stdin unless it is mapped or overwritten by its own output
+        let data = match input_path {
+            #[cfg(feature = "mmap")]
+            Some(path) if args.mmap => Some(InputData::map(path)?),
+            Some(path) if same_file => Some(InputData::read(path)?),
+            _ => None,
+        };
+        // `Send`, so the threads that compress the blocks can be handed the reader
+        let mut input: Box<dyn BufRead + Send> = match (&data, input_path) {
+            (Some(data), _) => Box::new(&data[..]),
+            (None, Some(path)) => Box::new(BufReader::new(File::open(path)?)),
+            (None, None) => Box::new(BufReader::new(io::stdin())),
+        };
+        let input_len = match (&data, input_path) {
+            (Some(data), _) => Some(data.len() as u64),
+            (None, Some(path)) => Some(fs::metadata(path)?.len()),
+            (None, None) => None,
+        };
+        // `--block-size auto` only reads a sample of a streamed file, stdin gets the largest blocks
+        let level = match (args.level.level(), &data, input_path) {
+            (Some(level), _, _) => level,
+            (None, _, _) if args.level.block_size != Some(BlockSizeChoice::Auto) => {
+                CompressionLevel::BEST
+            }
+            (None, Some(data), _) => CompressionLevel::try_from(BlockSize::auto(data).level())?,
+            (None, None, Some(path)) => {
+                CompressionLevel::try_from(input::auto_block_size(path)?.level())?
+            }
+            (None, None, None) => CompressionLevel::BEST,
+        };
+        let name = input_path.unwrap_or(Path::new("stdin")).display();
+
+        let budget = if args.small {
+            MemoryBudget::SMALL
+        } else {
+            MemoryBudget::UNLIMITED
+        };
+        let bar = self.progress_bar(input_len);
+        let dump = args
+            .dump_stages
+            .as_deref()
+            .map(StageDump::new)
+            .transpose()?;
+        if args.test {
+            let mut decoder =
+                BzDecoder::with_memory_budget(input, budget).with_progress(progress_sink(&bar));
+            if let Some(dump) = &dump {
+                decoder = decoder.with_observer(dump.clone());
+            }
+            let result = io::copy(&mut decoder, &mut io::sink());
+            bar.finish_and_clear();
+            result.with_context(|| format!("{name} is damaged"))?;
+            if let Some(dump) = &dump {
+                dump.finish()?;
+            }
+            let info = decoder.info();
+            let verbosity = args.verbosity(false);
+            if verbosity >= Verbosity::Normal {
+                eprintln!(
+                    "{name}: ok, {} streams, {} blocks, {} bytes compressed, {} bytes uncompressed",
+                    info.streams, info.blocks, info.compressed_len, info.uncompressed_len
+                );
+            }
+            if verbosity >= Verbosity::VeryVerbose {
+                eprintln!("    tested in {}", seconds(started.elapsed()));
+            }
+            return Ok(Transferred::new(info.compressed_len, info.uncompressed_len));
+        }
+
+        let output_path = match (&input_path, args.stdout) {
+            (_, true) | (None, _) => None,
+            (Some(path), false) => Some(args.output_path.clone().unwrap_or_else(|| {
+                if args.decompress {
+                    decompressed_path(path.to_path_buf())
+                } else {
+                    compressed_path(path.to_path_buf())
+                }
+            })),
+        };
+        let verbosity = args.verbosity(output_path.is_none());
+        if args.index && output_path.is_none() {
+            bail!("--index needs an output file");
+        }
+        if args.verify.is_some() && output_path.is_none() {
+            bail!("--verify needs an output file");
+        }
+        #[cfg(feature = "json")]
+        if args.stats_json && output_path.is_none() {
+            bail!("--stats-json needs an output file");
+        }
+        if let (Some(input), Some(_), false) = (input_path, &output_path, args.force) {
+            check_hard_links(input)?;
+        }
+        // existing outputs are refused as they are created, see `PartialOutputs::create`
+        let mut outputs = PartialOutputs::default();
+        // `Send` like the input, stdout isn't shared with other files, so it needn't be locked
+        let output: Box<dyn Write + Send> = match &output_path {
+            Some(path) => Box::new(outputs.create(path, args.force)?),
+            None => Box::new(io::stdout()),
+        };
+        // created with the output, so an existing index fails before anything is compressed
+        let index_file = match (&output_path, args.index) {
+            (Some(path), true) => Some(outputs.create(&index_path(path), args.force)?),
+            _ => None,
+        };
+        let mut output = CountingWriter::new(BufWriter::new(output));
+
+        // what `--force-decode` found wrong with the input, once its output is written
+        let mut damaged = None;
+        // a compressed output is checked against the length of the input before the input goes
+        let expected_uncompressed_len = if args.decompress {
+            let len = match &data {
+                _ if args.force_decode => {
+                    let mut buffered = Vec::new();
+                    let data = match &data {
+                        Some(data) => &data[..],
+                        None => {
+                            input.read_to_end(&mut buffered)?;
+                            &buffered[..]
+                        }
+                    };
+                    let options = DecompressOptions {
+                        ignore_crc_errors: true,
+                        resync: true,
+                        small: args.small,
+                    };
+                    let decompressed = bzippr::decompress_with(data, options)
+                        .with_context(|| format!("Can't decompress {name}"))?;
+                    for warning in &decompressed.crc_warnings {
+                        eprintln!(
+                            "{name}: CRC mismatch in block {}: stored {:#010x}, computed \
+                             {:#010x}, keeping its bytes anyway",
+                            warning.block, warning.stored, warning.computed
+                        );
+                    }
+                    for skipped in &decompressed.skipped {
+                        eprintln!(
+                            "{name}: skipped bits {}..{}, losing their bytes: {}",
+                            skipped.from_bit, skipped.to_bit, skipped.reason
+                        );
+                    }
+                    if !decompressed.is_complete() {
+                        damaged = Some(Damaged::Blocks {
+                            damaged: decompressed.crc_warnings.len() + decompressed.skipped.len(),
+                            blocks: decompressed.blocks,
+                        });
+                    }
+                    output.write_all(&decompressed.data)?;
+                    decompressed.data.len() as u64
+                }
+                // the blocks decoded at once take the fast inverse BWT, which `--small` rules out
+                #[cfg(feature = "parallel")]
+                Some(data) if !args.small && !args.progress && dump.is_none() => {
+                    let decompressed = self
+                        .pool
+                        .install(|| bzippr::decompress_parallel(data))
+                        .with_context(|| format!("Can't decompress {name}"))?;
+                    output.write_all(&decompressed)?;
+                    decompressed.len() as u64
+                }
+                _ => {
+                    let mut decoder = BzDecoder::with_memory_budget(input, budget)
+                        .with_progress(progress_sink(&bar));
+                    if let Some(dump) = &dump {
+                        decoder = decoder.with_observer(dump.clone());
+                    }
+                    let result = io::copy(&mut decoder, &mut output);
+                    bar.finish_and_clear();
+                    result.with_context(|| format!("Can't decompress {name}"))?
+                }
+            };
+            output.flush()?;
+            match verbosity {
+                Verbosity::Quiet => {}
+                Verbosity::Normal => eprintln!("Decompressed {name} into {len} bytes"),
+                Verbosity::Verbose | Verbosity::VeryVerbose => eprintln!("  {name}: done"),
+            }
+            if verbosity >= Verbosity::VeryVerbose {
+                eprintln!(
+                    "    {len} bytes out, decompressed in {}",
+                    seconds(started.elapsed())
+                );
+            }
+            None
+        } else {
+            // where the bits of every block went, for `--stats-json -vv`
+            #[cfg(feature = "json")]
+            let detailed_stats = args.stats_json && verbosity >= Verbosity::VeryVerbose;
+            #[cfg(not(feature = "json"))]
+            let detailed_stats = false;
+            #[cfg_attr(not(feature = "json"), allow(unused_variables))]
+            let (len, index, stats, block_stats, block_timings) = match &data {
+                #[cfg(feature = "parallel")]
+                _ if !args.progress && dump.is_none() && !detailed_stats && !args.time => {
+                    let mut writer = bzippr::format::writer::StreamWriter::builder()
+                        .level(level)
+                        .build();
+                    // a block for every thread at a time
+                    let batch = self.pool.current_num_threads();
+                    let len = self.pool.install(|| {
+                        writer.write_blocks_parallel_from(&mut input, &mut output, batch)
+                    })?;
+                    let index = writer.index().clone();
+                    let stats = *writer.stats();
+                    output.write_all(&writer.finish())?;
+                    output.flush()?;
+                    (len, index, stats, Vec::new(), Vec::new())
+                }
+                _ => {
+                    let mut encoder =
+                        BzEncoder::new(&mut output, level).with_progress(progress_sink(&bar));
+                    if let Some(dump) = &dump {
+                        encoder = encoder.with_observer(dump.clone());
+                    }
+                    if detailed_stats {
+                        encoder = encoder.with_detailed_stats();
+                    }
+                    if args.time {
+                        encoder = encoder.with_block_timings();
+                    }
+                    let len = io::copy(&mut input, &mut encoder)?;
+                    // compresses the last block, so the stats include it
+                    encoder.flush()?;
+                    let stats = *encoder.stats();
+                    let block_stats = encoder.block_stats().to_vec();
+                    let block_timings = encoder.block_timings().to_vec();
+                    let (inner, index) = encoder.finish_with_index()?;
+                    inner.flush()?;
+                    bar.finish_and_clear();
+                    (len, index, stats, block_stats, block_timings)
+                }
+            };
+            if let (Some(file), Some(path)) = (index_file, &output_path) {
+                write_index(&index, file, path, verbosity)?;
+            }
+            let block_size = level.block_size().bytes();
+            match verbosity {
+                Verbosity::Quiet => {}
+                Verbosity::Normal => eprintln!(
+                    "Compressed {len} bytes of {name} into {} bytes with blocks of {block_size} \
+                     bytes, ratio {:.2}%",
+                    output.written,
+                    100.0 - 100.0 * output.written as f64 / len.max(1) as f64
+                ),
+                Verbosity::Verbose | Verbosity::VeryVerbose => {
+                    eprintln!("  {name}: {}", ratio_stats(len, output.written))
+                }
+            }
+            if verbosity >= Verbosity::VeryVerbose {
+                let stages: Vec<String> = StageKind::ALL
+                    .iter()
+                    .map(|&kind| format!("{} {}", kind.name(), seconds(stats.timings.get(kind))))
+                    .collect();
+                eprintln!(
+                    "    {} blocks of up to {block_size} bytes, compressed in {} ({})",
+                    stats.blocks,
+                    seconds(started.elapsed()),
+                    stages.join(", ")
+                );
+            }
+            if args.time {
+                print_timings(&name, &index, &block_timings, started.elapsed());
+            }
+            #[cfg(feature = "json")]
+            if args.stats_json {
+                let file = name.to_string();
+                let report = StatsReport::new(
+                    &file,
+                    level,
+                    output.written,
+                    &stats,
+                    block_stats,
+                    started.elapsed(),
+                );
+                println!("{}", serde_json::to_string(&report)?);
+            }
+            Some(len)
+        };
+        let written = output.written;
+        drop(output);
+        if let Some(dump) = &dump {
+            dump.finish()?;
+        }
+        if let Some(path) = output_path {
+            // the outputs were only accessible to their owner while they were written
+            if let Some(input) = input_path {
+                let index = args.index.then(|| index_path(&path));
+                for output in iter::once(&path).chain(&index) {
+                    copy_metadata(input, output).with_context(|| {
+                        format!(
+                            "Can't give {} the metadata of {}",
+                            output.display(),
+                            input.display()
+                        )
+                    })?;
+                }
+            }
+            // with `--output-path` naming the input itself, the input is already gone, and the
+            // output of a damaged input is all there is to get out of it, which stays around
+            let delete_input =
+                input_path.filter(|_| !(args.keep || same_file || damaged.is_some()));
+            if let (Some(mode), Some(input)) = (args.verify, input_path) {
+                // a file that was its own output is only left in memory
+                let original: Box<dyn Read> = match &data {
+                    Some(data) => Box::new(&data[..]),
+                    None => Box::new(BufReader::new(File::open(input)?)),
+                };
+                verify_output(&path, original, mode)
+                    .with_context(|| format!("Verifying {} failed", path.display()))?;
+            }
+            if let Some(input) = delete_input {
+                check_output(&path, written, expected_uncompressed_len)
+                    .with_context(|| format!("Not deleting {}", input.display()))?;
+            }
+            outputs.keep();
+            if verbosity >= Verbosity::Normal {
+                eprintln!("Wrote {}", path.display());
+            }
+            if let Some(input) = delete_input {
+                fs::remove_file(input)
+                    .with_context(|| format!("Can't delete {}", input.display()))?;
+            }
+        }
+
+        // the compressed input of stdin isn't counted, but then there is only the one input
+        let bytes_in = expected_uncompressed_len.unwrap_or(input_len.unwrap_or(0));
+        match damaged {
+            Some(damaged) => Err(damaged.into()),
+            None => Ok(Transferred::new(bytes_in, written)),
+        }
+    }
+
+    /// A progress bar over the `len` bytes of an input file, or a spinner for stdin, hidden
+    /// without `--progress`
+    fn progress_bar(&self, len: Option<u64>) -> ProgressBar {
+        if !self.args.progress {
+            return ProgressBar::hidden();
+        }
+        match len {
+            Some(len) => ProgressBar::new(len).with_style(
+                ProgressStyle::with_template(
+                    "{bar:40} {bytes}/{total_bytes} ({bytes_per_sec}, {eta}) {msg}",
+                )
+                .expect("valid template"),
+            ),
+            None => ProgressBar::new_spinner().with_style(
+                ProgressStyle::with_template("{spinner} {bytes} ({bytes_per_sec}) {msg}")
+                    .expect("valid template"),
+            ),
+        }
+    }
+}
+
+/// Moves `bar` along with the input an encoder or decoder has taken in
+fn progress_sink(bar: &ProgressBar) -> impl FnMut(Progress) + Send + 'static {
+    let bar = bar.clone();
+    move |progress: Progress| {
+        bar.set_position(progress.bytes_in);
+        bar.set_message(format!("{} blocks", progress.blocks_done));
+    }
+}
+
+/// Make sure the output file at `path` holds all `written` bytes and, if it is compressed, that
+/// it decompresses to `uncompressed_len` bytes with every CRC intact, so the input it was made
+/// from can be deleted
+fn check_output(path: &Path, written: u64, uncompressed_len: Option<u64>) -> Result<()> {
+    let len = fs::metadata(path)?.len();
+    if len != written {
+        bail!(
+            "{} holds {len} of the {written} bytes written to it",
+            path.display()
+        );
+    }
+    if let Some(expected) = uncompressed_len {
+        let info = bzippr::verify(BufReader::new(File::open(path)?))
+            .with_context(|| format!("{} is damaged", path.display()))?;
+        if info.uncompressed_len != expected {
+            bail!(
+                "{} decompresses to {} bytes instead of {expected}",
+                path.display(),
+                info.uncompressed_len
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Decompress the output file at `path`, read back from disk rather than from any buffer, and
+/// compare it with the `original` it was made from as `mode` says
+fn verify_output(path: &Path, original: impl Read, mode: Verify) -> Result<()> {
+    let mode = match mode {
+        Verify::Crc => VerifyMode::Crc,
+        Verify::Full => VerifyMode::Full,
+    };
+    bzippr::verify_against(BufReader::new(File::open(path)?), original, mode)?;
+    Ok(())
+}
+
+/// Refuse what the reference tool refuses without `--force`: processing an `input` with other
+/// hard links, which deleting it wouldn't remove. Overwriting an existing output is refused by
+/// `PartialOutputs::create`.
+fn check_hard_links(input: &Path) -> Result<()> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+
+        let links = fs::metadata(input)?.nlink();
+        if links > 1 {
+            bail!(
+                "{} has other hard links ({links} in total), use --force to process it anyway",
+                input.display()
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Path of the index of the compressed file at `path`, with `.idx` added to its name
+fn index_path(path: &Path) -> PathBuf {
+    let mut index_path = path.as_os_str().to_os_string();
+    index_path.push(".idx");
+    index_path.into()
+}
+
+/// What `--stats-json` prints for a compressed file
+#[cfg(feature = "json")]
+#[derive(serde::Serialize)]
+struct StatsReport<'a> {
+    file: &'a str,
+    level: u8,
+    block_size: usize,
+    original_size: u64,
+    compressed_size: u64,
+    blocks: u64,
+    /// Bytes after the initial run-length encoding
+    rle1_size: u64,
+    /// Symbols after MTF and RLE2
+    mtf_symbols: u64,
+    /// Seconds spent in every stage, summed over all blocks
+    seconds: StageSeconds,
+    /// Seconds from reading the input to writing the output
+    wall_clock_seconds: f64,
+    /// Where the bits of every block went, with `-vv`
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    block_stats: Vec<BlockStats>,
+}
+
+#[cfg(feature = "json")]
+#[derive(serde::Serialize)]
+struct StageSeconds {
+    rle1: f64,
+    bwt: f64,
+    mtf: f64,
+    /// Picking the Huffman tables and selectors
+    huffman: f64,
+    /// Writing the coded block
+    emission: f64,
+    /// Handing the compressed bytes on
+    io: f64,
+    total: f64,
+}
+
+#[cfg(feature = "json")]
+impl<'a> StatsReport<'a> {
+    fn new(
+        file: &'a str,
+        level: CompressionLevel,
+        compressed_size: u64,
+        stats: &EncodeStats,
+        block_stats: Vec<BlockStats>,
+        wall_clock: Duration,
+    ) -> Self {
+        let timings = &stats.timings;
+        let seconds = |kind| timings.get(kind).as_secs_f64();
+        Self {
+            file,
+            level: level.value(),
+            block_size: level.block_size().bytes(),
+            original_size: stats.uncompressed_len,
+            compressed_size,
+            blocks: stats.blocks,
+            rle1_size: stats.rle1_len,
+            mtf_symbols: stats.mtf_symbols,
+            seconds: StageSeconds {
+                rle1: seconds(StageKind::Rle1),
+                bwt: seconds(StageKind::Bwt),
+                mtf: seconds(StageKind::Mtf),
+                huffman: seconds(StageKind::HuffmanBuild),
+                emission: seconds(StageKind::BitEmission),
+                io: seconds(StageKind::Io),
+                total: timings.total().as_secs_f64(),
+            },
+            wall_clock_seconds: wall_clock.as_secs_f64(),
+            block_stats,
+        }
+    }
+}
+
+/// The statistics `bzip2 -v` reports for an input of `uncompressed` bytes that compressed to
+/// `compressed` bytes
+fn ratio_stats(uncompressed: u64, compressed: u64) -> String {
+    if uncompressed == 0 {
+        return "no data compressed.".to_string();
+    }
+    let (uncompressed_f, compressed_f) = (uncompressed as f64, compressed as f64);
+    format!(
+        "{:6.3}:1, {:6.3} bits/byte, {:5.2}% saved, {uncompressed} in, {compressed} out.",
+        uncompressed_f / compressed_f,
+        8.0 * compressed_f / uncompressed_f,
+        100.0 * (1.0 - compressed_f / uncompressed_f)
+    )
+}
+
+fn seconds(duration: Duration) -> String {
+    format!("{:.3}s", duration.as_secs_f64())
+}
+
+/// `bytes` per `duration` in MB/s, "-" for no time at all
+fn throughput(bytes: u64, duration: Duration) -> String {
+    if duration.is_zero() {
+        "-".to_string()
+    } else {
+        format!("{:.1}", bytes as f64 / 1e6 / duration.as_secs_f64())
+    }
+}
+
+/// The `--time` table of a compressed file: a row for every block with the time of every
+/// `StageKind`, the sum over the blocks, the throughput of every stage over all blocks, and the
+/// wall time of the whole file
+fn print_timings(
+    name: impl fmt::Display,
+    index: &BlockIndex,
+    block_timings: &[Timings],
+    wall: Duration,
+) {
+    let mut header = format!("{:>8}", "block");
+    for kind in StageKind::ALL {
+        header += &format!(" {:>9}", kind.name());
+    }
+    eprintln!("{name}:");
+    eprintln!("{header} {:>9} {:>9}", "total", "MB/s");
+    let row = |label: &str, timings: &Timings, bytes: u64| {
+        let mut row = format!("{label:>8}");
+        for kind in StageKind::ALL {
+            row += &format!(" {:>9}", seconds(timings.get(kind)));
+        }
+        eprintln!(
+            "{row} {:>9} {:>9}",
+            seconds(timings.total()),
+            throughput(bytes, timings.total())
+        );
+    };
+    let mut totals = Timings::default();
+    for (i, (timings, entry)) in block_timings.iter().zip(index.entries()).enumerate() {
+        row(&i.to_string(), timings, entry.uncompressed_len);
+        totals += *timings;
+    }
+    let bytes = index.uncompressed_len();
+    row("total", &totals, bytes);
+    let mut rates = format!("{:>8}", "MB/s");
+    for kind in StageKind::ALL {
+        rates += &format!(" {:>9}", throughput(bytes, totals.get(kind)));
+    }
+    eprintln!("{rates} {:>9}", throughput(bytes, totals.total()));
+    eprintln!(
+        "wall time {}, {} MB/s",
+        seconds(wall),
+        throughput(bytes, wall)
     );
+}
+
+/// Write `index` to `file`, the index next to the compressed file at `path`, see `index_path`
+fn write_index(index: &BlockIndex, file: File, path: &Path, verbosity: Verbosity) -> Result<()> {
+    let index_path = index_path(path);
+    let mut file = BufWriter::new(file);
+    index.write_to(&mut file)?;
+    file.flush()?;
+    if verbosity >= Verbosity::Normal {
+        eprintln!(
+            "Wrote an index of {} blocks to {}",
+            index.len(),
+            index_path.display()
+        );
+    }
+    Ok(())
+}
 
-    let decompressed_data = BwtEncoded::new(mtf_enc.decode(), bwt_enc.original_index())
-        .decode()
-        .decode();
+fn recover(path: &Path, force: bool, verbosity: Verbosity) -> Result<()> {
+    let data = InputData::read(path)?;
+    let mut name = path
+        .file_name()
+        .with_context(|| format!("{} is not a file", path.display()))?
+        .to_os_string();
+    if !name.to_string_lossy().ends_with(".bz2") {
+        name.push(".bz2");
+    }
 
-    assert_eq!(data, decompressed_data);
+    let mut recovered = 0;
+    let mut failed = Vec::new();
+    for block in bzippr::format::recover::recover_blocks(&data) {
+        match block {
+            Ok(block) => {
+                // a block keeps its number whether or not its file is written, so a second
+                // attempt names it the same
+                recovered += 1;
+                let mut file_name = OsString::from(format!("rec{recovered:04}_"));
+                file_name.push(&name);
+                let out_path = path.with_file_name(file_name);
+                let mut outputs = PartialOutputs::default();
+                let written = outputs.create(&out_path, force).and_then(|mut file| {
+                    file.write_all(&block.stream)
+                        .with_context(|| format!("Can't write {}", out_path.display()))
+                });
+                if let Err(err) = written {
+                    eprintln!("{}: {err:#}", path.display());
+                    failed.push(exit_code(&err));
+                    continue;
+                }
+                outputs.keep();
+                if verbosity >= Verbosity::Normal {
+                    eprintln!(
+                        "Block at bit {} ({} bits, CRC {:#010x}) written to {}",
+                        block.offset,
+                        block.bit_len,
+                        block.crc,
+                        out_path.display()
+                    );
+                }
+            }
+            Err(damaged) if verbosity >= Verbosity::Normal => eprintln!("{damaged}"),
+            Err(_) => {}
+        }
+    }
+    if recovered == 0 {
+        bail!("No intact blocks found in {}", path.display());
+    }
+    if verbosity >= Verbosity::Normal {
+        eprintln!("Recovered {} blocks", recovered - failed.len());
+    }
+    files_failed(failed, recovered)
+}
 
-    println!("Success!");
+/// Print what `bzippr inspect` found in the file of `args`, failing if any of it is damaged
+fn inspect(args: &InspectArgs) -> Result<()> {
+    let data = InputData::read(&args.file)?;
+    let inspection = Inspection::new(&data)?;
+    #[cfg(feature = "json")]
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&inspection)?);
+    } else {
+        print!("{inspection}");
+    }
+    #[cfg(not(feature = "json"))]
+    print!("{inspection}");
 
+    let blocks = inspection.streams.iter().flat_map(|stream| &stream.blocks);
+    let damaged = blocks.clone().filter(|block| block.error.is_some()).count();
+    if damaged > 0 {
+        let blocks = blocks.count();
+        return Err(Damaged::Blocks { damaged, blocks }.into());
+    }
+    if let Some(offset) = inspection.trailing_garbage {
+        return Err(Damaged::TrailingGarbage(offset).into());
+    }
     Ok(())
 }
+
+/// Print a row of what `--list` finds in every input, or stdin without any, and a totals row for
+/// several. Every row is printed, whether the inputs before it failed or not, `fast` skips
+/// decoding the blocks. Returns the exit status of every input that failed.
+fn list(inputs: &[PathBuf], fast: bool) -> Vec<u8> {
+    println!(
+        "{:>12} {:>12} {:>7} {:>2} {:>6} {:>8} name",
+        "compressed", "uncompressed", "ratio", "bs", "blocks", "crc"
+    );
+    let stdin = [PathBuf::from("-")];
+    let inputs = if inputs.is_empty() {
+        &stdin[..]
+    } else {
+        inputs
+    };
+    let mut totals = Listing {
+        uncompressed_len: Some(0),
+        ..Listing::default()
+    };
+    let mut failed = Vec::new();
+    for path in inputs {
+        let name = match path.to_str() {
+            Some("-") => "stdin".into(),
+            _ => path.display().to_string(),
+        };
+        let listing = Listing::read(path, fast);
+        if let Some(err) = &listing.error {
+            eprintln!("{name}: {err:#}");
+            failed.push(exit_code(err));
+        }
+        println!("{}", listing.row(&name));
+        totals.add(&listing);
+    }
+    if inputs.len() > 1 {
+        println!("{}", totals.row("(totals)"));
+    }
+    failed
+}
+
+/// What `--list` finds in a `.bz2` file, as far as it gets
+#[derive(Default)]
+struct Listing {
+    compressed_len: u64,
+    /// `None` if the blocks weren't decoded, or not all of them
+    uncompressed_len: Option<u64>,
+    /// The block size digit of the first stream, 0 before it is read
+    block_size_digit: u8,
+    blocks: usize,
+    /// The CRC of every stream
+    crcs: Vec<u32>,
+    /// Why the file couldn't be listed completely
+    error: Option<anyhow::Error>,
+}
+
+impl Listing {
+    /// List the file at `path`, or stdin for `-`, as far as it gets
+    fn read(path: &Path, fast: bool) -> Self {
+        let mut listing = Self::default();
+        let data = if path == Path::new("-") {
+            let mut data = Vec::new();
+            io::stdin()
+                .lock()
+                .read_to_end(&mut data)
+                .map(|_| InputData::Buffered(data))
+        } else {
+            InputData::read(path)
+        };
+        let result = data.map_err(anyhow::Error::from).and_then(|data| {
+            listing.compressed_len = data.len() as u64;
+            listing.walk(&data, fast)
+        });
+        if let Err(err) = result {
+            listing.uncompressed_len = None;
+            listing.error = Some(err);
+        }
+        listing
+    }
+
+    /// Walk every stream of `data` with `Blocks`, decoding every block unless `fast`
+    fn walk(&mut self, data: &[u8], fast: bool) -> Result<()> {
+        let mut reader = BitReader::new(data);
+        let mut uncompressed_len = 0;
+        while reader.remaining() > 0 || self.crcs.is_empty() {
+            let offset = (reader.position() / 8) as usize;
+            if offset > 0 && !data[offset..].starts_with(STREAM_MAGIC) {
+                return Err(ReadError::TrailingGarbage { offset }.into());
+            }
+            let mut blocks = Blocks::new(&mut reader)?;
+            if self.crcs.is_empty() {
+                self.block_size_digit = blocks.block_size().level();
+            }
+            for block in blocks.by_ref() {
+                let block = block?;
+                if !fast {
+                    uncompressed_len += block.decode()?.len() as u64;
+                }
+                self.blocks += 1;
+            }
+            let footer = blocks
+                .footer()
+                .expect("The blocks end at the footer unless one of them fails");
+            if footer.crc != footer.blocks_crc {
+                return Err(ReadError::StreamCrcMismatch {
+                    stored: footer.crc,
+                    computed: footer.blocks_crc,
+                }
+                .into());
+            }
+            self.crcs.push(footer.crc);
+        }
+        self.uncompressed_len = (!fast).then_some(uncompressed_len);
+        Ok(())
+    }
+
+    /// Add the sizes and blocks of `other` to the totals in `self`, whose uncompressed size is
+    /// unknown once one of them is
+    fn add(&mut self, other: &Self) {
+        self.compressed_len += other.compressed_len;
+        self.uncompressed_len = self
+            .uncompressed_len
+            .zip(other.uncompressed_len)
+            .map(|(total, len)| total + len);
+        self.blocks += other.blocks;
+    }
+
+    fn row(&self, name: &str) -> String {
+        let (uncompressed, ratio) = match self.uncompressed_len {
+            _ if self.error.is_some() => ("error".to_string(), "-".to_string()),
+            // nothing to compress, so no ratio
+            Some(0) => ("0".to_string(), "-".to_string()),
+            Some(len) => (
+                len.to_string(),
+                format!(
+                    "{:.1}%",
+                    100.0 - 100.0 * self.compressed_len as f64 / len as f64
+                ),
+            ),
+            None => ("unknown".to_string(), "-".to_string()),
+        };
+        let digit = match self.block_size_digit {
+            0 => "-".to_string(),
+            digit => digit.to_string(),
+        };
+        let crc = match self.crcs[..] {
+            [] => "-".to_string(),
+            [crc] => format!("{crc:08x}"),
+            _ => "multiple".to_string(),
+        };
+        format!(
+            "{:>12} {uncompressed:>12} {ratio:>7} {digit:>2} {:>6} {crc:>8} {name}",
+            self.compressed_len, self.blocks
+        )
+    }
+}
+
+/// What `bzippr inspect` found wrong with a file
+#[derive(Debug, thiserror::Error)]
+enum Damaged {
+    #[error("{damaged} of {blocks} blocks are damaged")]
+    Blocks { damaged: usize, blocks: usize },
+    #[error("Trailing data after the last stream at byte {0}")]
+    TrailingGarbage(usize),
+}
+
+/// What the headers of the streams and blocks of a `.bz2` file declare
+#[cfg_attr(feature = "json", derive(serde::Serialize))]
+struct Inspection {
+    streams: Vec<InspectedStream>,
+    /// Byte offset of data after the last stream that doesn't start another one
+    trailing_garbage: Option<usize>,
+}
+
+#[cfg_attr(feature = "json", derive(serde::Serialize))]
+struct InspectedStream {
+    byte_offset: usize,
+    /// The digit of the header, the block size in units of 100 000 bytes
+    block_size_digit: u8,
+    blocks: Vec<InspectedBlock>,
+    /// `None` if the stream ends without one
+    footer: Option<InspectedFooter>,
+}
+
+#[cfg_attr(feature = "json", derive(serde::Serialize))]
+struct InspectedBlock {
+    /// Index of the block in its stream
+    index: usize,
+    bit_offset: u64,
+    /// What is wrong with the block, `None` if it decodes and matches its CRC
+    error: Option<String>,
+    /// What the block declares, `None` for a block too damaged to read it
+    details: Option<BlockDetails>,
+}
+
+#[cfg_attr(feature = "json", derive(serde::Serialize))]
+struct BlockDetails {
+    bit_len: u64,
+    crc: u32,
+    randomized: bool,
+    orig_ptr: usize,
+    /// Number of different bytes in the block
+    used_bytes: usize,
+    /// The bytes in use as ranges of consecutive values, first and last included
+    used_ranges: Vec<(u8, u8)>,
+    tables: usize,
+    selectors: usize,
+    /// How many selectors pick every table
+    selector_histogram: Vec<usize>,
+    /// Shortest and longest code of every table
+    code_length_ranges: Vec<(u8, u8)>,
+    /// Bits of Huffman coded symbols, up to and including the end of block
+    coded_bits: u64,
+}
+
+#[cfg_attr(feature = "json", derive(serde::Serialize))]
+struct InspectedFooter {
+    bit_offset: u64,
+    crc: u32,
+    /// The combined CRC of the CRCs the blocks declare, since the last damaged one
+    blocks_crc: u32,
+}
+
+impl Inspection {
+    /// Walk the streams of `data` with `Blocks`, going on at the next block or end of stream
+    /// magic after a damaged block. Fails only if the first stream has no valid header.
+    fn new(data: &[u8]) -> Result<Self> {
+        let magics: Vec<u64> = [BLOCK_MAGIC, END_OF_STREAM_MAGIC]
+            .into_iter()
+            .flat_map(|magic| find_magic(data, magic))
+            .collect();
+        let mut streams = Vec::new();
+        let mut offset = 0;
+        while offset < data.len() {
+            if offset > 0 && !data[offset..].starts_with(STREAM_MAGIC) {
+                return Ok(Self {
+                    streams,
+                    trailing_garbage: Some(offset),
+                });
+            }
+            let (stream, end) = InspectedStream::new(data, offset, &magics)?;
+            streams.push(stream);
+            match end {
+                Some(end) => offset = end,
+                None => break,
+            }
+        }
+        Ok(Self {
+            streams,
+            trailing_garbage: None,
+        })
+    }
+}
+
+impl InspectedStream {
+    /// Walk the stream at byte `offset` of `data`, returning it with the byte offset of its end,
+    /// `None` if no footer can be found after a damaged block. `magics` are the bit offsets of
+    /// every block and end of stream magic in `data`.
+    fn new(data: &[u8], offset: usize, magics: &[u64]) -> Result<(Self, Option<usize>)> {
+        let mut position = offset as u64 * 8;
+        let mut block_size = None;
+        let mut index = 0;
+        let mut blocks = Vec::new();
+        loop {
+            let mut reader = BitReader::new(data);
+            reader.skip(position)?;
+            let mut walk = match block_size {
+                Some(block_size) => Blocks::resume(&mut reader, block_size, index),
+                None => Blocks::new(&mut reader)
+                    .with_context(|| format!("No valid stream header at byte {offset}"))?,
+            };
+            block_size = Some(walk.block_size());
+            let mut next_block = position + if index == 0 { 32 } else { 0 };
+            let mut damage = None;
+            for block in walk.by_ref() {
+                match block {
+                    Ok(block) => {
+                        next_block = block.bit_offset + block.bit_len;
+                        index = block.index + 1;
+                        blocks.push(InspectedBlock::new(&block));
+                    }
+                    Err(err) => damage = Some(err),
+                }
+            }
+            let footer = walk.footer();
+            let stream = |blocks, footer: Option<Footer>| Self {
+                byte_offset: offset,
+                block_size_digit: block_size.map_or(0, BlockSize::level),
+                blocks,
+                footer: footer.map(|footer| InspectedFooter {
+                    bit_offset: footer.bit_offset,
+                    crc: footer.crc,
+                    blocks_crc: footer.blocks_crc,
+                }),
+            };
+            if footer.is_some() {
+                let end = reader.position().div_ceil(8) as usize;
+                return Ok((stream(blocks, footer), Some(end)));
+            }
+            blocks.push(InspectedBlock {
+                index,
+                bit_offset: next_block,
+                error: damage.map(|err| err.to_string()),
+                details: None,
+            });
+            index += 1;
+            match magics.iter().filter(|&&magic| magic > next_block).min() {
+                Some(&magic) => position = magic,
+                None => return Ok((stream(blocks, None), None)),
+            }
+        }
+    }
+}
+
+impl InspectedBlock {
+    fn new(block: &BlockInfo) -> Self {
+        let symbols = block.symbol_map.symbols();
+        let mut used_ranges: Vec<(u8, u8)> = Vec::new();
+        for byte in symbols {
+            match used_ranges.last_mut() {
+                Some((_, last)) if *last as u16 + 1 == byte as u16 => *last = byte,
+                _ => used_ranges.push((byte, byte)),
+            }
+        }
+        let mut selector_histogram = vec![0; block.tables()];
+        for &selector in &block.selectors {
+            selector_histogram[selector as usize] += 1;
+        }
+        let code_length_ranges = block
+            .code_lengths
+            .iter()
+            .map(|lengths| {
+                let min = lengths.iter().copied().min().unwrap_or(0);
+                (min, lengths.iter().copied().max().unwrap_or(0))
+            })
+            .collect();
+        Self {
+            index: block.index,
+            bit_offset: block.bit_offset,
+            error: block.decode().err().map(|err| err.to_string()),
+            details: Some(BlockDetails {
+                bit_len: block.bit_len,
+                crc: block.crc,
+                randomized: block.randomized,
+                orig_ptr: block.orig_ptr,
+                used_bytes: block.symbol_map.len(),
+                used_ranges,
+                tables: block.tables(),
+                selectors: block.selectors.len(),
+                selector_histogram,
+                code_length_ranges,
+                coded_bits: block.coded_bits,
+            }),
+        }
+    }
+}
+
+impl fmt::Display for Inspection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for stream in &self.streams {
+            writeln!(
+                f,
+                "stream at byte {}: BZh{}, blocks of up to {}00k",
+                stream.byte_offset, stream.block_size_digit, stream.block_size_digit
+            )?;
+            for block in &stream.blocks {
+                write!(f, "  block {} at bit {}", block.index, block.bit_offset)?;
+                match &block.error {
+                    Some(error) => writeln!(f, ": DAMAGED, {error}")?,
+                    None => writeln!(f)?,
+                }
+                if let Some(details) = &block.details {
+                    write!(f, "{details}")?;
+                }
+            }
+            match &stream.footer {
+                Some(footer) if footer.crc == footer.blocks_crc => writeln!(
+                    f,
+                    "  footer at bit {}: combined CRC {:#010x}",
+                    footer.bit_offset, footer.crc
+                )?,
+                Some(footer) => writeln!(
+                    f,
+                    "  footer at bit {}: combined CRC {:#010x}, the blocks combine to {:#010x}",
+                    footer.bit_offset, footer.crc, footer.blocks_crc
+                )?,
+                None => writeln!(f, "  no footer")?,
+            }
+        }
+        if let Some(offset) = self.trailing_garbage {
+            writeln!(f, "trailing data at byte {offset}")?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for BlockDetails {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ranges: Vec<String> = self
+            .used_ranges
+            .iter()
+            .map(|&(first, last)| {
+                if first == last {
+                    format!("{first:#04x}")
+                } else {
+                    format!("{first:#04x}-{last:#04x}")
+                }
+            })
+            .collect();
+        let tables: Vec<String> = (self.selector_histogram.iter().zip(&self.code_length_ranges))
+            .enumerate()
+            .map(|(table, (selectors, (min, max)))| {
+                format!("table {table}: {selectors} selectors, code lengths {min}-{max}")
+            })
+            .collect();
+        writeln!(
+            f,
+            "    {} bits, CRC {:#010x}, origin pointer {}{}",
+            self.bit_len,
+            self.crc,
+            self.orig_ptr,
+            if self.randomized { ", randomized" } else { "" }
+        )?;
+        writeln!(
+            f,
+            "    {} bytes in use: {}",
+            self.used_bytes,
+            ranges.join(" ")
+        )?;
+        writeln!(
+            f,
+            "    {} tables, {} selectors",
+            self.tables, self.selectors
+        )?;
+        for table in tables {
+            writeln!(f, "      {table}")?;
+        }
+        writeln!(f, "    {} bits of coded symbols", self.coded_bits)
+    }
+}
+
+/// The output files of an input, removed again unless `keep` is called, so an input that fails
+/// doesn't leave an empty or partial file behind, which a second attempt would then refuse to
+/// overwrite without `--force`. Like the reference tool, only regular files are removed, never
+/// e.g. `/dev/null` given as `--output-path`.
+#[derive(Default)]
+struct PartialOutputs(Vec<PathBuf>);
+
+impl PartialOutputs {
+    /// Create the output at `path`, which must not exist unless `force`
+    fn create(&mut self, path: &Path, force: bool) -> Result<File> {
+        let file = match create_output(path, force) {
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => bail!(
+                "{} already exists, use --force to overwrite it",
+                path.display()
+            ),
+            result => result.with_context(|| format!("Can't create {}", path.display()))?,
+        };
+        if file.metadata().is_ok_and(|metadata| metadata.is_file()) {
+            self.0.push(path.to_path_buf());
+        }
+        Ok(file)
+    }
+
+    /// The outputs are complete
+    fn keep(&mut self) {
+        self.0.clear();
+    }
+}
+
+impl Drop for PartialOutputs {
+    fn drop(&mut self) {
+        for path in &self.0 {
+            // the error that got here is the one worth reporting
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+/// Counts the bytes written through it, for the size of the output
+struct CountingWriter<W> {
+    inner: W,
+    written: u64,
+}
+
+impl<W: Write> CountingWriter<W> {
+    fn new(inner: W) -> Self {
+        Self { inner, written: 0 }
+    }
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.inner.write(buf)?;
+        self.written += len as u64;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Writes what every stage made of every block to files in a directory for `--dump-stages`,
+/// keeping the first error to report once the input is done, since an observer can't fail
+#[derive(Clone)]
+struct StageDump {
+    dir: PathBuf,
+    error: Arc<Mutex<Option<anyhow::Error>>>,
+}
+
+impl StageDump {
+    fn new(dir: &Path) -> Result<Self> {
+        fs::create_dir_all(dir).with_context(|| format!("Can't create {}", dir.display()))?;
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            error: Arc::default(),
+        })
+    }
+
+    fn write(&self, block: usize, extension: &str, contents: &[u8]) {
+        let path = self.dir.join(format!("block{block:04}.{extension}"));
+        if let Err(err) = fs::write(&path, contents) {
+            let mut error = self.error.lock().expect("no panics while holding the lock");
+            error.get_or_insert_with(|| {
+                anyhow::Error::new(err).context(format!("Can't write {}", path.display()))
+            });
+        }
+    }
+
+    /// The first file that couldn't be written
+    fn finish(&self) -> Result<()> {
+        match self
+            .error
+            .lock()
+            .expect("no panics while holding the lock")
+            .take()
+        {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+}
+
+impl StageObserver for StageDump {
+    fn rle1(&mut self, block: usize, rle1: &[u8]) {
+        self.write(block, "rle1", rle1);
+    }
+
+    fn bwt(&mut self, block: usize, last_column: &[u8], origin: usize) {
+        self.write(block, "bwt", last_column);
+        self.write(block, "origin", format!("{origin}\n").as_bytes());
+    }
+
+    fn mtf(&mut self, block: usize, indices: &[MtfIndex], stack: &[u8]) {
+        let symbols: Vec<u8> = indices
+            .iter()
+            .flat_map(|index| Symbol::from(index).value().to_be_bytes())
+            .collect();
+        self.write(block, "mtf", &symbols);
+        self.write(block, "stack", stack);
+    }
+
+    fn huffman(&mut self, block: usize, code_lengths: &[Vec<u8>], selectors: &[u8], bits: u64) {
+        let line = |values: &[u8]| {
+            let values: Vec<String> = values.iter().map(u8::to_string).collect();
+            values.join(" ")
+        };
+        let mut text = String::new();
+        for (table, lengths) in code_lengths.iter().enumerate() {
+            text += &format!("table {table}: {}\n", line(lengths));
+        }
+        text += &format!("selectors: {}\n", line(selectors));
+        text += &format!("coded bits: {bits}\n");
+        self.write(block, "huff", text.as_bytes());
+    }
+}
+
+fn compressed_path(path: PathBuf) -> PathBuf {
+    let mut path = path.into_os_string();
+    path.push(".bz2");
+    path.into()
+}
+
+/// Suffixes of compressed files and what `decompressed_path` replaces them with
+const COMPRESSED_SUFFIXES: [(&str, &str); 4] = [
+    (".bz2", ""),
+    (".bz", ""),
+    (".tbz2", ".tar"),
+    (".tbz", ".tar"),
+];
+
+/// Default output path for decompressing `path`, the same names the reference tool picks:
+/// `.bz2` and `.bz` are removed, `.tbz2` and `.tbz` become `.tar`, anything else gets `.out`
+fn decompressed_path(path: PathBuf) -> PathBuf {
+    let name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or_default();
+    for (suffix, replacement) in COMPRESSED_SUFFIXES {
+        if let Some(stem) = name.strip_suffix(suffix).filter(|stem| !stem.is_empty()) {
+            return path.with_file_name(format!("{stem}{replacement}"));
+        }
+    }
+    let mut path = path.into_os_string();
+    path.push(".out");
+    path.into()
+}
@@ -0,0 +1,183 @@
+use std::{
+    fs::{self, File, FileTimes, Metadata},
+    io,
+    path::Path,
+};
+
+/// Create the file at `path` to write an output to. Unless `overwrite`, the file must not exist
+/// yet: the check is part of creating it, so nothing can be put in its place in between, and a
+/// symbolic link there isn't followed. That fails with `ErrorKind::AlreadyExists`.
+///
+/// On Unix, the file is only readable and writable by its owner (mode 0600), like the reference
+/// tool creates it, so the output of a private input isn't readable by others while it is
+/// written, and neither is a file it overwrites. `copy_metadata` gives it the permissions of the
+/// input once it is complete.
+pub fn create_output(path: &Path, overwrite: bool) -> io::Result<File> {
+    let mut options = File::options();
+    options.write(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    if overwrite {
+        options.create(true).truncate(true);
+    } else {
+        options.create_new(true);
+    }
+    let file = options.open(path)?;
+    // the mode only applies to a new file, an overwritten one keeps its own
+    #[cfg(unix)]
+    if overwrite {
+        use std::os::unix::fs::PermissionsExt;
+
+        file.set_permissions(fs::Permissions::from_mode(0o600))?;
+    }
+    Ok(file)
+}
+
+/// Give the file at `to` the access and modification times and the permissions of the file at
+/// `from`, and on Unix also its owner and group where that is allowed, like the reference tool
+/// does for the file it writes.
+///
+/// Ownership can only be given away by a privileged user, so failing to change it is ignored,
+/// like the reference tool ignores it. The permissions come last, since they may make `to`
+/// read-only or, on Unix, hold setuid bits that changing the owner would clear.
+pub fn copy_metadata(from: &Path, to: &Path) -> io::Result<()> {
+    let metadata = fs::metadata(from)?;
+    let times = FileTimes::new()
+        .set_accessed(metadata.accessed()?)
+        .set_modified(metadata.modified()?);
+    File::options().write(true).open(to)?.set_times(times)?;
+    copy_owner(&metadata, to);
+    fs::set_permissions(to, metadata.permissions())
+}
+
+#[cfg(unix)]
+fn copy_owner(metadata: &Metadata, to: &Path) {
+    use std::os::unix::fs::{chown, MetadataExt};
+
+    let _ = chown(to, Some(metadata.uid()), Some(metadata.gid()));
+}
+
+/// Files have no owner to copy outside of Unix
+#[cfg(not(unix))]
+fn copy_owner(_metadata: &Metadata, _to: &Path) {}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use super::*;
+
+    fn files() -> (tempfile::TempDir, std::path::PathBuf, std::path::PathBuf) {
+        let dir = tempfile::tempdir().unwrap();
+        let from = dir.path().join("from");
+        let to = dir.path().join("to");
+        fs::write(&from, b"hello world\n").unwrap();
+        fs::write(&to, b"").unwrap();
+        (dir, from, to)
+    }
+
+    #[test]
+    fn test_times() {
+        let (_dir, from, to) = files();
+        let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+        let accessed = modified + Duration::from_secs(60);
+        File::options()
+            .write(true)
+            .open(&from)
+            .unwrap()
+            .set_times(
+                FileTimes::new()
+                    .set_accessed(accessed)
+                    .set_modified(modified),
+            )
+            .unwrap();
+
+        copy_metadata(&from, &to).unwrap();
+        let metadata = fs::metadata(&to).unwrap();
+        assert_eq!(metadata.modified().unwrap(), modified);
+        assert_eq!(metadata.accessed().unwrap(), accessed);
+    }
+
+    #[test]
+    fn test_read_only() {
+        let (_dir, from, to) = files();
+        let mut permissions = fs::metadata(&from).unwrap().permissions();
+        permissions.set_readonly(true);
+        fs::set_permissions(&from, permissions).unwrap();
+
+        copy_metadata(&from, &to).unwrap();
+        assert!(fs::metadata(&to).unwrap().permissions().readonly());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_mode_and_owner() {
+        use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+        let (_dir, from, to) = files();
+        fs::set_permissions(&from, fs::Permissions::from_mode(0o640)).unwrap();
+        fs::set_permissions(&to, fs::Permissions::from_mode(0o666)).unwrap();
+
+        copy_metadata(&from, &to).unwrap();
+        let (from, to) = (fs::metadata(&from).unwrap(), fs::metadata(&to).unwrap());
+        assert_eq!(to.mode() & 0o7777, 0o640);
+        assert_eq!((to.uid(), to.gid()), (from.uid(), from.gid()));
+    }
+
+    #[test]
+    fn test_create_output() {
+        let (dir, _, to) = files();
+        fs::write(&to, b"precious").unwrap();
+        let err = create_output(&to, false).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(fs::read(&to).unwrap(), b"precious");
+
+        create_output(&to, true).unwrap();
+        assert_eq!(fs::read(&to).unwrap(), b"");
+        let new = dir.path().join("new");
+        create_output(&new, false).unwrap();
+        assert!(new.exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_create_output_is_private() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let (dir, from, _) = files();
+        fs::set_permissions(&from, fs::Permissions::from_mode(0o644)).unwrap();
+        let to = dir.path().join("new");
+        let file = create_output(&to, false).unwrap();
+        // before anything is written, and while the file is still open
+        assert_eq!(file.metadata().unwrap().permissions().mode() & 0o777, 0o600);
+        copy_metadata(&from, &to).unwrap();
+        assert_eq!(
+            fs::metadata(&to).unwrap().permissions().mode() & 0o777,
+            0o644
+        );
+
+        let file = create_output(&to, true).unwrap();
+        assert_eq!(file.metadata().unwrap().permissions().mode() & 0o777, 0o600);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_create_output_doesnt_follow_links() {
+        let (dir, _, to) = files();
+        let link = dir.path().join("link");
+        let missing = dir.path().join("missing");
+        for target in [&to, &missing] {
+            let _ = fs::remove_file(&link);
+            std::os::unix::fs::symlink(target, &link).unwrap();
+            let err = create_output(&link, false).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+        }
+        assert!(!missing.exists());
+    }
+
+    #[test]
+    fn test_missing_input() {
+        let (dir, _, to) = files();
+        assert!(copy_metadata(&dir.path().join("missing"), &to).is_err());
+    }
+}
@@ -0,0 +1,339 @@
+//! FM-index over a BWT block, so a block can be searched for a pattern without decoding it.
+
+use alloc::{vec, vec::Vec};
+
+use core::ops::Range;
+
+use crate::bwt::BwtEncoded;
+
+/// Occurrence counts are stored for every `OCC_SAMPLE_RATE`-th row of the last column, which
+/// costs 2 bytes per byte of the block. Ranks in between are counted from the checkpoint before.
+const OCC_SAMPLE_RATE: usize = 512;
+/// Every `SA_SAMPLE_RATE`-th text position is stored with its row, `locate` walks at most this many
+/// LF steps back to the next sample.
+const SA_SAMPLE_RATE: usize = 32;
+
+/// Substring index built from the last column of a BWT block.
+///
+/// The BWT of bzip2 sorts rotations instead of suffixes, so the backward search finds every
+/// rotation that starts with the pattern, including the ones that wrap around the end of the
+/// block. `count` and `locate` leave those out and only report matches within the block.
+#[derive(Debug)]
+pub struct FmIndex {
+    /// Last column of the sorted rotations
+    last: Vec<u8>,
+    /// Row of the rotation that starts at text position 0
+    original_index: usize,
+    /// `first_rows[c]` is the number of bytes in the block smaller than `c`, i.e. the first row
+    /// whose rotation starts with `c`
+    first_rows: [usize; 257],
+    /// `occ[i][c]` is the number of `c`s in `last[..i * OCC_SAMPLE_RATE]`
+    occ: Vec<[u32; 256]>,
+    /// `(row, position)` of the sampled rows, sorted by row
+    samples: Vec<(usize, usize)>,
+}
+
+impl FmIndex {
+    /// Build the index from a BWT as produced by `BwtEncoded::encode`. This walks the LF mapping
+    /// of the whole block once to sample the text positions, which is about as much work as
+    /// decoding it, but every query after that only touches the index.
+    pub fn new(bwt: &BwtEncoded) -> Self {
+        let last = bwt.data();
+        let original_index = bwt.original_index();
+        let len = last.len();
+        u32::try_from(len).expect("BWT block must be smaller than 4 GiB");
+
+        let mut occ = Vec::with_capacity(len / OCC_SAMPLE_RATE + 2);
+        let mut counts = [0u32; 256];
+        occ.push(counts);
+        for chunk in last.chunks(OCC_SAMPLE_RATE) {
+            for &byte in chunk {
+                counts[byte as usize] += 1;
+            }
+            occ.push(counts);
+        }
+
+        let mut first_rows = [0; 257];
+        for byte in 0..256 {
+            first_rows[byte + 1] = first_rows[byte] + counts[byte] as usize;
+        }
+
+        let mut index = Self {
+            last,
+            original_index,
+            first_rows,
+            occ,
+            samples: Vec::new(),
+        };
+        index.samples = index.sample_positions();
+        index
+    }
+
+    /// Number of times `pattern` occurs in the block. An empty pattern matches nothing.
+    pub fn count(&self, pattern: &[u8]) -> usize {
+        if pattern.is_empty() || pattern.len() > self.last.len() {
+            return 0;
+        }
+        self.rows(pattern).len() - self.wrapping_matches(pattern)
+    }
+
+    /// Positions in the block at which `pattern` starts, in ascending order. An empty pattern
+    /// matches nothing.
+    pub fn locate(&self, pattern: &[u8]) -> Vec<usize> {
+        if pattern.is_empty() || pattern.len() > self.last.len() {
+            return Vec::new();
+        }
+        let last_start = self.last.len() - pattern.len();
+        let mut positions: Vec<usize> = self
+            .rows(pattern)
+            .map(|row| self.position(row))
+            .filter(|&position| position <= last_start)
+            .collect();
+        positions.sort_unstable();
+        positions
+    }
+
+    /// Rows whose rotation starts with `pattern`, found by backward search
+    fn rows(&self, pattern: &[u8]) -> Range<usize> {
+        let mut rows = 0..self.last.len();
+        for &byte in pattern.iter().rev() {
+            let first_row = self.first_rows[byte as usize];
+            rows = first_row + self.rank(byte, rows.start)..first_row + self.rank(byte, rows.end);
+            if rows.is_empty() {
+                return 0..0;
+            }
+        }
+        rows
+    }
+
+    /// Number of rotations starting with `pattern` that wrap around the end of the block. These
+    /// can only start in the last `pattern.len() - 1` positions, so the bytes on both sides of
+    /// the block boundary are extracted and searched directly.
+    fn wrapping_matches(&self, pattern: &[u8]) -> usize {
+        let overlap = pattern.len() - 1;
+        let mut boundary = vec![0; 2 * overlap];
+
+        // the last column of the rotation at position p holds the byte at p - 1
+        let mut row = self.original_index;
+        for byte in boundary[..overlap].iter_mut().rev() {
+            *byte = self.last[row];
+            row = self.lf(row);
+        }
+        let mut row = self.original_index;
+        for byte in &mut boundary[overlap..] {
+            *byte = self.first(row);
+            row = self.psi(row);
+        }
+
+        boundary
+            .windows(pattern.len())
+            .filter(|window| *window == pattern)
+            .count()
+    }
+
+    /// Text position of the rotation in `row`, found by walking the LF mapping back to the next
+    /// sampled row
+    fn position(&self, mut row: usize) -> usize {
+        let mut steps = 0;
+        loop {
+            if let Ok(sample) = self.samples.binary_search_by_key(&row, |&(row, _)| row) {
+                return self.samples[sample].1 + steps;
+            }
+            row = self.lf(row);
+            steps += 1;
+        }
+    }
+
+    /// Number of `byte`s in `last[..row]`
+    fn rank(&self, byte: u8, row: usize) -> usize {
+        let checkpoint = row / OCC_SAMPLE_RATE;
+        let counted = self.last[checkpoint * OCC_SAMPLE_RATE..row]
+            .iter()
+            .filter(|&&b| b == byte)
+            .count();
+        self.occ[checkpoint][byte as usize] as usize + counted
+    }
+
+    /// Row of the `n`-th (counting from 0) `byte` in the last column
+    fn select(&self, byte: u8, n: usize) -> usize {
+        let checkpoint = self
+            .occ
+            .partition_point(|counts| counts[byte as usize] as usize <= n)
+            - 1;
+        let start = checkpoint * OCC_SAMPLE_RATE;
+        let skip = n - self.occ[checkpoint][byte as usize] as usize;
+        self.last[start..]
+            .iter()
+            .enumerate()
+            .filter(|&(_, &b)| b == byte)
+            .nth(skip)
+            .map(|(offset, _)| start + offset)
+            .expect("Byte must occur n times in the last column")
+    }
+
+    /// First byte of the rotation in `row`
+    fn first(&self, row: usize) -> u8 {
+        self.first_rows[1..].partition_point(|&first_row| first_row <= row) as u8
+    }
+
+    /// Row of the rotation that starts one position before the one in `row`
+    fn lf(&self, row: usize) -> usize {
+        let byte = self.last[row];
+        self.first_rows[byte as usize] + self.rank(byte, row)
+    }
+
+    /// Row of the rotation that starts one position after the one in `row`, the inverse of `lf`
+    fn psi(&self, row: usize) -> usize {
+        let byte = self.first(row);
+        self.select(byte, row - self.first_rows[byte as usize])
+    }
+
+    /// Walk the LF mapping from the original row once and sample the positions of the rows.
+    ///
+    /// If the block is `copies` repetitions of a period of `period` bytes, every rotation occurs
+    /// `copies` times in adjacent rows, and LF splits into `copies` cycles of `period` rows that
+    /// each take the same place in every group of identical rows. The cycle of the original row
+    /// gets positions `0..period`, the next one `period..2 * period` and so on. LF steps from
+    /// the start of such a range back to its end, so the start of every range is sampled as well
+    /// and `position` never walks across it.
+    fn sample_positions(&self) -> Vec<(usize, usize)> {
+        let len = self.last.len();
+        if len == 0 {
+            return Vec::new();
+        }
+
+        // all of LF at once from running counts, instead of a rank lookup per row
+        let mut seen = [0; 256];
+        let lf: Vec<usize> = self
+            .last
+            .iter()
+            .map(|&byte| {
+                seen[byte as usize] += 1;
+                self.first_rows[byte as usize] + seen[byte as usize] - 1
+            })
+            .collect();
+
+        let mut step_of_row = vec![None; len];
+        let mut row = self.original_index;
+        let mut period = 0;
+        while step_of_row[row].is_none() {
+            step_of_row[row] = Some(period);
+            row = lf[row];
+            period += 1;
+        }
+        assert!(len.is_multiple_of(period), "Not a valid BWT");
+        let copies = len / period;
+        let offset = self.original_index % copies;
+
+        (0..len)
+            .filter_map(|row| {
+                let group = row - row % copies;
+                let step = step_of_row[group + offset].expect("Not a valid BWT");
+                let cycle = (row % copies + copies - offset) % copies;
+                let position = cycle * period + (period - step) % period;
+                (position.is_multiple_of(SA_SAMPLE_RATE) || position.is_multiple_of(period))
+                    .then_some((row, position))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+    use test_case::test_case;
+
+    use super::*;
+    use crate::{
+        rle::RleSequence,
+        stages::strategies::{self, fixtures},
+    };
+
+    /// A few KB of text between markers for its start and end
+    fn text() -> Vec<u8> {
+        [b"START ".as_slice(), &fixtures::text(4000), b" END"].concat()
+    }
+
+    fn index(data: &[u8]) -> FmIndex {
+        FmIndex::new(&BwtEncoded::encode(&RleSequence::from_encoded(
+            data.to_vec(),
+        )))
+    }
+
+    fn naive_locate(data: &[u8], pattern: &[u8]) -> Vec<usize> {
+        if pattern.is_empty() {
+            return Vec::new();
+        }
+        data.windows(pattern.len())
+            .enumerate()
+            .filter(|(_, window)| *window == pattern)
+            .map(|(position, _)| position)
+            .collect()
+    }
+
+    #[test_case(b"START"; "at position 0")]
+    #[test_case(b" END"; "at the end")]
+    #[test_case(b"the "; "frequent word")]
+    #[test_case(b"quick brown "; "across words")]
+    #[test_case(b"o"; "single byte")]
+    #[test_case(b"zebra"; "absent")]
+    #[test_case(b"ENDSTART"; "only across the block boundary")]
+    #[test_case(b""; "empty pattern")]
+    fn test_matches_naive_search(pattern: &[u8]) {
+        let data = text();
+        let fm = index(&data);
+        let expected = naive_locate(&data, pattern);
+        assert_eq!(fm.locate(pattern), expected);
+        assert_eq!(fm.count(pattern), expected.len());
+    }
+
+    #[test]
+    fn test_pattern_longer_than_text() {
+        let fm = index(b"abracadabra");
+        assert_eq!(fm.count(b"abracadabraa"), 0);
+        assert!(fm.locate(b"abracadabraa").is_empty());
+        assert_eq!(fm.locate(b"abracadabra"), vec![0]);
+    }
+
+    #[test_case(b"aaaaaaaaaa", b"aa" => vec![0, 1, 2, 3, 4, 5, 6, 7, 8]; "single byte repeated")]
+    #[test_case(b"abababababab", b"bab" => vec![1, 3, 5, 7, 9]; "period two")]
+    #[test_case(b"abcabcabcabc", b"cab" => vec![2, 5, 8]; "period three")]
+    #[test_case(b"b", b"b" => vec![0]; "single byte")]
+    #[test_case(b"", b"a" => Vec::<usize>::new(); "empty text")]
+    fn test_locate_periodic(data: &[u8], pattern: &[u8]) -> Vec<usize> {
+        let fm = index(data);
+        assert_eq!(fm.count(pattern), naive_locate(data, pattern).len());
+        fm.locate(pattern)
+    }
+
+    #[test]
+    fn test_locate_long_period() {
+        // period longer than the sample rate, so the cycles have samples of their own
+        let period: Vec<u8> = (0..40).map(|i| b'a' + i % 7).collect();
+        let data = period.repeat(3);
+        assert_eq!(
+            index(&data).locate(&period[..5]),
+            naive_locate(&data, &period[..5])
+        );
+    }
+
+    proptest! {
+        #[test]
+        fn prop_matches_naive_search(
+            data in strategies::bytes(96),
+            start in any::<prop::sample::Index>(),
+            len in 1..6usize,
+        ) {
+            let fm = index(&data);
+            let pattern = if data.is_empty() {
+                b"a".to_vec()
+            } else {
+                let start = start.index(data.len());
+                data[start..(start + len).min(data.len())].to_vec()
+            };
+            let expected = naive_locate(&data, &pattern);
+            prop_assert_eq!(fm.count(&pattern), expected.len());
+            prop_assert_eq!(fm.locate(&pattern), expected);
+        }
+    }
+}
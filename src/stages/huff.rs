@@ -1,71 +1,113 @@
-use crate::mtf::{MtfIndex, MtfTransform};
-use bitstream_io::{BigEndian, BitWrite, BitWriter};
-use std::collections::HashMap;
+use alloc::{collections::BinaryHeap, vec, vec::Vec};
+use core::{cmp::Reverse, mem};
 
-type SymbolIndex = usize;
-type SymbolCount = usize;
+use crate::{
+    bits::{BitReadError, BitReader, BitWriter},
+    mtf::{MtfIndex, MtfTransform},
+    symbol::{Symbol, MAX_ALPHABET_SIZE},
+};
+use thiserror::Error;
 
-type FrequencyMap = HashMap<SymbolIndex, SymbolCount>;
+type SymbolCount = u32;
 
-const MAX_HUFFMAN_LEN: usize = 20;
+/// Longest code the format allows
+pub const MAX_HUFFMAN_LEN: usize = 20;
 
-trait FrequencyMapping {
-    fn build(mtf: &MtfTransform) -> Self;
+/// Width of the length of the first symbol, which the deltas of a serialized table start from
+const START_LEN_BITS: u32 = 5;
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum HuffmanError {
+    #[error("Code length {length} of symbol {symbol} is not between 1 and 20")]
+    InvalidCodeLength { symbol: usize, length: u8 },
+    #[error("Input doesn't match any code of the table")]
+    InvalidCode,
+    #[error("Coded symbols end without an end of block symbol")]
+    MissingEob,
+    #[error("Selector refers to table {selector} of {tables}")]
+    InvalidSelector { selector: usize, tables: usize },
+    #[error("{count} code lengths are more than the largest alphabet has symbols")]
+    TooManyCodeLengths { count: usize },
+    #[error("Code lengths have more codes than fit into a prefix code")]
+    OversubscribedCode,
+    #[error(transparent)]
+    Read(#[from] BitReadError),
 }
 
-impl FrequencyMapping for FrequencyMap {
-    /// Builds a frequency map from the given Move-to-Front (MTF) transform.
-    ///
-    /// This method constructs a `FrequencyMap` by iterating over the indices
-    /// of the provided `MtfTransform` and counting the occurrences of each symbol.
-    /// The symbols are derived from the MTF indices as follows:
-    /// - `MtfIndex::RunA` is mapped to `0`.
-    /// - `MtfIndex::RunB` is mapped to `1`.
-    /// - `MtfIndex::Val(i)` is mapped to `i + 1`.
+/// How often every symbol of a block occurs, indexed by symbol value
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Frequencies {
+    counts: [SymbolCount; MAX_ALPHABET_SIZE],
+    alphabet_size: usize,
+}
+
+impl Frequencies {
+    /// Counts the symbols of the given Move-to-Front (MTF) transform.
     ///
-    /// Additionally, an End-Of-Block (EOB) symbol is inserted into the map with
-    /// a frequency of `1`. The EOB symbol is calculated as the number of symbols
-    /// in the stack of the MTF transform plus `1`.
+    /// The symbols are derived from the MTF indices via `Symbol::from` and counted in an array
+    /// indexed by their value, so counting is a single pass without any hashing. Additionally,
+    /// the End-Of-Block (EOB) symbol is counted once. It is the last symbol of the alphabet,
+    /// whose size depends on the number of symbols in the stack of the MTF transform.
     ///
     /// # Parameters
-    /// - `mtf`: A reference to an `MtfTransform` instance from which the frequency
-    ///   map will be built.
+    /// - `mtf`: A reference to an `MtfTransform` instance whose symbols are counted.
     ///
     /// # Returns
-    /// A `FrequencyMap` containing the frequency of each symbol derived from the
-    /// MTF transform, including the EOB symbol.
-    fn build(mtf: &MtfTransform) -> Self {
-        let mut freq_map: HashMap<SymbolIndex, SymbolCount> = FrequencyMap::new();
-        // insert RUNA and RUNB into map
-        freq_map.insert(0, 0);
-        freq_map.insert(1, 0);
-
-        let symbols = mtf.indices().iter().map(|idx| match idx {
-            MtfIndex::RunA => 0,
-            MtfIndex::RunB => 1,
-            MtfIndex::Val(i) => (*i as SymbolIndex) + 1,
-        });
-        for sym in symbols {
-            *freq_map.entry(sym as SymbolIndex).or_insert(0) += 1;
+    /// The frequency of every symbol of the alphabet, including the EOB symbol.
+    pub(crate) fn build(mtf: &MtfTransform) -> Self {
+        let mut counts = [0; MAX_ALPHABET_SIZE];
+        for sym in mtf.indices().iter().map(Symbol::from) {
+            counts[sym.value() as usize] += 1;
         }
-        // insert EOB into map
-        let eob = (mtf.num_stack().max(1) + 1) as SymbolIndex;
-        freq_map.insert(eob, 1);
+        let alphabet_size = Symbol::alphabet_size(mtf.num_stack());
+        // EOB is the last symbol and occurs once, at the end of the block
+        counts[alphabet_size - 1] = 1;
+        Self {
+            counts,
+            alphabet_size,
+        }
+    }
 
-        freq_map
+    /// The frequency of every symbol of the alphabet, indexed by its value
+    pub(crate) fn counts(&self) -> &[SymbolCount] {
+        &self.counts[..self.alphabet_size]
+    }
+
+    /// The symbols that get a leaf in the Huffman tree with their frequencies, in order of their
+    /// value: all symbols that occur, and RUNA and RUNB, which are always part of the tree.
+    fn leaves(&self) -> Vec<(Symbol, SymbolCount)> {
+        let mut leaves = Vec::with_capacity(self.alphabet_size);
+        leaves.extend(
+            (0..)
+                .zip(&self.counts[..self.alphabet_size])
+                // RUNA and RUNB are always in the tree
+                .filter(|&(value, &freq)| value < 2 || freq > 0)
+                .map(|(value, &freq)| {
+                    let symbol =
+                        Symbol::try_from(value).expect("The alphabet has at most 258 symbols");
+                    (symbol, freq)
+                }),
+        );
+        leaves
     }
 }
 
-#[derive(Debug, PartialEq, Eq)]
+/// Index of a node in the arena of a `HuffmanTree`
+type NodeId = usize;
+
+/// Key of a subtree in the heap the Huffman tree is built with, see `merge_order`
+type MergeOrder = Reverse<(SymbolCount, Option<Symbol>, Reverse<NodeId>)>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Node {
-    left: Option<Box<Self>>,
-    right: Option<Box<Self>>,
+    left: Option<NodeId>,
+    right: Option<NodeId>,
     freq: SymbolCount,
-    symbol: Option<SymbolIndex>,
+    symbol: Option<Symbol>,
 }
 
 impl Node {
-    fn new_leaf(freq: SymbolCount, symbol: Option<SymbolIndex>) -> Self {
+    fn new_leaf(freq: SymbolCount, symbol: Option<Symbol>) -> Self {
         Self {
             left: None,
             right: None,
@@ -73,175 +115,1014 @@ impl Node {
             symbol,
         }
     }
+}
 
-    fn new_branch(left: Self, right: Self) -> Self {
-        let freq = left.freq + right.freq;
+/// Huffman tree whose nodes live in a single arena and refer to their children by index, so
+/// building a tree only allocates the arena itself instead of one box per node.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct HuffmanTree {
+    nodes: Vec<Node>,
+    root: Option<NodeId>,
+}
+
+impl HuffmanTree {
+    fn with_capacity(capacity: usize) -> Self {
         Self {
-            left: Some(Box::new(left)),
-            right: Some(Box::new(right)),
+            nodes: Vec::with_capacity(capacity),
+            root: None,
+        }
+    }
+
+    fn push_leaf(&mut self, freq: SymbolCount, symbol: Option<Symbol>) -> NodeId {
+        self.nodes.push(Node::new_leaf(freq, symbol));
+        self.nodes.len() - 1
+    }
+
+    fn push_branch(&mut self, left: NodeId, right: NodeId) -> NodeId {
+        let freq = self.nodes[left].freq + self.nodes[right].freq;
+        self.nodes.push(Node {
+            left: Some(left),
+            right: Some(right),
             freq,
             symbol: None,
-        }
+        });
+        self.nodes.len() - 1
+    }
+
+    fn node(&self, id: NodeId) -> &Node {
+        &self.nodes[id]
+    }
+
+    fn root(&self) -> Option<&Node> {
+        self.root.map(|id| self.node(id))
     }
 
-    /// Calculates the depth of the current node in the tree.
+    /// Calculates the depth of the node `id` in the tree.
     ///
     /// The depth is determined by traversing the tree recursively and finding
-    /// the longest path from the current node to a leaf node. A leaf node is
+    /// the longest path from the node to a leaf node. A leaf node is
     /// defined as a node with no children (`left` and `right` are `None`).
     ///
     /// # Returns
     ///
     /// * `usize` - The depth of the node, where a leaf node has a depth of 1.
-    fn get_depth(&self) -> usize {
-        match (&self.left, &self.right) {
+    fn get_depth(&self, id: NodeId) -> usize {
+        let node = self.node(id);
+        match (node.left, node.right) {
             (None, None) => 1,
-            (None, Some(right)) => 1 + right.get_depth(),
-            (Some(left), None) => 1 + left.get_depth(),
-            (Some(left), Some(right)) => 1 + left.get_depth().max(right.get_depth()),
+            (None, Some(right)) => 1 + self.get_depth(right),
+            (Some(left), None) => 1 + self.get_depth(left),
+            (Some(left), Some(right)) => 1 + self.get_depth(left).max(self.get_depth(right)),
         }
     }
 }
 
-#[derive(Debug, Clone)]
-struct SymbolCode {
+/// The buffers a Huffman tree is built in, see `HuffmanEncoder::build_tree_into`
+#[derive(Debug, Default)]
+struct TreeBuffers {
+    tree: HuffmanTree,
+    /// Leaves of the tree being rebuilt by `HuffmanEncoder::rebalance`
+    leaves: Vec<(Symbol, SymbolCount)>,
+    heap: Vec<MergeOrder>,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct SymbolCode {
     code: u32, // codes can be up to 20 bits long
     width: u8,
 }
-type CodeTable = HashMap<SymbolIndex, SymbolCode>;
 
-struct HuffmanEncodedResult {}
+impl SymbolCode {
+    pub fn code(self) -> u32 {
+        self.code
+    }
+
+    pub fn width(self) -> u8 {
+        self.width
+    }
+}
+
+/// Code of every symbol of the alphabet, indexed by symbol value, so looking up a code while
+/// encoding is a plain array access. Symbols without a code have a width of 0.
+///
+/// With the `serde` feature, a table is serialized as its code lengths, which is all the format
+/// stores of it, and deserialized through `TryFrom<Vec<u8>>`, which checks them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Deserialize),
+    serde(try_from = "Vec<u8>")
+)]
+pub struct CodeTable {
+    codes: [SymbolCode; MAX_ALPHABET_SIZE],
+}
+
+impl CodeTable {
+    pub fn new() -> Self {
+        Self {
+            codes: [SymbolCode::default(); MAX_ALPHABET_SIZE],
+        }
+    }
+
+    /// Code of `symbol`, if it has one
+    pub fn get(&self, symbol: Symbol) -> Option<SymbolCode> {
+        Some(self.codes[symbol.value() as usize]).filter(|code| code.width > 0)
+    }
+
+    /// The symbols that have a code with their codes, in order of their value
+    pub fn iter(&self) -> impl Iterator<Item = (Symbol, SymbolCode)> + '_ {
+        (0..)
+            .zip(&self.codes)
+            .filter(|(_, code)| code.width > 0)
+            .map(|(value, &code)| {
+                let symbol = Symbol::try_from(value).expect("The table covers an alphabet");
+                (symbol, code)
+            })
+    }
+
+    /// Code length of every symbol up to the last one that has a code, 0 for the ones without,
+    /// the lengths `CanonicalCodes::from_lengths` builds the table from
+    pub fn code_lengths(&self) -> Vec<u8> {
+        let len = self
+            .codes
+            .iter()
+            .rposition(|code| code.width > 0)
+            .map_or(0, |last| last + 1);
+        self.codes[..len].iter().map(|code| code.width).collect()
+    }
+
+    /// Number of symbols that have a code
+    pub fn len(&self) -> usize {
+        self.codes.iter().filter(|code| code.width > 0).count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Default for CodeTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Canonical codes for code lengths from anywhere but a Huffman tree: there may be at most one
+/// for every symbol of the largest alphabet, none longer than `MAX_HUFFMAN_LEN`, and not more
+/// codes of any length than a prefix code has room for. 0 is a symbol without a code.
+impl TryFrom<Vec<u8>> for CodeTable {
+    type Error = HuffmanError;
+
+    fn try_from(lengths: Vec<u8>) -> Result<Self, HuffmanError> {
+        if lengths.len() > MAX_ALPHABET_SIZE {
+            return Err(HuffmanError::TooManyCodeLengths {
+                count: lengths.len(),
+            });
+        }
+        let mut kraft_sum = 0u64;
+        for (symbol, &length) in lengths.iter().enumerate() {
+            if length as usize > MAX_HUFFMAN_LEN {
+                return Err(HuffmanError::InvalidCodeLength { symbol, length });
+            }
+            if length > 0 {
+                kraft_sum += 1 << (MAX_HUFFMAN_LEN - length as usize);
+            }
+        }
+        if kraft_sum > 1 << MAX_HUFFMAN_LEN {
+            return Err(HuffmanError::OversubscribedCode);
+        }
+        Ok(CanonicalCodes::from_lengths(&lengths))
+    }
+}
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for CodeTable {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.code_lengths().serialize(serializer)
+    }
+}
+
+/// Canonical Huffman codes, which are fully determined by the code length of every symbol.
+///
+/// This is how bzip2 transmits its tables: only the lengths are written to the stream, and both
+/// sides assign the codes the same way, in order of increasing length and, among symbols of the
+/// same length, in order of increasing symbol value. Each code is the previous one plus one,
+/// shifted left whenever the length grows.
+pub struct CanonicalCodes;
+
+impl CanonicalCodes {
+    /// Assign canonical codes to the symbols whose value is the index into `lengths`. Symbols
+    /// with length 0 don't get a code. The lengths have to describe a prefix code, as the leaf
+    /// depths of a Huffman tree do, and be at most 20.
+    ///
+    /// The first code of every length follows from how many codes are shorter, so the symbols
+    /// don't have to be sorted by length: walking them in order of their value and handing out
+    /// the next code of their length assigns the same codes.
+    pub fn from_lengths(lengths: &[u8]) -> CodeTable {
+        let mut counts = [0u32; MAX_HUFFMAN_LEN + 1];
+        for &width in lengths {
+            counts[width as usize] += 1;
+        }
+        let mut next_codes = [0u32; MAX_HUFFMAN_LEN + 1];
+        let mut code = 0;
+        for width in 1..=MAX_HUFFMAN_LEN {
+            next_codes[width] = code;
+            code = (code + counts[width]) << 1;
+        }
+
+        let mut code_table = CodeTable::new();
+        for (slot, &width) in code_table.codes.iter_mut().zip(lengths) {
+            if width > 0 {
+                let next_code = &mut next_codes[width as usize];
+                *slot = SymbolCode {
+                    code: *next_code,
+                    width,
+                };
+                *next_code += 1;
+            }
+        }
+        code_table
+    }
+}
+
+/// Decodes symbols coded with the canonical codes of one table.
+///
+/// Canonical codes of the same length are consecutive numbers, assigned to the symbols in order
+/// of their value. So for every length it is enough to know the first code, how many codes there
+/// are and where their symbols start in the list of all symbols ordered by length, to map a code
+/// back to its symbol.
+pub struct HuffmanDecoder {
+    /// Symbols with a code, ordered by code length, then by value
+    symbols: Vec<Symbol>,
+    /// Number of code lengths the decoder was built from, the last one being EOB's
+    alphabet_size: usize,
+    /// First code, index of its symbol in `symbols` and number of codes, for every length
+    lengths: [(u32, usize, usize); MAX_HUFFMAN_LEN + 1],
+}
+
+impl HuffmanDecoder {
+    /// Decoder for the codes `CanonicalCodes::from_lengths` assigns for `lengths`, which must
+    /// all be at most 20.
+    pub fn from_lengths(lengths: &[u8]) -> Self {
+        let mut by_length: Vec<(u8, u16)> = (0..)
+            .zip(lengths)
+            .filter(|&(_, &width)| width > 0)
+            .map(|(value, &width)| (width, value))
+            .collect();
+        by_length.sort_unstable();
+        let symbols = by_length
+            .iter()
+            .map(|&(_, value)| {
+                Symbol::try_from(value).expect("Code lengths are given for an alphabet")
+            })
+            .collect();
+
+        let mut counts = [0; MAX_HUFFMAN_LEN + 1];
+        for &(width, _) in &by_length {
+            counts[width as usize] += 1;
+        }
+        let mut decoder_lengths = [(0, 0, 0); MAX_HUFFMAN_LEN + 1];
+        let (mut code, mut start) = (0u32, 0);
+        for width in 1..=MAX_HUFFMAN_LEN {
+            decoder_lengths[width] = (code, start, counts[width]);
+            code = (code + counts[width] as u32) << 1;
+            start += counts[width];
+        }
+        Self {
+            symbols,
+            alphabet_size: lengths.len(),
+            lengths: decoder_lengths,
+        }
+    }
+
+    /// Read codes from `reader` up to the EOB symbol and return the MTF indices before it, the
+    /// inverse of `HuffmanEncoder::encode` with the decoder built from its `code_lengths`. The
+    /// lengths have to cover a whole alphabet, see `Symbol::alphabet_size`, so the last one is
+    /// EOB's. Input that ends before EOB fails with `HuffmanError::Read`.
+    pub fn decode(&self, reader: &mut BitReader) -> Result<Vec<MtfIndex>, HuffmanError> {
+        let mut indices = Vec::new();
+        loop {
+            let symbol = self.decode_symbol(reader)?;
+            match symbol
+                .to_mtf_index(self.alphabet_size)
+                .expect("The decoder only has symbols of its alphabet")
+            {
+                Some(index) => indices.push(index),
+                None => return Ok(indices),
+            }
+        }
+    }
+
+    /// Read the next code from `reader` and return its symbol
+    pub fn decode_symbol(&self, reader: &mut BitReader) -> Result<Symbol, HuffmanError> {
+        let mut code = 0;
+        for &(first, start, count) in &self.lengths[1..] {
+            code = (code << 1) | reader.read_bit()? as u32;
+            if code >= first && ((code - first) as usize) < count {
+                return Ok(self.symbols[start + (code - first) as usize]);
+            }
+        }
+        Err(HuffmanError::InvalidCode)
+    }
+}
+
+/// Huffman coded symbols of a block, packed into bytes. The last byte is padded with zero bits,
+/// `bit_len` tells how many of the bits are codes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HuffmanEncodedResult {
+    data: Vec<u8>,
+    bit_len: u64,
+}
+
+impl HuffmanEncodedResult {
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn bit_len(&self) -> u64 {
+        self.bit_len
+    }
 
-struct HuffmanEncoder {
-    root: Option<Node>,
+    pub fn into_data(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+pub struct HuffmanEncoder {
+    tree: HuffmanTree,
+    code_lengths: Vec<u8>,
     code_table: CodeTable,
 }
 
 impl HuffmanEncoder {
     pub fn new(mtf_encode: &MtfTransform) -> Self {
-        let Some(mut root) = Self::build_tree(mtf_encode) else {
-            return Self::empty();
+        let mut buffers = TreeBuffers {
+            tree: Self::build_tree(mtf_encode),
+            ..TreeBuffers::default()
         };
-        while root.get_depth() > MAX_HUFFMAN_LEN {
-            Self::rebalance(&mut root);
+        if buffers.tree.root.is_none() {
+            return Self::empty();
         }
-        let mut code_table = CodeTable::new();
-        Self::get_codes(&root, 0, 0, &mut code_table);
+        let root = Self::limit_depth(&mut buffers);
+        let tree = buffers.tree;
+        let mut code_lengths = vec![0; Symbol::alphabet_size(mtf_encode.num_stack())];
+        Self::get_code_lengths(&tree, root, 0, &mut code_lengths);
+        let code_table = CanonicalCodes::from_lengths(&code_lengths);
         Self {
-            root: Some(root),
-            code_table: code_table.clone(),
+            tree,
+            code_lengths,
+            code_table,
         }
     }
 
-    pub fn encode(&self, mtf: MtfTransform) -> HuffmanEncodedResult {
-        let mut buf = Vec::new();
-        let mut writer = BitWriter::endian(&mut buf, BigEndian);
-        for idx in mtf.indices {
-            let symbol = match idx {
-                MtfIndex::RunA => 0usize,
-                MtfIndex::RunB => 1,
-                MtfIndex::Val(v) => v as usize + 1,
-            };
-            let SymbolCode { code, width } = self.code_table.get(&symbol).unwrap(); // TODO: error handling
-            let _ = writer.write_var(*width as u32, *code); // TODO: error handling
+    /// Code length of every symbol of the alphabet, indexed by symbol value. This is all a
+    /// decoder needs to rebuild the codes.
+    pub fn code_lengths(&self) -> &[u8] {
+        &self.code_lengths
+    }
+
+    /// Code all symbols of `mtf`, followed by the EOB symbol, into a fresh buffer.
+    pub fn encode(&self, mtf: &MtfTransform) -> HuffmanEncodedResult {
+        let mut writer = BitWriter::new();
+        let bit_len = self.encode_into(mtf, &mut writer);
+        HuffmanEncodedResult {
+            data: writer.into_bytes(),
+            bit_len,
+        }
+    }
+
+    /// Write the codes for all symbols of `mtf` to `writer`, terminated by the code of the EOB
+    /// symbol, and return the number of bits written. The writer is not flushed, so it can be
+    /// reused to write several blocks into the same buffer.
+    pub fn encode_into(&self, mtf: &MtfTransform, writer: &mut BitWriter) -> u64 {
+        let eob = Symbol::eob(Symbol::alphabet_size(mtf.num_stack()));
+        let mut bit_len = 0;
+        for symbol in mtf.indices().iter().map(Symbol::from).chain([eob]) {
+            let SymbolCode { code, width } = self
+                .code_table
+                .get(symbol)
+                .expect("Every symbol of the block has a code");
+            writer.write_bits(code, width as u32);
+            bit_len += width as u64;
         }
-        let _ = writer.byte_align(); // TODO: error handling
-        HuffmanEncodedResult {}
+        bit_len
     }
 
     pub(crate) fn empty() -> Self {
         Self {
-            root: None,
-            code_table: HashMap::new(),
+            tree: HuffmanTree::default(),
+            code_lengths: Vec::new(),
+            code_table: CodeTable::new(),
         }
     }
 
-    fn build_tree(mtf_encode: &MtfTransform) -> Option<Node> {
-        let freq_map = FrequencyMap::build(mtf_encode);
-        let mut freq_list: Vec<Node> = Vec::with_capacity(freq_map.len());
-        for (data, freq) in freq_map {
-            freq_list.push(Node::new_leaf(freq, Some(data)));
-        }
+    fn build_tree(mtf_encode: &MtfTransform) -> HuffmanTree {
+        Self::tree_from_freqs(Frequencies::build(mtf_encode).leaves())
+    }
+
+    /// Build the Huffman tree of the symbols in `freq_list` by repeatedly merging the two least
+    /// frequent subtrees, which a min-heap hands out in O(log n) each, so the whole tree takes
+    /// O(n log n). Ties are broken by `merge_order`, so the resulting tree does not depend on
+    /// the order of the frequencies.
+    fn tree_from_freqs(freq_list: Vec<(Symbol, SymbolCount)>) -> HuffmanTree {
+        let mut buffers = TreeBuffers::default();
+        Self::build_tree_into(&freq_list, &mut buffers);
+        buffers.tree
+    }
 
-        // sort in ascending order
-        freq_list.sort_by(|a, b| b.freq.cmp(&a.freq));
+    /// Same as `tree_from_freqs`, building the tree in `buffers.tree` with `buffers.heap`,
+    /// which keep their capacity from tree to tree
+    fn build_tree_into(freq_list: &[(Symbol, SymbolCount)], buffers: &mut TreeBuffers) {
+        let TreeBuffers { tree, heap, .. } = buffers;
+        tree.nodes.clear();
+        tree.root = None;
+        // a tree with n leaves has n - 1 branches
+        tree.nodes.reserve_exact(2 * freq_list.len() - 1);
+        heap.clear();
+        heap.reserve_exact(freq_list.len());
+        for &(symbol, freq) in freq_list {
+            let leaf = tree.push_leaf(freq, Some(symbol));
+            heap.push(Self::merge_order(tree, leaf));
+        }
+        let mut merges = BinaryHeap::from(mem::take(heap));
+        let pop =
+            |heap: &mut BinaryHeap<MergeOrder>| heap.pop().map(|Reverse((_, _, Reverse(id)))| id);
 
-        while freq_list.len() != 1 {
-            // TODO: Lmax of bzip2 is 20, so the tree cannot be deeper. Check for this constraint
-            let left_node = freq_list.pop().unwrap(); // TODO: Error handling
-            let right_node = freq_list.pop().unwrap(); // TODO: Error handling
-            let new_node = Node::new_branch(left_node, right_node);
-            freq_list.push(new_node);
-            freq_list.sort_by(|a, b| b.freq.cmp(&a.freq));
+        while let Some(left_node) = pop(&mut merges) {
+            let Some(right_node) = pop(&mut merges) else {
+                tree.root = Some(left_node);
+                break;
+            };
+            let branch = tree.push_branch(left_node, right_node);
+            merges.push(Self::merge_order(tree, branch));
         }
+        *heap = merges.into_vec();
+    }
 
-        freq_list.pop()
+    /// Position of the subtree `id` in the max-heap of `tree_from_freqs`: the least frequent
+    /// subtree comes first, on a tie branches come before leaves, newer branches before older
+    /// ones and leaves in order of their symbol.
+    fn merge_order(tree: &HuffmanTree, id: NodeId) -> MergeOrder {
+        let node = tree.node(id);
+        Reverse((node.freq, node.symbol, Reverse(id)))
     }
 
-    fn rebalance(_node: &mut Node) {
-        // TODO: yeah abracadabra rebalance this tree!
-        todo!()
+    /// Rebalance `tree` until no code is longer than `MAX_HUFFMAN_LEN`, and return its root
+    fn limit_depth(buffers: &mut TreeBuffers) -> NodeId {
+        let mut root = buffers
+            .tree
+            .root
+            .expect("A Huffman tree has at least one leaf");
+        // the root is not part of any code, so a code is one shorter than the depth of its leaf
+        while buffers.tree.get_depth(root) - 1 > MAX_HUFFMAN_LEN {
+            Self::rebalance(buffers);
+            root = buffers
+                .tree
+                .root
+                .expect("A rebalanced tree keeps its leaves");
+        }
+        root
     }
 
-    /// Build code table for Huffman tree by traversing the tree with a DFS
+    /// Rebuild `tree` from the frequencies of its leaves, halved, the way bzip2 limits its code
+    /// lengths.
     ///
-    /// Method: we start with the current_symbol_code of 0 and a code width of 0. For every left
-    /// branch we traverse, we shift the current code 1 to the left and increase the code width
-    /// by 1. For every right branch, we traverse, we shift the current code 1 to the left and OR
-    /// a 1 into the current position, then increase width by 1. When we encounter a leaf, we
-    /// write the symbol and its current code in to the code table.
-    fn get_codes(
-        node: &Node,
-        current_symbol_code: u32,
-        code_width: u8,
-        code_table: &mut CodeTable,
+    /// Codes get long when the frequencies are very skewed, e.g. grow like the Fibonacci numbers.
+    /// Halving every frequency (and rounding up, so no symbol ends up with frequency 0) flattens
+    /// the skew, repeating it eventually leaves every symbol with the same frequency and the tree
+    /// balanced. Each round costs a little compression on the rare symbols, which is why it only
+    /// runs while the tree is too deep.
+    fn rebalance(buffers: &mut TreeBuffers) {
+        let mut leaves = mem::take(&mut buffers.leaves);
+        leaves.clear();
+        leaves.extend(
+            buffers
+                .tree
+                .nodes
+                .iter()
+                .filter_map(|node| node.symbol.map(|symbol| (symbol, 1 + node.freq / 2))),
+        );
+        Self::build_tree_into(&leaves, buffers);
+        buffers.leaves = leaves;
+    }
+
+    /// Collect the code length of every leaf of the Huffman tree by traversing the tree with a
+    /// DFS. The code of a leaf is as long as the path from the root to it, the codes themselves
+    /// are assigned canonically from the lengths afterwards.
+    fn get_code_lengths(tree: &HuffmanTree, id: NodeId, code_width: u8, code_lengths: &mut [u8]) {
+        let node = tree.node(id);
+        if let Some(symbol) = node.symbol {
+            code_lengths[symbol.value() as usize] = code_width;
+        }
+        for child in [node.left, node.right].into_iter().flatten() {
+            Self::get_code_lengths(tree, child, code_width + 1, code_lengths);
+        }
+    }
+}
+
+/// Number of symbols coded with the same table, each group gets its own selector
+pub const GROUP_SIZE: usize = 50;
+/// bzip2 uses at least two and at most six tables per block
+pub const MIN_TABLES: usize = 2;
+pub const MAX_TABLES: usize = 6;
+/// Maximum number of times the tables are refitted to the groups that picked them, unless
+/// `MultiTableEncoder::with_iterations` says otherwise. The reference encoder's number.
+pub const DEFAULT_ITERATIONS: usize = 4;
+/// Code length the initial tables give the symbols outside their part of the alphabet
+const INITIAL_LEN_OUTSIDE: u8 = 15;
+
+/// Huffman coding with several tables, the way bzip2 codes a block.
+///
+/// The symbols are coded in groups of `GROUP_SIZE`, and each group picks the table that codes it
+/// in the fewest bits, which it records in its selector. Blocks whose statistics change along the
+/// way (e.g. text followed by binary data) then code every part with a table that fits it, where
+/// a single table has to compromise. The tables are found like the reference encoder does: each
+/// starts out cheap for one range of the alphabet, then up to `DEFAULT_ITERATIONS` times every
+/// table is rebuilt from the frequencies of the groups that picked it and every group picks its
+/// cheapest table again. The bits the block takes are counted along the way, a refit that
+/// doesn't make the block any smaller ends the refinement and is dropped.
+#[derive(Debug, Default)]
+pub struct MultiTableEncoder {
+    code_lengths: Vec<Vec<u8>>,
+    code_tables: Vec<CodeTable>,
+    selectors: Vec<u8>,
+    cost: u64,
+}
+
+impl MultiTableEncoder {
+    pub fn new(mtf: &MtfTransform) -> Self {
+        Self::with_iterations(mtf, DEFAULT_ITERATIONS)
+    }
+
+    /// Same as `new`, refitting the tables up to `iterations` times instead of
+    /// `DEFAULT_ITERATIONS`. More iterations take longer and never code the symbols in more bits,
+    /// though the tables and selectors may come out a few bits larger. The first refit is always
+    /// made, so 0 is the same as 1.
+    pub fn with_iterations(mtf: &MtfTransform, iterations: usize) -> Self {
+        let mut encoder = Self::default();
+        encoder.refit(mtf, iterations, &mut HuffmanScratch::default());
+        encoder
+    }
+
+    /// Same as `with_iterations`, but fits the tables of `mtf` in place of the ones this encoder
+    /// had, working in the buffers of `scratch`. Once the buffers have grown to the largest block,
+    /// fitting the tables of another block doesn't allocate.
+    pub fn refit(&mut self, mtf: &MtfTransform, iterations: usize, scratch: &mut HuffmanScratch) {
+        self.fit_in_place(mtf, iterations, scratch);
+        self.code_tables.clear();
+        self.code_tables.extend(
+            self.code_lengths
+                .iter()
+                .map(|lengths| CanonicalCodes::from_lengths(lengths)),
+        );
+    }
+
+    /// The code lengths, selectors and cost `new` settles on, without building the codes, for
+    /// callers that only need to know how large the block gets
+    pub fn fit(mtf: &MtfTransform) -> (Vec<Vec<u8>>, Vec<u8>, u64) {
+        Self::fit_with_iterations(mtf, DEFAULT_ITERATIONS)
+    }
+
+    /// Same as `fit`, for `with_iterations`
+    pub fn fit_with_iterations(
+        mtf: &MtfTransform,
+        iterations: usize,
+    ) -> (Vec<Vec<u8>>, Vec<u8>, u64) {
+        let mut encoder = Self::default();
+        encoder.fit_in_place(mtf, iterations, &mut HuffmanScratch::default());
+        (encoder.code_lengths, encoder.selectors, encoder.cost)
+    }
+
+    /// Find the code lengths, selectors and cost of `mtf` like `fit_with_iterations`, keeping
+    /// them in this encoder without building the codes
+    fn fit_in_place(
+        &mut self,
+        mtf: &MtfTransform,
+        iterations: usize,
+        scratch: &mut HuffmanScratch,
     ) {
-        match (&node.left, &node.right) {
-            (None, None) => {
-                // leaf, so save the code table entry
-                code_table.insert(
-                    node.symbol.unwrap(),
-                    SymbolCode {
-                        code: current_symbol_code,
-                        width: code_width,
-                    },
-                );
+        let alphabet_size = Symbol::alphabet_size(mtf.num_stack());
+        let HuffmanScratch {
+            symbols,
+            code_lengths,
+            selectors,
+            table_freqs,
+            tree,
+        } = scratch;
+        symbol_values(mtf, symbols);
+        initial_code_lengths(symbols, alphabet_size, code_lengths);
+        select_tables(code_lengths, symbols, selectors);
+        // the initial tables leave symbols without a code, so the first refit is always taken
+        let mut refit = |selectors: &[u8], code_lengths: &mut Vec<Vec<u8>>| {
+            refit_tables(
+                selectors,
+                symbols,
+                alphabet_size,
+                table_freqs,
+                tree,
+                code_lengths,
+            )
+        };
+        refit(selectors, &mut self.code_lengths);
+        self.cost = select_tables(&self.code_lengths, symbols, &mut self.selectors);
+
+        for _ in 1..iterations {
+            refit(&self.selectors, code_lengths);
+            let refitted_cost = select_tables(code_lengths, symbols, selectors);
+            if refitted_cost >= self.cost {
+                break;
+            }
+            mem::swap(&mut self.code_lengths, code_lengths);
+            mem::swap(&mut self.selectors, selectors);
+            self.cost = refitted_cost;
+        }
+    }
+
+    pub fn num_tables(&self) -> usize {
+        self.code_lengths.len()
+    }
+
+    /// Code length of every symbol in every table, indexed by table and symbol value. Every
+    /// symbol of the alphabet has a code in every table.
+    pub fn code_lengths(&self) -> &[Vec<u8>] {
+        &self.code_lengths
+    }
+
+    /// Table of each group of `GROUP_SIZE` symbols
+    pub fn selectors(&self) -> &[u8] {
+        &self.selectors
+    }
+
+    /// Number of bits `encode` codes the block in, without the tables and selectors
+    pub fn cost(&self) -> u64 {
+        self.cost
+    }
+
+    /// Same as `HuffmanEncoder::encode`, coding each group with the table of its selector.
+    pub fn encode(&self, mtf: &MtfTransform) -> HuffmanEncodedResult {
+        let mut writer = BitWriter::new();
+        let bit_len = self.encode_into(mtf, &mut writer);
+        HuffmanEncodedResult {
+            data: writer.into_bytes(),
+            bit_len,
+        }
+    }
+
+    /// Same as `HuffmanEncoder::encode_into`, coding each group with the table of its selector.
+    pub fn encode_into(&self, mtf: &MtfTransform, writer: &mut BitWriter) -> u64 {
+        let eob = Symbol::eob(Symbol::alphabet_size(mtf.num_stack()));
+        let symbols = mtf.indices().iter().map(Symbol::from).chain([eob]);
+        let mut bit_len = 0;
+        for (position, symbol) in symbols.enumerate() {
+            let selector = self.selectors[position / GROUP_SIZE];
+            let SymbolCode { code, width } = self.code_tables[selector as usize]
+                .get(symbol)
+                .expect("Every symbol of the alphabet has a code in every table");
+            writer.write_bits(code, width as u32);
+            bit_len += width as u64;
+        }
+        bit_len
+    }
+}
+
+/// Number of tables bzip2 uses for a block of `symbol_count` symbols: more tables only pay for
+/// their own code lengths once there are enough groups to use them
+pub fn num_tables(symbol_count: usize) -> usize {
+    match symbol_count {
+        0..200 => MIN_TABLES,
+        200..600 => 3,
+        600..1200 => 4,
+        1200..2400 => 5,
+        _ => MAX_TABLES,
+    }
+}
+
+/// The buffers `MultiTableEncoder::refit` fits the tables of a block in, kept from block to block
+#[derive(Debug, Default)]
+pub struct HuffmanScratch {
+    /// Values of the symbols of the block, see `symbol_values`
+    symbols: Vec<usize>,
+    /// Code lengths and selectors of the tables being tried, next to the ones the encoder keeps
+    code_lengths: Vec<Vec<u8>>,
+    selectors: Vec<u8>,
+    /// Frequencies of the symbols of the groups that picked each table
+    table_freqs: Vec<[SymbolCount; MAX_ALPHABET_SIZE]>,
+    tree: TreeBuffers,
+}
+
+/// Values of the symbols of `mtf`, terminated by EOB, in `symbols`
+fn symbol_values(mtf: &MtfTransform, symbols: &mut Vec<usize>) {
+    let eob = Symbol::eob(Symbol::alphabet_size(mtf.num_stack()));
+    symbols.clear();
+    symbols.extend(
+        mtf.indices()
+            .iter()
+            .map(Symbol::from)
+            .chain([eob])
+            .map(|symbol| symbol.value() as usize),
+    );
+}
+
+/// Make `code_lengths` hold `tables` tables of `alphabet_size` lengths each, reusing the tables
+/// it already has
+fn resize_tables(code_lengths: &mut Vec<Vec<u8>>, tables: usize, alphabet_size: usize) {
+    code_lengths.resize_with(tables, Vec::new);
+    for lengths in code_lengths {
+        lengths.clear();
+        // room for any alphabet, so a block with more symbols doesn't grow the tables again
+        lengths.reserve(MAX_ALPHABET_SIZE);
+        lengths.resize(alphabet_size, 0);
+    }
+}
+
+/// Starting point for the refinement: split the alphabet into one range per table, each holding
+/// about the same share of the symbols, and make each table cheap for its own range only
+fn initial_code_lengths(symbols: &[usize], alphabet_size: usize, code_lengths: &mut Vec<Vec<u8>>) {
+    let mut freqs = [0; MAX_ALPHABET_SIZE];
+    for &value in symbols {
+        freqs[value] += 1;
+    }
+    let tables = num_tables(symbols.len());
+    resize_tables(code_lengths, tables, alphabet_size);
+    let mut remaining = symbols.len();
+    let mut range_start = 0;
+    for (table, lengths) in code_lengths.iter_mut().enumerate() {
+        let target = remaining / (tables - table);
+        let mut range_end = range_start;
+        let mut covered = 0;
+        while range_end < alphabet_size && (covered < target || range_end == range_start) {
+            covered += freqs[range_end];
+            range_end += 1;
+        }
+        for (value, length) in lengths.iter_mut().enumerate() {
+            if !(range_start..range_end).contains(&value) {
+                *length = INITIAL_LEN_OUTSIDE;
             }
-            (None, Some(right)) => {
-                Self::get_codes(
-                    &right,
-                    1 | current_symbol_code << 1,
-                    code_width + 1, // panic on overflow which is probably good?
-                    code_table,
-                );
+        }
+        remaining -= covered;
+        range_start = range_end;
+    }
+}
+
+/// Selector of every group of `symbols` in `selectors`, the table that codes it in the fewest
+/// bits, returning the number of bits all groups take with the tables they picked
+fn select_tables(code_lengths: &[Vec<u8>], symbols: &[usize], selectors: &mut Vec<u8>) -> u64 {
+    selectors.clear();
+    let mut cost = 0;
+    for group in symbols.chunks(GROUP_SIZE) {
+        let (selector, group_cost) = cheapest_table(code_lengths, group);
+        selectors.push(selector as u8);
+        cost += group_cost;
+    }
+    cost
+}
+
+/// Code lengths of every table in `code_lengths`, rebuilt from the frequencies of the groups of
+/// `symbols` whose selector picked it, counted in `table_freqs` and built in `tree`. A table no
+/// group picked gets a code for every symbol all the same.
+fn refit_tables(
+    selectors: &[u8],
+    symbols: &[usize],
+    alphabet_size: usize,
+    table_freqs: &mut Vec<[SymbolCount; MAX_ALPHABET_SIZE]>,
+    tree: &mut TreeBuffers,
+    code_lengths: &mut Vec<Vec<u8>>,
+) {
+    let tables = num_tables(symbols.len());
+    table_freqs.clear();
+    table_freqs.resize(tables, [0; MAX_ALPHABET_SIZE]);
+    for (group, &selector) in symbols.chunks(GROUP_SIZE).zip(selectors) {
+        for &value in group {
+            table_freqs[selector as usize][value] += 1;
+        }
+    }
+    resize_tables(code_lengths, tables, alphabet_size);
+    for (freqs, lengths) in table_freqs.iter().zip(code_lengths) {
+        code_lengths_for(&freqs[..alphabet_size], tree, lengths);
+    }
+}
+
+/// Index of the table that codes `group` in the fewest bits, the first one on a tie, and the
+/// number of bits it takes
+fn cheapest_table(code_lengths: &[Vec<u8>], group: &[usize]) -> (usize, u64) {
+    (0..code_lengths.len())
+        .map(|table| {
+            let cost = group
+                .iter()
+                .map(|&value| code_lengths[table][value] as u64)
+                .sum();
+            (table, cost)
+        })
+        .min_by_key(|&(_, cost)| cost)
+        .expect("There is at least one table")
+}
+
+/// Length-limited Huffman code lengths for an alphabet with the given frequencies, indexed by
+/// symbol value, in `code_lengths`, which has a length for every symbol. Symbols that don't
+/// occur still get a code.
+fn code_lengths_for(freqs: &[SymbolCount], tree: &mut TreeBuffers, code_lengths: &mut [u8]) {
+    let mut freq_list = mem::take(&mut tree.leaves);
+    freq_list.clear();
+    freq_list.extend((0..).zip(freqs).map(|(value, &freq)| {
+        let symbol = Symbol::try_from(value).expect("Frequencies are given for an alphabet");
+        (symbol, freq)
+    }));
+    HuffmanEncoder::build_tree_into(&freq_list, tree);
+    tree.leaves = freq_list;
+    let root = HuffmanEncoder::limit_depth(tree);
+    HuffmanEncoder::get_code_lengths(&tree.tree, root, 0, code_lengths);
+}
+
+/// Write the code `lengths` of a table the way bzip2 does: the length of the first symbol in 5
+/// bits, then for every symbol the steps from the length before it, `10` for +1 and `11` for -1,
+/// each symbol terminated by a `0`. Neighbouring symbols mostly have similar lengths, so most of
+/// them take one to three bits.
+pub fn serialize_lengths(lengths: &[u8], writer: &mut BitWriter) -> Result<(), HuffmanError> {
+    let Some(&first) = lengths.first() else {
+        return Ok(());
+    };
+    if let Some((symbol, &length)) = lengths
+        .iter()
+        .enumerate()
+        .find(|&(_, &length)| !(1..=MAX_HUFFMAN_LEN as u8).contains(&length))
+    {
+        return Err(HuffmanError::InvalidCodeLength { symbol, length });
+    }
+    writer.write_bits(first as u32, START_LEN_BITS);
+    let mut current = first;
+    for &length in lengths {
+        while current < length {
+            writer.write_bits(0b10, 2);
+            current += 1;
+        }
+        while current > length {
+            writer.write_bits(0b11, 2);
+            current -= 1;
+        }
+        writer.write_bit(false);
+    }
+    Ok(())
+}
+
+/// Number of bits `serialize_lengths` writes for valid code `lengths`
+pub fn serialized_lengths_len(lengths: &[u8]) -> u64 {
+    let Some(&first) = lengths.first() else {
+        return 0;
+    };
+    let mut current = first;
+    let steps: u64 = lengths
+        .iter()
+        .map(|&length| {
+            let step = current.abs_diff(length) as u64;
+            current = length;
+            2 * step + 1
+        })
+        .sum();
+    START_LEN_BITS as u64 + steps
+}
+
+/// Read the code lengths of a table of `alphabet_size` symbols written by `serialize_lengths`.
+/// Fails as soon as the running length leaves `1..=20`, like the reference decoder does.
+pub fn deserialize_lengths(
+    reader: &mut BitReader,
+    alphabet_size: usize,
+) -> Result<Vec<u8>, HuffmanError> {
+    if alphabet_size == 0 {
+        return Ok(Vec::new());
+    }
+    let mut current = reader.read_bits(START_LEN_BITS)? as u8;
+    let mut lengths = Vec::with_capacity(alphabet_size);
+    for symbol in 0..alphabet_size {
+        loop {
+            if !(1..=MAX_HUFFMAN_LEN as u8).contains(&current) {
+                return Err(HuffmanError::InvalidCodeLength {
+                    symbol,
+                    length: current,
+                });
             }
-            (Some(left), None) => {
-                Self::get_codes(&left, current_symbol_code << 1, code_width + 1, code_table);
+            if !reader.read_bit()? {
+                break;
             }
-            (Some(left), Some(right)) => {
-                Self::get_codes(&left, current_symbol_code << 1, code_width + 1, code_table);
-                Self::get_codes(
-                    &right,
-                    1 | (current_symbol_code << 1),
-                    code_width + 1,
-                    code_table,
-                );
+            if reader.read_bit()? {
+                current -= 1;
+            } else {
+                current += 1;
             }
         }
+        lengths.push(current);
     }
+    Ok(lengths)
+}
+
+/// Position of every selector in a move-to-front list of the `tables` tables, which moves each
+/// selector to the front after it. Neighbouring groups mostly pick the same table, so most
+/// positions are 0.
+pub fn mtf_selectors(selectors: &[u8], tables: usize) -> Result<Vec<u8>, HuffmanError> {
+    check_selectors(selectors, tables)?;
+    Ok(selector_positions(selectors).collect())
+}
+
+/// Fail on the first of `selectors` that doesn't refer to one of the `tables` tables
+fn check_selectors(selectors: &[u8], tables: usize) -> Result<(), HuffmanError> {
+    match selectors
+        .iter()
+        .find(|&&selector| selector as usize >= tables)
+    {
+        Some(&selector) => Err(HuffmanError::InvalidSelector {
+            selector: selector as usize,
+            tables,
+        }),
+        None => Ok(()),
+    }
+}
+
+/// The positions of `mtf_selectors`, for selectors `check_selectors` let through. The tables past
+/// the ones the selectors refer to never move, so the list can hold every table there may be.
+fn selector_positions(selectors: &[u8]) -> impl Iterator<Item = u8> + '_ {
+    let mut list: [u8; 256] = core::array::from_fn(|table| table as u8);
+    selectors.iter().map(move |&selector| {
+        let position = list
+            .iter()
+            .position(|&table| table == selector)
+            .expect("Every selector is in the list");
+        list[..=position].rotate_right(1);
+        position as u8
+    })
+}
+
+/// Inverse of `mtf_selectors`
+pub fn unmtf_selectors(positions: &[u8], tables: usize) -> Result<Vec<u8>, HuffmanError> {
+    let mut list: Vec<u8> = (0..tables as u8).collect();
+    positions
+        .iter()
+        .map(|&position| {
+            let position = position as usize;
+            if position >= tables {
+                return Err(HuffmanError::InvalidSelector {
+                    selector: position,
+                    tables,
+                });
+            }
+            let selector = list[position];
+            list[..=position].rotate_right(1);
+            Ok(selector)
+        })
+        .collect()
+}
+
+/// Write the `selectors` of a block the way bzip2 does: the move-to-front position of every
+/// selector in unary, as many `1`s as the position followed by a `0`.
+pub fn serialize_selectors(
+    selectors: &[u8],
+    tables: usize,
+    writer: &mut BitWriter,
+) -> Result<(), HuffmanError> {
+    check_selectors(selectors, tables)?;
+    for position in selector_positions(selectors) {
+        for _ in 0..position {
+            writer.write_bit(true);
+        }
+        writer.write_bit(false);
+    }
+    Ok(())
+}
+
+/// Inverse of `serialize_selectors`, reading `count` selectors of a block with `tables` tables
+pub fn deserialize_selectors(
+    reader: &mut BitReader,
+    count: usize,
+    tables: usize,
+) -> Result<Vec<u8>, HuffmanError> {
+    let positions = (0..count)
+        .map(|_| {
+            let mut position = 0;
+            while reader.read_bit()? {
+                position += 1;
+                if position >= tables {
+                    return Err(HuffmanError::InvalidSelector {
+                        selector: position,
+                        tables,
+                    });
+                }
+            }
+            Ok(position as u8)
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    unmtf_selectors(&positions, tables)
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::mtf::t;
+    use crate::mtf::{t, MtfIndex};
+    use crate::stages::counting_alloc as alloc;
 
     use super::*;
     use test_case::test_case;
 
     /// utility method to easily construct MtfTransform structs in tests
-    fn get_mtf(indices: Vec<SymbolIndex>, stack: Vec<u8>) -> MtfTransform {
-        MtfTransform {
-            indices: indices
+    fn get_mtf(indices: Vec<usize>, stack: Vec<u8>) -> MtfTransform {
+        MtfTransform::from_parts(
+            indices
                 .iter()
                 .map(|&i| match i {
                     t::RUNA => MtfIndex::RunA,
@@ -250,75 +1131,646 @@ mod tests {
                 })
                 .collect(),
             stack,
-        }
+        )
+        .unwrap()
+    }
+
+    /// utility method to build symbols from their raw values in tests
+    fn sym(value: u16) -> Symbol {
+        Symbol::try_from(value).unwrap()
+    }
+
+    #[test_case(vec![], vec![] => vec![(0, 0), (1, 0), (2, 1)]; "empty")]
+    #[test_case(vec![t::RUNA, t::RUNA, t::RUNB], vec![0] => vec![(0, 2), (1, 1), (2, 1)]; "one run")]
+    #[test_case(vec![1, 2, 3, t::RUNA, t::RUNA, t::RUNB], vec![1, 10, 42, 100] => vec![
+        (0, 2), (1, 1), (2, 1), (3, 1), (4, 1), (5, 1)]; "run at end")]
+    fn test_freq_map(indices: Vec<usize>, stack: Vec<u8>) -> Vec<(u16, SymbolCount)> {
+        Frequencies::build(&get_mtf(indices, stack))
+            .leaves()
+            .into_iter()
+            .map(|(symbol, freq)| (symbol.value(), freq))
+            .collect()
+    }
+
+    #[test_case(vec![t::RUNA], vec![97] => 2; "one byte stack")]
+    #[test_case((1..=255).collect(), (0..=255).collect() => 257; "full stack")]
+    fn test_freq_map_eob(indices: Vec<usize>, stack: Vec<u8>) -> u16 {
+        let leaves = Frequencies::build(&get_mtf(indices, stack)).leaves();
+        let &(eob, freq) = leaves.last().unwrap();
+        assert_eq!(freq, 1);
+        eob.value()
+    }
+
+    #[test_case(MtfTransform::empty() => HuffmanTree {
+        nodes: vec![
+            Node { left: None, right: None, freq: 0, symbol: Some(sym(0)) },
+            Node { left: None, right: None, freq: 0, symbol: Some(sym(1)) },
+            Node { left: None, right: None, freq: 1, symbol: Some(sym(2)) },
+            Node { left: Some(0), right: Some(1), freq: 0, symbol: None },
+            Node { left: Some(3), right: Some(2), freq: 1, symbol: None },
+        ],
+        root: Some(4) }; "empty")]
+    fn test_encode(mtf: MtfTransform) -> HuffmanTree {
+        HuffmanEncoder::new(&mtf).tree
+    }
+
+    #[test_case(vec![], vec![] => vec![0b0000_0000]; "empty")]
+    #[test_case(vec![t::RUNA, t::RUNA, t::RUNB], vec![0] => vec![0b0010_1100]; "one run")]
+    #[test_case(vec![1, 2, 3, t::RUNA, t::RUNA, t::RUNB], vec![1, 10, 42, 100] => vec![0b1011_1011, 0b1000_0100, 0b0100_0000]; "run at end")]
+    fn test_encode_into(indices: Vec<usize>, stack: Vec<u8>) -> Vec<u8> {
+        let mtf = get_mtf(indices, stack);
+        let mut writer = BitWriter::new();
+        HuffmanEncoder::new(&mtf).encode_into(&mtf, &mut writer);
+        writer.into_bytes()
+    }
+
+    #[test_case(vec![], vec![] => (vec![0b0000_0000], 1); "empty")]
+    #[test_case(vec![t::RUNA, t::RUNA, t::RUNB], vec![0] => (vec![0b0010_1100], 6); "one run")]
+    #[test_case(vec![1, 2, 3, t::RUNA, t::RUNA, t::RUNB], vec![1, 10, 42, 100] => (vec![0b1011_1011, 0b1000_0100, 0b0100_0000], 18); "run at end")]
+    fn test_encode_result(indices: Vec<usize>, stack: Vec<u8>) -> (Vec<u8>, u64) {
+        let mtf = get_mtf(indices, stack);
+        let result = HuffmanEncoder::new(&mtf).encode(&mtf);
+        assert_eq!(result.data().len() as u64, result.bit_len().div_ceil(8));
+        (result.data().to_vec(), result.bit_len())
+    }
+
+    #[test_case(&[] => Vec::<(u16, u32, u8)>::new(); "no symbols")]
+    #[test_case(&[1, 1] => vec![(0, 0b0, 1), (1, 0b1, 1)]; "two symbols")]
+    #[test_case(&[2, 1, 3, 3] => vec![(0, 0b10, 2), (1, 0b0, 1), (2, 0b110, 3), (3, 0b111, 3)]; "ordered by length")]
+    #[test_case(&[3, 0, 3, 2, 2, 2] => vec![(0, 0b110, 3), (2, 0b111, 3), (3, 0b00, 2), (4, 0b01, 2), (5, 0b10, 2)]; "unused symbol")]
+    #[test_case(&[2, 2, 2, 2] => vec![(0, 0b00, 2), (1, 0b01, 2), (2, 0b10, 2), (3, 0b11, 2)]; "all the same length")]
+    fn test_canonical_codes(lengths: &[u8]) -> Vec<(u16, u32, u8)> {
+        CanonicalCodes::from_lengths(lengths)
+            .iter()
+            .map(|(symbol, code)| (symbol.value(), code.code(), code.width()))
+            .collect()
+    }
+
+    #[test_case(&[3, 0, 3, 2, 2, 2], 0 => Some((0b110, 3)); "first symbol")]
+    #[test_case(&[3, 0, 3, 2, 2, 2], 1 => None; "unused symbol")]
+    #[test_case(&[3, 0, 3, 2, 2, 2], 6 => None; "outside the lengths")]
+    fn test_code_table_get(lengths: &[u8], value: u16) -> Option<(u32, u8)> {
+        let codes = CanonicalCodes::from_lengths(lengths);
+        assert_eq!(codes.len(), 5);
+        codes
+            .get(sym(value))
+            .map(|code| (code.code(), code.width()))
     }
 
-    #[test_case(vec![], vec![] => HashMap::from([(0, 0), (1, 0), (2, 1)]); "empty")]
-    #[test_case(vec![t::RUNA, t::RUNA, t::RUNB], vec![0] => HashMap::from([(0, 2), (1, 1), (2, 1)]); "one run")]
-    #[test_case(vec![1, 2, 3, t::RUNA, t::RUNA, t::RUNB], vec![1, 10, 100, 42] => HashMap::from([
-        (0, 2), (1, 1), (2, 1), (3, 1), (4, 1), (5, 1)]); "run at end")]
-    fn test_freq_map(indices: Vec<SymbolIndex>, stack: Vec<u8>) -> FrequencyMap {
-        FrequencyMap::build(&get_mtf(indices, stack))
+    #[test_case(vec![] => Ok(vec![]); "no symbols")]
+    #[test_case(vec![3, 0, 3, 2, 2, 2] => Ok(vec![3, 0, 3, 2, 2, 2]); "unused symbol")]
+    #[test_case(vec![1, 0, 0] => Ok(vec![1]); "trailing symbols without a code")]
+    #[test_case(vec![20; 258] => Ok(vec![20; 258]); "longest codes of the largest alphabet")]
+    #[test_case(vec![1; 259] => Err(HuffmanError::TooManyCodeLengths { count: 259 }); "too many lengths")]
+    #[test_case(vec![2, 21] => Err(HuffmanError::InvalidCodeLength { symbol: 1, length: 21 }); "too long")]
+    #[test_case(vec![1, 1, 1] => Err(HuffmanError::OversubscribedCode); "oversubscribed")]
+    fn test_code_table_try_from(lengths: Vec<u8>) -> Result<Vec<u8>, HuffmanError> {
+        CodeTable::try_from(lengths).map(|table| table.code_lengths())
     }
 
-    #[test_case(MtfTransform::empty() => Node {
-        left: Some(Box::new(Node {
-            left: Some(Box::new(Node { left: None, right: None, freq: 0, symbol: Some(0) })),
-            right: Some(Box::new(Node { left: None, right: None, freq: 0, symbol: Some(1) })), freq: 0, symbol: None })),
-        right: Some(Box::new(Node { left: None, right: None, freq: 1, symbol: Some(2) })), freq: 1, symbol: None }; "empty")]
-    fn test_encode(mtf: MtfTransform) -> Node {
-        HuffmanEncoder::new(&mtf).root.unwrap()
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_code_table_serde_roundtrip() {
+        let table = CanonicalCodes::from_lengths(&[3, 0, 3, 2, 2, 2]);
+        let json = serde_json::to_string(&table).unwrap();
+        assert_eq!(json, "[3,0,3,2,2,2]");
+        assert_eq!(serde_json::from_str::<CodeTable>(&json).unwrap(), table);
+        let bytes = bincode::serialize(&table).unwrap();
+        assert_eq!(bincode::deserialize::<CodeTable>(&bytes).unwrap(), table);
+        assert!(serde_json::from_str::<CodeTable>("[1,1,1]").is_err());
+        assert!(serde_json::from_str::<CodeTable>("[21]").is_err());
+    }
+
+    /// The codes of the encoder can be rebuilt from nothing but its code lengths
+    #[test_case(vec![t::RUNA, t::RUNA, t::RUNB], vec![0]; "one run")]
+    #[test_case(vec![1, t::RUNA, 4, 2, 3, t::RUNA, t::RUNB, 1, 4, 2, t::RUNB, 3, 4, 5, t::RUNB, t::RUNA, t::RUNA, 2, 1], vec![97, 98, 101, 102, 121, 122]; "bbyaeeeeeeafeeeybzzzzzzzzzyz")]
+    fn test_codes_from_code_lengths(indices: Vec<usize>, stack: Vec<u8>) {
+        let encoder = HuffmanEncoder::new(&get_mtf(indices, stack));
+        assert_eq!(
+            CanonicalCodes::from_lengths(encoder.code_lengths()),
+            encoder.code_table
+        );
     }
 
     #[test_case(vec![], vec![] => 1; "empty")]
     #[test_case(vec![t::RUNA, t::RUNA, t::RUNB], vec![0] => 4; "one run")]
-    #[test_case(vec![1, 2, 3, t::RUNA, t::RUNA, t::RUNB], vec![1, 10, 100, 42] => 7; "run at end")]
-    fn test_freqs_in_tree(indices: Vec<SymbolIndex>, stack: Vec<u8>) -> usize {
+    #[test_case(vec![1, 2, 3, t::RUNA, t::RUNA, t::RUNB], vec![1, 10, 42, 100] => 7; "run at end")]
+    fn test_freqs_in_tree(indices: Vec<usize>, stack: Vec<u8>) -> SymbolCount {
         HuffmanEncoder::new(&get_mtf(indices, stack))
-            .root
+            .tree
+            .root()
             .unwrap()
             .freq
     }
     #[test_case(0, None => Node { left: None, right: None, freq: 0, symbol: None }; "empty")]
-    #[test_case(1337, Some(42) => Node { left: None, right: None, freq: 1337, symbol: Some(42) }; "lotsa 42s")]
+    #[test_case(1337, Some(42) => Node { left: None, right: None, freq: 1337, symbol: Some(sym(42)) }; "lotsa 42s")]
     #[test_case(1337, None => Node { left: None, right: None, freq: 1337, symbol: None }; "whole lotta nuthin")]
-    fn test_new_leaf(freq: SymbolCount, symbol: Option<SymbolIndex>) -> Node {
-        Node::new_leaf(freq, symbol)
-    }
-
-    #[test_case(0, None, 0, None => Node {
-        left: Some(Box::new(Node { left: None, right: None, freq: 0, symbol: None })),
-        right: Some(Box::new(Node { left: None, right: None, freq: 0, symbol: None })),
-        freq: 0,
-        symbol: None }; "empty")]
-    #[test_case(1312, Some(42), 25, Some(23) => Node {
-        left: Some(Box::new(Node { left: None, right: None, freq: 1312, symbol: Some(42) })),
-        right: Some(Box::new(Node { left: None, right: None, freq: 25, symbol: Some(23) })),
-        freq: 1337,
-        symbol: None }; "long")]
+    fn test_new_leaf(freq: SymbolCount, symbol: Option<u16>) -> Node {
+        Node::new_leaf(freq, symbol.map(sym))
+    }
+
+    #[test_case(0, None, 0, None => Node { left: Some(0), right: Some(1), freq: 0, symbol: None }; "empty")]
+    #[test_case(1312, Some(42), 25, Some(23) => Node { left: Some(0), right: Some(1), freq: 1337, symbol: None }; "long")]
     fn test_new_branch(
         freq_left: SymbolCount,
-        symbol_left: Option<SymbolIndex>,
+        symbol_left: Option<u16>,
         freq_right: SymbolCount,
-        symbol_right: Option<SymbolIndex>,
+        symbol_right: Option<u16>,
     ) -> Node {
-        Node::new_branch(
-            Node::new_leaf(freq_left, symbol_left),
-            Node::new_leaf(freq_right, symbol_right),
-        )
+        let mut tree = HuffmanTree::default();
+        let left = tree.push_leaf(freq_left, symbol_left.map(sym));
+        let right = tree.push_leaf(freq_right, symbol_right.map(sym));
+        let branch = tree.push_branch(left, right);
+        assert_eq!(tree.node(left).symbol, symbol_left.map(sym));
+        assert_eq!(tree.node(right).symbol, symbol_right.map(sym));
+        *tree.node(branch)
+    }
+
+    #[test_case(vec![], vec![] => 2; "empty")]
+    #[test_case(vec![t::RUNA, 1, 1, 1, 1, 1], vec![97, 98] => 3; "run at beginning")]
+    #[test_case(vec![1, t::RUNA, 4, 2, 3, t::RUNA, t::RUNB, 1, 4, 2, t::RUNB, 3, 4, 5, t::RUNB, t::RUNA, t::RUNA, 2, 1], vec![97, 98, 101, 102, 121, 122] => 4; "bbyaeeeeeeafeeeybzzzzzzzzzyz")]
+    fn test_depth(indices: Vec<usize>, stack: Vec<u8>) -> usize {
+        let encoder = HuffmanEncoder::new(&get_mtf(indices, stack));
+        let depth = encoder.tree.get_depth(encoder.tree.root.unwrap()) - 1;
+        let max_width = encoder.code_table.iter().map(|(_, code)| code.width).max();
+        assert_eq!(max_width, Some(depth as u8));
+        depth
+    }
+
+    /// `Val(k)` occurs `fib(k)` times, which without rebalancing gives the rarest symbols codes of
+    /// about as many bits as there are symbols
+    fn fibonacci_mtf(symbols: usize) -> MtfTransform {
+        let (mut a, mut b) = (1, 1);
+        let mut indices = Vec::new();
+        for value in 1..=symbols {
+            indices.extend(std::iter::repeat_n(value, a));
+            (a, b) = (b, a + b);
+        }
+        get_mtf(indices, (0..=symbols as u8).collect())
+    }
+
+    #[test_case(10 => false; "short enough")]
+    #[test_case(30 => true; "too deep")]
+    fn test_rebalance(symbols: usize) -> bool {
+        let mtf = fibonacci_mtf(symbols);
+        let unbalanced = HuffmanEncoder::build_tree(&mtf);
+        let encoder = HuffmanEncoder::new(&mtf);
+
+        let max_width = encoder.code_table.iter().map(|(_, code)| code.width).max();
+        assert!(max_width.unwrap() as usize <= MAX_HUFFMAN_LEN);
+        // the codes still form a complete prefix code
+        let kraft: f64 = encoder
+            .code_table
+            .iter()
+            .map(|(_, code)| 0.5f64.powi(code.width as i32))
+            .sum();
+        assert_eq!(kraft, 1.0);
+        assert_eq!(encoder.code_table.len(), symbols + 3);
+        encoder.tree != unbalanced
+    }
+
+    /// Building a tree allocates a fixed number of buffers (leaves, arena, heap) instead of one
+    /// per node, no matter how many symbols the alphabet has
+    #[test]
+    fn test_build_tree_allocations() {
+        let small = get_mtf(vec![t::RUNA, 1], vec![97, 98]);
+        let full = get_mtf(
+            (1..=255).chain((1..=255).rev()).collect(),
+            (0..=255).collect(),
+        );
+        let allocations = |mtf: &MtfTransform| {
+            let (_, count) = alloc::count(|| HuffmanEncoder::build_tree(mtf));
+            count
+        };
+        assert_eq!(allocations(&small), 3);
+        assert_eq!(allocations(&full), 3);
+    }
+
+    /// Ties between equal frequencies are broken by symbol, not by where a symbol comes in the
+    /// list, so shuffling the frequencies gives the same code lengths
+    #[test_case(&[(0, 5), (1, 5), (2, 5), (3, 5), (4, 5)]; "all equal")]
+    #[test_case(&[(0, 1), (1, 1), (2, 2), (3, 4), (4, 2), (5, 1)]; "some equal")]
+    #[test_case(&[(0, 0), (1, 0), (2, 1)]; "empty block")]
+    fn test_tree_independent_of_freq_order(freqs: &[(u16, SymbolCount)]) {
+        let code_lengths = |freqs: Vec<(u16, SymbolCount)>| {
+            let mut code_lengths = vec![0; freqs.len()];
+            let leaves = freqs.into_iter().map(|(value, freq)| (sym(value), freq));
+            let tree = HuffmanEncoder::tree_from_freqs(leaves.collect());
+            HuffmanEncoder::get_code_lengths(&tree, tree.root.unwrap(), 0, &mut code_lengths);
+            code_lengths
+        };
+        let forward = code_lengths(freqs.to_vec());
+        let backward = code_lengths(freqs.iter().rev().copied().collect());
+        assert_eq!(forward, backward);
     }
 
     /// Requirement: "NumSyms is computed as NumStack - 1 + 3 , where NumStack is the number of
     /// symbols in the stack from the MTF stage."
     #[test_case(vec![], vec![] => 3; "empty")]
     #[test_case(vec![t::RUNA, t::RUNA, t::RUNB], vec![0] => 3; "one run")]
-    #[test_case(vec![1, 2, 3, t::RUNA, t::RUNA, t::RUNB], vec![1, 10, 100, 42] => 6; "run at end")]
+    #[test_case(vec![1, 2, 3, t::RUNA, t::RUNA, t::RUNB], vec![1, 10, 42, 100] => 6; "run at end")]
     #[test_case(vec![t::RUNA, t::RUNB], vec![ 97 ] => 3; "one symbol")]
     #[test_case(vec![t::RUNA, 1, 1, 1, 1, 1], vec![ 97, 98 ] => 4; "run at beginning")]
     #[test_case(vec![t::RUNA, 1, 2, t::RUNA, 1, 2, t::RUNA, 1, 2, t::RUNA, 1, 2], vec![ 97, 98, 99 ] => 5; "back and forth")]
     #[test_case(vec![t::RUNA, 1, 2, t::RUNA, t::RUNA], vec![97, 98, 99] => 5; "runas at end")]
     #[test_case(vec![1, t::RUNA, 4, 2, 3, t::RUNA, t::RUNB, 1, 4, 2, t::RUNB, 3, 4, 5, t::RUNB, t::RUNA, t::RUNA, 2, 1], vec![97, 98, 101, 102, 121, 122] => 8; "bbyaeeeeeeafeeeybzzzzzzzzzyz")]
-    fn test_num_syms(indices: Vec<SymbolIndex>, stack: Vec<u8>) -> usize {
-        FrequencyMap::build(&get_mtf(indices, stack)).len()
+    fn test_num_syms(indices: Vec<usize>, stack: Vec<u8>) -> usize {
+        Frequencies::build(&get_mtf(indices, stack)).leaves().len()
+    }
+
+    /// Text-like statistics (a few small indices) followed by binary-like ones (many large
+    /// indices), which a single table can't fit both of
+    fn two_regimes_mtf() -> MtfTransform {
+        let mut indices = Vec::new();
+        for i in 0..3000 {
+            indices.push([1, 2, 1, t::RUNA, 1, 3][i % 6]);
+        }
+        for i in 0..3000 {
+            indices.push(10 + i * 7 % 30);
+        }
+        get_mtf(indices, (0..=40).collect())
+    }
+
+    #[test_case(0 => 2; "empty")]
+    #[test_case(199 => 2; "below 200")]
+    #[test_case(200 => 3; "200")]
+    #[test_case(1199 => 4; "below 1200")]
+    #[test_case(2400 => 6; "2400")]
+    #[test_case(900_000 => 6; "full block")]
+    fn test_num_tables(symbol_count: usize) -> usize {
+        num_tables(symbol_count)
+    }
+
+    #[test_case(MtfTransform::empty(); "empty")]
+    #[test_case(get_mtf(vec![t::RUNA, t::RUNA, t::RUNB], vec![0]); "one run")]
+    #[test_case(fibonacci_mtf(25); "skewed")]
+    #[test_case(two_regimes_mtf(); "two regimes")]
+    fn test_multi_table_codes(mtf: MtfTransform) {
+        let encoder = MultiTableEncoder::new(&mtf);
+        let symbol_count = mtf.len() + 1;
+        assert_eq!(encoder.num_tables(), num_tables(symbol_count));
+        assert_eq!(encoder.selectors().len(), symbol_count.div_ceil(GROUP_SIZE));
+        assert!(encoder
+            .selectors()
+            .iter()
+            .all(|&selector| (selector as usize) < encoder.num_tables()));
+        for lengths in encoder.code_lengths() {
+            assert_eq!(lengths.len(), Symbol::alphabet_size(mtf.num_stack()));
+            assert!(lengths
+                .iter()
+                .all(|&width| (1..=MAX_HUFFMAN_LEN as u8).contains(&width)));
+            let kraft: f64 = lengths.iter().map(|&width| 0.5f64.powi(width as i32)).sum();
+            assert_eq!(kraft, 1.0);
+        }
+        let result = encoder.encode(&mtf);
+        assert_eq!(result.data().len() as u64, result.bit_len().div_ceil(8));
+        assert_eq!(encoder.cost(), result.bit_len());
+    }
+
+    /// Every refit that is kept makes the block smaller than the tables of the first refit do
+    #[test_case(fibonacci_mtf(25); "skewed")]
+    #[test_case(two_regimes_mtf(); "two regimes")]
+    fn test_refinement_lowers_cost(mtf: MtfTransform) {
+        let alphabet_size = Symbol::alphabet_size(mtf.num_stack());
+        let (mut symbols, mut selectors) = (Vec::new(), Vec::new());
+        let (mut initial_lengths, mut first_refit) = (Vec::new(), Vec::new());
+        symbol_values(&mtf, &mut symbols);
+        initial_code_lengths(&symbols, alphabet_size, &mut initial_lengths);
+        select_tables(&initial_lengths, &symbols, &mut selectors);
+        refit_tables(
+            &selectors,
+            &symbols,
+            alphabet_size,
+            &mut Vec::new(),
+            &mut TreeBuffers::default(),
+            &mut first_refit,
+        );
+        let first_cost = select_tables(&first_refit, &symbols, &mut selectors);
+
+        let encoder = MultiTableEncoder::new(&mtf);
+        assert!(encoder.cost() <= first_cost);
+        // the selectors fit the final tables
+        let cost = select_tables(encoder.code_lengths(), &symbols, &mut selectors);
+        assert_eq!(
+            (selectors.as_slice(), cost),
+            (encoder.selectors(), encoder.cost())
+        );
+    }
+
+    #[test_case(&[vec![1, 2, 2], vec![2, 2, 1]], &[0, 0, 2, 1] => (0, 6); "first table")]
+    #[test_case(&[vec![1, 2, 2], vec![2, 2, 1]], &[2, 2, 1] => (1, 4); "second table")]
+    #[test_case(&[vec![1, 2, 2], vec![2, 2, 1]], &[0, 2] => (0, 3); "tie")]
+    fn test_cheapest_table(code_lengths: &[Vec<u8>], group: &[usize]) -> (usize, u64) {
+        cheapest_table(code_lengths, group)
+    }
+
+    #[test]
+    fn test_multi_table_beats_single_table() {
+        let mtf = two_regimes_mtf();
+        let single = HuffmanEncoder::new(&mtf).encode(&mtf).bit_len();
+        let multi = MultiTableEncoder::new(&mtf).encode(&mtf).bit_len();
+        // the tables themselves cost at most 6 * 42 * 20 bits, which is still far below the gain
+        assert!(multi + 6 * 42 * 20 < single, "{multi} vs {single}");
+    }
+
+    /// `bz2.compress(b"hello world\n")` from Python, made by the reference library
+    const HELLO_WORLD_BZ2: &[u8] = &[
+        0x42, 0x5a, 0x68, 0x39, 0x31, 0x41, 0x59, 0x26, 0x53, 0x59, 0x4e, 0xec, 0xe8, 0x36, 0x00,
+        0x00, 0x02, 0x51, 0x80, 0x00, 0x10, 0x40, 0x00, 0x06, 0x44, 0x90, 0x80, 0x20, 0x00, 0x31,
+        0x06, 0x4c, 0x41, 0x01, 0xa7, 0xa9, 0xa5, 0x80, 0xbb, 0x94, 0x31, 0xf8, 0xbb, 0x92, 0x29,
+        0xc2, 0x84, 0x82, 0x77, 0x67, 0x41, 0xb0,
+    ];
+    /// Bit offset of the two code length tables in `HELLO_WORLD_BZ2`, for its alphabet of 11
+    /// symbols, and their combined length
+    const HELLO_WORLD_TABLES_START: u64 = 237;
+    const HELLO_WORLD_TABLES_LEN: u64 = 56;
+    const HELLO_WORLD_ALPHABET_SIZE: usize = 11;
+    const HELLO_WORLD_LENGTHS: [[u8; 11]; 2] = [
+        [4, 4, 4, 3, 3, 4, 3, 3, 3, 4, 4],
+        [4, 4, 4, 4, 4, 3, 4, 2, 4, 3, 4],
+    ];
+
+    fn read_bits(data: &[u8], start: u64, len: u64) -> Vec<bool> {
+        let mut reader = BitReader::new(data);
+        reader.skip(start).unwrap();
+        (0..len).map(|_| reader.read_bit().unwrap()).collect()
+    }
+
+    #[test]
+    fn test_deserialize_lengths_of_reference_block() {
+        let mut reader = BitReader::new(HELLO_WORLD_BZ2);
+        reader.skip(HELLO_WORLD_TABLES_START).unwrap();
+        for expected in HELLO_WORLD_LENGTHS {
+            let lengths = deserialize_lengths(&mut reader, HELLO_WORLD_ALPHABET_SIZE).unwrap();
+            assert_eq!(lengths, expected);
+        }
+        assert_eq!(
+            reader.position(),
+            HELLO_WORLD_TABLES_START + HELLO_WORLD_TABLES_LEN
+        );
+    }
+
+    #[test]
+    fn test_serialize_lengths_like_reference_block() {
+        let mut writer = BitWriter::new();
+        for lengths in HELLO_WORLD_LENGTHS {
+            serialize_lengths(&lengths, &mut writer).unwrap();
+        }
+        assert_eq!(writer.bit_len(), HELLO_WORLD_TABLES_LEN);
+        assert_eq!(
+            read_bits(&writer.into_bytes(), 0, HELLO_WORLD_TABLES_LEN),
+            read_bits(
+                HELLO_WORLD_BZ2,
+                HELLO_WORLD_TABLES_START,
+                HELLO_WORLD_TABLES_LEN
+            )
+        );
+    }
+
+    #[test_case(&[] => 0; "empty")]
+    #[test_case(&[5] => 6; "single symbol")]
+    #[test_case(&[3, 3, 3] => 8; "no deltas")]
+    #[test_case(&[1, 20, 1] => 5 + 1 + 39 + 39; "largest steps")]
+    fn test_serialized_lengths_size(lengths: &[u8]) -> u64 {
+        let mut writer = BitWriter::new();
+        serialize_lengths(lengths, &mut writer).unwrap();
+        let bit_len = writer.bit_len();
+        assert_eq!(serialized_lengths_len(lengths), bit_len);
+        let data = writer.into_bytes();
+        let mut reader = BitReader::new(&data);
+        assert_eq!(
+            deserialize_lengths(&mut reader, lengths.len()).unwrap(),
+            lengths
+        );
+        bit_len
+    }
+
+    #[test_case(&[4, 0, 4] => (1, 0); "zero")]
+    #[test_case(&[21] => (0, 21); "too long")]
+    fn test_serialize_invalid_lengths(lengths: &[u8]) -> (usize, u8) {
+        let mut writer = BitWriter::new();
+        match serialize_lengths(lengths, &mut writer) {
+            Err(HuffmanError::InvalidCodeLength { symbol, length }) => (symbol, length),
+            other => panic!("unexpected result {other:?}"),
+        }
+    }
+
+    /// `11` steps below 1, `10` steps above 20, a start length of 0 is invalid right away
+    #[test_case(&[0b0000_1110, 0b0000_0000] => (0, 0); "decrement below one")]
+    #[test_case(&[0b1010_0101, 0b0000_0000] => (0, 21); "increment above twenty")]
+    #[test_case(&[0b0000_0000] => (0, 0); "start length zero")]
+    #[test_case(&[0b0000_1011, 0b0000_0000] => (1, 0); "second symbol")]
+    fn test_deserialize_invalid_lengths(data: &[u8]) -> (usize, u8) {
+        let mut reader = BitReader::new(data);
+        match deserialize_lengths(&mut reader, 3) {
+            Err(HuffmanError::InvalidCodeLength { symbol, length }) => (symbol, length),
+            other => panic!("unexpected result {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_deserialize_truncated_lengths() {
+        let mut reader = BitReader::new(&[0b0001_1101]);
+        assert!(matches!(
+            deserialize_lengths(&mut reader, 3),
+            Err(HuffmanError::Read(_))
+        ));
+    }
+
+    #[test]
+    fn test_multi_table_lengths_roundtrip() {
+        let mtf = two_regimes_mtf();
+        let encoder = MultiTableEncoder::new(&mtf);
+        let mut writer = BitWriter::new();
+        for lengths in encoder.code_lengths() {
+            serialize_lengths(lengths, &mut writer).unwrap();
+        }
+        let data = writer.into_bytes();
+        let mut reader = BitReader::new(&data);
+        for lengths in encoder.code_lengths() {
+            assert_eq!(
+                &deserialize_lengths(&mut reader, lengths.len()).unwrap(),
+                lengths
+            );
+        }
+    }
+
+    /// `bz2.compress(b"".join(bytes([97 + i % 26]) * (1 + i % 3) for i in range(300)))` from
+    /// Python, made by the reference library, whose block switches tables between its groups
+    const ALPHABET_RUNS_BZ2: &[u8] = &[
+        0x42, 0x5a, 0x68, 0x39, 0x31, 0x41, 0x59, 0x26, 0x53, 0x59, 0xc4, 0xf4, 0x25, 0x5b, 0x00,
+        0x00, 0x09, 0x81, 0x80, 0x3f, 0xff, 0xff, 0xf0, 0x30, 0x00, 0xd8, 0x68, 0xa0, 0x00, 0x00,
+        0xc9, 0x90, 0x22, 0x55, 0x18, 0x00, 0x00, 0x00, 0xa9, 0xaa, 0xa8, 0xd0, 0x34, 0x64, 0x00,
+        0x7a, 0x9c, 0x08, 0x5b, 0xaf, 0x61, 0xbb, 0x44, 0x46, 0xe4, 0x22, 0x37, 0x70, 0x88, 0xdd,
+        0xe2, 0x23, 0x78, 0x08, 0x8d, 0xe2, 0x22, 0x37, 0x90, 0x88, 0xdc, 0xc4, 0x46, 0xf3, 0x11,
+        0x1b, 0xd0, 0x44, 0x6e, 0x82, 0x23, 0x75, 0x11, 0x1d, 0x10, 0xb6, 0x8b, 0x67, 0xa8, 0xb5,
+        0xb3, 0xdb, 0x68, 0xb6, 0x7b, 0xed, 0x16, 0xcf, 0x81, 0x6b, 0x26, 0xeb, 0x02, 0x29, 0xc2,
+        0xc0, 0x8a, 0x72, 0x04, 0x62, 0x9d, 0x2c, 0x08, 0xa7, 0x6b, 0x02, 0x39, 0xfc, 0x2d, 0x0e,
+        0x0b, 0x44, 0x5b, 0x82, 0x2d, 0x16, 0xb7, 0xf8, 0xbb, 0x92, 0x29, 0xc2, 0x84, 0x86, 0x27,
+        0xa1, 0x2a, 0xd8,
+    ];
+
+    /// Selectors of reference blocks, read from the bit offset of their number of tables, which
+    /// the number of selectors and the selectors follow
+    #[test_case(HELLO_WORLD_BZ2, 217 => (2, vec![1], vec![1]); "hello world")]
+    #[test_case(ALPHABET_RUNS_BZ2, 185 => (3, vec![2, 0, 0, 0, 2, 1], vec![2, 2, 2, 2, 1, 2]); "alphabet runs")]
+    fn test_selectors_of_reference_block(data: &[u8], start: u64) -> (usize, Vec<u8>, Vec<u8>) {
+        let mut reader = BitReader::new(data);
+        reader.skip(start).unwrap();
+        let tables = reader.read_bits(3).unwrap() as usize;
+        let count = reader.read_bits(15).unwrap() as usize;
+        let selectors_start = reader.position();
+        let selectors = deserialize_selectors(&mut reader, count, tables).unwrap();
+        let selectors_len = reader.position() - selectors_start;
+
+        // writing them again gives the same bits
+        let mut writer = BitWriter::new();
+        serialize_selectors(&selectors, tables, &mut writer).unwrap();
+        assert_eq!(writer.bit_len(), selectors_len);
+        assert_eq!(
+            read_bits(&writer.into_bytes(), 0, selectors_len),
+            read_bits(data, selectors_start, selectors_len)
+        );
+        let positions = mtf_selectors(&selectors, tables).unwrap();
+        assert_eq!(unmtf_selectors(&positions, tables).unwrap(), selectors);
+        (tables, positions, selectors)
+    }
+
+    #[test_case(&[], 2 => Vec::<u8>::new(); "none")]
+    #[test_case(&[0, 0, 0], 2 => vec![0, 0, 0]; "same table")]
+    #[test_case(&[1, 1, 0, 1], 2 => vec![1, 0, 1, 1]; "back and forth")]
+    #[test_case(&[5, 4, 5, 0], 6 => vec![5, 5, 1, 2]; "six tables")]
+    fn test_mtf_selectors(selectors: &[u8], tables: usize) -> Vec<u8> {
+        let positions = mtf_selectors(selectors, tables).unwrap();
+        assert_eq!(unmtf_selectors(&positions, tables).unwrap(), selectors);
+        positions
+    }
+
+    #[test]
+    fn test_multi_table_selectors_roundtrip() {
+        let mtf = two_regimes_mtf();
+        let encoder = MultiTableEncoder::new(&mtf);
+        let mut writer = BitWriter::new();
+        serialize_selectors(encoder.selectors(), encoder.num_tables(), &mut writer).unwrap();
+        let data = writer.into_bytes();
+        let mut reader = BitReader::new(&data);
+        let count = encoder.selectors().len();
+        assert_eq!(
+            deserialize_selectors(&mut reader, count, encoder.num_tables()).unwrap(),
+            encoder.selectors()
+        );
+    }
+
+    #[test]
+    fn test_invalid_selectors() {
+        assert_eq!(
+            mtf_selectors(&[0, 2], 2),
+            Err(HuffmanError::InvalidSelector {
+                selector: 2,
+                tables: 2
+            })
+        );
+        assert_eq!(
+            unmtf_selectors(&[0, 3], 3),
+            Err(HuffmanError::InvalidSelector {
+                selector: 3,
+                tables: 3
+            })
+        );
+    }
+
+    #[test_case(&[0b1110_0000], 2 => matches Err(HuffmanError::InvalidSelector { selector: 2, tables: 2 }); "too many ones")]
+    #[test_case(&[0b0101_0101], 5 => matches Err(HuffmanError::Read(_)); "truncated")]
+    fn test_deserialize_invalid_selectors(
+        data: &[u8],
+        count: usize,
+    ) -> Result<Vec<u8>, HuffmanError> {
+        deserialize_selectors(&mut BitReader::new(data), count, 2)
+    }
+
+    #[test_case(&[2, 1, 3, 3]; "ordered by length")]
+    #[test_case(&[3, 0, 3, 2, 2, 2]; "unused symbol")]
+    #[test_case(&HELLO_WORLD_LENGTHS[1]; "reference table")]
+    fn test_decode_symbols(lengths: &[u8]) {
+        let codes = CanonicalCodes::from_lengths(lengths);
+        let symbols: Vec<Symbol> = codes.iter().map(|(symbol, _)| symbol).collect();
+        let mut writer = BitWriter::new();
+        for &symbol in symbols.iter().rev().chain(&symbols) {
+            let code = codes.get(symbol).unwrap();
+            writer.write_bits(code.code(), code.width() as u32);
+        }
+        let data = writer.into_bytes();
+        let mut reader = BitReader::new(&data);
+        let decoder = HuffmanDecoder::from_lengths(lengths);
+        for symbol in symbols.iter().rev().chain(&symbols) {
+            assert_eq!(decoder.decode_symbol(&mut reader), Ok(*symbol));
+        }
+    }
+
+    #[test_case(vec![], vec![]; "empty")]
+    #[test_case(vec![t::RUNA, t::RUNA, t::RUNB], vec![0]; "one run")]
+    #[test_case(vec![1, 2, 3, t::RUNA, t::RUNA, t::RUNB], vec![1, 10, 42, 100]; "run at end")]
+    #[test_case(vec![t::RUNA, 1, t::RUNA], vec![97, 98]; "runa at end")]
+    #[test_case(vec![t::RUNA, 1, 2, 1], vec![97, 98, 99]; "value at end")]
+    fn test_decode_until_eob(indices: Vec<usize>, stack: Vec<u8>) {
+        let mtf = get_mtf(indices, stack);
+        let encoder = HuffmanEncoder::new(&mtf);
+        let mut writer = BitWriter::new();
+        encoder.encode_into(&mtf, &mut writer);
+        // the same block again right after it, which decoding must not run into
+        encoder.encode_into(&mtf, &mut writer);
+        let data = writer.into_bytes();
+
+        let decoder = HuffmanDecoder::from_lengths(encoder.code_lengths());
+        let mut reader = BitReader::new(&data);
+        assert_eq!(decoder.decode(&mut reader).as_ref(), Ok(mtf.indices()));
+        assert_eq!(reader.position(), encoder.encode(&mtf).bit_len());
+    }
+
+    #[test]
+    fn test_decode_missing_eob() {
+        let mtf = get_mtf(
+            vec![1, 2, 3, t::RUNA, t::RUNA, t::RUNB],
+            vec![1, 10, 42, 100],
+        );
+        let encoder = HuffmanEncoder::new(&mtf);
+        let coded = encoder.encode(&mtf);
+        // without the code of EOB that ends the block, the padding zeros decode as something else
+        let eob_len = *encoder.code_lengths().last().unwrap() as u64;
+        let mut writer = BitWriter::new();
+        let mut reader = BitReader::new(coded.data());
+        for _ in 0..coded.bit_len() - eob_len {
+            writer.write_bits(reader.read_bit().unwrap() as u32, 1);
+        }
+        let data = writer.into_bytes();
+        let decoder = HuffmanDecoder::from_lengths(encoder.code_lengths());
+        assert!(matches!(
+            decoder.decode(&mut BitReader::new(&data)),
+            Err(HuffmanError::Read(_))
+        ));
+    }
+
+    #[test]
+    fn test_decode_invalid_code() {
+        // 1 and 2 bit codes only use up half of the code space
+        let decoder = HuffmanDecoder::from_lengths(&[1, 2]);
+        let mut reader = BitReader::new(&[0b1111_1111, 0b1111_1111, 0b1111_1111]);
+        assert_eq!(
+            decoder.decode_symbol(&mut reader),
+            Err(HuffmanError::InvalidCode)
+        );
+        let mut reader = BitReader::new(&[0b1000_0000]);
+        assert_eq!(decoder.decode_symbol(&mut reader), Ok(sym(1)));
+        assert_eq!(decoder.decode_symbol(&mut reader), Ok(sym(0)));
+    }
+
+    #[test]
+    fn test_decode_truncated() {
+        let decoder = HuffmanDecoder::from_lengths(&[1, 2, 2]);
+        let mut reader = BitReader::new(&[]);
+        assert!(matches!(
+            decoder.decode_symbol(&mut reader),
+            Err(HuffmanError::Read(_))
+        ));
     }
 }
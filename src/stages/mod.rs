@@ -1,4 +1,58 @@
 pub mod bwt;
+pub mod fm_index;
 pub mod huff;
 pub mod mtf;
+#[cfg(feature = "std")]
+pub mod pipeline;
 pub mod rle;
+pub mod symbol;
+
+#[cfg(test)]
+pub(crate) mod counting_alloc;
+#[cfg(test)]
+pub(crate) mod strategies;
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::{
+        bwt::BwtEncoded, counting_alloc as alloc, mtf::MtfTransform, rle::RleSequence, strategies,
+    };
+
+    /// Handing the buffers on from stage to stage saves the last column the BWT allocates and the
+    /// copy of it the MTF stage reads
+    #[test]
+    fn test_owned_handoff_saves_copies() {
+        let rle = RleSequence::encode(&b"the quick brown fox jumps over the lazy dog ".repeat(6));
+        let block_len = rle.len();
+
+        let (borrowed, borrowed_bytes) = alloc::count_bytes(|| {
+            let bwt = BwtEncoded::encode(&rle);
+            (bwt.original_index(), MtfTransform::encode(&bwt.data()))
+        });
+        let rle = RleSequence::from_encoded(rle.sequence().to_vec());
+        let (owned, owned_bytes) = alloc::count_bytes(|| {
+            let bwt = BwtEncoded::encode_owned(rle);
+            (bwt.original_index(), MtfTransform::from_bwt_owned(bwt))
+        });
+
+        assert_eq!(owned, borrowed);
+        assert!(owned_bytes + 2 * block_len <= borrowed_bytes);
+    }
+
+    proptest! {
+        #[test]
+        fn prop_pipeline_roundtrip(data in strategies::bytes(256)) {
+            let rle = RleSequence::encode(&data);
+            let bwt = BwtEncoded::encode(&rle);
+            let mtf = MtfTransform::from_bwt(&bwt);
+
+            let decoded = BwtEncoded::new(mtf.decode().unwrap(), bwt.original_index())
+                .decode()
+                .decode()
+                .unwrap();
+            prop_assert_eq!(decoded, data);
+        }
+    }
+}
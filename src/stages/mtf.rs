@@ -1,4 +1,8 @@
-use std::collections::BTreeSet;
+use alloc::{vec, vec::Vec};
+
+use thiserror::Error;
+
+use crate::bwt::BwtEncoded;
 
 #[derive(Debug, PartialEq, Eq)]
 pub enum MtfIndex {
@@ -7,35 +11,129 @@ pub enum MtfIndex {
     Val(u8),
 }
 
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum MtfError {
+    #[error("Byte {0} appears more than once in the stack")]
+    DuplicateStackByte(u8),
+    #[error("Stack is not sorted in ascending order")]
+    UnsortedStack,
+    #[error("Index {index} is out of bounds for a stack of {stack_len} bytes")]
+    IndexOutOfBounds { index: u8, stack_len: usize },
+    #[error("Stack is empty but there are indices to decode")]
+    EmptyStack,
+    #[error("Runs expand to more than usize::MAX bytes")]
+    RunLengthOverflow,
+}
+
 #[derive(Debug, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(try_from = "MtfParts")
+)]
 pub struct MtfTransform {
     pub(crate) indices: Vec<MtfIndex>,
     pub(crate) stack: Vec<u8>,
 }
 
+/// Unchecked fields of a deserialized `MtfTransform`, validated by `MtfTransform::from_parts`
+#[cfg(feature = "serde")]
+#[derive(serde::Deserialize)]
+struct MtfParts {
+    indices: Vec<MtfIndex>,
+    stack: Vec<u8>,
+}
+
+#[cfg(feature = "serde")]
+impl TryFrom<MtfParts> for MtfTransform {
+    type Error = MtfError;
+
+    fn try_from(parts: MtfParts) -> Result<Self, MtfError> {
+        Self::from_parts(parts.indices, parts.stack)
+    }
+}
+
+/// An `MtfIndex` is serialized as the value of its `Symbol`: 0 for RUNA, 1 for RUNB and `i + 1` for
+/// the index `i`. Index 0 is always part of a run, so it has no value of its own and can't be
+/// serialized.
+#[cfg(feature = "serde")]
+impl serde::Serialize for MtfIndex {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if *self == MtfIndex::Val(0) {
+            return Err(serde::ser::Error::custom(
+                "MTF index 0 can only be serialized as part of a run",
+            ));
+        }
+        serializer.serialize_u16(crate::symbol::Symbol::from(self).value())
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for MtfIndex {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        match u16::deserialize(deserializer)? {
+            0 => Ok(MtfIndex::RunA),
+            1 => Ok(MtfIndex::RunB),
+            value @ 2..=256 => Ok(MtfIndex::Val((value - 1) as u8)),
+            value => Err(serde::de::Error::custom(format!(
+                "{value} is not the symbol of an MTF index"
+            ))),
+        }
+    }
+}
+
 impl MtfTransform {
     /// Perform an MTF Transform on the passed data. As part of the transform, a second RLE
     /// pass is also performed. The "BZIP2: Format Specification" handbook says about this:
     /// "In practice, most implementations will combine the MTF and RLE2 stages"
     /// https://github.com/dsnet/compress/blob/39efe44ab707ffd2c1ef32cc7dbebfe584718686/doc/bzip2-format.pdf
-    /// So that's what we're doing here:
+    /// So that's what we're doing here.
+    ///
+    /// `data` is the last column of a BWT, `from_bwt` takes the transform itself. The original
+    /// index isn't part of the MTF transform, `pipeline::MtfBlock` carries it on to the container.
     pub fn encode(data: &[u8]) -> Self {
-        // TODO: think long and hard if the input to the decode shouldn't rather be a BwtEncoded
+        let mut mtf = Self {
+            indices: Vec::with_capacity(data.len()),
+            stack: Vec::new(),
+        };
+        Self::encode_into(data, &mut mtf);
+        mtf
+    }
+
+    /// Same as `encode` on the last column of `bwt`, read in place instead of copied out of the
+    /// transform with `BwtEncoded::data`
+    pub fn from_bwt(bwt: &BwtEncoded) -> Self {
+        Self::encode(bwt.last_column())
+    }
+
+    /// Same as `from_bwt`, but takes the transform over. Read the original index before handing
+    /// it over.
+    pub fn from_bwt_owned(bwt: BwtEncoded) -> Self {
+        Self::encode(&bwt.into_data())
+    }
+
+    /// Same as `encode`, but writes the result into `out`, reusing its buffers.
+    pub fn encode_into(data: &[u8], out: &mut Self) {
+        out.indices.clear();
+        out.stack.clear();
         if data.is_empty() {
-            return Self::empty();
+            return;
         }
 
-        let unique_bytes: BTreeSet<u8> = data.iter().copied().collect();
-        let stack: Vec<u8> = unique_bytes.into_iter().collect();
+        extend_stack(data, &mut out.stack);
+
+        let mut stack_buf = [0u8; 256];
+        let working_stack = &mut stack_buf[..out.stack.len()];
+        working_stack.copy_from_slice(&out.stack);
 
-        // MTF Transform
-        let mut working_stack = stack.clone();
-        let mut mtf_indices = Vec::with_capacity(data.len());
+        // MTF Transform and RLE2 encoding in one pass: zero indices are only counted and get
+        // emitted as RUNA/RUNB once the next non-zero index comes along
+        let mut run_length = 0;
         let mut current_byte = None;
 
         for &byte in data {
             if current_byte == Some(byte) {
-                mtf_indices.push(0);
+                run_length += 1;
                 continue;
             }
 
@@ -44,82 +142,123 @@ impl MtfTransform {
                 .iter()
                 .position(|&x| x == byte)
                 .expect("Byte must exist in the stack");
-            if position > 0 {
-                // TODO: Check if this is really more performant than using a VecDeque with remove() and push_front()
-                let val = working_stack[position];
-                working_stack[0..=position].rotate_right(1);
-                working_stack[0] = val;
+            current_byte = Some(byte);
+            if position == 0 {
+                run_length += 1;
+                continue;
             }
 
-            mtf_indices.push(position as u8);
-            current_byte = Some(byte);
-        }
+            // TODO: Check if this is really more performant than using a VecDeque with remove() and push_front()
+            working_stack[0..=position].rotate_right(1);
+            working_stack[0] = byte;
 
-        // RLE2 encoding
-        let mut indices: Vec<MtfIndex> = Vec::with_capacity(mtf_indices.len());
-        for chunk in mtf_indices.chunk_by(|&a, &b| (a == 0 && b == 0) || (a != 0 && b != 0)) {
-            if chunk[0] == 0 {
-                emit_run(chunk.len(), &mut indices);
-            } else {
-                indices.extend(chunk.iter().map(|&i| MtfIndex::Val(i)));
-            }
+            emit_run(run_length, &mut out.indices);
+            run_length = 0;
+            out.indices.push(MtfIndex::Val(position as u8));
         }
 
-        Self { indices, stack }
+        emit_run(run_length, &mut out.indices);
     }
 
-    pub fn decode(&self) -> Vec<u8> {
+    /// Undo the transform. Unlike a transform from `encode` or `from_parts`, one built from
+    /// unchecked parts may hold indices past the end of its stack or runs that don't fit into
+    /// memory, which fail here instead of panicking.
+    pub fn decode(&self) -> Result<Vec<u8>, MtfError> {
         if self.is_empty() {
-            return Vec::new();
+            return Ok(Vec::new());
         }
 
-        // RLE2 decoding pass
-        let mut mtf_indices = Vec::with_capacity(self.indices.len() * 2); // TODO: Find a better way to estimate required capacity
-        let mut run_length = 0;
-        let mut power = 1;
+        let mut result = Vec::with_capacity(self.decoded_len()?);
+        let mut stack_buf = [0u8; 256];
+        let working_stack = &mut stack_buf[..self.stack.len()];
+        working_stack.copy_from_slice(&self.stack);
+
+        // RLE2 and MTF decoding in one pass: a run always repeats the byte at the front of the
+        // stack, so it can be written out as soon as the next non-run index comes along
+        let mut run = Run::default();
 
         for idx in &self.indices {
             match idx {
-                MtfIndex::RunA => {
-                    run_length += power;
-                    // TODO: test potential overflow of power here
-                    power <<= 1;
-                }
-                MtfIndex::RunB => {
-                    run_length += power * 2;
-                    power <<= 1;
-                }
+                MtfIndex::RunA | MtfIndex::RunB => run.push(idx)?,
                 MtfIndex::Val(found_index) => {
-                    if run_length > 0 {
-                        mtf_indices.extend(std::iter::repeat(0).take(run_length));
-                        run_length = 0;
-                        power = 1;
-                    }
-                    mtf_indices.push(*found_index);
+                    emit_decoded_run(run.take(), working_stack, &mut result)?;
+                    let idx = *found_index as usize;
+                    let symbol = *working_stack.get(idx).ok_or(MtfError::IndexOutOfBounds {
+                        index: *found_index,
+                        stack_len: self.stack.len(),
+                    })?;
+                    working_stack[0..=idx].rotate_right(1);
+                    working_stack[0] = symbol;
+                    result.push(symbol);
                 }
             }
         }
 
-        if run_length > 0 {
-            mtf_indices.extend(std::iter::repeat(0).take(run_length));
-        }
+        emit_decoded_run(run.take(), working_stack, &mut result)?;
+        Ok(result)
+    }
 
-        // MTF decoding pass
-        let mut result = Vec::with_capacity(mtf_indices.len());
-        let mut working_stack = self.stack.clone();
+    /// Number of bytes `decode` produces, computed from the indices alone without decoding them.
+    /// Fails if the runs add up to more than `usize::MAX` bytes.
+    pub fn decoded_len(&self) -> Result<usize, MtfError> {
+        let mut len: usize = 0;
+        let mut run_position = 0;
 
-        for idx in mtf_indices.iter().map(|&i| i as usize) {
-            let symbol = working_stack.get(idx).expect("Invalid index in MTF decode");
-            result.push(*symbol);
+        for idx in &self.indices {
+            let weight = match idx {
+                MtfIndex::RunA => 1,
+                MtfIndex::RunB => 2,
+                MtfIndex::Val(_) => {
+                    run_position = 0;
+                    len = len.checked_add(1).ok_or(MtfError::RunLengthOverflow)?;
+                    continue;
+                }
+            };
+            // the n-th symbol of a run stands for `weight * 2^n` zero indices
+            len = 1usize
+                .checked_shl(run_position)
+                .and_then(|power| power.checked_mul(weight))
+                .and_then(|run| len.checked_add(run))
+                .ok_or(MtfError::RunLengthOverflow)?;
+            run_position += 1;
+        }
 
-            if idx > 0 {
-                let val = working_stack[idx];
-                working_stack[0..=idx].rotate_right(1);
-                working_stack[0] = val;
-            }
+        Ok(len)
+    }
+
+    /// Build an MTF transform from indices and stack that come from an untrusted source, e.g. a
+    /// decoded block, whose symbol map gives the stack as its used symbols. The stack has to be
+    /// strictly ascending (which also means free of duplicates), it may only be empty if there
+    /// are no indices, and every `Val` has to point into it.
+    pub fn from_parts(indices: Vec<MtfIndex>, stack: Vec<u8>) -> Result<Self, MtfError> {
+        if let Some(pair) = stack.windows(2).find(|pair| pair[0] >= pair[1]) {
+            return Err(if pair[0] == pair[1] {
+                MtfError::DuplicateStackByte(pair[0])
+            } else {
+                MtfError::UnsortedStack
+            });
         }
+        if stack.is_empty() && !indices.is_empty() {
+            return Err(MtfError::EmptyStack);
+        }
+        let out_of_bounds = indices.iter().find_map(|idx| match idx {
+            MtfIndex::Val(i) if *i as usize >= stack.len() => Some(*i),
+            _ => None,
+        });
+        if let Some(index) = out_of_bounds {
+            return Err(MtfError::IndexOutOfBounds {
+                index,
+                stack_len: stack.len(),
+            });
+        }
+
+        Ok(Self::from_parts_unchecked(indices, stack))
+    }
 
-        result
+    /// Build an MTF transform without checking the invariants of `from_parts`. Only for parts that
+    /// are known to be valid, decoding an invalid transform fails.
+    pub(crate) fn from_parts_unchecked(indices: Vec<MtfIndex>, stack: Vec<u8>) -> Self {
+        Self { indices, stack }
     }
 
     pub fn empty() -> Self {
@@ -141,11 +280,146 @@ impl MtfTransform {
         self.stack.len()
     }
 
+    /// The bytes that occur in the encoded data in ascending order, i.e. the initial stack. This
+    /// is the set the symbol map of a block transmits, `from_parts` takes it back as the stack.
+    pub fn used_symbols(&self) -> &[u8] {
+        &self.stack
+    }
+
     pub fn indices(&self) -> &Vec<MtfIndex> {
         &self.indices
     }
 }
 
+/// Sorted stack of the bytes that occur in `data`, the initial stack of both MTF passes.
+pub fn stack(data: &[u8]) -> Vec<u8> {
+    let mut stack = Vec::new();
+    extend_stack(data, &mut stack);
+    stack
+}
+
+fn extend_stack(data: &[u8], out: &mut Vec<u8>) {
+    // mark every byte that occurs, then scanning the table in order yields the sorted stack
+    let mut present = [false; 256];
+    for &byte in data {
+        present[byte as usize] = true;
+    }
+    out.extend((0..=u8::MAX).filter(|&byte| present[byte as usize]));
+}
+
+/// Plain MTF pass without RLE2: the index of every byte of `data` in a stack that starts out as
+/// `stack(data)`. `MtfTransform::encode` is the same as `rle2_pass(&mtf_pass(data))` with that
+/// stack, done in a single pass.
+pub fn mtf_pass(data: &[u8]) -> Vec<u8> {
+    let mut working_stack = stack(data);
+    data.iter()
+        .map(|&byte| {
+            let position = working_stack
+                .iter()
+                .position(|&x| x == byte)
+                .expect("Byte must exist in the stack");
+            working_stack[0..=position].rotate_right(1);
+            position as u8
+        })
+        .collect()
+}
+
+/// Inverse of `mtf_pass`, `stack` being the stack the indices refer to.
+pub fn inverse_mtf_pass(indices: &[u8], stack: &[u8]) -> Result<Vec<u8>, MtfError> {
+    let mut working_stack = stack.to_vec();
+    indices
+        .iter()
+        .map(|&index| {
+            let idx = index as usize;
+            let byte = *working_stack.get(idx).ok_or(MtfError::IndexOutOfBounds {
+                index,
+                stack_len: stack.len(),
+            })?;
+            working_stack[0..=idx].rotate_right(1);
+            Ok(byte)
+        })
+        .collect()
+}
+
+/// RLE2 pass on plain MTF indices: every run of zeros becomes its RUNA/RUNB representation, any
+/// other index is kept as is.
+pub fn rle2_pass(indices: &[u8]) -> Vec<MtfIndex> {
+    let mut out = Vec::with_capacity(indices.len());
+    let mut run_length = 0;
+    for &idx in indices {
+        if idx == 0 {
+            run_length += 1;
+            continue;
+        }
+        emit_run(run_length, &mut out);
+        run_length = 0;
+        out.push(MtfIndex::Val(idx));
+    }
+    emit_run(run_length, &mut out);
+    out
+}
+
+/// Inverse of `rle2_pass`, expanding RUNA/RUNB back into runs of zeros.
+pub fn inverse_rle2_pass(indices: &[MtfIndex]) -> Result<Vec<u8>, MtfError> {
+    let mut out = Vec::with_capacity(indices.len());
+    let mut run = Run::default();
+    for idx in indices {
+        match idx {
+            MtfIndex::RunA | MtfIndex::RunB => run.push(idx)?,
+            MtfIndex::Val(found_index) => {
+                out.extend(core::iter::repeat_n(0, run.take()));
+                out.push(*found_index);
+            }
+        }
+    }
+    out.extend(core::iter::repeat_n(0, run.take()));
+    Ok(out)
+}
+
+/// Length of a run of zero indices, summed up from its RUNA/RUNB symbols
+#[derive(Default)]
+struct Run {
+    len: usize,
+    /// Number of symbols of the run so far, the next one stands for `weight * 2^position` zeros
+    position: u32,
+}
+
+impl Run {
+    fn push(&mut self, idx: &MtfIndex) -> Result<(), MtfError> {
+        let weight = match idx {
+            MtfIndex::RunA => 1usize,
+            _ => 2,
+        };
+        self.len = 1usize
+            .checked_shl(self.position)
+            .and_then(|power| power.checked_mul(weight))
+            .and_then(|run| self.len.checked_add(run))
+            .ok_or(MtfError::RunLengthOverflow)?;
+        self.position += 1;
+        Ok(())
+    }
+
+    /// The length of the run, starting the next one
+    fn take(&mut self) -> usize {
+        core::mem::take(self).len
+    }
+}
+
+/// Write a decoded run of zero indices, i.e. `run_length` copies of the byte at the front of the
+/// stack
+#[inline(always)]
+fn emit_decoded_run(
+    run_length: usize,
+    working_stack: &[u8],
+    out: &mut Vec<u8>,
+) -> Result<(), MtfError> {
+    if run_length > 0 {
+        let symbol = *working_stack.first().ok_or(MtfError::EmptyStack)?;
+        out.extend(core::iter::repeat_n(symbol, run_length));
+    }
+    Ok(())
+}
+
 #[inline(always)]
 fn emit_run(mut run_length: usize, out: &mut Vec<MtfIndex>) {
     while run_length > 0 {
@@ -168,11 +442,16 @@ pub(crate) mod t {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::{
+        rle::RleSequence,
+        stages::{counting_alloc as alloc, strategies},
+    };
+    use proptest::prelude::*;
     use test_case::test_case;
 
     #[test_case(b"" => (vec![], vec![]); "empty")]
-    #[test_case(&vec![0] => (vec![t::RUNA], vec![0]); "zero")]
-    #[test_case(&vec![0, 0, 0, 0, 0, 0] => (vec![t::RUNB, t::RUNB], vec![0]); "zeroes")]
+    #[test_case(&[0] => (vec![t::RUNA], vec![0]); "zero")]
+    #[test_case(&[0, 0, 0, 0, 0, 0] => (vec![t::RUNB, t::RUNB], vec![0]); "zeroes")]
     #[test_case(b"a" => (vec![t::RUNA], vec![97]); "single byte")]
     #[test_case(b"abcdefg" => (vec![t::RUNA, 1, 2, 3, 4, 5, 6], vec![97, 98, 99, 100, 101, 102, 103]); "all unique bytes")]
     #[test_case(b"gab" => (vec![2, 1, 2], vec![97, 98, 103]); "no runs")]
@@ -225,8 +504,244 @@ mod tests {
                 .collect(),
             stack,
         };
-        mtf.decode()
+        let decoded = mtf.decode().unwrap();
+        assert_eq!(mtf.decoded_len(), Ok(decoded.len()));
+        decoded
+    }
+
+    #[test]
+    fn test_mtf_decode_allocates_once() {
+        // 20 RUNAs stand for a run of 2^20 - 1 bytes
+        let mut indices: Vec<MtfIndex> = (0..20).map(|_| MtfIndex::RunA).collect();
+        indices.extend([MtfIndex::Val(1), MtfIndex::RunB]);
+        let mtf = MtfTransform::from_parts(indices, vec![97, 98]).unwrap();
+
+        let (decoded, allocations) = alloc::count(|| mtf.decode().unwrap());
+        assert_eq!(allocations, 1);
+        assert_eq!(decoded.len(), (1 << 20) - 1 + 1 + 2);
+        assert_eq!(decoded.capacity(), decoded.len());
+        assert_eq!(mtf.decoded_len(), Ok(decoded.len()));
+    }
+
+    fn indices(symbols: &[usize]) -> Vec<MtfIndex> {
+        symbols
+            .iter()
+            .map(|&i| match i {
+                t::RUNA => MtfIndex::RunA,
+                t::RUNB => MtfIndex::RunB,
+                v => MtfIndex::Val(v as u8),
+            })
+            .collect()
+    }
+
+    #[test_case(vec![t::RUNA, 2], vec![97, 98] => MtfError::IndexOutOfBounds { index: 2, stack_len: 2 }; "index equal to stack length")]
+    #[test_case(vec![1, 255], vec![97, 98, 99] => MtfError::IndexOutOfBounds { index: 255, stack_len: 3 }; "largest index")]
+    #[test_case(vec![t::RUNA], vec![] => MtfError::EmptyStack; "run without stack")]
+    #[test_case(vec![1], vec![] => MtfError::IndexOutOfBounds { index: 1, stack_len: 0 }; "index without stack")]
+    #[test_case(vec![t::RUNB; usize::BITS as usize], vec![97] => MtfError::RunLengthOverflow; "too many runbs")]
+    fn test_mtf_decode_malformed(symbols: Vec<usize>, stack: Vec<u8>) -> MtfError {
+        MtfTransform::from_parts_unchecked(indices(&symbols), stack)
+            .decode()
+            .unwrap_err()
+    }
+
+    #[test_case(&[0, 3], &[97, 98] => MtfError::IndexOutOfBounds { index: 3, stack_len: 2 }; "past the end")]
+    #[test_case(&[0], &[] => MtfError::IndexOutOfBounds { index: 0, stack_len: 0 }; "empty stack")]
+    fn test_inverse_mtf_pass_malformed(indices: &[u8], stack: &[u8]) -> MtfError {
+        inverse_mtf_pass(indices, stack).unwrap_err()
+    }
+
+    #[test_case(usize::BITS, t::RUNB => Err(MtfError::RunLengthOverflow); "too many runbs")]
+    #[test_case(usize::BITS + 1, t::RUNA => Err(MtfError::RunLengthOverflow); "too many runas")]
+    #[test_case(3, t::RUNA => Ok(vec![0; 7]); "short run")]
+    fn test_inverse_rle2_pass_overflow(
+        run_symbols: u32,
+        symbol: usize,
+    ) -> Result<Vec<u8>, MtfError> {
+        inverse_rle2_pass(&indices(&vec![symbol; run_symbols as usize]))
+    }
+
+    #[test_case(usize::BITS - 1, t::RUNA => Ok((1 << (usize::BITS - 1)) - 1); "largest power of two run")]
+    #[test_case(usize::BITS, t::RUNA => Ok(usize::MAX); "run of usize max")]
+    #[test_case(usize::BITS + 1, t::RUNA => Err(MtfError::RunLengthOverflow); "too many runas")]
+    #[test_case(usize::BITS, t::RUNB => Err(MtfError::RunLengthOverflow); "too many runbs")]
+    fn test_decoded_len_overflow(run_symbols: u32, symbol: usize) -> Result<usize, MtfError> {
+        let indices = (0..run_symbols)
+            .map(|_| match symbol {
+                t::RUNA => MtfIndex::RunA,
+                _ => MtfIndex::RunB,
+            })
+            .collect();
+        MtfTransform::from_parts(indices, vec![0])?.decoded_len()
+    }
+
+    #[test_case(b"a"; "single byte")]
+    #[test_case(b"aaaaabbbbbccccc"; "repeated blocks")]
+    #[test_case(b"bbyaeeeeeeafeeeybzzzzzzzzzyz"; "bbyaeeeeeeafeeeybzzzzzzzzzyz")]
+    fn test_mtf_encode_into_reuses_buffers(data: &[u8]) {
+        let mut mtf = MtfTransform::encode(b"zyxwvutsrqponmlkjihgfedcba");
+        let (indices, stack) = (mtf.indices.as_ptr(), mtf.stack.as_ptr());
+        MtfTransform::encode_into(data, &mut mtf);
+        assert_eq!(mtf, MtfTransform::encode(data));
+        assert_eq!(mtf.indices.as_ptr(), indices);
+        assert_eq!(mtf.stack.as_ptr(), stack);
+    }
+
+    #[test]
+    fn test_from_bwt_reads_column_in_place() {
+        let bwt = BwtEncoded::encode(&RleSequence::encode(&b"banana bandana ".repeat(40)));
+        let (mtf, bytes) = alloc::count_bytes(|| MtfTransform::from_bwt(&bwt));
+        assert_eq!(mtf, MtfTransform::encode(&bwt.data()));
+        // the indices and the stack, but no copy of the column
+        assert!(bytes < bwt.last_column().len() * size_of::<MtfIndex>() + 256);
+    }
+
+    #[test]
+    fn test_mtf_encode_all_bytes() {
+        let data: Vec<u8> = (0..=u8::MAX).rev().chain(0..=u8::MAX).collect();
+        let mtf = MtfTransform::encode(&data);
+        assert_eq!(mtf.stack, (0..=u8::MAX).collect::<Vec<_>>());
+        assert_eq!(mtf.decode(), Ok(data));
+    }
+
+    #[test]
+    fn test_mtf_encode_1mb() {
+        let data: Vec<u8> = (0..1 << 20)
+            .map(|i: u32| (i.wrapping_mul(2_654_435_761) >> 26) as u8)
+            .collect();
+        let mtf = MtfTransform::encode(&data);
+        assert_eq!(mtf.stack, (0..64).collect::<Vec<_>>());
+        assert_eq!(mtf.decode(), Ok(data));
+    }
+
+    #[test_case(vec![], vec![] => Ok(()); "empty")]
+    #[test_case(vec![t::RUNA, t::RUNB], vec![97] => Ok(()); "runs only")]
+    #[test_case(vec![t::RUNA, 1, 2, t::RUNA], vec![97, 98, 99] => Ok(()); "highest index")]
+    #[test_case(vec![], vec![97] => Ok(()); "stack without indices")]
+    #[test_case(vec![t::RUNA, 1], vec![97, 98, 98] => Err(MtfError::DuplicateStackByte(98)); "duplicate byte in stack")]
+    #[test_case(vec![t::RUNA, 1], vec![97, 99, 98] => Err(MtfError::UnsortedStack); "unsorted stack")]
+    #[test_case(vec![t::RUNA, 3], vec![97, 98, 99] => Err(MtfError::IndexOutOfBounds { index: 3, stack_len: 3 }); "index equal to stack length")]
+    #[test_case(vec![t::RUNA], vec![] => Err(MtfError::EmptyStack); "indices without stack")]
+    fn test_from_parts(indices: Vec<usize>, stack: Vec<u8>) -> Result<(), MtfError> {
+        let indices: Vec<MtfIndex> = indices
+            .iter()
+            .map(|&i| match i {
+                t::RUNA => MtfIndex::RunA,
+                t::RUNB => MtfIndex::RunB,
+                v => MtfIndex::Val(v as u8),
+            })
+            .collect();
+        MtfTransform::from_parts(indices, stack).map(|_| ())
+    }
+
+    #[cfg(feature = "serde")]
+    #[test_case(b""; "empty")]
+    #[test_case(b"bbyaeeeeeeafeeeybzzzzzzzzzyz"; "bbyaeeeeeeafeeeybzzzzzzzzzyz")]
+    fn test_serde_roundtrip(data: &[u8]) {
+        let mtf = MtfTransform::encode(data);
+        let json = serde_json::to_string(&mtf).unwrap();
+        assert_eq!(serde_json::from_str::<MtfTransform>(&json).unwrap(), mtf);
+        let bytes = bincode::serialize(&mtf).unwrap();
+        assert_eq!(bincode::deserialize::<MtfTransform>(&bytes).unwrap(), mtf);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serialize_indices_as_symbols() {
+        let mtf = MtfTransform::encode(b"aaaaabbbbbccccc");
+        assert_eq!(
+            serde_json::to_string(&mtf).unwrap(),
+            r#"{"indices":[0,1,2,1,0,3,1,0],"stack":[97,98,99]}"#
+        );
+        assert!(serde_json::to_string(&MtfIndex::Val(0)).is_err());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test_case(r#"{"indices":[0,2],"stack":[97,98,98]}"#; "duplicate byte in stack")]
+    #[test_case(r#"{"indices":[0,2],"stack":[98,97]}"#; "unsorted stack")]
+    #[test_case(r#"{"indices":[0,4],"stack":[97,98]}"#; "index out of bounds")]
+    #[test_case(r#"{"indices":[0,257],"stack":[97,98]}"#; "not a symbol")]
+    fn test_deserialize_invalid(json: &str) {
+        assert!(serde_json::from_str::<MtfTransform>(json).is_err());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_deserialize_duplicate_stack_bincode() {
+        let mtf = MtfTransform::from_parts_unchecked(vec![MtfIndex::RunA], vec![97, 97]);
+        let bytes = bincode::serialize(&mtf).unwrap();
+        assert!(bincode::deserialize::<MtfTransform>(&bytes).is_err());
+    }
+
+    #[test_case(b"" => Vec::<u8>::new(); "empty")]
+    #[test_case(b"banana" => vec![1, 1, 2, 1, 1, 1]; "banana")]
+    #[test_case(b"aaab" => vec![0, 0, 0, 1]; "leading run")]
+    #[test_case(b"abccba" => vec![0, 1, 2, 0, 1, 2]; "back and forth")]
+    fn test_mtf_pass(data: &[u8]) -> Vec<u8> {
+        let indices = mtf_pass(data);
+        assert_eq!(inverse_mtf_pass(&indices, &stack(data)), Ok(data.to_vec()));
+        indices
+    }
+
+    #[test_case(vec![] => Vec::<usize>::new(); "empty")]
+    #[test_case(vec![1, 1, 2, 1, 1, 1] => vec![1, 1, 2, 1, 1, 1]; "banana")]
+    #[test_case(vec![0] => vec![t::RUNA]; "single zero")]
+    #[test_case(vec![0; 5] => vec![t::RUNA, t::RUNB]; "five zeros")]
+    #[test_case(vec![0; 6] => vec![t::RUNB, t::RUNB]; "six zeros")]
+    #[test_case(vec![0; 7] => vec![t::RUNA, t::RUNA, t::RUNA]; "seven zeros")]
+    #[test_case(vec![0, 0, 0, 1] => vec![t::RUNA, t::RUNA, 1]; "run before index")]
+    fn test_rle2_pass(indices: Vec<u8>) -> Vec<usize> {
+        let encoded = rle2_pass(&indices);
+        assert_eq!(inverse_rle2_pass(&encoded), Ok(indices));
+        encoded
+            .iter()
+            .map(|i| match i {
+                MtfIndex::RunA => t::RUNA,
+                MtfIndex::RunB => t::RUNB,
+                MtfIndex::Val(v) => *v as usize,
+            })
+            .collect()
     }
 
-    // TODO: tests with corrupted data, e.g. indexes out of bounds
+    proptest! {
+        #[test]
+        fn prop_fused_equals_separate_passes(data in strategies::bytes(4096)) {
+            let mtf = MtfTransform::encode(&data);
+            prop_assert_eq!(&mtf.indices, &rle2_pass(&mtf_pass(&data)));
+            prop_assert_eq!(&mtf.stack, &stack(&data));
+            prop_assert_eq!(
+                mtf.decode(),
+                inverse_rle2_pass(&mtf.indices)
+                    .and_then(|indices| inverse_mtf_pass(&indices, &mtf.stack))
+            );
+        }
+
+        #[test]
+        fn prop_roundtrip(data in strategies::bytes(4096)) {
+            let mtf = MtfTransform::encode(&data);
+            prop_assert_eq!(mtf.decoded_len(), Ok(data.len()));
+            prop_assert_eq!(mtf.decode(), Ok(data));
+        }
+
+        #[test]
+        fn prop_encoded_parts_are_valid(data in strategies::bytes(4096)) {
+            let MtfTransform { indices, stack } = MtfTransform::encode(&data);
+            prop_assert!(MtfTransform::from_parts(indices, stack).is_ok());
+        }
+
+        #[test]
+        fn prop_stack_strictly_increasing(data in strategies::bytes(4096)) {
+            let mtf = MtfTransform::encode(&data);
+            prop_assert!(mtf.stack.windows(2).all(|pair| pair[0] < pair[1]));
+        }
+    }
+
+    proptest! {
+        #![proptest_config(strategies::large_input_config())]
+
+        #[test]
+        fn prop_roundtrip_near_block_size(data in strategies::near_block_size()) {
+            prop_assert_eq!(MtfTransform::encode(&data).decode(), Ok(data));
+        }
+    }
 }
@@ -0,0 +1,417 @@
+//! The stages of a block as types that only fit together in the order bzip2 runs them.
+//!
+//! Every stage is a marker type implementing `Stage`, whose `Input` is the `Output` of the stage
+//! before it, so a pipeline in the wrong order doesn't compile:
+//!
+//! `Rle1::encode(raw).then::<Bwt>().then::<Mtf>().then::<Huffman>()`
+//!
+//! `StageInverse::decode` undoes a stage, going back from a `HuffmanBlock` to the raw bytes takes
+//! the `decode` of every stage in reverse order.
+
+use crate::{
+    bits::BitReader,
+    bwt::BwtEncoded,
+    format::{block_size::CompressionLevel, blocks::BlockSplitter},
+    huff::{
+        Frequencies, HuffmanDecoder, HuffmanEncodedResult, HuffmanError, MultiTableEncoder,
+        GROUP_SIZE,
+    },
+    mtf::MtfTransform,
+    rle::RleSequence,
+    symbol::Symbol,
+    Result,
+};
+
+/// One stage of the pipeline, taking the output of the stage before it
+pub trait Stage {
+    type Input;
+    type Output;
+
+    fn encode(input: Self::Input) -> Self::Output;
+}
+
+/// A stage that can be undone
+pub trait StageInverse: Stage {
+    /// Undo the stage. Outputs that no `encode` produces, e.g. ones built from corrupt data, fail
+    /// with the error of the step that notices.
+    fn decode(output: Self::Output) -> Result<Self::Input>;
+}
+
+/// The output of a stage, which can be handed on to the next one
+pub trait StageOutput: Sized {
+    /// Run the stage `S`, which has to be the one that takes this output
+    fn then<S: Stage<Input = Self>>(self) -> S::Output {
+        S::encode(self)
+    }
+}
+
+/// RLE1, from the raw bytes of a block to an `RleSequence`
+pub enum Rle1 {}
+
+/// Burrows-Wheeler transform of the RLE1 sequence
+pub enum Bwt {}
+
+/// Move-to-front transform and RLE2 of the last column of the BWT
+pub enum Mtf {}
+
+/// Huffman coding of the MTF indices with several tables, see `MultiTableEncoder`
+pub enum Huffman {}
+
+/// An MTF transform together with the original index of the BWT it came from, which the MTF
+/// stage passes through for the inverse BWT
+#[derive(Debug, PartialEq, Eq)]
+pub struct MtfBlock {
+    mtf: MtfTransform,
+    original_index: usize,
+}
+
+impl MtfBlock {
+    pub fn mtf(&self) -> &MtfTransform {
+        &self.mtf
+    }
+
+    pub fn original_index(&self) -> usize {
+        self.original_index
+    }
+}
+
+/// Everything the Huffman stage makes of a block: the coded symbols, the tables and selectors to
+/// decode them with, the bytes of the MTF stack and the original index of the BWT. These are the
+/// parts a block of a `.bz2` stream is made of, besides its CRC.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HuffmanBlock {
+    coded: HuffmanEncodedResult,
+    code_lengths: Vec<Vec<u8>>,
+    selectors: Vec<u8>,
+    stack: Vec<u8>,
+    original_index: usize,
+}
+
+impl HuffmanBlock {
+    pub fn coded(&self) -> &HuffmanEncodedResult {
+        &self.coded
+    }
+
+    /// Code lengths of every table, see `MultiTableEncoder::code_lengths`
+    pub fn code_lengths(&self) -> &[Vec<u8>] {
+        &self.code_lengths
+    }
+
+    pub fn selectors(&self) -> &[u8] {
+        &self.selectors
+    }
+
+    /// The bytes of the block, in ascending order
+    pub fn stack(&self) -> &[u8] {
+        &self.stack
+    }
+
+    pub fn original_index(&self) -> usize {
+        self.original_index
+    }
+}
+
+impl StageOutput for RleSequence {}
+impl StageOutput for BwtEncoded {}
+impl StageOutput for MtfBlock {}
+impl StageOutput for HuffmanBlock {}
+
+impl Stage for Rle1 {
+    type Input = Vec<u8>;
+    type Output = RleSequence;
+
+    fn encode(raw: Vec<u8>) -> RleSequence {
+        RleSequence::encode(&raw)
+    }
+}
+
+impl StageInverse for Rle1 {
+    fn decode(sequence: RleSequence) -> Result<Vec<u8>> {
+        Ok(sequence.try_decode(true)?)
+    }
+}
+
+impl Stage for Bwt {
+    type Input = RleSequence;
+    type Output = BwtEncoded;
+
+    fn encode(sequence: RleSequence) -> BwtEncoded {
+        BwtEncoded::encode_owned(sequence)
+    }
+}
+
+impl StageInverse for Bwt {
+    fn decode(bwt: BwtEncoded) -> Result<RleSequence> {
+        Ok(bwt.decode())
+    }
+}
+
+impl Stage for Mtf {
+    type Input = BwtEncoded;
+    type Output = MtfBlock;
+
+    fn encode(bwt: BwtEncoded) -> MtfBlock {
+        let original_index = bwt.original_index();
+        MtfBlock {
+            mtf: MtfTransform::from_bwt_owned(bwt),
+            original_index,
+        }
+    }
+}
+
+impl StageInverse for Mtf {
+    fn decode(block: MtfBlock) -> Result<BwtEncoded> {
+        Ok(BwtEncoded::try_new(
+            block.mtf.decode()?,
+            block.original_index,
+        )?)
+    }
+}
+
+impl Stage for Huffman {
+    type Input = MtfBlock;
+    type Output = HuffmanBlock;
+
+    fn encode(block: MtfBlock) -> HuffmanBlock {
+        let encoder = MultiTableEncoder::new(&block.mtf);
+        HuffmanBlock {
+            coded: encoder.encode(&block.mtf),
+            code_lengths: encoder.code_lengths().to_vec(),
+            selectors: encoder.selectors().to_vec(),
+            stack: block.mtf.used_symbols().to_vec(),
+            original_index: block.original_index,
+        }
+    }
+}
+
+impl StageInverse for Huffman {
+    fn decode(block: HuffmanBlock) -> Result<MtfBlock> {
+        let alphabet_size = Symbol::alphabet_size(block.stack.len());
+        let decoders: Vec<HuffmanDecoder> = block
+            .code_lengths
+            .iter()
+            .map(|lengths| HuffmanDecoder::from_lengths(lengths))
+            .collect();
+        // the table of every symbol, switching every `GROUP_SIZE` symbols
+        let mut tables = block
+            .selectors
+            .iter()
+            .flat_map(|&selector| std::iter::repeat_n(&decoders[selector as usize], GROUP_SIZE));
+        let mut reader = BitReader::new(block.coded.data());
+        let mut indices = Vec::new();
+        loop {
+            let Some(decoder) = tables.next() else {
+                return Err(HuffmanError::MissingEob.into());
+            };
+            match decoder
+                .decode_symbol(&mut reader)?
+                .to_mtf_index(alphabet_size)?
+            {
+                Some(index) => indices.push(index),
+                None => break,
+            }
+        }
+        Ok(MtfBlock {
+            mtf: MtfTransform::from_parts(indices, block.stack)?,
+            original_index: block.original_index,
+        })
+    }
+}
+
+/// What the stages made of every block of an input, see `analyze`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct PipelineReport {
+    pub blocks: Vec<BlockReport>,
+}
+
+/// What the stages made of one block
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct BlockReport {
+    /// Bytes of the block before RLE1
+    pub len: usize,
+    /// Bytes after RLE1, the length of the BWT
+    pub rle1_len: usize,
+    /// Original index of the BWT, the origin pointer of the block header
+    pub original_index: usize,
+    /// Number of symbols the MTF stage codes the block with: RUNA, RUNB, the bytes of the stack
+    /// but the first one, and EOB
+    pub alphabet_size: usize,
+    /// Number of RUNA and RUNB symbols, the digits RLE2 writes the runs of zeros of the MTF with
+    pub run_a: u32,
+    pub run_b: u32,
+    /// How often every symbol occurs, indexed by symbol value, EOB included
+    pub symbol_histogram: Vec<u32>,
+    /// Code lengths of every Huffman table, see `MultiTableEncoder::code_lengths`
+    pub code_lengths: Vec<Vec<u8>>,
+}
+
+/// Run the stages on every block of `data`, split the way `compress` splits it with
+/// `CompressionLevel::BEST`, and report what each stage made of the block. Nothing is written,
+/// the report is for looking into the stages, e.g. how many runs of zeros the BWT and MTF left
+/// in an input.
+pub fn analyze(data: &[u8]) -> PipelineReport {
+    let blocks = BlockSplitter::new(data, CompressionLevel::BEST.block_size())
+        .map(analyze_block)
+        .collect();
+    PipelineReport { blocks }
+}
+
+fn analyze_block(block: &[u8]) -> BlockReport {
+    let sequence = Rle1::encode(block.to_vec());
+    let rle1_len = sequence.len();
+    let mtf_block = sequence.then::<Bwt>().then::<Mtf>();
+    let original_index = mtf_block.original_index();
+
+    let symbol_histogram = Frequencies::build(mtf_block.mtf()).counts().to_vec();
+
+    let huffman_block = mtf_block.then::<Huffman>();
+    BlockReport {
+        len: block.len(),
+        rle1_len,
+        original_index,
+        alphabet_size: symbol_histogram.len(),
+        run_a: symbol_histogram[0],
+        run_b: symbol_histogram[1],
+        symbol_histogram,
+        code_lengths: huffman_block.code_lengths,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+    use test_case::test_case;
+
+    use super::*;
+    use crate::{mtf::MtfIndex, rle::RleError, stages::strategies, Error};
+
+    fn pipeline(raw: &[u8]) -> HuffmanBlock {
+        Rle1::encode(raw.to_vec())
+            .then::<Bwt>()
+            .then::<Mtf>()
+            .then::<Huffman>()
+    }
+
+    fn inverse(block: HuffmanBlock) -> Result<Vec<u8>> {
+        let mtf = Huffman::decode(block)?;
+        let bwt = Mtf::decode(mtf)?;
+        let sequence = Bwt::decode(bwt)?;
+        Rle1::decode(sequence)
+    }
+
+    #[test_case(b""; "empty")]
+    #[test_case(b"a"; "single byte")]
+    #[test_case(b"hello world\n"; "hello world")]
+    #[test_case(&[b'a'; 1000]; "one long run")]
+    #[test_case(include_bytes!("pipeline.rs"); "source code")]
+    fn test_roundtrip(raw: &[u8]) {
+        assert_eq!(inverse(pipeline(raw)), Ok(raw.to_vec()));
+    }
+
+    #[test]
+    fn test_same_as_stages_by_hand() {
+        let raw = b"the quick brown fox jumps over the lazy dog ".repeat(6);
+        let bwt = BwtEncoded::encode(&RleSequence::encode(&raw));
+        let mtf = MtfTransform::from_bwt(&bwt);
+        let encoder = MultiTableEncoder::new(&mtf);
+
+        let block = pipeline(&raw);
+        assert_eq!(block.original_index(), bwt.original_index());
+        assert_eq!(block.stack(), mtf.used_symbols());
+        assert_eq!(block.selectors(), encoder.selectors());
+        assert_eq!(block.code_lengths(), encoder.code_lengths());
+        assert_eq!(block.coded(), &encoder.encode(&mtf));
+    }
+
+    #[test]
+    fn test_analyze_same_as_stages_by_hand() {
+        let raw = b"the quick brown fox jumps over the lazy dog ".repeat(6);
+        let sequence = RleSequence::encode(&raw);
+        let bwt = BwtEncoded::encode(&sequence);
+        let mtf = MtfTransform::from_bwt(&bwt);
+        let encoder = MultiTableEncoder::new(&mtf);
+
+        let report = analyze(&raw);
+        assert_eq!(report.blocks.len(), 1);
+        let block = &report.blocks[0];
+        assert_eq!(block.len, raw.len());
+        assert_eq!(block.rle1_len, sequence.len());
+        assert_eq!(block.original_index, bwt.original_index());
+        assert_eq!(block.alphabet_size, Symbol::alphabet_size(mtf.num_stack()));
+        assert_eq!(block.symbol_histogram.len(), block.alphabet_size);
+        assert_eq!(
+            block.symbol_histogram.iter().sum::<u32>() as usize,
+            mtf.len() + 1
+        );
+        let count = |index: MtfIndex| mtf.indices().iter().filter(|&i| *i == index).count();
+        assert_eq!(block.run_a as usize, count(MtfIndex::RunA));
+        assert_eq!(block.run_b as usize, count(MtfIndex::RunB));
+        assert_eq!(block.symbol_histogram[2] as usize, count(MtfIndex::Val(1)));
+        assert_eq!(block.symbol_histogram.last(), Some(&1));
+        assert_eq!(block.code_lengths, encoder.code_lengths());
+    }
+
+    #[test_case(b"" => Vec::<usize>::new(); "empty")]
+    #[test_case(b"hello world\n" => vec![12]; "hello world")]
+    #[test_case(&(0..=255).cycle().take(1_000_000).collect::<Vec<u8>>() => vec![900_000, 100_000]; "two blocks")]
+    fn test_analyze_blocks(data: &[u8]) -> Vec<usize> {
+        let report = analyze(data);
+        let info = crate::verify(crate::compress(data, CompressionLevel::BEST).as_slice()).unwrap();
+        assert_eq!(report.blocks.len() as u64, info.blocks);
+        report.blocks.iter().map(|block| block.len).collect()
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_report_serde_roundtrip() {
+        let report = analyze(b"hello world\n");
+        let json = serde_json::to_string(&report).unwrap();
+        assert!(json.contains("\"original_index\""));
+        assert_eq!(
+            serde_json::from_str::<PipelineReport>(&json).unwrap(),
+            report
+        );
+    }
+
+    #[test]
+    fn test_missing_eob() {
+        let mut block = pipeline(b"hello world\n");
+        // the symbols are all there, but no selector says how to decode them
+        block.selectors.clear();
+        assert_eq!(
+            inverse(block),
+            Err(Error::Huffman(HuffmanError::MissingEob))
+        );
+    }
+
+    #[test]
+    fn test_invalid_original_index() {
+        let mut block = pipeline(b"hello world\n");
+        block.original_index = 12;
+        assert_eq!(
+            inverse(block),
+            Err(Error::IndexOutOfBounds { index: 12, len: 12 })
+        );
+    }
+
+    #[test]
+    fn test_invalid_run_count() {
+        let sequence = RleSequence::from_encoded(b"aaaa\xfc".to_vec());
+        assert_eq!(
+            Rle1::decode(sequence),
+            Err(Error::Rle(RleError::InvalidRunCount {
+                value: 252,
+                position: 4
+            }))
+        );
+    }
+
+    proptest! {
+        #[test]
+        fn prop_roundtrip(raw in strategies::bytes(1024)) {
+            prop_assert_eq!(inverse(pipeline(&raw)), Ok(raw));
+        }
+    }
+}
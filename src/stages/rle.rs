@@ -1,15 +1,69 @@
+//! RLE1, the run-length encoding bzip2 applies to the raw input before anything else.
+//!
+//! Runs of four to 255 equal bytes are written as four of the byte and a count byte with the
+//! number of further repeats. An `RleSequence` always holds bytes in this encoded form: raw input
+//! goes in through `RleSequence::encode` or `RleEncoder`, bytes that are already encoded, e.g.
+//! from an inverse BWT, through `TryFrom` or `RleSequence::from_encoded`, and `decode` turns the
+//! sequence back into raw bytes.
+
+use alloc::{vec, vec::Vec};
+
+use thiserror::Error;
+
+/// Largest count byte a conforming encoder writes after four equal bytes: runs are at most 255
+/// bytes long, four of which are written out.
+pub const MAX_RUN_COUNT: u8 = 251;
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum RleError {
+    #[error("Run count {value} at position {position} is larger than {MAX_RUN_COUNT}")]
+    InvalidRunCount { value: u8, position: usize },
+    #[error("Run of four bytes at position {position} is missing its count byte")]
+    TruncatedRun { position: usize },
+}
+
+/// RLE1-encoded bytes, the input of the BWT stage.
+///
+/// There is deliberately no `From<Vec<u8>>`: a `Vec<u8>` could be raw input as well as encoded
+/// bytes, and taking one for the other silently corrupts the data. Raw input has to go through
+/// `encode`, encoded bytes through `TryFrom`, which checks them, or `from_encoded`, which doesn't.
+///
+/// With the `serde` feature, deserializing goes through `TryFrom<Vec<u8>>` as well.
 #[derive(Debug, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(try_from = "Vec<u8>")
+)]
 pub struct RleSequence(Vec<u8>);
 
 impl RleSequence {
+    /// Take bytes that are already RLE1-encoded as they are, without checking them. Errors in them
+    /// only show up when decoding, which is what a decompressor wants for the output of the
+    /// inverse BWT, since the block CRC has the last word on it anyway.
+    pub fn from_encoded(sequence: Vec<u8>) -> Self {
+        Self(sequence)
+    }
+
+    /// Length of the encoded sequence, not of the data it decodes to
     pub fn len(&self) -> usize {
         self.0.len()
     }
 
+    /// The encoded bytes
     pub fn sequence(&self) -> &[u8] {
         &self.0
     }
 
+    pub fn into_sequence(self) -> Vec<u8> {
+        self.0
+    }
+
+    /// The buffer `encode_into` writes to, for reserving its capacity ahead of time
+    pub(crate) fn buffer_mut(&mut self) -> &mut Vec<u8> {
+        &mut self.0
+    }
+
     pub fn is_empty(&self) -> bool {
         self.0.is_empty()
     }
@@ -18,77 +72,206 @@ impl RleSequence {
         Self(vec![])
     }
 
+    /// Encode the raw bytes `data`
     pub fn encode(data: &[u8]) -> Self {
-        // worst case is x1.25 if data consists solely of sequences of four (e.g. b"aaaabbbbaaaabbbb")
-        let mut sequence = Vec::with_capacity(data.len() * 125 / 100);
-        let chunks = data.chunk_by(|a, b| a == b);
+        // worst case is x1.25 if data consists solely of sequences of four (e.g. b"aaaabbbbaaaabbbb"),
+        // and since a slice is at most isize::MAX bytes long, 1.25 times its length fits in a usize
+        let mut sequence = Self(Vec::with_capacity(data.len() + data.len() / 4));
+        Self::encode_into(data, &mut sequence);
+        sequence
+    }
+
+    /// Same as `encode`, but writes the result into `out`, reusing its buffer.
+    pub fn encode_into(data: &[u8], out: &mut Self) {
+        out.0.clear();
+        for chunk in data.chunk_by(|a, b| a == b) {
+            emit_run(chunk[0], chunk.len(), &mut out.0);
+        }
+    }
+
+    /// Decode the sequence back into the raw bytes, trusting every count byte, even the ones no
+    /// encoder writes. Only a run of four bytes at the very end, whose count byte is missing, is
+    /// an error.
+    pub fn decode(&self) -> Result<Vec<u8>, RleError> {
+        self.try_decode(false)
+    }
+
+    /// Decode the sequence. With `strict`, a count byte above `MAX_RUN_COUNT` is an error
+    /// instead of being expanded: no conforming encoder writes one, so it can only come from
+    /// a corrupted or crafted stream.
+    pub fn try_decode(&self, strict: bool) -> Result<Vec<u8>, RleError> {
+        let mut data = Vec::new();
+        for_each_run(&self.0, strict, |byte, len| match len {
+            1 => data.push(byte),
+            len => data.extend(core::iter::repeat_n(byte, len)),
+        })?;
+        Ok(data)
+    }
+
+    /// Check the sequence the way a strict `try_decode` does, without decoding it, and return
+    /// the length of the decoded data.
+    ///
+    /// Every count byte has to follow four equal bytes and be at most `MAX_RUN_COUNT`. A count
+    /// byte after fewer repeats can't be told apart from a literal byte, so that kind of damage
+    /// is left to the block CRC.
+    pub fn validate(&self) -> Result<usize, RleError> {
+        let mut len = 0;
+        for_each_run(&self.0, true, |_, run_len| len += run_len)?;
+        Ok(len)
+    }
+}
 
-        for chunk in chunks {
-            let value = chunk[0];
-            let mut remaining_length = chunk.len();
+/// Call `emit` with the byte and length of every run `sequence` decodes to, in order
+fn for_each_run(
+    sequence: &[u8],
+    strict: bool,
+    mut emit: impl FnMut(u8, usize),
+) -> Result<(), RleError> {
+    let mut iter = sequence.iter().enumerate();
 
-            while remaining_length > 0 {
-                let run_length = remaining_length.min(255);
+    let mut consecutive_count = 0;
+    let mut last_byte = None;
 
-                if run_length < 4 {
-                    sequence.extend(std::iter::repeat(value).take(run_length));
-                } else {
-                    sequence.extend(std::iter::repeat(value).take(4));
-                    sequence.push((run_length - 4) as u8);
-                }
+    while let Some((position, &byte)) = iter.next() {
+        emit(byte, 1);
+        if Some(byte) == last_byte {
+            consecutive_count += 1;
+        } else {
+            consecutive_count = 1;
+            last_byte = Some(byte);
+        }
 
-                remaining_length -= run_length;
+        if consecutive_count == 4 {
+            let Some((position, &run_length)) = iter.next() else {
+                return Err(RleError::TruncatedRun {
+                    position: position - 3,
+                });
+            };
+            if strict && run_length > MAX_RUN_COUNT {
+                return Err(RleError::InvalidRunCount {
+                    value: run_length,
+                    position,
+                });
             }
+            emit(byte, run_length as usize);
+            consecutive_count = 0;
+            last_byte = None;
         }
-        Self(sequence)
     }
 
-    pub fn decode(&self) -> Vec<u8> {
-        let mut data = Vec::new();
-        let mut iter = self.0.iter();
+    Ok(())
+}
 
-        let mut consecutive_count = 0;
-        let mut last_byte = None;
+/// Incremental RLE1 encoder for input that arrives in pieces, e.g. read from a `BufReader`.
+///
+/// A run that straddles the boundary between two pushed chunks is carried over instead of being
+/// emitted twice, so the result of `finish` is the same as `RleSequence::encode` on the
+/// concatenated input, no matter how it was chunked.
+///
+/// Complete runs of 255 bytes are written as soon as they are seen, so the pending run stays
+/// short no matter how long the input is. The encoded sequence itself is kept until `finish`,
+/// so the encoder takes memory in proportion to its output: it is meant for one block at a
+/// time, not for a whole stream.
+#[derive(Debug, Default)]
+pub struct RleEncoder {
+    sequence: Vec<u8>,
+    run_value: u8,
+    run_length: usize,
+    input_len: u64,
+}
+
+impl RleEncoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
 
-        while let Some(&byte) = iter.next() {
-            data.push(byte);
-            if Some(byte) == last_byte {
-                consecutive_count += 1;
+    pub fn push(&mut self, data: &[u8]) {
+        self.input_len += data.len() as u64;
+        for chunk in data.chunk_by(|a, b| a == b) {
+            if self.run_length > 0 && self.run_value == chunk[0] {
+                self.run_length += chunk.len();
             } else {
-                consecutive_count = 1;
-                last_byte = Some(byte);
+                emit_run(self.run_value, self.run_length, &mut self.sequence);
+                self.run_value = chunk[0];
+                self.run_length = chunk.len();
             }
+            let complete_runs = self.run_length - self.run_length % 255;
+            emit_run(self.run_value, complete_runs, &mut self.sequence);
+            self.run_length -= complete_runs;
+        }
+    }
 
-            if consecutive_count == 4 {
-                if let Some(&run_length) = iter.next() {
-                    data.extend(std::iter::repeat(byte).take(run_length as usize));
-                }
-                consecutive_count = 0;
-                last_byte = None;
-            }
+    /// Number of bytes pushed so far. Counted in a `u64`, since a stream can be longer than
+    /// `usize::MAX` bytes on 32-bit targets.
+    pub fn input_len(&self) -> u64 {
+        self.input_len
+    }
+
+    /// Emit the pending run and return the encoded sequence.
+    pub fn finish(mut self) -> RleSequence {
+        emit_run(self.run_value, self.run_length, &mut self.sequence);
+        RleSequence(self.sequence)
+    }
+}
+
+/// Write a run of `run_length` times `value` to `out`, splitting it into runs of at most 255 bytes
+#[inline(always)]
+fn emit_run(value: u8, mut remaining_length: usize, out: &mut Vec<u8>) {
+    while remaining_length > 0 {
+        let run_length = remaining_length.min(255);
+
+        if run_length < 4 {
+            out.extend(core::iter::repeat_n(value, run_length));
+        } else {
+            let count = (run_length - 4) as u8;
+            debug_assert!(count <= MAX_RUN_COUNT);
+            out.extend(core::iter::repeat_n(value, 4));
+            out.push(count);
         }
 
-        data
+        remaining_length -= run_length;
     }
 }
 
-impl From<&[u8]> for RleSequence {
-    fn from(data: &[u8]) -> Self {
-        Self(data.to_vec())
+/// Take bytes that are already RLE1-encoded, if they pass `RleSequence::validate`
+impl TryFrom<Vec<u8>> for RleSequence {
+    type Error = RleError;
+
+    fn try_from(sequence: Vec<u8>) -> Result<Self, RleError> {
+        let sequence = Self(sequence);
+        sequence.validate()?;
+        Ok(sequence)
+    }
+}
+
+/// Same as `TryFrom<Vec<u8>>`, copying the bytes
+impl TryFrom<&[u8]> for RleSequence {
+    type Error = RleError;
+
+    fn try_from(sequence: &[u8]) -> Result<Self, RleError> {
+        sequence.to_vec().try_into()
     }
 }
 
-impl From<Vec<u8>> for RleSequence {
-    fn from(data: Vec<u8>) -> Self {
-        Self(data)
+impl From<RleSequence> for Vec<u8> {
+    fn from(sequence: RleSequence) -> Self {
+        sequence.0
+    }
+}
+
+impl AsRef<[u8]> for RleSequence {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use proptest::prelude::*;
     use test_case::test_case;
 
     use super::*;
+    use crate::stages::strategies;
 
     #[test_case(&[] => RleSequence(Vec::<u8>::new()); "empty")]
     #[test_case(b"aaaaa" => RleSequence(vec![b'a', b'a', b'a', b'a', 1]); "five same bytes")]
@@ -123,7 +306,9 @@ mod tests {
     #[test_case(RleSequence(vec![b'a', b'a', b'a', b'a', 251]) => [b'a'; 255].to_vec(); "long run")]
     #[test_case(RleSequence(vec![b'a', b'a', b'a', b'a', 251, b'a']) => [b'a'; 256].to_vec(); "overlong run")]
     fn test_rle_decode(seq: RleSequence) -> Vec<u8> {
-        seq.decode()
+        let decoded = seq.decode().unwrap();
+        assert_eq!(seq.validate(), Ok(decoded.len()));
+        decoded
     }
 
     #[test_case(&[]; "empty")]
@@ -141,6 +326,171 @@ mod tests {
     #[test_case(&[b'a'; 255]; "long run")]
     #[test_case(&[b'a'; 256]; "overlong run")]
     fn test_roundtrip(data: &[u8]) {
-        assert_eq!(data, RleSequence::encode(data).decode());
+        assert_eq!(RleSequence::encode(data).decode(), Ok(data.to_vec()));
+    }
+
+    #[test_case(b"abc".to_vec() => Ok(b"abc".to_vec()); "literals")]
+    #[test_case(vec![b'a', b'a', b'a', b'a', 3] => Ok(vec![b'a', b'a', b'a', b'a', 3]); "run")]
+    #[test_case(vec![b'a', b'a', b'a', b'a', 252] => Err(RleError::InvalidRunCount { value: 252, position: 4 }); "count too large")]
+    #[test_case(vec![b'a', b'a', b'a', b'a'] => Err(RleError::TruncatedRun { position: 0 }); "run without count")]
+    fn test_try_from(sequence: Vec<u8>) -> Result<Vec<u8>, RleError> {
+        assert_eq!(
+            RleSequence::try_from(sequence.as_slice()),
+            RleSequence::try_from(sequence.clone())
+        );
+        RleSequence::try_from(sequence).map(Vec::from)
+    }
+
+    #[test]
+    fn test_raw_and_encoded_bytes_differ() {
+        // five equal raw bytes encode to four and a count, the same bytes as an encoded sequence
+        // are four equal bytes and a count of 97 further repeats
+        let raw = b"aaaaa".to_vec();
+        assert_eq!(RleSequence::encode(&raw).as_ref(), b"aaaa\x01");
+        let encoded = RleSequence::try_from(raw).unwrap();
+        assert_eq!(encoded.decode().map(|data| data.len()), Ok(101));
+    }
+
+    #[test_case(b"aaaaabcdefg"; "repeat plus one at beginning")]
+    #[test_case(b"abcddddd"; "repeat plus one at end")]
+    #[test_case(&[b'a'; 256]; "overlong run")]
+    fn test_encode_into_reuses_buffer(data: &[u8]) {
+        let mut out = RleSequence::encode(b"xyzaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaabc");
+        let buffer = out.0.as_ptr();
+        RleSequence::encode_into(data, &mut out);
+        assert_eq!(out, RleSequence::encode(data));
+        assert_eq!(out.0.as_ptr(), buffer);
+    }
+
+    #[test_case(b"", 1; "empty")]
+    #[test_case(b"aaaaabcdefg", 1; "single bytes")]
+    #[test_case(b"aaaaabcdefg", 2; "run split in the middle")]
+    #[test_case(b"xyzaaaabc", 4; "run split after literals")]
+    #[test_case(b"aaaabbbbaaaa", 3; "runs split unevenly")]
+    #[test_case(&[b'a'; 600], 7; "overlong run over many chunks")]
+    #[test_case(&[b'a'; 600], 255; "overlong run split at max run length")]
+    #[test_case(&[b'a'; 600], 1000; "chunk larger than data")]
+    fn test_incremental_encode(data: &[u8], chunk_size: usize) {
+        let mut encoder = RleEncoder::new();
+        for chunk in data.chunks(chunk_size) {
+            encoder.push(chunk);
+        }
+        assert_eq!(encoder.finish(), RleSequence::encode(data));
+    }
+
+    #[test_case(vec![b'a', b'a', b'a', b'a', 251], true => Ok(255); "largest count strict")]
+    #[test_case(vec![b'a', b'a', b'a', b'a', 252], false => Ok(256); "count 252 permissive")]
+    #[test_case(vec![b'a', b'a', b'a', b'a', 252], true => Err(RleError::InvalidRunCount { value: 252, position: 4 }); "count 252 strict")]
+    #[test_case(vec![b'x', b'b', b'b', b'b', b'b', 255, b'c'], true => Err(RleError::InvalidRunCount { value: 255, position: 5 }); "count 255 strict")]
+    #[test_case(vec![b'a', b'a', b'a', b'a', 0, 255, 255, 255, 255, 3], true => Ok(11); "count bytes look like data")]
+    fn test_try_decode(sequence: Vec<u8>, strict: bool) -> Result<usize, RleError> {
+        RleSequence(sequence)
+            .try_decode(strict)
+            .map(|data| data.len())
+    }
+
+    #[test_case(vec![b'a', b'a', b'a', b'a'] => RleError::TruncatedRun { position: 0 }; "run without count")]
+    #[test_case(vec![b'x', b'a', b'a', b'a', b'a', 2, b'b', b'b', b'b', b'b'] => RleError::TruncatedRun { position: 6 }; "last of several runs")]
+    #[test_case(vec![b'a', b'a', b'a', b'a', 0, b'a', b'a', b'a', b'a'] => RleError::TruncatedRun { position: 5 }; "run after run of the same byte")]
+    fn test_decode_truncated_run(sequence: Vec<u8>) -> RleError {
+        let sequence = RleSequence(sequence);
+        assert_eq!(sequence.try_decode(true), sequence.decode());
+        assert_eq!(sequence.validate(), Err(sequence.decode().unwrap_err()));
+        sequence.decode().unwrap_err()
+    }
+
+    #[test_case(vec![] => Ok(0); "empty")]
+    #[test_case(vec![b'a', b'b', b'b', b'b'] => Ok(4); "three repeats need no count")]
+    #[test_case(vec![b'a', b'a', b'a', b'a', 251, b'a'] => Ok(256); "overlong run")]
+    #[test_case(vec![b'a', b'a', b'a', b'a', 252] => Err(RleError::InvalidRunCount { value: 252, position: 4 }); "count too large")]
+    fn test_validate(sequence: Vec<u8>) -> Result<usize, RleError> {
+        RleSequence(sequence).validate()
+    }
+
+    #[test]
+    fn test_incremental_encode_pending_run_stays_short() {
+        let mut encoder = RleEncoder::new();
+        let chunk = [b'a'; 1000];
+        for _ in 0..100 {
+            encoder.push(&chunk);
+            assert!(encoder.run_length < 255);
+        }
+        assert_eq!(encoder.input_len(), 100_000);
+        assert_eq!(encoder.finish(), RleSequence::encode(&[b'a'; 100_000]));
+    }
+
+    #[test]
+    fn test_incremental_encode_input_len_past_4_gib() {
+        // pretend a little more than 4 GiB have been pushed already
+        let mut encoder = RleEncoder {
+            input_len: u32::MAX as u64,
+            ..RleEncoder::default()
+        };
+        for _ in 0..4 {
+            encoder.push(&[b'a'; 1000]);
+        }
+        assert_eq!(encoder.input_len(), u32::MAX as u64 + 4000);
+        assert_eq!(encoder.finish(), RleSequence::encode(&[b'a'; 4000]));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test_case(b""; "empty")]
+    #[test_case(b"aaaaabbbbbbbbbbbbc"; "runs")]
+    fn test_serde_roundtrip(data: &[u8]) {
+        let sequence = RleSequence::encode(data);
+        let json = serde_json::to_string(&sequence).unwrap();
+        assert_eq!(
+            serde_json::from_str::<RleSequence>(&json).unwrap(),
+            sequence
+        );
+        let bytes = bincode::serialize(&sequence).unwrap();
+        assert_eq!(
+            bincode::deserialize::<RleSequence>(&bytes).unwrap(),
+            sequence
+        );
+    }
+
+    #[cfg(feature = "serde")]
+    #[test_case("[97,97,97,97,252]"; "count byte too large")]
+    #[test_case("[97,97,97,97]"; "missing count byte")]
+    fn test_deserialize_checks_sequence(json: &str) {
+        assert!(serde_json::from_str::<RleSequence>(json).is_err());
+    }
+
+    proptest! {
+        #[test]
+        fn prop_roundtrip(data in strategies::bytes(4096)) {
+            prop_assert_eq!(RleSequence::encode(&data).decode(), Ok(data));
+        }
+
+        #[test]
+        fn prop_encoded_passes_strict_decode(data in strategies::bytes(4096)) {
+            let sequence = RleSequence::encode(&data);
+            prop_assert_eq!(sequence.validate(), Ok(data.len()));
+            prop_assert_eq!(sequence.try_decode(true), Ok(data));
+        }
+
+        #[test]
+        fn prop_validate_agrees_with_decode(sequence in strategies::runs(4096)) {
+            let sequence = RleSequence(sequence);
+            prop_assert_eq!(
+                sequence.validate(),
+                sequence.try_decode(true).map(|data| data.len())
+            );
+        }
+
+        #[test]
+        fn prop_encoded_at_most_125_percent_of_input(data in strategies::bytes(4096)) {
+            prop_assert!(RleSequence::encode(&data).len() * 4 <= data.len() * 5);
+        }
+    }
+
+    proptest! {
+        #![proptest_config(strategies::large_input_config())]
+
+        #[test]
+        fn prop_roundtrip_near_block_size(data in strategies::near_block_size()) {
+            prop_assert_eq!(RleSequence::encode(&data).decode(), Ok(data));
+        }
     }
 }
@@ -0,0 +1,95 @@
+//! proptest strategies shared by the stage tests
+
+use proptest::{collection::vec, prelude::*};
+
+pub mod fixtures;
+
+#[cfg(feature = "std")]
+use crate::CompressionLevel;
+
+/// Blocks are at most this large after RLE1, so inputs around it are interesting for the stages
+/// that work on whole blocks.
+pub const BLOCK_SIZE: usize = 900_000;
+
+/// Runs of random bytes with random lengths, including runs longer than RLE1's maximum of 255
+pub fn runs(max_len: usize) -> impl Strategy<Value = Vec<u8>> {
+    vec((any::<u8>(), 1..=300usize), 0..=max_len.div_ceil(4)).prop_map(move |runs| {
+        let mut data: Vec<u8> = runs
+            .into_iter()
+            .flat_map(|(byte, run_length)| std::iter::repeat_n(byte, run_length))
+            .collect();
+        data.truncate(max_len);
+        data
+    })
+}
+
+/// Bytes from an alphabet of at most four adjacent values
+pub fn few_symbols(max_len: usize) -> impl Strategy<Value = Vec<u8>> {
+    (any::<u8>(), vec(0..4u8, 0..=max_len))
+        .prop_map(|(base, offsets)| offsets.iter().map(|o| base.wrapping_add(*o)).collect())
+}
+
+/// Runs of at least 256 bytes, which RLE1 has to split since a run holds at most 255 repeats
+pub fn long_runs(max_len: usize) -> impl Strategy<Value = Vec<u8>> {
+    vec((any::<u8>(), 256..=2000usize), 0..=max_len.div_ceil(256)).prop_map(move |runs| {
+        let mut data: Vec<u8> = runs
+            .into_iter()
+            .flat_map(|(byte, run_length)| std::iter::repeat_n(byte, run_length))
+            .collect();
+        data.truncate(max_len);
+        data
+    })
+}
+
+/// The same byte over and over, the input with the fewest distinct rotations for the BWT
+pub fn identical(max_len: usize) -> impl Strategy<Value = Vec<u8>> {
+    (any::<u8>(), 0..=max_len).prop_map(|(byte, len)| vec![byte; len])
+}
+
+/// Uniformly random bytes, the full alphabet with (almost) nothing for the stages to exploit
+pub fn high_entropy(max_len: usize) -> impl Strategy<Value = Vec<u8>> {
+    vec(any::<u8>(), 0..=max_len)
+}
+
+/// Whole blocks of every kind the stages treat differently: the mix of `bytes`, plus runs of 256
+/// bytes and more and inputs of a single byte value
+pub fn blocks(max_len: usize) -> impl Strategy<Value = Vec<u8>> {
+    prop_oneof![
+        high_entropy(max_len),
+        runs(max_len),
+        long_runs(max_len),
+        identical(max_len),
+        few_symbols(max_len),
+    ]
+}
+
+/// Any compression level
+#[cfg(feature = "std")]
+pub fn levels() -> impl Strategy<Value = CompressionLevel> {
+    prop::sample::select(CompressionLevel::ALL.to_vec())
+}
+
+/// Mix of uniformly random bytes, long runs and small alphabets of up to `max_len` bytes
+pub fn bytes(max_len: usize) -> impl Strategy<Value = Vec<u8>> {
+    prop_oneof![high_entropy(max_len), runs(max_len), few_symbols(max_len),]
+}
+
+/// Inputs within a few bytes of the block size, made up of long runs so the stages don't take
+/// forever on them
+pub fn near_block_size() -> impl Strategy<Value = Vec<u8>> {
+    (BLOCK_SIZE - 8..=BLOCK_SIZE + 8, any::<u8>(), any::<u8>()).prop_map(|(len, a, b)| {
+        let mut data = vec![a; len];
+        data[len / 2..].fill(b);
+        data
+    })
+}
+
+/// Config for properties over large inputs: only a few cases, and shrinking is bounded since
+/// every shrink step runs a whole block through the stage again.
+pub fn large_input_config() -> ProptestConfig {
+    ProptestConfig {
+        cases: 4,
+        max_shrink_iters: 32,
+        ..ProptestConfig::default()
+    }
+}
@@ -0,0 +1,50 @@
+//! Fixed inputs of a given length, the same bytes on every run and machine, for the tests and
+//! benchmarks that need a particular kind of data rather than arbitrary ones. `benches/` include
+//! this file as a module of their own, so it only uses `std`.
+
+const SEED: u64 = 0x5eed_b212_0000_0001;
+
+const WORDS: &str =
+    "the quick brown fox jumps over the lazy dog bzip2 block sorting compression huffman coding and\n";
+
+/// Small xorshift generator so the inputs are identical on every machine without pulling in `rand`
+struct XorShift(u64);
+
+impl XorShift {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+}
+
+/// Words of `WORDS` in random order, text with a small alphabet that compresses well
+pub fn text(len: usize) -> Vec<u8> {
+    let words: Vec<&str> = WORDS.split_inclusive(' ').collect();
+    let mut rng = XorShift(SEED);
+    let mut data = Vec::with_capacity(len + 16);
+    while data.len() < len {
+        data.extend_from_slice(words[rng.next() as usize % words.len()].as_bytes());
+    }
+    data.truncate(len);
+    data
+}
+
+/// Random bytes, which don't compress at all
+pub fn random(len: usize) -> Vec<u8> {
+    let mut rng = XorShift(SEED);
+    (0..len).map(|_| rng.next() as u8).collect()
+}
+
+/// Runs of 4 to 303 random bytes, which RLE1 shrinks
+pub fn runs(len: usize) -> Vec<u8> {
+    let mut rng = XorShift(SEED);
+    let mut data = Vec::with_capacity(len + 300);
+    while data.len() < len {
+        let run_length = 4 + rng.next() as usize % 300;
+        data.extend(std::iter::repeat_n(rng.next() as u8, run_length));
+    }
+    data.truncate(len);
+    data
+}
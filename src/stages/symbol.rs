@@ -0,0 +1,164 @@
+use thiserror::Error;
+
+use crate::mtf::MtfIndex;
+
+/// Largest possible alphabet: RUNA, RUNB, the MTF indices 1..=255 and EOB
+pub const MAX_ALPHABET_SIZE: usize = 258;
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum SymbolError {
+    #[error("Symbol {0} is larger than any alphabet allows")]
+    Invalid(u16),
+    #[error("Symbol {symbol} is out of range for an alphabet of {alphabet_size} symbols")]
+    OutOfRange { symbol: u16, alphabet_size: usize },
+}
+
+/// A symbol of the alphabet the MTF/RLE2 stage hands to the Huffman stage.
+///
+/// The mapping is the one of the format spec: `RUNA` is 0, `RUNB` is 1, the MTF index `i` is
+/// `i + 1`, and the end of block symbol `EOB` is the last symbol of the alphabet. The alphabet of
+/// a block depends on the size of its MTF stack, see `alphabet_size`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(try_from = "u16")
+)]
+pub struct Symbol(u16);
+
+impl Symbol {
+    pub fn run_a() -> Self {
+        Self(0)
+    }
+
+    pub fn run_b() -> Self {
+        Self(1)
+    }
+
+    pub fn val(index: u8) -> Self {
+        Self(index as u16 + 1)
+    }
+
+    /// End of block symbol for an alphabet of `alphabet_size` symbols
+    pub fn eob(alphabet_size: usize) -> Self {
+        debug_assert!((3..=MAX_ALPHABET_SIZE).contains(&alphabet_size));
+        Self(alphabet_size as u16 - 1)
+    }
+
+    /// Number of symbols in the alphabet of a block whose MTF stack holds `stack_len` bytes, i.e.
+    /// "NumSyms is computed as NumStack - 1 + 3" from the format spec. An empty stack is treated
+    /// like a stack of one byte so the alphabet always has RUNA, RUNB and EOB.
+    pub fn alphabet_size(stack_len: usize) -> usize {
+        stack_len.max(1) + 2
+    }
+
+    pub fn value(self) -> u16 {
+        self.0
+    }
+
+    /// Map the symbol back to the MTF index it stands for, `None` meaning end of block.
+    pub fn to_mtf_index(self, alphabet_size: usize) -> Result<Option<MtfIndex>, SymbolError> {
+        let eob = Self::eob(alphabet_size);
+        match self {
+            _ if self > eob => Err(SymbolError::OutOfRange {
+                symbol: self.0,
+                alphabet_size,
+            }),
+            _ if self == eob => Ok(None),
+            Self(0) => Ok(Some(MtfIndex::RunA)),
+            Self(1) => Ok(Some(MtfIndex::RunB)),
+            Self(v) => Ok(Some(MtfIndex::Val((v - 1) as u8))),
+        }
+    }
+}
+
+impl From<&MtfIndex> for Symbol {
+    fn from(idx: &MtfIndex) -> Self {
+        match idx {
+            MtfIndex::RunA => Self::run_a(),
+            MtfIndex::RunB => Self::run_b(),
+            MtfIndex::Val(i) => Self::val(*i),
+        }
+    }
+}
+
+impl TryFrom<u16> for Symbol {
+    type Error = SymbolError;
+
+    fn try_from(value: u16) -> Result<Self, Self::Error> {
+        if value as usize >= MAX_ALPHABET_SIZE {
+            return Err(SymbolError::Invalid(value));
+        }
+        Ok(Self(value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use test_case::test_case;
+
+    use super::*;
+
+    #[test_case(MtfIndex::RunA => 0; "runa")]
+    #[test_case(MtfIndex::RunB => 1; "runb")]
+    #[test_case(MtfIndex::Val(1) => 2; "smallest index")]
+    #[test_case(MtfIndex::Val(255) => 256; "largest index")]
+    fn test_from_mtf_index(idx: MtfIndex) -> u16 {
+        Symbol::from(&idx).value()
+    }
+
+    #[test_case(0 => 3; "empty stack")]
+    #[test_case(1 => 3; "one byte")]
+    #[test_case(2 => 4; "two bytes")]
+    #[test_case(256 => MAX_ALPHABET_SIZE; "all bytes")]
+    fn test_alphabet_size(stack_len: usize) -> usize {
+        Symbol::alphabet_size(stack_len)
+    }
+
+    #[test_case(1 => 2; "one byte")]
+    #[test_case(256 => 257; "all bytes")]
+    fn test_eob(stack_len: usize) -> u16 {
+        Symbol::eob(Symbol::alphabet_size(stack_len)).value()
+    }
+
+    #[test_case(0, 1 => Ok(Some(MtfIndex::RunA)); "runa")]
+    #[test_case(1, 1 => Ok(Some(MtfIndex::RunB)); "runb")]
+    #[test_case(2, 1 => Ok(None); "eob of one byte stack")]
+    #[test_case(3, 1 => Err(SymbolError::OutOfRange { symbol: 3, alphabet_size: 3 }); "past eob of one byte stack")]
+    #[test_case(2, 2 => Ok(Some(MtfIndex::Val(1))); "index")]
+    #[test_case(256, 256 => Ok(Some(MtfIndex::Val(255))); "largest index")]
+    #[test_case(257, 256 => Ok(None); "eob of full stack")]
+    fn test_to_mtf_index(symbol: u16, stack_len: usize) -> Result<Option<MtfIndex>, SymbolError> {
+        Symbol::try_from(symbol)
+            .unwrap()
+            .to_mtf_index(Symbol::alphabet_size(stack_len))
+    }
+
+    #[test_case(257 => Ok(Symbol(257)); "largest")]
+    #[test_case(258 => Err(SymbolError::Invalid(258)); "too large")]
+    fn test_try_from(value: u16) -> Result<Symbol, SymbolError> {
+        Symbol::try_from(value)
+    }
+
+    #[cfg(feature = "serde")]
+    #[test_case("257" => Ok(Symbol(257)); "largest")]
+    #[test_case("258" => Err(()); "too large")]
+    fn test_deserialize(json: &str) -> Result<Symbol, ()> {
+        serde_json::from_str(json).map_err(|_| ())
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        for stack_len in [1, 2, 100, 256] {
+            let alphabet_size = Symbol::alphabet_size(stack_len);
+            let mut indices = vec![MtfIndex::RunA, MtfIndex::RunB];
+            indices.extend((1..stack_len).map(|i| MtfIndex::Val(i as u8)));
+            for idx in indices {
+                assert_eq!(
+                    Symbol::from(&idx).to_mtf_index(alphabet_size),
+                    Ok(Some(idx))
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,245 @@
+//! `wasm-bindgen` bindings, for web apps that write and read `.bz2` data in the browser. Byte
+//! slices and vectors are `Uint8Array`s on the JavaScript side, errors are thrown as `Error`s.
+//!
+//! The forward BWT sorts the rotations of a block in about five words per byte, so even a
+//! block of 900 000 bytes stays in the low tens of megabytes.
+
+use std::{
+    io::{self, Read, Write},
+    mem,
+};
+
+use wasm_bindgen::prelude::*;
+
+use crate::{BzDecoder, BzEncoder, CompressionLevel, MemoryBudget};
+
+/// Compress `data` into a `.bz2` stream with blocks of `level` times 100 000 bytes, 1 to 9
+#[wasm_bindgen(js_name = compress)]
+pub fn compress(data: &[u8], level: u8) -> Result<Vec<u8>, JsError> {
+    Ok(crate::compress(data, CompressionLevel::try_from(level)?))
+}
+
+/// Decompress `data`, one or more `.bz2` streams back to back
+#[wasm_bindgen(js_name = decompress)]
+pub fn decompress(data: &[u8]) -> Result<Vec<u8>, JsError> {
+    Ok(crate::decompress(data)?)
+}
+
+/// Compresses the chunks pushed into it into one `.bz2` stream, a block at a time, for input
+/// that arrives in pieces or is too large to hold at once
+#[wasm_bindgen]
+pub struct Compressor {
+    encoder: BzEncoder<Vec<u8>>,
+}
+
+#[wasm_bindgen]
+impl Compressor {
+    #[wasm_bindgen(constructor)]
+    pub fn new(level: u8) -> Result<Compressor, JsError> {
+        let level = CompressionLevel::try_from(level)?;
+        Ok(Self {
+            encoder: BzEncoder::new(Vec::new(), level),
+        })
+    }
+
+    /// Add `chunk` to the input and return the compressed bytes of the blocks it completed,
+    /// often none
+    pub fn push(&mut self, chunk: &[u8]) -> Vec<u8> {
+        self.encoder
+            .write_all(chunk)
+            .expect("Writing to a Vec never fails");
+        mem::take(self.encoder.get_mut())
+    }
+
+    /// Compress the rest of the input and return the last bytes of the stream
+    pub fn finish(self) -> Vec<u8> {
+        self.encoder.finish().expect("Writing to a Vec never fails")
+    }
+}
+
+/// Decompresses `.bz2` data pushed into it in chunks, returning every block as soon as all of
+/// it arrived
+#[wasm_bindgen]
+pub struct Decompressor {
+    decoder: BzDecoder<Chunks>,
+}
+
+/// The chunks pushed into a `Decompressor` that the decoder didn't read yet. Running out of
+/// them before `finish` fails with `WouldBlock`, so the decoder stops and picks up again with
+/// the next chunk.
+struct Chunks {
+    data: Vec<u8>,
+    pos: usize,
+    finished: bool,
+}
+
+impl Read for Chunks {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let available = &self.data[self.pos..];
+        if available.is_empty() && !self.finished {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        let len = available.len().min(buf.len());
+        buf[..len].copy_from_slice(&available[..len]);
+        self.pos += len;
+        if self.pos == self.data.len() {
+            self.data.clear();
+            self.pos = 0;
+        }
+        Ok(len)
+    }
+}
+
+#[wasm_bindgen]
+impl Decompressor {
+    /// `small` for the slower inverse BWT that takes less memory per byte of a block, like
+    /// `bzip2 -s`
+    #[wasm_bindgen(constructor)]
+    pub fn new(small: bool) -> Decompressor {
+        let chunks = Chunks {
+            data: Vec::new(),
+            pos: 0,
+            finished: false,
+        };
+        let budget = match small {
+            true => MemoryBudget::SMALL,
+            false => MemoryBudget::UNLIMITED,
+        };
+        Self {
+            decoder: BzDecoder::with_memory_budget(chunks, budget),
+        }
+    }
+
+    /// Add `chunk` to the compressed input and return the decompressed bytes of the blocks it
+    /// completed, often none
+    pub fn push(&mut self, chunk: &[u8]) -> Result<Vec<u8>, JsError> {
+        Ok(self.decode(chunk)?)
+    }
+
+    /// Decompress the rest of the input, which has to end with a complete stream
+    pub fn finish(mut self) -> Result<Vec<u8>, JsError> {
+        Ok(self.decode_rest()?)
+    }
+}
+
+impl Decompressor {
+    fn decode(&mut self, chunk: &[u8]) -> io::Result<Vec<u8>> {
+        self.decoder.get_mut().data.extend_from_slice(chunk);
+        let mut out = Vec::new();
+        match self.decoder.read_to_end(&mut out) {
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => Ok(out),
+            Err(err) => Err(err),
+            Ok(_) => Ok(out),
+        }
+    }
+
+    fn decode_rest(&mut self) -> io::Result<Vec<u8>> {
+        self.decoder.get_mut().finished = true;
+        let mut out = Vec::new();
+        self.decoder.read_to_end(&mut out)?;
+        Ok(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+    use test_case::test_case;
+
+    use super::*;
+    use crate::stages::{counting_alloc as alloc, strategies};
+
+    fn compress_in_chunks(data: &[u8], level: u8, chunk_len: usize) -> Vec<u8> {
+        let mut compressor = Compressor::new(level).unwrap();
+        let mut out = Vec::new();
+        for chunk in data.chunks(chunk_len) {
+            out.extend(compressor.push(chunk));
+        }
+        out.extend(compressor.finish());
+        out
+    }
+
+    fn decompress_in_chunks(data: &[u8], chunk_len: usize) -> io::Result<Vec<u8>> {
+        let mut decompressor = Decompressor::new(false);
+        let mut out = Vec::new();
+        for chunk in data.chunks(chunk_len) {
+            out.extend(decompressor.decode(chunk)?);
+        }
+        out.extend(decompressor.decode_rest()?);
+        Ok(out)
+    }
+
+    #[test_case(b"", 9; "empty")]
+    #[test_case(b"hello world\n", 9; "hello world")]
+    #[test_case(&include_bytes!("wasm.rs").repeat(30), 1; "several blocks")]
+    fn test_roundtrip(data: &[u8], level: u8) {
+        let compressed = compress(data, level).unwrap();
+        assert_eq!(decompress(&compressed).unwrap(), data);
+    }
+
+    #[test_case(b"hello world\n", 5; "hello world")]
+    #[test_case(&include_bytes!("wasm.rs").repeat(30), 999; "several blocks")]
+    fn test_chunks_same_as_whole(data: &[u8], chunk_len: usize) {
+        let compressed = compress_in_chunks(data, 1, chunk_len);
+        assert_eq!(compressed, compress(data, 1).unwrap());
+        assert_eq!(decompress_in_chunks(&compressed, chunk_len).unwrap(), data);
+    }
+
+    #[test]
+    fn test_blocks_before_finish() {
+        let data = include_bytes!("wasm.rs").repeat(30);
+        let mut compressor = Compressor::new(1).unwrap();
+        let compressed = compressor.push(&data);
+        assert!(!compressed.is_empty());
+
+        let mut decompressor = Decompressor::new(true);
+        let out = decompressor.decode(&compressed).unwrap();
+        assert!(!out.is_empty());
+        assert!(data.starts_with(&out));
+    }
+
+    #[test]
+    fn test_truncated() {
+        let compressed = compress(b"hello world\n", 9).unwrap();
+        let mut decompressor = Decompressor::new(false);
+        // without the last byte of the combined CRC, the block is still all there
+        let out = decompressor
+            .decode(&compressed[..compressed.len() - 1])
+            .unwrap();
+        assert_eq!(out, b"hello world\n");
+        let err = decompressor.decode_rest().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn test_corrupt() {
+        let mut compressed = compress(b"hello world\n", 9).unwrap();
+        compressed[10] ^= 0x01;
+        let err = decompress_in_chunks(&compressed, 7).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_memory_of_a_full_block() {
+        // a full block of bytes without runs for RLE1 to shorten
+        let data: Vec<u8> = (0..900_000u32)
+            .map(|i| (i.wrapping_mul(2_654_435_761) >> 24) as u8)
+            .collect();
+        let mut compressor = Compressor::new(9).unwrap();
+        let (compressed, bytes) = alloc::count_bytes(|| {
+            let mut compressed = compressor.push(&data);
+            compressed.extend(compressor.finish());
+            compressed
+        });
+        assert!(bytes < 12 * mem::size_of::<usize>() * data.len());
+        assert_eq!(decompress(&compressed).unwrap(), data);
+    }
+
+    proptest! {
+        #[test]
+        fn prop_roundtrip(data in strategies::bytes(1024), chunk_len in 1usize..256) {
+            let compressed = compress_in_chunks(&data, 1, chunk_len);
+            prop_assert_eq!(decompress_in_chunks(&compressed, chunk_len).unwrap(), data);
+        }
+    }
+}
@@ -0,0 +1,841 @@
+//! The `bzippr` binary as a user runs it: every test runs it on files in a temporary directory of
+//! its own and checks what it left behind there, its exit status and what it reported.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::{Command, Output},
+};
+
+use bzippr::{bits::BitReader, decompress, Blocks};
+use tempfile::TempDir;
+use test_case::test_case;
+
+const TEXT: &[u8] = include_bytes!("fixtures/text.txt");
+
+/// Run the binary in `dir` with `args`
+fn bzippr(dir: &Path, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_bzippr"))
+        .current_dir(dir)
+        .args(args)
+        .output()
+        .expect("The binary runs")
+}
+
+/// A temporary directory with a file `name` holding `data`, and the path of that file
+fn dir_with(name: &str, data: &[u8]) -> (TempDir, PathBuf) {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join(name);
+    fs::write(&path, data).unwrap();
+    (dir, path)
+}
+
+fn stderr(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stderr).into_owned()
+}
+
+#[test_case(&[] => true; "default")]
+#[test_case(&["--keep"] => false; "keep")]
+fn test_compress_deletes_input(flags: &[&str]) -> bool {
+    let (dir, path) = dir_with("a.txt", TEXT);
+    let output = bzippr(dir.path(), &[flags, &["a.txt"]].concat());
+    assert!(output.status.success(), "{}", stderr(&output));
+    let compressed = fs::read(dir.path().join("a.txt.bz2")).unwrap();
+    assert_eq!(decompress(&compressed), Ok(TEXT.to_vec()));
+    !path.exists()
+}
+
+#[test]
+fn test_decompress() {
+    let (dir, path) = dir_with("a.txt.bz2", &bzippr::compress(TEXT, Default::default()));
+    let output = bzippr(dir.path(), &["-d", "a.txt.bz2"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(fs::read(dir.path().join("a.txt")).unwrap(), TEXT);
+    assert!(!path.exists());
+}
+
+/// A file of several blocks is compressed a block at a time, to what the library makes of all of
+/// it at once, and decompressed and tested the same way, also with `--small`
+#[test_case(&[]; "default")]
+#[test_case(&["--small"]; "small")]
+fn test_several_blocks(flags: &[&str]) {
+    let data = TEXT.repeat(250_000 / TEXT.len() + 1);
+    let (dir, path) = dir_with("a.txt", &data);
+    let output = bzippr(dir.path(), &[flags, &["-1", "-k", "a.txt"]].concat());
+    assert!(output.status.success(), "{}", stderr(&output));
+    let compressed = fs::read(dir.path().join("a.txt.bz2")).unwrap();
+    assert_eq!(
+        compressed,
+        bzippr::compress(&data, bzippr::CompressionLevel::One)
+    );
+
+    let output = bzippr(dir.path(), &[flags, &["-t", "a.txt.bz2"]].concat());
+    assert!(output.status.success(), "{}", stderr(&output));
+    fs::remove_file(&path).unwrap();
+    let output = bzippr(dir.path(), &[flags, &["-d", "a.txt.bz2"]].concat());
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(fs::read(path).unwrap(), data);
+}
+
+/// With `--threads`, a file and stdin are both read a batch of blocks at a time, into the stream
+/// a single thread writes
+#[cfg(feature = "parallel")]
+#[test]
+fn test_threads_read_batches() {
+    let data = TEXT.repeat(250_000 / TEXT.len() + 1);
+    let expected = bzippr::compress(&data, bzippr::CompressionLevel::One);
+    let (dir, path) = dir_with("a.txt", &data);
+    let output = bzippr(dir.path(), &["-T", "2", "-1", "-k", "a.txt"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(fs::read(dir.path().join("a.txt.bz2")).unwrap(), expected);
+
+    let output = Command::new(env!("CARGO_BIN_EXE_bzippr"))
+        .args(["-T", "2", "-1", "-c"])
+        .stdin(fs::File::open(path).unwrap())
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(output.stdout, expected);
+}
+
+/// The input is truncated when its own output is created, so it has to be read first
+#[test]
+fn test_output_path_is_input() {
+    let (dir, path) = dir_with("a.txt", TEXT);
+    let output = bzippr(
+        dir.path(),
+        &["--force", "--verify=full", "-o", "a.txt", "a.txt"],
+    );
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(decompress(&fs::read(path).unwrap()), Ok(TEXT.to_vec()));
+}
+
+#[test]
+fn test_one_of_several_files_fails() {
+    let dir = tempfile::tempdir().unwrap();
+    for name in ["a.txt", "c.txt"] {
+        fs::write(dir.path().join(name), TEXT).unwrap();
+    }
+    let output = bzippr(dir.path(), &["a.txt", "b.txt", "c.txt"]);
+    assert!(!output.status.success());
+    let stderr = stderr(&output);
+    assert!(stderr.contains("b.txt"), "{stderr}");
+    assert!(stderr.contains("1 of 3 files failed"), "{stderr}");
+    // the others are still compressed
+    for name in ["a.txt.bz2", "c.txt.bz2"] {
+        let compressed = fs::read(dir.path().join(name)).unwrap();
+        assert_eq!(decompress(&compressed), Ok(TEXT.to_vec()));
+    }
+}
+
+#[test]
+fn test_existing_output_needs_force() {
+    let (dir, path) = dir_with("a.txt", TEXT);
+    fs::write(dir.path().join("a.txt.bz2"), b"precious").unwrap();
+
+    let output = bzippr(dir.path(), &["a.txt"]);
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("already exists"));
+    assert_eq!(fs::read(dir.path().join("a.txt.bz2")).unwrap(), b"precious");
+    assert!(path.exists());
+
+    let output = bzippr(dir.path(), &["--force", "a.txt"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let compressed = fs::read(dir.path().join("a.txt.bz2")).unwrap();
+    assert_eq!(decompress(&compressed), Ok(TEXT.to_vec()));
+}
+
+/// A piece `recover` would write that already exists is refused like any other output, the other
+/// pieces are still written
+#[test]
+fn test_recover_existing_piece_needs_force() {
+    let data = TEXT.repeat(8);
+    let mut compressed = bzippr::compress(&data, bzippr::CompressionLevel::One);
+    // break the last of the three blocks
+    let len = compressed.len();
+    compressed[len - 100] ^= 0x55;
+    let (dir, _) = dir_with("a.bz2", &compressed);
+    let existing = dir.path().join("rec0001_a.bz2");
+    fs::write(&existing, b"precious").unwrap();
+
+    let output = bzippr(dir.path(), &["recover", "a.bz2"]);
+    assert_eq!(output.status.code(), Some(1));
+    let message = stderr(&output);
+    assert!(
+        message.contains("rec0001_a.bz2 already exists"),
+        "{message}"
+    );
+    assert!(message.contains("1 of 2 files failed"), "{message}");
+    assert_eq!(fs::read(&existing).unwrap(), b"precious");
+    let second = fs::read(dir.path().join("rec0002_a.bz2")).unwrap();
+
+    let output = bzippr(dir.path(), &["recover", "--force", "a.bz2"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let first = fs::read(&existing).unwrap();
+    let recovered = [decompress(&first).unwrap(), decompress(&second).unwrap()].concat();
+    assert!(data.starts_with(&recovered));
+}
+
+#[cfg(unix)]
+#[test]
+fn test_output_link_isnt_followed() {
+    let (dir, path) = dir_with("a.txt", TEXT);
+    let victim = dir.path().join("victim");
+    fs::write(&victim, b"precious").unwrap();
+    std::os::unix::fs::symlink(&victim, dir.path().join("a.txt.bz2")).unwrap();
+
+    let output = bzippr(dir.path(), &["a.txt"]);
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("already exists"));
+    assert_eq!(fs::read(&victim).unwrap(), b"precious");
+    assert!(path.exists());
+}
+
+#[test]
+fn test_existing_index_needs_force() {
+    let (dir, path) = dir_with("a.txt", TEXT);
+    fs::write(dir.path().join("a.txt.bz2.idx"), b"precious").unwrap();
+
+    let output = bzippr(dir.path(), &["--index", "a.txt"]);
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("a.txt.bz2.idx already exists"));
+    // the output made before the index failed is gone again
+    assert!(!dir.path().join("a.txt.bz2").exists());
+    assert!(path.exists());
+}
+
+#[cfg(unix)]
+#[test]
+fn test_hard_link_needs_force() {
+    let (dir, path) = dir_with("a.txt", TEXT);
+    fs::hard_link(&path, dir.path().join("b.txt")).unwrap();
+
+    let output = bzippr(dir.path(), &["a.txt"]);
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("hard links"));
+    assert!(!dir.path().join("a.txt.bz2").exists());
+
+    let output = bzippr(dir.path(), &["--force", "a.txt"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(dir.path().join("a.txt.bz2").exists());
+    // the other link still holds the data
+    assert_eq!(fs::read(dir.path().join("b.txt")).unwrap(), TEXT);
+}
+
+#[test_case(&["-q"], &[], &["Compressed", "Wrote", "saved"]; "quiet")]
+#[test_case(&[], &["Compressed", "Wrote a.txt.bz2"], &["saved", "compressed in"]; "normal")]
+#[test_case(&["-v"], &["a.txt:", ":1,", "bits/byte", "% saved"], &["compressed in"]; "verbose")]
+#[test_case(&["-vv"], &["% saved", "blocks of up to", "compressed in"], &[]; "very verbose")]
+fn test_verbosity(flags: &[&str], expected: &[&str], unexpected: &[&str]) {
+    let (dir, _) = dir_with("a.txt", TEXT);
+    let output = bzippr(dir.path(), &[flags, &["a.txt"]].concat());
+    assert!(output.status.success());
+    let stderr = stderr(&output);
+    for part in expected {
+        assert!(stderr.contains(part), "{part:?} missing from {stderr:?}");
+    }
+    for part in unexpected {
+        assert!(!stderr.contains(part), "{part:?} in {stderr:?}");
+    }
+}
+
+#[cfg(feature = "json")]
+#[test]
+fn test_stats_json() {
+    let (dir, _) = dir_with("a.txt", TEXT);
+    let output = bzippr(dir.path(), &["--stats-json", "-q", "a.txt"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(stdout.lines().count(), 1);
+    let report: serde_json::Value = serde_json::from_str(&stdout).unwrap();
+    let compressed_size = fs::metadata(dir.path().join("a.txt.bz2")).unwrap().len();
+    assert_eq!(report["file"], "a.txt");
+    assert_eq!(report["original_size"], TEXT.len() as u64);
+    assert_eq!(report["compressed_size"], compressed_size);
+    assert!(report["blocks"].as_u64().unwrap() >= 1);
+    assert!(report.get("block_stats").is_none());
+}
+
+#[cfg(feature = "json")]
+#[test]
+fn test_stats_json_block_stats() {
+    let (dir, _) = dir_with("a.txt", &TEXT.repeat(5));
+    let output = bzippr(dir.path(), &["--stats-json", "-vv", "-1", "a.txt"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let report: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    let blocks = report["block_stats"].as_array().unwrap();
+    assert_eq!(blocks.len(), 2);
+    let bits: u64 = blocks
+        .iter()
+        .map(|block| {
+            let tables = block["tables"].as_array().unwrap();
+            for table in tables {
+                let shannon_bits = table["shannon_bits"].as_f64().unwrap();
+                assert!(shannon_bits <= table["coded_bits"].as_f64().unwrap());
+            }
+            [
+                "header_bits",
+                "symbol_map_bits",
+                "selector_bits",
+                "table_bits",
+            ]
+            .iter()
+            .map(|part| block[part].as_u64().unwrap())
+            .chain(
+                tables
+                    .iter()
+                    .map(|table| table["coded_bits"].as_u64().unwrap()),
+            )
+            .sum::<u64>()
+        })
+        .sum();
+    // the blocks between the stream header and footer, padded to a whole byte
+    let compressed_size = fs::metadata(dir.path().join("a.txt.bz2")).unwrap().len();
+    assert_eq!((32 + bits + 80).div_ceil(8), compressed_size);
+}
+
+#[test]
+fn test_time() {
+    let (dir, _) = dir_with("a.txt", &TEXT.repeat(5));
+    let output = bzippr(dir.path(), &["--time", "-q", "-1", "a.txt"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let stderr = stderr(&output);
+    let lines: Vec<&str> = stderr.lines().collect();
+    assert_eq!(lines[0], "a.txt:");
+    let header: Vec<&str> = lines[1].split_whitespace().collect();
+    assert_eq!(
+        header,
+        ["block", "rle1", "bwt", "mtf", "huffman", "emission", "io", "total", "MB/s"]
+    );
+    let labels: Vec<&str> = lines[2..]
+        .iter()
+        .map(|line| line.split_whitespace().next().unwrap())
+        .collect();
+    assert_eq!(labels, ["0", "1", "total", "MB/s", "wall"]);
+    for row in &lines[2..5] {
+        assert_eq!(row.split_whitespace().count(), header.len(), "{row}");
+    }
+    assert!(dir.path().join("a.txt.bz2").exists());
+
+    let output = bzippr(dir.path(), &["--time", "-d", "a.txt.bz2"]);
+    assert!(!output.status.success());
+}
+
+#[test]
+fn test_block_size_auto() {
+    let (dir, _) = dir_with("a.txt", TEXT);
+
+    let output = bzippr(dir.path(), &["-k", "-c", "a.txt"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let default = output.stdout;
+    let output = bzippr(dir.path(), &["-k", "-c", "-9", "a.txt"]);
+    let best = output.stdout;
+    assert_eq!(default, best);
+    assert!(best.starts_with(b"BZh9"));
+
+    let output = bzippr(dir.path(), &["-k", "-c", "--block-size", "auto", "a.txt"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let auto = output.stdout;
+    // a file this small fits the smallest block, and a smaller block doesn't cost anything
+    assert!(auto.starts_with(b"BZh1"));
+    assert!(auto.len() <= best.len());
+    assert_eq!(bzippr::decompress(&auto).unwrap(), TEXT);
+
+    let output = bzippr(dir.path(), &["--block-size", "auto", "-9", "a.txt"]);
+    assert!(!output.status.success());
+}
+
+#[test]
+fn test_recursive() {
+    let dir = tempfile::tempdir().unwrap();
+    let logs = dir.path().join("logs");
+    fs::create_dir_all(logs.join("old")).unwrap();
+    fs::create_dir_all(logs.join(".cache")).unwrap();
+    for name in ["a.log", "old/b.log", ".hidden.log", ".cache/c.log"] {
+        fs::write(logs.join(name), TEXT).unwrap();
+    }
+    let done = bzippr::compress(b"already compressed", Default::default());
+    fs::write(logs.join("done.log.bz2"), done).unwrap();
+
+    let output = bzippr(dir.path(), &["-r", "-q", "logs"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    for name in ["a.log.bz2", "old/b.log.bz2"] {
+        let compressed = fs::read(logs.join(name)).unwrap();
+        assert_eq!(decompress(&compressed), Ok(TEXT.to_vec()));
+    }
+    // hidden files and compressed ones are left alone
+    for name in [".hidden.log", ".cache/c.log", "done.log.bz2"] {
+        assert!(logs.join(name).exists(), "{name}");
+    }
+    assert!(!logs.join("done.log.bz2.bz2").exists());
+
+    let output = bzippr(dir.path(), &["-r", "-d", "-q", "logs"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(fs::read(logs.join("old/b.log")).unwrap(), TEXT);
+    assert_eq!(
+        fs::read(logs.join("done.log")).unwrap(),
+        b"already compressed"
+    );
+}
+
+#[test]
+fn test_recursive_include() {
+    let dir = tempfile::tempdir().unwrap();
+    let logs = dir.path().join("logs");
+    fs::create_dir_all(logs.join("old")).unwrap();
+    for name in ["a.log", "old/b.log", "notes.txt", "old/c.csv"] {
+        fs::write(logs.join(name), TEXT).unwrap();
+    }
+
+    let output = bzippr(
+        dir.path(),
+        &["-r", "--include", "*.log", "--include", "*.csv", "logs"],
+    );
+    let stderr = stderr(&output);
+    assert!(output.status.success(), "{stderr}");
+    for name in ["a.log.bz2", "old/b.log.bz2", "old/c.csv.bz2"] {
+        assert!(logs.join(name).exists(), "{name}");
+    }
+    assert!(logs.join("notes.txt").exists());
+    assert!(!logs.join("notes.txt.bz2").exists());
+    let compressed: u64 = ["a.log.bz2", "old/b.log.bz2", "old/c.csv.bz2"]
+        .iter()
+        .map(|name| fs::metadata(logs.join(name)).unwrap().len())
+        .sum();
+    let summary = format!(
+        "3 files, {} bytes in, {compressed} bytes out, ratio ",
+        3 * TEXT.len()
+    );
+    assert!(
+        stderr.lines().last().unwrap().starts_with(&summary),
+        "{stderr}"
+    );
+
+    let output = bzippr(dir.path(), &["-r", "-d", "-q", "logs"]);
+    assert!(output.status.success());
+    assert!(output.stderr.is_empty(), "{:?}", output.stderr);
+    assert_eq!(fs::read(logs.join("old/c.csv")).unwrap(), TEXT);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_recursive_keeps_going() {
+    use std::os::unix::fs::{symlink, PermissionsExt};
+
+    let dir = tempfile::tempdir().unwrap();
+    let logs = dir.path().join("logs");
+    fs::create_dir_all(logs.join("sub")).unwrap();
+    for name in ["a.log", "sub/b.log", "sub/c.log"] {
+        fs::write(logs.join(name), TEXT).unwrap();
+    }
+    symlink("..", logs.join("sub/loop")).unwrap();
+    let unreadable = logs.join("sub/c.log");
+    fs::set_permissions(&unreadable, fs::Permissions::from_mode(0o000)).unwrap();
+    // permissions don't stop root
+    let readable = fs::read(&unreadable).is_ok();
+
+    let output = bzippr(dir.path(), &["-r", "-k", "-q", "--follow-symlinks", "logs"]);
+    let stderr = stderr(&output);
+    assert_eq!(output.status.code(), Some(1), "{stderr}");
+    assert!(stderr.contains("loop"), "{stderr}");
+    let failed = if readable { 1 } else { 2 };
+    assert!(
+        stderr.contains(&format!("{failed} of 4 files failed")),
+        "{stderr}"
+    );
+    for name in ["a.log.bz2", "sub/b.log.bz2"] {
+        let compressed = fs::read(logs.join(name)).unwrap();
+        assert_eq!(decompress(&compressed), Ok(TEXT.to_vec()));
+    }
+    assert_eq!(logs.join("sub/c.log.bz2").exists(), readable);
+}
+
+#[test]
+fn test_metadata_is_kept() {
+    let (dir, path) = dir_with("a.txt", TEXT);
+    let mtime = std::time::UNIX_EPOCH + std::time::Duration::from_secs(1_000_000_000);
+    fs::File::options()
+        .write(true)
+        .open(&path)
+        .unwrap()
+        .set_modified(mtime)
+        .unwrap();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o640)).unwrap();
+    }
+
+    let output = bzippr(dir.path(), &["--index", "a.txt"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let metadata = fs::metadata(dir.path().join("a.txt.bz2")).unwrap();
+    assert_eq!(metadata.modified().unwrap(), mtime);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o640);
+        let index = fs::metadata(dir.path().join("a.txt.bz2.idx")).unwrap();
+        assert_eq!(index.permissions().mode() & 0o777, 0o640);
+    }
+}
+
+#[test]
+fn test_failed_decompression_leaves_no_output() {
+    let compressed = bzippr::compress(TEXT, Default::default());
+    let (dir, path) = dir_with("bad.bz2", &compressed[..compressed.len() / 2]);
+
+    let output = bzippr(dir.path(), &["-d", "bad.bz2"]);
+    assert!(!output.status.success());
+    assert!(!dir.path().join("bad").exists());
+    assert!(path.exists());
+
+    // so trying again doesn't need `--force`
+    fs::write(&path, &compressed).unwrap();
+    let output = bzippr(dir.path(), &["-d", "bad.bz2"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(fs::read(dir.path().join("bad")).unwrap(), TEXT);
+}
+
+#[test_case(&[]; "default")]
+#[test_case(&["--small"]; "small")]
+#[test_case(&["--progress"]; "progress")]
+fn test_damaged_stream_leaves_no_output(flags: &[&str]) {
+    // the first stream is intact and written out before the damage in the second one shows
+    let mut data = bzippr::compress(TEXT, Default::default());
+    let second = bzippr::compress(TEXT, Default::default());
+    let damaged = data.len() + second.len() / 2;
+    data.extend(second);
+    data[damaged] ^= 0xff;
+    let (dir, path) = dir_with("a.txt.bz2", &data);
+
+    let output = bzippr(dir.path(), &[flags, &["-d", "a.txt.bz2"]].concat());
+    assert!(!output.status.success());
+    assert!(
+        stderr(&output).contains("Can't decompress"),
+        "{}",
+        stderr(&output)
+    );
+    assert!(!dir.path().join("a.txt").exists());
+    assert_eq!(fs::read(path).unwrap(), data);
+}
+
+#[test_case(&["-d", "a.txt.bz2"], None => 1; "missing file")]
+#[test_case(&["--no-such-flag", "a.txt.bz2"], Some(b"") => 1; "bad argument")]
+#[test_case(&["-d", "a.txt.bz2"], Some(&[0x1f, 0x8b, 0x08, 0x00]) => 1; "gzip")]
+#[test_case(&["-d", "a.txt.bz2"], Some(b"BZh91AY&SY\0\0") => 2; "truncated")]
+#[test_case(&["-t", "a.txt.bz2"], Some(b"BZh9\x12\x34\x56\x78\x9a\xbc\0\0\0\0") => 2; "bad block magic")]
+#[test_case(&["-d", "a.txt.bz2", "b.txt.bz2"], Some(b"BZh91AY&SY\0\0") => 2; "corrupt and missing file")]
+fn test_exit_code(args: &[&str], data: Option<&[u8]>) -> i32 {
+    let dir = tempfile::tempdir().unwrap();
+    if let Some(data) = data {
+        fs::write(dir.path().join("a.txt.bz2"), data).unwrap();
+    }
+    bzippr(dir.path(), args).status.code().unwrap()
+}
+
+#[test]
+fn test_unsupported_format_hint() {
+    let (dir, path) = dir_with("a.txt.bz2", &[0x1f, 0x8b, 0x08, 0x00]);
+    let output = bzippr(dir.path(), &["-d", "a.txt.bz2"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(
+        stderr(&output).contains("try gunzip"),
+        "{}",
+        stderr(&output)
+    );
+    assert!(path.exists());
+}
+
+#[test_case("--verify"; "crc")]
+#[test_case("--verify=full"; "full")]
+fn test_verify(flag: &str) {
+    let (dir, path) = dir_with("a.txt", TEXT);
+    let output = bzippr(dir.path(), &[flag, "a.txt"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let compressed = fs::read(dir.path().join("a.txt.bz2")).unwrap();
+    assert_eq!(decompress(&compressed), Ok(TEXT.to_vec()));
+    assert!(!path.exists());
+}
+
+#[test]
+fn test_inspect() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures");
+    let output = bzippr(&dir, &["inspect", "concatenated.bz2"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let expected = fs::read_to_string(dir.join("concatenated.bz2.inspect")).unwrap();
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), expected);
+}
+
+/// `text_x8.txt.1.bz2` with a byte of its second block flipped
+fn damaged_second_block() -> (TempDir, PathBuf) {
+    let mut data = include_bytes!("fixtures/text_x8.txt.1.bz2").to_vec();
+    data[8000] ^= 0xff;
+    dir_with("damaged.bz2", &data)
+}
+
+#[test]
+fn test_inspect_damaged_block() {
+    let (dir, _) = damaged_second_block();
+    let output = bzippr(dir.path(), &["inspect", "damaged.bz2"]);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(stderr(&output).contains("1 of 3 blocks are damaged"));
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let blocks: Vec<_> = stdout
+        .lines()
+        .filter(|line| line.starts_with("  block"))
+        .collect();
+    assert_eq!(
+        blocks,
+        [
+            "  block 0 at bit 32",
+            "  block 1 at bit 59227: DAMAGED, Block of 101622 bytes exceeds the block size of 100000 \
+             bytes",
+            "  block 2 at bit 118296",
+        ]
+    );
+    assert!(stdout.contains("footer at bit 157616: combined CRC 0x9a26fdc3"));
+}
+
+#[cfg(feature = "json")]
+#[test]
+fn test_inspect_json() {
+    let (dir, _) = damaged_second_block();
+    let output = bzippr(dir.path(), &["inspect", "--json", "damaged.bz2"]);
+    assert_eq!(output.status.code(), Some(2));
+    let inspection: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    let stream = &inspection["streams"][0];
+    assert_eq!(stream["block_size_digit"], 1);
+    let blocks = stream["blocks"].as_array().unwrap();
+    let offsets: Vec<_> = blocks.iter().map(|block| &block["bit_offset"]).collect();
+    assert_eq!(offsets, [32, 59227, 118296]);
+    let errors: Vec<_> = blocks
+        .iter()
+        .map(|block| block["error"].is_null())
+        .collect();
+    assert_eq!(errors, [true, false, true]);
+    let first = &blocks[0]["details"];
+    assert_eq!(first["orig_ptr"], 87756);
+    assert_eq!(first["used_bytes"], 28);
+    assert_eq!(first["tables"], 6);
+    assert_eq!(first["selectors"], 455);
+    let histogram = first["selector_histogram"].as_array().unwrap();
+    assert_eq!(
+        histogram.iter().map(|n| n.as_u64().unwrap()).sum::<u64>(),
+        455
+    );
+    assert_eq!(stream["footer"]["crc"], 0x9a26fdc3u32);
+    assert_eq!(inspection["trailing_garbage"], serde_json::Value::Null);
+}
+
+#[test]
+fn test_force_decode() {
+    let mut data = include_bytes!("fixtures/text_x8.txt.1.bz2").to_vec();
+    // the lowest bit of the origin pointer of the second block, which then decodes to a
+    // rotation of its bytes
+    let bit = 59227 + 48 + 32 + 1 + 23;
+    data[bit / 8] ^= 0x80 >> (bit % 8);
+    let (dir, path) = dir_with("a.txt.bz2", &data);
+
+    let output = bzippr(dir.path(), &["-d", "a.txt.bz2"]);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(!dir.path().join("a.txt").exists());
+
+    let output = bzippr(dir.path(), &["-d", "--force-decode", "a.txt.bz2"]);
+    assert_eq!(output.status.code(), Some(2));
+    let stderr = stderr(&output);
+    assert!(stderr.contains("CRC mismatch in block 1"), "{stderr}");
+    assert!(stderr.contains("1 of 3 blocks are damaged"), "{stderr}");
+    assert!(path.exists());
+    let decompressed = fs::read(dir.path().join("a.txt")).unwrap();
+    let expected = TEXT.repeat(8);
+    assert_eq!(decompressed.len(), expected.len());
+    // the first and the last of the three blocks of 100 000 bytes are intact
+    assert_eq!(decompressed[..99_000], expected[..99_000]);
+    assert_eq!(decompressed[201_000..], expected[201_000..]);
+    assert_ne!(decompressed, expected);
+}
+
+#[test]
+fn test_force_decode_skips_broken_block() {
+    let mut data = include_bytes!("fixtures/text_x8.txt.1.bz2").to_vec();
+    // a bit of the magic of the second block, which then can't be found where it should be
+    let bit = 59227 + 40;
+    data[bit / 8] ^= 0x80 >> (bit % 8);
+    let (dir, _) = dir_with("a.txt.bz2", &data);
+
+    let output = bzippr(dir.path(), &["-d", "--force-decode", "a.txt.bz2"]);
+    assert_eq!(output.status.code(), Some(2));
+    let stderr = stderr(&output);
+    assert!(stderr.contains("skipped bits 59227..118296"), "{stderr}");
+    assert!(stderr.contains("1 of 3 blocks are damaged"), "{stderr}");
+    let decompressed = fs::read(dir.path().join("a.txt")).unwrap();
+    let expected = TEXT.repeat(8);
+    // the second block is missing from the output, the other two are intact
+    let missing = expected.len() - decompressed.len();
+    assert!((99_000..=100_000).contains(&missing), "{missing}");
+    assert_eq!(decompressed[..99_000], expected[..99_000]);
+    assert_eq!(
+        decompressed[decompressed.len() - 40_000..],
+        expected[expected.len() - 40_000..]
+    );
+}
+
+#[test]
+fn test_dump_stages() {
+    let (dir, _) = dir_with("a.txt", &TEXT.repeat(5));
+    let output = bzippr(dir.path(), &["-1", "-k", "--dump-stages", "enc", "a.txt"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let output = bzippr(
+        dir.path(),
+        &["-d", "-c", "--dump-stages", "dec", "a.txt.bz2"],
+    );
+    assert!(output.status.success(), "{}", stderr(&output));
+
+    let dump = |name: &str| {
+        let mut files: Vec<(String, Vec<u8>)> = fs::read_dir(dir.path().join(name))
+            .unwrap()
+            .map(|entry| {
+                let entry = entry.unwrap();
+                let name = entry.file_name().to_string_lossy().into_owned();
+                (name, fs::read(entry.path()).unwrap())
+            })
+            .collect();
+        files.sort();
+        files
+    };
+    let encoded = dump("enc");
+    let names: Vec<&str> = encoded.iter().map(|(name, _)| name.as_str()).collect();
+    assert_eq!(
+        names,
+        [
+            "block0000.bwt",
+            "block0000.huff",
+            "block0000.mtf",
+            "block0000.origin",
+            "block0000.rle1",
+            "block0000.stack",
+            "block0001.bwt",
+            "block0001.huff",
+            "block0001.mtf",
+            "block0001.origin",
+            "block0001.rle1",
+            "block0001.stack",
+        ]
+    );
+    let file = |name: &str| &encoded.iter().find(|(file, _)| file == name).unwrap().1;
+    for block in ["block0000", "block0001"] {
+        let rle1 = file(&format!("{block}.rle1"));
+        assert_eq!(file(&format!("{block}.bwt")).len(), rle1.len());
+        let origin = String::from_utf8(file(&format!("{block}.origin")).clone()).unwrap();
+        assert!(origin.trim().parse::<usize>().unwrap() < rle1.len());
+        assert_eq!(file(&format!("{block}.mtf")).len() % 2, 0);
+        let huff = String::from_utf8(file(&format!("{block}.huff")).clone()).unwrap();
+        assert!(huff.starts_with("table 0: "), "{huff}");
+        assert!(huff.contains("\ncoded bits: "), "{huff}");
+    }
+    // the text has no runs for RLE1 to shorten
+    let rle1 = [
+        file("block0000.rle1").clone(),
+        file("block0001.rle1").clone(),
+    ]
+    .concat();
+    assert_eq!(rle1, TEXT.repeat(5));
+    assert_eq!(dump("dec"), encoded);
+}
+
+/// The columns of the rows `--list` printed after its heading
+fn list_rows(output: &Output) -> Vec<Vec<String>> {
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let mut lines = stdout.lines();
+    assert_eq!(
+        lines
+            .next()
+            .map(str::split_whitespace)
+            .unwrap()
+            .collect::<Vec<_>>(),
+        [
+            "compressed",
+            "uncompressed",
+            "ratio",
+            "bs",
+            "blocks",
+            "crc",
+            "name"
+        ]
+    );
+    lines
+        .map(|line| line.split_whitespace().map(String::from).collect())
+        .collect()
+}
+
+#[test]
+fn test_list() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures");
+    let output = bzippr(&dir, &["--list", "text.txt.9.bz2", "text_x8.txt.1.bz2"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let rows = list_rows(&output);
+    // the CRC of the footer, which isn't at a whole byte
+    let crc = |name: &str| {
+        let data = fs::read(dir.join(name)).unwrap();
+        let mut reader = BitReader::new(&data);
+        let mut blocks = Blocks::new(&mut reader).unwrap();
+        blocks.by_ref().for_each(|block| drop(block.unwrap()));
+        format!("{:08x}", blocks.footer().unwrap().crc)
+    };
+    let expected = [
+        [
+            "4018",
+            "30058",
+            "86.6%",
+            "9",
+            "1",
+            &crc("text.txt.9.bz2"),
+            "text.txt.9.bz2",
+        ],
+        [
+            "19712",
+            "240464",
+            "91.8%",
+            "1",
+            "3",
+            &crc("text_x8.txt.1.bz2"),
+            "text_x8.txt.1.bz2",
+        ],
+        ["23730", "270522", "91.2%", "-", "4", "-", "(totals)"],
+    ];
+    assert_eq!(rows, expected);
+    assert_eq!(TEXT.len(), 30058);
+    assert_eq!(TEXT.len() * 8, 240464);
+}
+
+#[test]
+fn test_list_fast() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures");
+    let output = bzippr(&dir, &["--list", "--fast", "text_x8.txt.1.bz2"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let rows = list_rows(&output);
+    assert_eq!(rows.len(), 1);
+    assert_eq!(rows[0][..5], ["19712", "unknown", "-", "1", "3"]);
+}
+
+#[test]
+fn test_list_damaged_file() {
+    let (dir, _) = damaged_second_block();
+    fs::write(
+        dir.path().join("a.bz2"),
+        include_bytes!("fixtures/text.txt.9.bz2"),
+    )
+    .unwrap();
+    let output = bzippr(dir.path(), &["--list", "damaged.bz2", "a.bz2"]);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(
+        stderr(&output).contains("damaged.bz2: "),
+        "{}",
+        stderr(&output)
+    );
+    let rows = list_rows(&output);
+    assert_eq!(rows[0][1..3], ["error", "-"]);
+    assert_eq!(rows[0][6], "damaged.bz2");
+    assert_eq!(rows[1][..5], ["4018", "30058", "86.6%", "9", "1"]);
+    assert_eq!(rows[2][1..3], ["unknown", "-"]);
+}
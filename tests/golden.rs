@@ -0,0 +1,88 @@
+//! The bytes `compress` writes, pinned by their SHA-256: the stability policy on `compress`
+//! promises the same output for the same input and level on every run, platform and release,
+//! until a release announces a change. A hash that changes here breaks that promise, so it may
+//! only be updated along with such an announcement.
+//!
+//! Every output is also checked to decompress to its input, to compress to the same bytes a
+//! second time in the same process, and to match what `BzEncoder` and `compress_parallel` write.
+
+use std::{fs, io::Write, path::Path};
+
+use bzippr::{compress, decompress, BzEncoder, CompressionLevel};
+use sha2::{Digest, Sha256};
+use test_case::test_case;
+
+fn fixture(name: &str) -> Vec<u8> {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/fixtures")
+        .join(name);
+    fs::read(&path).unwrap_or_else(|err| panic!("Can't read {}: {err}", path.display()))
+}
+
+/// Bytes of a linear congruential generator, the same on every platform
+fn noise(len: usize) -> Vec<u8> {
+    let mut state: u32 = 1;
+    (0..len)
+        .map(|_| {
+            state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+            (state >> 16) as u8
+        })
+        .collect()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+#[test_case(
+    Vec::new(), CompressionLevel::BEST,
+    "d3dda84eb03b9738d118eb2be78e246106900493c0ae07819ad60815134a8058";
+    "empty"
+)]
+#[test_case(
+    b"hello world\n".to_vec(), CompressionLevel::BEST,
+    "c6e47dbb6b69a5102b616f7cd684f3a62129795b99b4feee745734fac2afe1ce";
+    "hello world"
+)]
+#[test_case(
+    fixture("text.txt"), CompressionLevel::BEST,
+    "63d0d0fa362e2a23591d45bcdd4254f05d8cab2157532c5d96968c7bb15c7309";
+    "text"
+)]
+#[test_case(
+    fixture("text.txt").repeat(8), CompressionLevel::FASTEST,
+    "d6ddaec01a80b4af9706c27af4910386ef39e2e1c69b09f226f2a4e829617f25";
+    "text in three blocks"
+)]
+#[test_case(
+    fixture("binary.bin"), CompressionLevel::Five,
+    "855d3a89fda5f4858fd5b37519ddf64f98f1efc6e7fdb182a4fab583ea43b01c";
+    "binary"
+)]
+#[test_case(
+    (0..=255).collect(), CompressionLevel::BEST,
+    "be74e5659e1a23a363b171d5d954357db7e707c7d5643a55e351a82c613bb44f";
+    "every byte once"
+)]
+#[test_case(
+    vec![b'a'; 250_000], CompressionLevel::FASTEST,
+    "9cfb60b35d35b74a6f388d86253531dd691809a942ae403b2fd23384af1ef7f5";
+    "a long run"
+)]
+#[test_case(
+    noise(150_000), CompressionLevel::Two,
+    "91e4add03400ecc162fa7448c6524c581773c8342c4f44d3c0b29816020b86a8";
+    "pseudorandom bytes"
+)]
+fn test_golden(data: Vec<u8>, level: CompressionLevel, sha256: &str) {
+    let compressed = compress(&data, level);
+    assert_eq!(hex(&<Sha256 as Digest>::digest(&compressed)), sha256);
+    assert_eq!(decompress(&compressed), Ok(data.clone()));
+    assert_eq!(compress(&data, level), compressed);
+
+    let mut encoder = BzEncoder::new(Vec::new(), level);
+    encoder.write_all(&data).unwrap();
+    assert_eq!(encoder.finish().unwrap(), compressed);
+    #[cfg(feature = "parallel")]
+    assert_eq!(bzippr::compress_parallel(&data, level), compressed);
+}
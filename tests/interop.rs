@@ -0,0 +1,244 @@
+//! Compatibility with the reference implementation: the `.bz2` files in `fixtures/`, written by
+//! bzip2 1.0.8, have to decompress to the files they were made from, and whatever bzippr writes
+//! has to decompress with the `bzip2` crate and, where it is installed, the `bzip2` binary.
+//!
+//! The fixtures were made from `text.txt`, `binary.bin` (random bytes between runs of up to 1000
+//! equal bytes), `unicode.txt` (lines in a dozen scripts, with emoji and combining marks) and the
+//! empty `empty.txt`:
+//!
+//! ```text
+//! bzip2 -1 -c text.txt > text.txt.1.bz2                          # and -5, -9
+//! bzip2 -9 -c binary.bin > binary.bin.bz2                        # and unicode.txt, empty.txt
+//! for i in 1 2 3 4 5 6 7 8; do cat text.txt; done | bzip2 -1 > text_x8.txt.1.bz2   # and -2
+//! cat text.txt.9.bz2 binary.bin.bz2 > concatenated.bz2
+//! ```
+//!
+//! bzip2 0.9.0 randomized blocks that were slow to sort, newer versions never do. Instead,
+//! `randomized.bz2` is the eight times `text.txt` of `text_x8.txt.1.bz2`, written by bzippr with
+//! every block randomized, which bzip2 1.0.8 decompresses correctly.
+//!
+//! A failing check of bzippr's output writes the stream to a temporary file that outlives the test
+//! and names it in the message, to feed it to other tools.
+
+use std::{
+    fs,
+    io::{self, Read, Write},
+    path::Path,
+    process::{Command, Stdio},
+};
+
+use bzippr::{
+    bits::BitReader, compress, decompress, Blocks, BzDecoder, BzEncoder, CompressionLevel,
+    MemoryBudget,
+};
+use proptest::prelude::*;
+use test_case::test_case;
+
+fn fixture(name: &str) -> Vec<u8> {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/fixtures")
+        .join(name);
+    fs::read(&path).unwrap_or_else(|err| panic!("Can't read {}: {err}", path.display()))
+}
+
+fn text_x8() -> Vec<u8> {
+    fixture("text.txt").repeat(8)
+}
+
+fn concatenated() -> Vec<u8> {
+    [fixture("text.txt"), fixture("binary.bin")].concat()
+}
+
+/// Write `compressed` to a temporary file that is kept after the test and return a note of its
+/// path for the message of a failed check
+fn dump(compressed: &[u8]) -> String {
+    let mut file = tempfile::Builder::new()
+        .prefix("bzippr-interop-")
+        .suffix(".bz2")
+        .tempfile()
+        .unwrap();
+    file.write_all(compressed).unwrap();
+    let (_, path) = file.keep().unwrap();
+    format!("the stream is in {}", path.display())
+}
+
+/// Decompress `compressed` with the `bzip2` crate, which takes several streams back to back
+fn bzip2_crate_decompress(compressed: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    if let Err(err) = bzip2::read::MultiBzDecoder::new(compressed).read_to_end(&mut out) {
+        panic!("The bzip2 crate failed: {err}, {}", dump(compressed));
+    }
+    out
+}
+
+/// Run the `bzip2` binary with `args` on `compressed`, returning its output, or `None` if it
+/// isn't installed
+fn bzip2_binary(args: &[&str], compressed: &[u8]) -> Option<Vec<u8>> {
+    let mut child = match Command::new("bzip2")
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+    {
+        Ok(child) => child,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return None,
+        Err(err) => panic!("Can't run bzip2: {err}"),
+    };
+    // written from another thread, bzip2 blocks on its output before it has read all the input
+    let mut stdin = child.stdin.take().unwrap();
+    let input = compressed.to_vec();
+    let writer = std::thread::spawn(move || stdin.write_all(&input));
+    let output = child.wait_with_output().unwrap();
+    writer.join().unwrap().unwrap();
+    assert!(
+        output.status.success(),
+        "bzip2 {} failed: {}, {}, {}",
+        args.join(" "),
+        output.status,
+        String::from_utf8_lossy(&output.stderr).trim(),
+        dump(compressed)
+    );
+    Some(output.stdout)
+}
+
+/// Decompress `compressed` with the `bzip2` binary, `None` if it isn't installed
+fn bzip2_binary_decompress(compressed: &[u8]) -> Option<Vec<u8>> {
+    bzip2_binary(&["-dc"], compressed)
+}
+
+/// Check `compressed` with `bzip2 -t`, which tests every CRC, `false` if it isn't installed
+fn bzip2_binary_test(compressed: &[u8]) -> bool {
+    bzip2_binary(&["-t"], compressed).is_some()
+}
+
+#[test_case("empty.txt.bz2", Vec::new(); "empty")]
+#[test_case("text.txt.1.bz2", fixture("text.txt"); "text level 1")]
+#[test_case("text.txt.5.bz2", fixture("text.txt"); "text level 5")]
+#[test_case("text.txt.9.bz2", fixture("text.txt"); "text level 9")]
+#[test_case("binary.bin.bz2", fixture("binary.bin"); "binary")]
+#[test_case("unicode.txt.bz2", fixture("unicode.txt"); "unicode")]
+#[test_case("text_x8.txt.1.bz2", text_x8(); "several blocks level 1")]
+#[test_case("text_x8.txt.2.bz2", text_x8(); "several blocks level 2")]
+#[test_case("concatenated.bz2", concatenated(); "concatenated streams")]
+#[test_case("randomized.bz2", text_x8(); "randomized blocks")]
+fn test_decompress_reference_output(name: &str, original: Vec<u8>) {
+    let compressed = fixture(name);
+    assert_eq!(decompress(&compressed).unwrap(), original);
+
+    for budget in [MemoryBudget::UNLIMITED, MemoryBudget::SMALL] {
+        let mut out = Vec::new();
+        BzDecoder::with_memory_budget(compressed.as_slice(), budget)
+            .read_to_end(&mut out)
+            .unwrap();
+        assert_eq!(out, original);
+    }
+}
+
+#[test_case(Vec::new(), CompressionLevel::BEST; "empty")]
+#[test_case(fixture("text.txt"), CompressionLevel::BEST; "text")]
+#[test_case(fixture("binary.bin"), CompressionLevel::BEST; "binary")]
+#[test_case(fixture("unicode.txt"), CompressionLevel::BEST; "unicode")]
+#[test_case(cycle(100_000), CompressionLevel::FASTEST; "exactly one block")]
+#[test_case(text_x8(), CompressionLevel::FASTEST; "several blocks")]
+fn test_reference_decompresses(data: Vec<u8>, level: CompressionLevel) {
+    let compressed = compress(&data, level);
+    assert!(
+        bzip2_crate_decompress(&compressed) == data,
+        "The bzip2 crate decompressed other data, {}",
+        dump(&compressed)
+    );
+    match bzip2_binary_decompress(&compressed) {
+        Some(out) => assert!(
+            out == data,
+            "bzip2 -dc decompressed other data, {}",
+            dump(&compressed)
+        ),
+        None => eprintln!("bzip2 isn't installed, only checked with the bzip2 crate"),
+    }
+}
+
+#[test_case(Vec::new(), CompressionLevel::BEST; "empty")]
+#[test_case(fixture("text.txt"), CompressionLevel::BEST; "text")]
+#[test_case(fixture("binary.bin"), CompressionLevel::BEST; "binary")]
+#[test_case(fixture("unicode.txt"), CompressionLevel::BEST; "unicode")]
+#[test_case(cycle(100_000), CompressionLevel::FASTEST; "exactly one block")]
+#[test_case(text_x8(), CompressionLevel::FASTEST; "several blocks")]
+fn test_reference_tests(data: Vec<u8>, level: CompressionLevel) {
+    if !bzip2_binary_test(&compress(&data, level)) {
+        eprintln!("bzip2 isn't installed, skipped");
+    }
+}
+
+/// `len` bytes without any runs, cycling through 251 values
+fn cycle(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i % 251) as u8).collect()
+}
+
+/// Blocks that take every stage down its degenerate path: RLE1 collapsing a run to almost
+/// nothing, the BWT of a string whose rotations are all equal, an MTF stack of a single byte that
+/// leaves only RUNA, RUNB and EOB, and Huffman tables for that alphabet of three symbols, which
+/// still give a code to the ones that never occur.
+#[test_case(Vec::new(), CompressionLevel::BEST, 0; "empty")]
+#[test_case(b"a".to_vec(), CompressionLevel::BEST, 1; "a single byte")]
+#[test_case((0..=255).collect(), CompressionLevel::BEST, 1; "every byte once")]
+#[test_case(vec![0xff; 900_000], CompressionLevel::BEST, 1; "a run of 900 000 bytes")]
+#[test_case(b"aaaa".to_vec(), CompressionLevel::BEST, 1; "four equal bytes")]
+#[test_case(b"ab".repeat(300_000), CompressionLevel::FASTEST, 6; "two alternating bytes")]
+#[test_case(cycle(100_000), CompressionLevel::FASTEST, 1; "exactly one full block")]
+#[test_case(cycle(100_001), CompressionLevel::FASTEST, 2; "one byte past a block")]
+fn test_degenerate_blocks(data: Vec<u8>, level: CompressionLevel, blocks: usize) {
+    let compressed = compress(&data, level);
+    let mut reader = BitReader::new(&compressed);
+    let found: Vec<_> = Blocks::new(&mut reader)
+        .unwrap()
+        .map(Result::unwrap)
+        .collect();
+    assert_eq!(found.len(), blocks);
+    for block in &found {
+        // every symbol of the alphabet, at least RUNA, RUNB and EOB, has a code in every table
+        for lengths in &block.code_lengths {
+            assert!(lengths.len() >= 3 && !lengths.contains(&0), "{lengths:?}");
+        }
+    }
+    assert_eq!(decompress(&compressed), Ok(data.clone()));
+    assert!(
+        bzip2_crate_decompress(&compressed) == data,
+        "The bzip2 crate decompressed other data, {}",
+        dump(&compressed)
+    );
+    if let Some(out) = bzip2_binary_decompress(&compressed) {
+        assert!(
+            out == data,
+            "bzip2 -dc decompressed other data, {}",
+            dump(&compressed)
+        );
+    }
+}
+
+#[test]
+fn test_reference_decompresses_encoder_output() {
+    let data = text_x8();
+    let mut encoder = BzEncoder::new(Vec::new(), CompressionLevel::FASTEST);
+    for chunk in data.chunks(4096) {
+        encoder.write_all(chunk).unwrap();
+    }
+    let compressed = encoder.finish().unwrap();
+    assert!(
+        bzip2_crate_decompress(&compressed) == data,
+        "The bzip2 crate decompressed other data, {}",
+        dump(&compressed)
+    );
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(64))]
+
+    #[test]
+    fn prop_reference_decompresses(
+        data in prop::collection::vec(prop_oneof![any::<u8>(), Just(b'a')], 0..4096),
+        level in prop::sample::select(&CompressionLevel::ALL[..]),
+    ) {
+        prop_assert_eq!(bzip2_crate_decompress(&compress(&data, level)), data);
+    }
+}